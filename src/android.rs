@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::path::Path;
+use crate::error::Result;
+use crate::paths::open_file;
+use serde::Serialize;
+use crate::ext4::Ext4;
+use crate::image::list_parts;
+use crate::sparse::Volume;
+
+/// Where build.prop lives: system-as-root images keep it under /system,
+/// older system and vendor images at the root.
+const BUILD_PROP_PATHS: [&str; 3] = ["system/build.prop", "build.prop", "etc/build.prop"];
+
+/// Android build details read from a partition's build.prop.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub part: String,
+    /// Path of the build.prop inside the partition's filesystem.
+    pub path: String,
+    pub fingerprint: Option<String>,
+    pub security_patch: Option<String>,
+    pub android_version: Option<String>,
+}
+
+fn parse_props(text: &str) -> HashMap<&str, &str> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect()
+}
+
+/// First of `keys` present, so vendor and system partitions' prefixed
+/// properties (`ro.vendor.build.*`, `ro.system.build.*`) are found too.
+fn first_prop(props: &HashMap<&str, &str>, keys: &[&str]) -> Option<String> {
+    keys.iter().find_map(|key| props.get(key)).map(|value| value.to_string())
+}
+
+/// Looks for build.prop in every ext4 (or sparse ext4) partition of an RKFW or
+/// RKAF image and returns the build fingerprint, security patch level and
+/// Android version found there.
+pub fn android_build_info(image_path: impl AsRef<Path>) -> Result<Vec<BuildInfo>> {
+    let mut fp = open_file(image_path)?;
+    let mut found = Vec::new();
+    for part in list_parts(&mut fp)? {
+        let volume = Volume::open(&mut fp, part.file_offset, part.byte_count)?;
+        let Some(mut fs) = Ext4::open(&mut fp, volume)? else {
+            continue;
+        };
+
+        for path in BUILD_PROP_PATHS {
+            // A damaged filesystem should not stop the other partitions being inspected
+            let Ok(Some(data)) = fs.read_file(path) else {
+                continue;
+            };
+            let text = String::from_utf8_lossy(&data);
+            let props = parse_props(&text);
+            found.push(BuildInfo {
+                part: part.name.clone(),
+                path: format!("/{}", path),
+                fingerprint: first_prop(&props, &["ro.build.fingerprint", "ro.system.build.fingerprint", "ro.vendor.build.fingerprint"]),
+                security_patch: first_prop(&props, &["ro.build.version.security_patch", "ro.vendor.build.security_patch"]),
+                android_version: first_prop(&props, &["ro.build.version.release", "ro.system.build.version.release", "ro.vendor.build.version.release"]),
+            });
+            break;
+        }
+    }
+    Ok(found)
+}
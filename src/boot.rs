@@ -0,0 +1,394 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::error::{AfptoolError, IoContext, Result};
+use chrono::{Datelike, Timelike};
+use crate::image::{list_parts, rkfw_sections};
+use crate::pack::rkcrc32;
+use crate::paths::{join_path, open_file};
+use crate::text::clean_line;
+use crate::{RKAF_SIGNATURE, RKFW_SIGNATURE};
+
+const HEADER_SIZE: usize = 0x66;
+const ENTRY_SIZE: usize = 0x39;
+const NAME_CHARS: usize = 20;
+
+/// File describing an unpacked loader, written next to its entries.
+pub(crate) const BOOT_METADATA_FILE: &str = "boot-metadata.txt";
+
+/// The RC4 key all Rockchip tools use to scramble loader code.
+const RC4_KEY: [u8; 16] = [124, 78, 3, 4, 85, 5, 9, 7, 45, 44, 123, 56, 23, 13, 23, 17];
+
+/// Scrambles or unscrambles loader data (RC4 is its own inverse). As in the
+/// IDB on flash, every 512-byte sector is run through a fresh keystream.
+pub(crate) fn rc4_sectors(data: &mut [u8]) {
+    for sector in data.chunks_mut(512) {
+        let mut state: [u8; 256] = std::array::from_fn(|i| i as u8);
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(RC4_KEY[i % RC4_KEY.len()]);
+            state.swap(i, j as usize);
+        }
+        let (mut i, mut j) = (0u8, 0u8);
+        for byte in sector {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(state[i as usize]);
+            state.swap(i as usize, j as usize);
+            *byte ^= state[state[i as usize].wrapping_add(state[j as usize]) as usize];
+        }
+    }
+}
+
+/// Which table of the loader an entry belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Run by the mask ROM first, usually DDR init.
+    Code471,
+    /// Run next by the mask ROM, usually usbplug.
+    Code472,
+    /// Written to flash as the idbloader (FlashData, FlashBoot).
+    Loader,
+}
+
+impl EntryKind {
+    fn type_code(self) -> u32 {
+        match self {
+            EntryKind::Code471 => 1,
+            EntryKind::Code472 => 2,
+            EntryKind::Loader => 4,
+        }
+    }
+
+    fn parse(label: &str) -> Result<EntryKind> {
+        match label {
+            "471" => Ok(EntryKind::Code471),
+            "472" => Ok(EntryKind::Code472),
+            "loader" => Ok(EntryKind::Loader),
+            _ => Err(AfptoolError::Parse(format!("Unknown loader entry kind: {}", label))),
+        }
+    }
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            EntryKind::Code471 => "471",
+            EntryKind::Code472 => "472",
+            EntryKind::Loader => "loader",
+        })
+    }
+}
+
+/// One entry of a loader, with its data as stored in the blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoaderEntry {
+    pub kind: EntryKind,
+    pub name: String,
+    /// Milliseconds the mask ROM waits after running the entry.
+    pub delay: u32,
+    pub data: Vec<u8>,
+}
+
+impl LoaderEntry {
+    /// File name the entry is unpacked to, e.g. `471-DDR.bin`.
+    pub fn file_name(&self) -> String {
+        let name: String = self.name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' })
+            .collect();
+        format!("{}-{}.bin", self.kind, name)
+    }
+}
+
+/// A Rockchip loader (the BOOT section of an RKFW image, or MiniLoaderAll.bin)
+/// as written by boot_merger: a header, three entry tables and the entry data,
+/// followed by a CRC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootLoader {
+    /// `BOOT` or `LDR `.
+    pub tag: [u8; 4],
+    pub version: u32,
+    pub merge_version: u32,
+    /// Year (little endian), month, day, hour, minute and second.
+    pub release_time: [u8; 7],
+    pub chip: u32,
+    pub sign_flag: u8,
+    /// Zero when the entries are RC4-scrambled.
+    pub rc4_flag: u8,
+    pub entries: Vec<LoaderEntry>,
+    /// Whether the trailing CRC matched when the loader was parsed.
+    pub crc_ok: bool,
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn parse_number(key: &str, value: &str) -> Result<u32> {
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| AfptoolError::Parse(format!("Invalid {} in {}: {}", key, BOOT_METADATA_FILE, value)))
+}
+
+impl BootLoader {
+    pub fn parse(data: &[u8]) -> Result<BootLoader> {
+        if data.len() < HEADER_SIZE || !(&data[..4] == b"BOOT" || &data[..4] == b"LDR ") {
+            return Err(AfptoolError::Parse("Not a Rockchip loader (no BOOT or LDR header)".to_string()));
+        }
+
+        let mut entries = Vec::new();
+        let mut end = HEADER_SIZE;
+        for (kind, table) in [(EntryKind::Code471, 25), (EntryKind::Code472, 31), (EntryKind::Loader, 37)] {
+            let (count, table_offset, entry_size) = (data[table] as usize, u32_at(data, table + 1) as usize, data[table + 5] as usize);
+            if count > 0 && entry_size != ENTRY_SIZE {
+                return Err(AfptoolError::Parse(format!("Loader {} entry size is {:#x}, expected {:#x}", kind, entry_size, ENTRY_SIZE)));
+            }
+            for i in 0..count {
+                let at = table_offset + i * ENTRY_SIZE;
+                let entry = data.get(at..at + ENTRY_SIZE)
+                    .ok_or_else(|| AfptoolError::Parse(format!("Loader {} entry table runs past the end of the loader", kind)))?;
+                let name: Vec<u16> = entry[5..5 + NAME_CHARS * 2]
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                    .take_while(|&c| c != 0)
+                    .collect();
+                let (offset, size) = (u32_at(entry, 45) as usize, u32_at(entry, 49) as usize);
+                let entry_data = data.get(offset..offset + size)
+                    .ok_or_else(|| AfptoolError::Parse(format!("Data of loader entry {} runs past the end of the loader", String::from_utf16_lossy(&name))))?;
+                end = end.max(offset + size);
+                entries.push(LoaderEntry {
+                    kind,
+                    name: String::from_utf16_lossy(&name),
+                    delay: u32_at(entry, 53),
+                    data: entry_data.to_vec(),
+                });
+            }
+        }
+
+        let crc_ok = data.len() >= end + 4 && u32_at(data, end) == rkcrc32(0, &data[..end]);
+        Ok(BootLoader {
+            tag: data[..4].try_into().unwrap(),
+            version: u32_at(data, 6),
+            merge_version: u32_at(data, 10),
+            release_time: data[14..21].try_into().unwrap(),
+            chip: u32_at(data, 21),
+            sign_flag: data[43],
+            rc4_flag: data[44],
+            entries,
+            crc_ok,
+        })
+    }
+
+    /// Release time as `YYYY-MM-DD HH:MM:SS`.
+    pub fn release_time_text(&self) -> String {
+        let t = &self.release_time;
+        format!("{}-{:02}-{:02} {:02}:{:02}:{:02}", u16::from_le_bytes([t[0], t[1]]), t[2], t[3], t[4], t[5], t[6])
+    }
+
+    /// Whether the entry data is RC4-scrambled.
+    pub fn scrambled(&self) -> bool {
+        self.rc4_flag == 0
+    }
+
+    /// Writes every entry to `{kind}-{name}.bin` in `dst_path`, plus
+    /// boot-metadata.txt with the header fields and the entry order. With
+    /// `decrypt`, scrambled entries are written as plain binaries and marked
+    /// so [`BootLoader::load_dir`] scrambles them again.
+    pub fn save_dir(&self, dst_path: impl AsRef<Path>, decrypt: bool) -> Result<()> {
+        let dst_path = dst_path.as_ref();
+        let decrypt = decrypt && self.scrambled();
+        std::fs::create_dir_all(dst_path)?;
+        let mut metadata = File::create(join_path(dst_path, BOOT_METADATA_FILE))?;
+        writeln!(metadata, "TAG: {}", String::from_utf8_lossy(&self.tag))?;
+        writeln!(metadata, "VERSION: {:#010x}", self.version)?;
+        writeln!(metadata, "MERGE_VERSION: {:#010x}", self.merge_version)?;
+        writeln!(metadata, "RELEASE_TIME: {}", self.release_time_text())?;
+        writeln!(metadata, "CHIP: {:#010x}", self.chip)?;
+        writeln!(metadata, "SIGN_FLAG: {}", self.sign_flag)?;
+        writeln!(metadata, "RC4_FLAG: {}", self.rc4_flag)?;
+        if decrypt {
+            writeln!(metadata, "DECRYPTED: 1")?;
+        }
+        writeln!(metadata, "# ENTRY: kind,name,file,delay")?;
+        for entry in &self.entries {
+            let file_name = entry.file_name();
+            let mut data = entry.data.clone();
+            if decrypt {
+                rc4_sectors(&mut data);
+            }
+            std::fs::write(join_path(dst_path, &file_name), &data)?;
+            writeln!(metadata, "ENTRY: {},{},{},{}", entry.kind, entry.name, file_name, entry.delay)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a loader unpacked by [`BootLoader::save_dir`], picking up any
+    /// entry files that were replaced since.
+    pub fn load_dir(input_dir: impl AsRef<Path>) -> Result<BootLoader> {
+        let input_dir = input_dir.as_ref();
+        let metadata_path = join_path(input_dir, BOOT_METADATA_FILE);
+        let metadata = std::fs::read_to_string(&metadata_path)
+            .with_context(|| format!("Cannot read {}", metadata_path.display()))?;
+
+        let mut loader = BootLoader {
+            tag: *b"BOOT",
+            version: 0,
+            merge_version: 0,
+            release_time: [0; 7],
+            chip: 0,
+            sign_flag: 0,
+            rc4_flag: 0,
+            entries: Vec::new(),
+            crc_ok: true,
+        };
+        let mut decrypted = false;
+        for line in metadata.lines().map(clean_line) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once(':')
+                .ok_or_else(|| AfptoolError::Parse(format!("Invalid line in {}: {}", BOOT_METADATA_FILE, line)))?;
+            let value = value.trim();
+            match key.trim() {
+                "TAG" => {
+                    loader.tag = format!("{:4}", value).as_bytes().try_into()
+                        .map_err(|_| AfptoolError::Parse(format!("Invalid TAG in {}: {}", BOOT_METADATA_FILE, value)))?;
+                }
+                "VERSION" => loader.version = parse_number(key, value)?,
+                "MERGE_VERSION" => loader.merge_version = parse_number(key, value)?,
+                "RELEASE_TIME" => {
+                    let time = chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+                        .map_err(|_| AfptoolError::Parse(format!("Invalid RELEASE_TIME in {}: {}", BOOT_METADATA_FILE, value)))?;
+                    let year = (time.year() as u16).to_le_bytes();
+                    loader.release_time = [
+                        year[0], year[1], time.month() as u8, time.day() as u8,
+                        time.hour() as u8, time.minute() as u8, time.second() as u8,
+                    ];
+                }
+                "CHIP" => loader.chip = parse_number(key, value)?,
+                "SIGN_FLAG" => loader.sign_flag = parse_number(key, value)? as u8,
+                "RC4_FLAG" => loader.rc4_flag = parse_number(key, value)? as u8,
+                "DECRYPTED" => decrypted = parse_number(key, value)? != 0,
+                "ENTRY" => {
+                    let fields: Vec<&str> = value.split(',').map(str::trim).collect();
+                    let [kind, name, file, delay] = fields[..] else {
+                        return Err(AfptoolError::Parse(format!("Invalid ENTRY in {}: {}", BOOT_METADATA_FILE, value)));
+                    };
+                    if name.encode_utf16().count() >= NAME_CHARS {
+                        return Err(AfptoolError::Layout(format!("Loader entry name {} is longer than {} characters", name, NAME_CHARS - 1)));
+                    }
+                    let path = join_path(input_dir, file);
+                    let data = std::fs::read(&path).with_context(|| format!("Cannot read {}", path.display()))?;
+                    loader.entries.push(LoaderEntry {
+                        kind: EntryKind::parse(kind)?,
+                        name: name.to_string(),
+                        delay: parse_number("delay", delay)?,
+                        data,
+                    });
+                }
+                other => return Err(AfptoolError::Parse(format!("Unknown key {} in {}", other, BOOT_METADATA_FILE))),
+            }
+        }
+        if decrypted {
+            for entry in &mut loader.entries {
+                rc4_sectors(&mut entry.data);
+            }
+        }
+        Ok(loader)
+    }
+
+    /// Builds the loader blob: header, the 471, 472 and loader tables, the
+    /// entry data in table order and the CRC over all of it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let kinds = [EntryKind::Code471, EntryKind::Code472, EntryKind::Loader];
+        let ordered: Vec<&LoaderEntry> = kinds.iter()
+            .flat_map(|kind| self.entries.iter().filter(move |entry| entry.kind == *kind))
+            .collect();
+
+        let mut data = vec![0u8; HEADER_SIZE];
+        data[..4].copy_from_slice(&self.tag);
+        data[4..6].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+        data[6..10].copy_from_slice(&self.version.to_le_bytes());
+        data[10..14].copy_from_slice(&self.merge_version.to_le_bytes());
+        data[14..21].copy_from_slice(&self.release_time);
+        data[21..25].copy_from_slice(&self.chip.to_le_bytes());
+        let mut table_offset = HEADER_SIZE;
+        for (kind, field) in kinds.iter().zip([25usize, 31, 37]) {
+            let count = self.entries.iter().filter(|entry| entry.kind == *kind).count();
+            data[field] = count as u8;
+            data[field + 1..field + 5].copy_from_slice(&(table_offset as u32).to_le_bytes());
+            data[field + 5] = ENTRY_SIZE as u8;
+            table_offset += count * ENTRY_SIZE;
+        }
+        data[43] = self.sign_flag;
+        data[44] = self.rc4_flag;
+
+        let mut data_offset = table_offset;
+        for entry in &ordered {
+            let mut record = [0u8; ENTRY_SIZE];
+            record[0] = ENTRY_SIZE as u8;
+            record[1..5].copy_from_slice(&entry.kind.type_code().to_le_bytes());
+            for (i, c) in entry.name.encode_utf16().take(NAME_CHARS - 1).enumerate() {
+                record[5 + i * 2..7 + i * 2].copy_from_slice(&c.to_le_bytes());
+            }
+            record[45..49].copy_from_slice(&(data_offset as u32).to_le_bytes());
+            record[49..53].copy_from_slice(&(entry.data.len() as u32).to_le_bytes());
+            record[53..57].copy_from_slice(&entry.delay.to_le_bytes());
+            data.extend_from_slice(&record);
+            data_offset += entry.data.len();
+        }
+        for entry in &ordered {
+            data.extend_from_slice(&entry.data);
+        }
+        let crc = rkcrc32(0, &data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data
+    }
+}
+
+/// Rebuilds a loader from a directory written by [`unpack_boot`], so a DDR
+/// init or miniloader entry can be swapped without a donor loader. Entries
+/// unpacked with `decrypt` are scrambled again.
+pub fn pack_boot(input_dir: impl AsRef<Path>, output_path: impl AsRef<Path>) -> Result<BootLoader> {
+    let loader = BootLoader::load_dir(input_dir)?;
+    if loader.entries.is_empty() {
+        return Err(AfptoolError::Layout(format!("No loader entries listed in {}", BOOT_METADATA_FILE)));
+    }
+    std::fs::write(output_path, loader.to_bytes())?;
+    Ok(loader)
+}
+
+/// Reads the loader out of a loader file, the BOOT section of an RKFW image
+/// or the bootloader partition of an RKAF image.
+pub(crate) fn read_loader_blob(input_path: &Path) -> Result<Vec<u8>> {
+    let mut fp = open_file(input_path)?;
+    let mut magic = [0u8; 4];
+    fp.read_exact(&mut magic)?;
+    let (offset, len) = match &magic[..] {
+        RKFW_SIGNATURE => rkfw_sections(&mut fp)?.unwrap_or_default()[0],
+        RKAF_SIGNATURE => {
+            let part = list_parts(&mut fp)?
+                .into_iter()
+                .find(|part| part.name == "bootloader")
+                .ok_or_else(|| AfptoolError::Parse(format!("{} has no bootloader partition", input_path.display())))?;
+            (part.file_offset, part.byte_count as u64)
+        }
+        _ => (0, fp.metadata()?.len()),
+    };
+    let mut data = vec![0u8; len as usize];
+    fp.seek(SeekFrom::Start(offset))?;
+    fp.read_exact(&mut data)
+        .map_err(|_| AfptoolError::Parse(format!("Loader in {} is truncated", input_path.display())))?;
+    Ok(data)
+}
+
+/// Splits a loader into its 471, 472 and loader entries, as described in
+/// [`BootLoader::save_dir`]. `input_path` may be a loader file or an RKFW or
+/// RKAF image containing one.
+pub fn unpack_boot(input_path: impl AsRef<Path>, dst_path: impl AsRef<Path>, decrypt: bool) -> Result<BootLoader> {
+    let loader = BootLoader::parse(&read_loader_blob(input_path.as_ref())?)?;
+    loader.save_dir(dst_path, decrypt)?;
+    Ok(loader)
+}
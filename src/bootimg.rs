@@ -0,0 +1,153 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use crate::error::{AfptoolError, Result};
+use serde::Serialize;
+use crate::image::cstr_field;
+
+pub(crate) const BOOT_MAGIC: &[u8; 8] = b"ANDROID!";
+/// Header bytes [`BootImage::parse`] reads, enough for every version up to 4.
+pub(crate) const BOOT_HEADER_LEN: usize = 1660;
+/// Page size of version 3 and later headers, which no longer store one.
+const FIXED_PAGE_SIZE: u64 = 4096;
+
+/// Layout of an Android boot or recovery image (`ANDROID!`). Each section
+/// starts on a page boundary after the header page, in the order listed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BootImage {
+    pub header_version: u32,
+    pub page_size: u64,
+    /// Name, offset in the image and size of each non-empty section.
+    pub sections: Vec<(&'static str, u64, u64)>,
+    /// Board name; version 3 and later headers have none.
+    pub name: String,
+    pub cmdline: String,
+    /// Packed Android version and security patch level.
+    pub os_version: u32,
+}
+
+impl BootImage {
+    /// Parses the header at the start of a `len`-byte image, or returns
+    /// `None` when it has no boot image magic.
+    pub(crate) fn parse(header: &[u8], len: u64) -> Result<Option<BootImage>> {
+        if !header.starts_with(BOOT_MAGIC) {
+            return Ok(None);
+        }
+        if header.len() < 0x30 {
+            return Err(AfptoolError::Parse("Android boot image header is truncated".to_string()));
+        }
+        // Fields past a short header (a tiny image) read as zero
+        let field = |at: usize| header.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as u64).unwrap_or(0);
+        let header_version = field(40) as u32;
+        let text = |from: usize, to: usize| header.get(from..to.min(header.len())).map(cstr_field).unwrap_or_default();
+        let (page_size, sizes, name, cmdline, os_version) = match header_version {
+            0..=2 => {
+                let mut sizes = vec![("kernel", field(8)), ("ramdisk", field(16)), ("second", field(24))];
+                if header_version >= 1 {
+                    sizes.push(("recovery_dtbo", field(1632)));
+                }
+                if header_version == 2 {
+                    sizes.push(("dtb", field(1648)));
+                }
+                // The extra command line carries on where the first one ends
+                (field(36), sizes, text(48, 64), text(64, 576) + &text(608, 1632), field(44))
+            }
+            3 | 4 => {
+                let mut sizes = vec![("kernel", field(8)), ("ramdisk", field(12))];
+                if header_version == 4 {
+                    sizes.push(("signature", field(1580)));
+                }
+                (FIXED_PAGE_SIZE, sizes, String::new(), text(44, 1580), field(16))
+            }
+            version => return Err(AfptoolError::Parse(format!("Unknown Android boot image header version {}", version))),
+        };
+        if !page_size.is_power_of_two() || page_size < 2048 {
+            return Err(AfptoolError::Parse(format!("Android boot image has an invalid page size {}", page_size)));
+        }
+
+        let mut offset = page_size;
+        let mut sections = Vec::new();
+        for (name, size) in sizes {
+            if size == 0 {
+                continue;
+            }
+            if offset + size > len {
+                return Err(AfptoolError::Parse(format!(
+                    "Android boot image {} at {:#x}+{:#x} runs past the end of the {:#x}-byte image", name, offset, size, len
+                )));
+            }
+            sections.push((name, offset, size));
+            offset += size.div_ceil(page_size) * page_size;
+        }
+        Ok(Some(BootImage { header_version, page_size, sections, name, cmdline, os_version: os_version as u32 }))
+    }
+}
+
+/// One section of an Android boot image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BootSection {
+    /// `kernel`, `ramdisk`, `second`, `recovery_dtbo`, `dtb` or `signature`.
+    pub name: String,
+    /// Offset from the start of the boot image.
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// What `info` reports about an Android boot or recovery image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BootImageInfo {
+    /// Partition holding the image, when it was found inside a firmware image.
+    pub part: Option<String>,
+    pub header_version: u32,
+    pub page_size: u64,
+    /// Non-empty sections, in image order.
+    pub sections: Vec<BootSection>,
+    pub name: String,
+    pub cmdline: String,
+    /// Android version, e.g. `11.0.0`, when the header records one.
+    pub os_version: Option<String>,
+    /// Security patch level as `YYYY-MM`.
+    pub os_patch_level: Option<String>,
+}
+
+impl BootImageInfo {
+    fn new(part: Option<String>, boot: BootImage) -> BootImageInfo {
+        // Version a.b.c and patch year/month packed as 7/7/7 and 7/4 bits
+        let version = boot.os_version >> 11;
+        let level = boot.os_version & 0x7ff;
+        BootImageInfo {
+            part,
+            header_version: boot.header_version,
+            page_size: boot.page_size,
+            sections: boot.sections.into_iter()
+                .map(|(name, offset, size)| BootSection { name: name.to_string(), offset, size })
+                .collect(),
+            name: boot.name,
+            cmdline: boot.cmdline,
+            os_version: (version != 0).then(|| format!("{}.{}.{}", version >> 14, (version >> 7) & 0x7f, version & 0x7f)),
+            os_patch_level: (level != 0).then(|| format!("{}-{:02}", 2000 + (level >> 4), level & 0xf)),
+        }
+    }
+
+    /// Size of the named section, 0 when the image has none.
+    pub fn section_size(&self, name: &str) -> u64 {
+        self.sections.iter().find(|section| section.name == name).map_or(0, |section| section.size)
+    }
+}
+
+/// Reads the Android boot image header of `len` bytes at `offset`, or
+/// returns `None` when there is none.
+pub(crate) fn read_boot_image_at(fp: &mut (impl Read + Seek), offset: u64, len: u64, part: Option<String>) -> Result<Option<BootImageInfo>> {
+    let mut header = Vec::with_capacity(BOOT_HEADER_LEN);
+    fp.seek(SeekFrom::Start(offset))?;
+    fp.take(len.min(BOOT_HEADER_LEN as u64)).read_to_end(&mut header)?;
+    Ok(BootImage::parse(&header, len)?.map(|boot| BootImageInfo::new(part, boot)))
+}
+
+/// Summarizes an extracted boot.img or recovery.img, or returns `None` when
+/// the file has no `ANDROID!` header.
+pub fn boot_image_info(path: impl AsRef<Path>) -> Result<Option<BootImageInfo>> {
+    let mut file = File::open(path.as_ref())?;
+    let len = file.metadata()?.len();
+    read_boot_image_at(&mut file, 0, len, None)
+}
@@ -0,0 +1,347 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use crate::error::{AfptoolError, IoContext, Result};
+use crate::paths::open_file;
+use crate::chips::chip_name_to_code;
+use crate::layout::check_flash_layout;
+use crate::pack::{rkfw_header, set_header_text, CrcWriter, RKAF_ALIGNMENT};
+use crate::{UpdateHeader, UpdatePart, MAX_FULL_PATH_LEN, MAX_NAME_LEN, MAX_PARTS, RKAF_SIGNATURE, UPDATE_HEADER_SIZE};
+
+/// Where the contents of an [`RkafPart`] come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartData {
+    /// Read from this file when the image is written.
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+/// One partition of an image built with [`RkafBuilder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RkafPart {
+    pub name: String,
+    /// Path stored in the header, e.g. `Image/boot.img`.
+    pub full_path: String,
+    /// Start and size on flash in 512-byte sectors; 0 for entries such as
+    /// `package-file` that are not written to flash.
+    pub flash_offset: u32,
+    pub flash_size: u32,
+    pub data: PartData,
+}
+
+impl RkafPart {
+    pub fn from_file(name: &str, full_path: &str, path: impl AsRef<Path>) -> RkafPart {
+        RkafPart::new(name, full_path, PartData::File(path.as_ref().to_path_buf()))
+    }
+
+    pub fn from_bytes(name: &str, full_path: &str, data: impl Into<Vec<u8>>) -> RkafPart {
+        RkafPart::new(name, full_path, PartData::Bytes(data.into()))
+    }
+
+    fn new(name: &str, full_path: &str, data: PartData) -> RkafPart {
+        RkafPart { name: name.to_string(), full_path: full_path.to_string(), flash_offset: 0, flash_size: 0, data }
+    }
+
+    /// Places the partition on flash, in 512-byte sectors.
+    pub fn at(mut self, flash_offset: u32, flash_size: u32) -> RkafPart {
+        self.flash_offset = flash_offset;
+        self.flash_size = flash_size;
+        self
+    }
+
+    fn data_len(&self) -> Result<u64> {
+        match &self.data {
+            PartData::File(path) => std::fs::metadata(path)
+                .map(|metadata| metadata.len())
+                .with_context(|| format!("Cannot open {}", path.display())),
+            PartData::Bytes(bytes) => Ok(bytes.len() as u64),
+        }
+    }
+}
+
+/// Assembles an RKAF update image from partitions given in code, for callers
+/// that have no unpacked tree with a `package-file` and
+/// `partition-metadata.txt` to hand to [`crate::pack_rkaf`].
+#[derive(Debug, Clone)]
+pub struct RkafBuilder {
+    model: String,
+    manufacturer: String,
+    machine_id: String,
+    version: u32,
+    gbk: bool,
+    alignment: u32,
+    parts: Vec<RkafPart>,
+}
+
+impl RkafBuilder {
+    pub fn new(model: &str, manufacturer: &str) -> RkafBuilder {
+        RkafBuilder {
+            model: model.to_string(),
+            manufacturer: manufacturer.to_string(),
+            machine_id: String::new(),
+            version: 0x01000000,
+            gbk: false,
+            alignment: RKAF_ALIGNMENT as u32,
+            parts: Vec::new(),
+        }
+    }
+
+    /// Machine id the update is restricted to; empty (the default) accepts
+    /// any device.
+    pub fn machine_id(mut self, machine_id: &str) -> RkafBuilder {
+        self.machine_id = machine_id.to_string();
+        self
+    }
+
+    /// Header version as `major.minor.build`, 1.0.0 unless set.
+    pub fn version(mut self, version: &str) -> Result<RkafBuilder> {
+        let mut header = UpdateHeader::default();
+        header.set_version(version)?;
+        self.version = header.version;
+        Ok(self)
+    }
+
+    /// Stores model and manufacturer in GBK rather than UTF-8.
+    pub fn gbk(mut self, gbk: bool) -> RkafBuilder {
+        self.gbk = gbk;
+        self
+    }
+
+    /// Boundary each partition's data starts on, 2048 bytes unless set.
+    pub fn alignment(mut self, alignment: u32) -> RkafBuilder {
+        self.alignment = alignment;
+        self
+    }
+
+    pub fn part(mut self, part: RkafPart) -> RkafBuilder {
+        self.parts.push(part);
+        self
+    }
+
+    /// Header and the `(length, padded length)` of each partition's data.
+    fn layout(&self) -> Result<(UpdateHeader, Vec<(u64, u64)>)> {
+        if self.alignment == 0 {
+            return Err(AfptoolError::Layout("Alignment must not be zero".to_string()));
+        }
+        if self.parts.is_empty() || self.parts.len() > MAX_PARTS {
+            return Err(AfptoolError::Layout(format!(
+                "An RKAF image holds 1 to {} partitions, not {}", MAX_PARTS, self.parts.len()
+            )));
+        }
+        let alignment = self.alignment as u64;
+        let mut header = UpdateHeader::default();
+        header.magic.copy_from_slice(RKAF_SIGNATURE);
+        set_header_text(&mut header.model, &self.model, self.gbk)?;
+        set_header_text(&mut header.manufacturer, &self.manufacturer, self.gbk)?;
+        header.set_id(&self.machine_id);
+        header.version = self.version;
+        header.num_parts = self.parts.len() as u32;
+
+        let mut offset = (UPDATE_HEADER_SIZE as u64).div_ceil(alignment) * alignment;
+        let mut sizes = Vec::new();
+        for (entry, part) in header.parts.iter_mut().zip(&self.parts) {
+            let len = part.data_len()?;
+            let padded = len.div_ceil(alignment) * alignment;
+            let mut fields = UpdatePart::default();
+            let len_name = part.name.len().min(MAX_NAME_LEN - 1);
+            fields.name[..len_name].copy_from_slice(&part.name.as_bytes()[..len_name]);
+            let len_path = part.full_path.len().min(MAX_FULL_PATH_LEN - 1);
+            fields.full_path[..len_path].copy_from_slice(&part.full_path.as_bytes()[..len_path]);
+            fields.flash_offset = part.flash_offset;
+            fields.flash_size = part.flash_size;
+            fields.set_offset(offset)?;
+            fields.set_byte_count(len)?;
+            fields.padded_size = padded as u32;
+            *entry = fields;
+            sizes.push((len, padded));
+            offset += padded;
+        }
+        header.set_image_length(offset);
+        check_flash_layout(&header, self.alignment)?;
+        Ok((header, sizes))
+    }
+
+    /// Size in bytes of the image [`RkafBuilder::write_to`] produces,
+    /// trailing CRC included.
+    pub fn image_size(&self) -> Result<u64> {
+        Ok(self.layout()?.0.image_length()? + 4)
+    }
+
+    /// Writes the image front to back, so `out` can be a pipe or socket as
+    /// well as a file. Returns the number of bytes written.
+    pub fn write_to(&self, out: &mut impl Write) -> Result<u64> {
+        let (header, sizes) = self.layout()?;
+        let mut out = CrcWriter::new(out);
+        let header_bytes = header.to_bytes();
+        out.write_all(&header_bytes)?;
+        let header_end = header.parts[0].offset();
+        write_zeros(&mut out, header_end - header_bytes.len() as u64)?;
+        for (part, (len, padded)) in self.parts.iter().zip(sizes) {
+            let copied = match &part.data {
+                PartData::File(path) => {
+                    let file = File::open(path).with_context(|| format!("Cannot open {}", path.display()))?;
+                    io::copy(&mut file.take(len), &mut out)?
+                }
+                PartData::Bytes(bytes) => {
+                    out.write_all(bytes)?;
+                    len
+                }
+            };
+            if copied != len {
+                return Err(AfptoolError::changed(format!("{} changed size while the image was written", part.name)));
+            }
+            write_zeros(&mut out, padded - len)?;
+        }
+        let crc = out.crc;
+        out.inner.write_all(&crc.to_le_bytes())?;
+        out.inner.flush()?;
+        Ok(header.image_length()? + 4)
+    }
+
+    pub fn write_file(&self, path: impl AsRef<Path>) -> Result<u64> {
+        let mut out = io::BufWriter::new(File::create(path)?);
+        self.write_to(&mut out)
+    }
+}
+
+/// The RKAF image an [`RkfwBuilder`] wraps.
+#[derive(Debug, Clone)]
+pub enum RkafPayload {
+    File(PathBuf),
+    Bytes(Vec<u8>),
+    /// Built while the RKFW image is written, without an intermediate file.
+    Builder(RkafBuilder),
+}
+
+impl RkafPayload {
+    /// Size in bytes, after checking the payload starts like an RKAF image.
+    fn size(&self) -> Result<u64> {
+        let (name, magic, size) = match self {
+            RkafPayload::File(path) => {
+                let mut file = File::open(path).with_context(|| format!("Cannot open {}", path.display()))?;
+                let mut magic = [0u8; 4];
+                let magic = file.read_exact(&mut magic).ok().map(|_| magic.to_vec());
+                (path.display().to_string(), magic, file.metadata()?.len())
+            }
+            RkafPayload::Bytes(bytes) => ("RKAF payload".to_string(), bytes.get(..4).map(<[u8]>::to_vec), bytes.len() as u64),
+            RkafPayload::Builder(builder) => return builder.image_size(),
+        };
+        if magic.as_deref() != Some(RKAF_SIGNATURE) {
+            return Err(AfptoolError::Parse(format!("{} must be a valid RKAF file", name)));
+        }
+        Ok(size)
+    }
+}
+
+/// Wraps a loader and an RKAF image into an RKFW firmware image, as
+/// [`crate::wrap_rkaf`] does for files on disk.
+#[derive(Debug, Clone)]
+pub struct RkfwBuilder {
+    chip: String,
+    version: String,
+    timestamp: i64,
+    code: u32,
+    loader: Option<PartData>,
+    update: Option<RkafPayload>,
+}
+
+impl RkfwBuilder {
+    /// `version` is `major.minor.build` and `timestamp` the build date in
+    /// Unix seconds, both as given to `pack-rkfw`.
+    pub fn new(chip: &str, version: &str, timestamp: i64, code: u32) -> RkfwBuilder {
+        RkfwBuilder {
+            chip: chip.to_string(),
+            version: version.to_string(),
+            timestamp,
+            code,
+            loader: None,
+            update: None,
+        }
+    }
+
+    /// The BOOT section, usually a `*_loader_*.bin` from boot_merger.
+    pub fn loader(mut self, loader: PartData) -> RkfwBuilder {
+        self.loader = Some(loader);
+        self
+    }
+
+    pub fn update(mut self, update: RkafPayload) -> RkfwBuilder {
+        self.update = Some(update);
+        self
+    }
+
+    fn parts(&self) -> Result<(&PartData, &RkafPayload)> {
+        match (&self.loader, &self.update) {
+            (Some(loader), Some(update)) => Ok((loader, update)),
+            (None, _) => Err(AfptoolError::Layout("RKFW image has no loader".to_string())),
+            (_, None) => Err(AfptoolError::Layout("RKFW image has no RKAF payload".to_string())),
+        }
+    }
+
+    /// Writes the image front to back, MD5 trailer last, and returns the
+    /// number of bytes written.
+    pub fn write_to(&self, out: &mut impl Write) -> Result<u64> {
+        let (loader, update) = self.parts()?;
+        let loader_size = match loader {
+            PartData::File(path) => std::fs::metadata(path).with_context(|| format!("Cannot open {}", path.display()))?.len(),
+            PartData::Bytes(bytes) => bytes.len() as u64,
+        };
+        let update_size = update.size()?;
+        let header = rkfw_header(chip_name_to_code(&self.chip)?, &self.version, self.timestamp, self.code, loader_size, update_size, &[])?;
+
+        let mut out = Md5Writer { inner: out, context: md5::Context::new() };
+        out.write_all(&header)?;
+        let copied = match loader {
+            PartData::File(path) => io::copy(&mut open_file(path)?.take(loader_size), &mut out)?,
+            PartData::Bytes(bytes) => {
+                out.write_all(bytes)?;
+                loader_size
+            }
+        };
+        let written = match update {
+            RkafPayload::File(path) => io::copy(&mut open_file(path)?.take(update_size), &mut out)?,
+            RkafPayload::Bytes(bytes) => {
+                out.write_all(bytes)?;
+                update_size
+            }
+            RkafPayload::Builder(builder) => builder.write_to(&mut out)?,
+        };
+        if copied != loader_size || written != update_size {
+            return Err(AfptoolError::changed("Input changed size while packing"));
+        }
+
+        let Md5Writer { inner: out, context } = out;
+        let md5_hex = format!("{:x}", context.finalize());
+        out.write_all(md5_hex.as_bytes())?;
+        out.flush()?;
+        Ok(header.len() as u64 + loader_size + update_size + md5_hex.len() as u64)
+    }
+
+    pub fn write_file(&self, path: impl AsRef<Path>) -> Result<u64> {
+        let mut out = io::BufWriter::new(File::create(path)?);
+        self.write_to(&mut out)
+    }
+}
+
+fn write_zeros(out: &mut impl Write, count: u64) -> io::Result<()> {
+    io::copy(&mut io::repeat(0).take(count), out)?;
+    Ok(())
+}
+
+/// Passes writes through while hashing everything written with MD5.
+struct Md5Writer<'a, W: Write> {
+    inner: &'a mut W,
+    context: md5::Context,
+}
+
+impl<W: Write> Write for Md5Writer<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.context.consume(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
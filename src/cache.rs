@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use crate::error::Result;
+use crate::paths::{join_path, path_from_bytes, path_to_bytes};
+use crate::tree::md5_file;
+
+pub(crate) const CACHE_FILE: &str = ".afptool-pack-cache";
+
+/// Size and modification time used to decide whether a file changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileStamp {
+    pub size: u64,
+    pub mtime_ns: u128,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CachedFile {
+    pub stamp: FileStamp,
+    pub md5: String,
+    /// Offset of the file's data in the previous output image.
+    pub offset: u64,
+    /// How the file was turned into that data: `raw`, or the compression
+    /// it was unpacked from, such as `gz`.
+    pub transform: String,
+}
+
+/// Record of the last incremental pack of an input tree.
+#[derive(Debug, Default)]
+pub(crate) struct PackCache {
+    pub output: Option<(PathBuf, FileStamp)>,
+    pub files: HashMap<String, CachedFile>,
+}
+
+pub(crate) fn stamp(path: &Path) -> Option<FileStamp> {
+    let meta = std::fs::metadata(path).ok()?;
+    let mtime_ns = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+    Some(FileStamp { size: meta.len(), mtime_ns })
+}
+
+impl PackCache {
+    pub fn load(input_dir: &Path) -> PackCache {
+        let mut cache = PackCache::default();
+        let file = match File::open(join_path(input_dir, CACHE_FILE)) {
+            Ok(f) => f,
+            Err(_) => return cache,
+        };
+
+        // The output path is kept as raw bytes, so it may not be UTF-8
+        for line in BufReader::new(file).split(b'\n').map_while(Result::ok) {
+            if let Some(path) = line.strip_prefix(b"output\t") {
+                let mut fields = path.splitn(3, |&byte| byte == b'\t');
+                let (size, mtime) = (fields.next().and_then(parse_field), fields.next().and_then(parse_field));
+                if let (Some(size), Some(mtime_ns), Some(path)) = (size, mtime, fields.next()) {
+                    cache.output = Some((path_from_bytes(path), FileStamp { size, mtime_ns }));
+                }
+                continue;
+            }
+            let Ok(line) = String::from_utf8(line) else {
+                continue;
+            };
+            let fields: Vec<&str> = line.split('\t').collect();
+            if let ["file", size, mtime, md5, offset, transform, path] = fields.as_slice() {
+                if let (Ok(size), Ok(mtime_ns), Ok(offset)) = (size.parse(), mtime.parse(), offset.parse()) {
+                    cache.files.insert(path.to_string(), CachedFile {
+                        stamp: FileStamp { size, mtime_ns },
+                        md5: md5.to_string(),
+                        offset,
+                        transform: transform.to_string(),
+                    });
+                }
+            }
+        }
+        cache
+    }
+
+    pub fn save(&self, input_dir: &Path) -> Result<()> {
+        let mut file = File::create(join_path(input_dir, CACHE_FILE))?;
+        if let Some((path, stamp)) = &self.output {
+            write!(file, "output\t{}\t{}\t", stamp.size, stamp.mtime_ns)?;
+            file.write_all(&path_to_bytes(path))?;
+            writeln!(file)?;
+        }
+        let mut paths: Vec<&String> = self.files.keys().collect();
+        paths.sort();
+        for path in paths {
+            let entry = &self.files[path];
+            writeln!(
+                file,
+                "file\t{}\t{}\t{}\t{}\t{}\t{}",
+                entry.stamp.size, entry.stamp.mtime_ns, entry.md5, entry.offset, entry.transform, path
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The previous output image, if it still exists untouched.
+    pub fn previous_output(&self) -> Option<&Path> {
+        match &self.output {
+            Some((path, recorded)) if stamp(path) == Some(*recorded) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Returns the input's MD5, consulting the cache before re-reading the file,
+    /// and whether the data it was packed as is unchanged since the last pack:
+    /// the same content through the same `transform`.
+    pub fn check(&self, path: &str, full_path: &Path, current: FileStamp, transform: &str) -> Result<(String, bool)> {
+        match self.files.get(path) {
+            Some(entry) if entry.stamp == current => Ok((entry.md5.clone(), entry.transform == transform)),
+            Some(entry) if entry.stamp.size == current.size => {
+                let md5 = md5_file(full_path)?;
+                let unchanged = md5 == entry.md5 && entry.transform == transform;
+                Ok((md5, unchanged))
+            }
+            _ => Ok((md5_file(full_path)?, false)),
+        }
+    }
+}
+
+fn parse_field<T: std::str::FromStr>(field: &[u8]) -> Option<T> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
@@ -0,0 +1,119 @@
+use std::io::Read;
+use std::path::Path;
+use crate::error::{IoContext, Result};
+use serde::{Deserialize, Serialize};
+use crate::image::{cstr_field, list_parts, locate_rkaf, read_rkaf_header, read_rkfw_header};
+use crate::paths::open_file;
+use crate::tree::md5_file;
+use crate::{chip_code_to_name, RKAF_SIGNATURE, RKFW_SIGNATURE};
+
+/// Header metadata of one firmware image found by [`index_dir`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub path: String,
+    pub format: String,
+    pub size: u64,
+    pub md5: String,
+    pub chip: Option<String>,
+    pub chip_code: Option<u32>,
+    pub version: Option<String>,
+    pub code: Option<u32>,
+    pub date: Option<String>,
+    pub timestamp: Option<i64>,
+    pub model: String,
+    pub manufacturer: String,
+    pub partitions: Vec<String>,
+}
+
+/// Filters for [`query_catalog`]; unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct CatalogQuery {
+    pub chip: Option<String>,
+    pub model: Option<String>,
+    pub manufacturer: Option<String>,
+    pub version: Option<String>,
+}
+
+impl CatalogQuery {
+    fn matches(&self, entry: &CatalogEntry) -> bool {
+        let contains = |value: &str, needle: &Option<String>| {
+            needle.as_ref().is_none_or(|n| value.to_lowercase().contains(&n.to_lowercase()))
+        };
+        self.chip.as_ref().is_none_or(|chip| entry.chip.as_ref().is_some_and(|c| c.eq_ignore_ascii_case(chip)))
+            && contains(&entry.model, &self.model)
+            && contains(&entry.manufacturer, &self.manufacturer)
+            && self.version.as_ref().is_none_or(|v| entry.version.as_ref() == Some(v))
+    }
+}
+
+fn describe_image(path: &Path, relative: String) -> Result<Option<CatalogEntry>> {
+    let mut fp = open_file(path)?;
+    let mut signature = [0u8; 4];
+    if fp.read_exact(&mut signature).is_err() {
+        return Ok(None);
+    }
+    let format = match &signature[..] {
+        RKFW_SIGNATURE => "RKFW",
+        RKAF_SIGNATURE => "RKAF",
+        _ => return Ok(None),
+    };
+
+    let rkfw = read_rkfw_header(&mut fp)?;
+    let base = locate_rkaf(&mut fp)?;
+    let header = read_rkaf_header(&mut fp, base)?;
+    let partitions = list_parts(&mut fp)?.into_iter().map(|part| part.name).collect();
+
+    Ok(Some(CatalogEntry {
+        path: relative,
+        format: format.to_string(),
+        size: fp.metadata()?.len(),
+        md5: md5_file(path)?,
+        chip: rkfw.as_ref().and_then(|h| chip_code_to_name(h.chip_code)).map(str::to_string),
+        chip_code: rkfw.as_ref().map(|h| h.chip_code),
+        version: rkfw.as_ref().map(|h| h.version.clone()),
+        code: rkfw.as_ref().map(|h| h.code),
+        date: rkfw.as_ref().map(|h| h.date.clone()),
+        timestamp: rkfw.and_then(|h| h.timestamp),
+        model: cstr_field(&header.model).trim().to_string(),
+        manufacturer: cstr_field(&header.manufacturer).trim().to_string(),
+        partitions,
+    }))
+}
+
+fn scan_dir(root: &Path, dir: &Path, entries: &mut Vec<CatalogEntry>) -> Result<()> {
+    let mut children: Vec<_> = std::fs::read_dir(dir)?.collect::<std::io::Result<_>>()?;
+    children.sort_by_key(|entry| entry.file_name());
+
+    for child in children {
+        let path = child.path();
+        let file_type = child.file_type()?;
+        if file_type.is_dir() {
+            scan_dir(root, &path, entries)?;
+        } else if file_type.is_file() {
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_string_lossy().to_string();
+            // Unreadable or damaged files are skipped rather than aborting the scan
+            match describe_image(&path, relative) {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => {}
+                Err(e) => log::warn!("skipping {}: {}", path.display(), e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Scans `dir` recursively for RKFW/RKAF images and writes their metadata to `db`.
+pub fn index_dir(dir: impl AsRef<Path>, db: impl AsRef<Path>) -> Result<Vec<CatalogEntry>> {
+    let dir = dir.as_ref();
+    let mut entries = Vec::new();
+    scan_dir(dir, dir, &mut entries)?;
+    std::fs::write(db, serde_json::to_string_pretty(&entries)?)?;
+    Ok(entries)
+}
+
+pub fn query_catalog(db: impl AsRef<Path>, query: &CatalogQuery) -> Result<Vec<CatalogEntry>> {
+    let db = db.as_ref();
+    let data = std::fs::read_to_string(db).with_context(|| format!("Cannot read catalog {}", db.display()))?;
+    let entries: Vec<CatalogEntry> = serde_json::from_str(&data)?;
+    Ok(entries.into_iter().filter(|entry| query.matches(entry)).collect())
+}
@@ -0,0 +1,208 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::error::{AfptoolError, Result};
+use crate::paths::open_file;
+use crate::cancel::CancelToken;
+use crate::image::{list_parts, locate_rkaf, read_rkaf_header, read_rkfw_header, RkfwHeader};
+use crate::pack::rkcrc32;
+use crate::timings::phase;
+
+/// Outcome of one check made by [`verify_image`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageCheck {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl ImageCheck {
+    fn new(name: impl Into<String>, passed: bool, detail: String) -> ImageCheck {
+        ImageCheck { name: name.into(), passed, detail }
+    }
+}
+
+/// rkcrc32 of the RKAF image at `base` next to the one stored after its
+/// `length` bytes, or `None` for the stored value when the file ends first.
+pub(crate) fn rkaf_crc(fp: &mut (impl Read + Seek), base: u64, length: u64, cancel: &CancelToken) -> Result<(u32, Option<u32>)> {
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut checksum = 0;
+    let mut remaining = length;
+    fp.seek(SeekFrom::Start(base))?;
+    while remaining > 0 {
+        cancel.check()?;
+        let read_len = std::cmp::min(remaining as usize, buffer.len());
+        {
+            let _phase = phase("io");
+            fp.read_exact(&mut buffer[..read_len])
+                .map_err(|_| AfptoolError::Parse("RKAF image is shorter than its header length".to_string()))?;
+        }
+        let _phase = phase("hash");
+        checksum = rkcrc32(checksum, &buffer[..read_len]);
+        remaining -= read_len as u64;
+    }
+    let mut stored = [0u8; 4];
+    let stored = fp.read_exact(&mut stored).ok().map(|_| u32::from_le_bytes(stored));
+    Ok((checksum, stored))
+}
+
+/// Recomputes the rkcrc32 of the RKAF image at `base` and stores it after
+/// its `length` bytes.
+pub(crate) fn write_rkaf_crc(fp: &mut File, base: u64, length: u64, cancel: &CancelToken) -> Result<()> {
+    let (checksum, _) = rkaf_crc(fp, base, length, cancel)?;
+    fp.seek(SeekFrom::Start(base + length))?;
+    fp.write_all(&checksum.to_le_bytes())?;
+    Ok(())
+}
+
+/// Recomputes the MD5 of the RKFW file `header` was read from and stores it
+/// right after the embedded update image, dropping anything beyond. A raw
+/// 16-byte trailer stays raw; otherwise the hex form `pack_rkfw` writes is used.
+pub(crate) fn write_rkfw_md5(fp: &mut File, header: &RkfwHeader) -> Result<()> {
+    let body_len = header.update_offset as u64 + header.update_size;
+    let raw = fp.seek(SeekFrom::End(0))?.saturating_sub(body_len) == 16;
+
+    let mut context = md5::Context::new();
+    fp.seek(SeekFrom::Start(0))?;
+    let mut body = fp.take(body_len);
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let read_len = body.read(&mut buffer)?;
+        if read_len == 0 {
+            break;
+        }
+        context.consume(&buffer[..read_len]);
+    }
+    if body.limit() > 0 {
+        return Err(AfptoolError::Parse("RKFW image is shorter than its header says".to_string()));
+    }
+    let digest = context.finalize();
+
+    fp.seek(SeekFrom::Start(body_len))?;
+    if raw {
+        fp.write_all(&digest.0)?;
+    } else {
+        fp.write_all(format!("{:x}", digest).as_bytes())?;
+    }
+    fp.set_len(body_len + if raw { 16 } else { 32 })?;
+    Ok(())
+}
+
+/// What the MD5 trailer of an RKFW image says about its body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Md5Trailer {
+    /// The trailer (32 hex digits or 16 raw bytes) matches.
+    Match(String),
+    Mismatch { stored: String, computed: String },
+    /// Nothing, or something of another length, follows the update image.
+    Missing(u64),
+}
+
+/// Checks the MD5 that follows the embedded update image of the RKFW file
+/// `header` was read from.
+/// `pack_rkfw` writes it as lowercase hex; some vendor tools store the 16
+/// raw bytes instead.
+pub(crate) fn rkfw_md5(fp: &mut (impl Read + Seek), header: &RkfwHeader) -> Result<Md5Trailer> {
+    let body_len = header.update_offset as u64 + header.update_size;
+    let trailer_len = fp.seek(SeekFrom::End(0))?.saturating_sub(body_len);
+    if trailer_len != 32 && trailer_len != 16 {
+        return Ok(Md5Trailer::Missing(trailer_len));
+    }
+
+    let mut context = md5::Context::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut remaining = body_len;
+    fp.seek(SeekFrom::Start(0))?;
+    while remaining > 0 {
+        let read_len = std::cmp::min(remaining as usize, buffer.len());
+        {
+            let _phase = phase("io");
+            fp.read_exact(&mut buffer[..read_len])?;
+        }
+        let _phase = phase("hash");
+        context.consume(&buffer[..read_len]);
+        remaining -= read_len as u64;
+    }
+    let computed = format!("{:x}", context.finalize());
+
+    let mut trailer = vec![0u8; trailer_len as usize];
+    fp.read_exact(&mut trailer)?;
+    let stored = if trailer_len == 32 {
+        String::from_utf8_lossy(&trailer).to_ascii_lowercase()
+    } else {
+        trailer.iter().map(|byte| format!("{:02x}", byte)).collect()
+    };
+    Ok(if stored == computed {
+        Md5Trailer::Match(computed)
+    } else {
+        Md5Trailer::Mismatch { stored, computed }
+    })
+}
+
+/// Checks an RKFW or RKAF image without extracting it: the RKFW MD5 trailer
+/// and section bounds, the RKAF length and trailing CRC, and that every
+/// partition lies inside the image. Returns one entry per check; reading
+/// errors (an unknown signature, an unreadable file) are returned as `Err`.
+pub fn verify_image(image_path: impl AsRef<Path>) -> Result<Vec<ImageCheck>> {
+    verify_image_from_reader(&mut open_file(image_path)?)
+}
+
+/// [`verify_image`] for an image that is not a file on disk.
+pub fn verify_image_from_reader(fp: &mut (impl Read + Seek)) -> Result<Vec<ImageCheck>> {
+    let file_len = fp.seek(SeekFrom::End(0))?;
+    let mut checks = Vec::new();
+
+    let base = locate_rkaf(fp)?;
+    let mut rkaf_len = file_len - base;
+    if let Some(rkfw) = read_rkfw_header(fp)? {
+        let boot_end = rkfw.boot_offset as u64 + rkfw.boot_size as u64;
+        let update_end = rkfw.update_offset as u64 + rkfw.update_size;
+        checks.push(ImageCheck::new(
+            "RKFW sections",
+            boot_end <= file_len && update_end <= file_len,
+            format!("BOOT ends at {:#x}, update image at {:#x}, file is {:#x} bytes", boot_end, update_end, file_len),
+        ));
+        rkaf_len = rkfw.update_size;
+        let check = match rkfw_md5(fp, &rkfw)? {
+            Md5Trailer::Match(md5) => ImageCheck::new("RKFW MD5", true, md5),
+            Md5Trailer::Mismatch { stored, computed } => {
+                ImageCheck::new("RKFW MD5", false, format!("stored {}, computed {}", stored, computed))
+            }
+            Md5Trailer::Missing(len) => ImageCheck::new("RKFW MD5", false, format!("no MD5 trailer ({} bytes after the update image)", len)),
+        };
+        checks.push(check);
+    }
+
+    let header = read_rkaf_header(fp, base)?;
+    let length = header.image_length()?;
+    checks.push(ImageCheck::new(
+        "RKAF length",
+        length.checked_add(4) == Some(rkaf_len),
+        format!("header says {:#x} bytes plus the CRC, image is {:#x} bytes", length, rkaf_len),
+    ));
+    let check = if length.checked_add(4).is_none_or(|end| end > rkaf_len) {
+        ImageCheck::new("RKAF CRC", false, "image ends before the CRC".to_string())
+    } else {
+        match rkaf_crc(fp, base, length, &CancelToken::default())? {
+            (computed, Some(stored)) if stored == computed => ImageCheck::new("RKAF CRC", true, format!("{:#010x}", computed)),
+            (computed, stored) => ImageCheck::new(
+                "RKAF CRC",
+                false,
+                format!("stored {:#010x}, computed {:#010x}", stored.unwrap_or(0), computed),
+            ),
+        }
+    };
+    checks.push(check);
+
+    // A base and length this large can only come from a damaged header
+    let data_end = base.saturating_add(length);
+    for part in list_parts(fp)?.into_iter().filter(|part| !part.is_marker()) {
+        let end = part.file_offset.checked_add(part.byte_count);
+        checks.push(ImageCheck::new(
+            format!("Partition {}", part.name),
+            end.is_some_and(|end| end <= data_end),
+            format!("{:#x}+{:#x}, RKAF data ends at {:#x}", part.file_offset, part.byte_count, data_end),
+        ));
+    }
+    Ok(checks)
+}
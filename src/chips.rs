@@ -0,0 +1,269 @@
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use crate::error::{AfptoolError, IoContext, Result};
+
+/// A chip family as the RKFW header names it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChipFamily {
+    pub name: &'static str,
+    /// Other names `--chip` accepts for the same family.
+    pub aliases: &'static [&'static str],
+    /// First byte of the chip field, the last character of the chip's
+    /// Rockchip ID.
+    pub code: u8,
+    /// The up to three characters of the Rockchip ID before the code, in
+    /// reading order: `330` for RK3399's `330C`. Not always the digits of
+    /// the marketing name.
+    pub tag: &'static str,
+    /// The RKFW code field (0x0a) stock firmware for the family is usually
+    /// packed with, used when none is given.
+    pub typical_code: u32,
+}
+
+impl ChipFamily {
+    /// The 32-bit chip field at 0x15: the code byte, then the tag in
+    /// reverse order, so RK3326 (`3326`) is stored as `36 32 33 33`.
+    pub fn field(&self) -> u32 {
+        self.tag.bytes().rev().enumerate().fold(self.code as u32, |field, (i, byte)| field | (byte as u32) << (8 * (i + 1)))
+    }
+}
+
+/// Code field of images from the current SDKs, and of families loaded
+/// without a `default_code`.
+pub(crate) const CODE_FIELD: u32 = 0x0200_0000;
+/// Code field of images for the older families, packed by the 1.x SDK tools.
+const LEGACY_CODE_FIELD: u32 = 0x0103_0000;
+
+/// Known families, with the tags stock images carry. Aliases get the same
+/// chip field as the canonical name.
+pub(crate) const CHIP_FAMILIES: &[ChipFamily] = &[
+    ChipFamily { name: "RK29xx", aliases: &["RK29"], code: 0x50, tag: "", typical_code: LEGACY_CODE_FIELD },
+    ChipFamily { name: "RK30xx", aliases: &["RK30"], code: 0x60, tag: "", typical_code: LEGACY_CODE_FIELD },
+    ChipFamily { name: "RK31xx", aliases: &["RK31"], code: 0x70, tag: "", typical_code: LEGACY_CODE_FIELD },
+    ChipFamily { name: "RK32xx", aliases: &["RK32"], code: 0x80, tag: "", typical_code: LEGACY_CODE_FIELD },
+    ChipFamily { name: "RK3368", aliases: &[], code: 0x41, tag: "330", typical_code: LEGACY_CODE_FIELD },
+    ChipFamily { name: "RK3326", aliases: &[], code: 0x36, tag: "332", typical_code: CODE_FIELD },
+    ChipFamily { name: "RK3562", aliases: &[], code: 0x32, tag: "356", typical_code: CODE_FIELD },
+    ChipFamily { name: "RK3566", aliases: &["RK3568"], code: 0x38, tag: "356", typical_code: CODE_FIELD },
+    ChipFamily { name: "PX30", aliases: &[], code: 0x30, tag: "", typical_code: CODE_FIELD },
+    ChipFamily { name: "RK3036", aliases: &[], code: b'A', tag: "303", typical_code: LEGACY_CODE_FIELD },
+    ChipFamily { name: "RK3128", aliases: &["RK3126"], code: b'A', tag: "312", typical_code: LEGACY_CODE_FIELD },
+    ChipFamily { name: "RK3288", aliases: &[], code: b'A', tag: "320", typical_code: LEGACY_CODE_FIELD },
+    ChipFamily { name: "RK3308", aliases: &[], code: b'8', tag: "330", typical_code: CODE_FIELD },
+    ChipFamily { name: "RK3328", aliases: &[], code: b'H', tag: "322", typical_code: CODE_FIELD },
+    ChipFamily { name: "RK3399", aliases: &["RK3399PRO"], code: b'C', tag: "330", typical_code: CODE_FIELD },
+    ChipFamily { name: "RK1808", aliases: &[], code: b'A', tag: "180", typical_code: CODE_FIELD },
+    ChipFamily { name: "RK3588", aliases: &["RK3588S"], code: b'8', tag: "358", typical_code: CODE_FIELD },
+];
+
+/// Families loaded from a chip database.
+static USER_CHIPS: RwLock<Vec<&'static ChipFamily>> = RwLock::new(Vec::new());
+
+/// Loaded families first, so an entry can redefine a built-in one.
+fn families() -> Vec<&'static ChipFamily> {
+    let loaded = USER_CHIPS.read().map(|chips| chips.clone()).unwrap_or_default();
+    loaded.into_iter().chain(CHIP_FAMILIES).collect()
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    let strip = |name: &str| name.to_ascii_uppercase().trim_start_matches("RK").to_string();
+    strip(a) == strip(b)
+}
+
+/// The family with this chip field. A field holding only the code byte, as
+/// some tools write, is matched on that byte; where several families share
+/// it, the earlier table entry wins.
+pub fn chip_family(code: u32) -> Option<&'static ChipFamily> {
+    let families = families();
+    families.iter().find(|family| family.field() == code)
+        .or_else(|| families.iter().find(|family| family.code as u32 == code))
+        .copied()
+}
+
+pub fn chip_code_to_name(code: u32) -> Option<&'static str> {
+    chip_family(code).map(|family| family.name)
+}
+
+/// The chip field for a family name, with or without the `RK` prefix and in
+/// any case (`rk3399pro`, `3326`).
+pub fn chip_name_to_code(chip: &str) -> Result<u32> {
+    families().into_iter()
+        .find(|family| same_name(family.name, chip) || family.aliases.iter().any(|alias| same_name(alias, chip)))
+        .map(ChipFamily::field)
+        .ok_or_else(|| AfptoolError::Invalid(format!("Unsupported chip family: {}", chip)))
+}
+
+/// The RKFW code field images for this chip field are usually packed with,
+/// or `None` for an unknown family.
+pub fn chip_default_code(code: u32) -> Option<u32> {
+    chip_family(code).map(|family| family.typical_code)
+}
+
+/// Where the chip database is looked for when `--chip-db` is not given:
+/// `afptool-rs/chips.toml` under the user's configuration directory.
+pub fn default_chip_db() -> Option<PathBuf> {
+    let config = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    }?;
+    Some(config.join("afptool-rs").join("chips.toml"))
+}
+
+/// Adds the families in a chip database to the lookups and returns how many
+/// it held. The file is a list of TOML `[[chip]]` tables:
+///
+/// ```toml
+/// [[chip]]
+/// name = "RK3576"
+/// aliases = ["RK3576J"]
+/// code = 0x36            # or a one-character string, "6"
+/// tag = "357"            # the first three digits of the name if left out
+/// default_code = "0x02000000"
+/// ```
+///
+/// Only that subset of TOML is read: strings, integers and arrays of
+/// strings, one key per line.
+pub fn load_chip_db(path: impl AsRef<Path>) -> Result<usize> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    let chips = parse_chip_db(&text).map_err(|e| e.within(path.display()))?;
+    let count = chips.len();
+    let mut loaded = USER_CHIPS.write().map_err(|_| AfptoolError::other("Chip database lock poisoned"))?;
+    // A later file's entries take precedence over an earlier one's
+    loaded.splice(0..0, chips);
+    Ok(count)
+}
+
+enum DbValue {
+    Text(String),
+    Number(u32),
+    List(Vec<String>),
+}
+
+fn parse_db_value(value: &str) -> Option<DbValue> {
+    let quoted = |item: &str| item.strip_prefix('"')?.strip_suffix('"').map(str::to_string);
+    if let Some(items) = value.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        let items = items.split(',').map(str::trim).filter(|item| !item.is_empty());
+        return items.map(quoted).collect::<Option<Vec<_>>>().map(DbValue::List);
+    }
+    if let Some(text) = quoted(value) {
+        return Some(DbValue::Text(text));
+    }
+    let value = value.replace('_', "");
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }.map(DbValue::Number)
+}
+
+#[derive(Default)]
+struct ChipDraft {
+    line: usize,
+    name: Option<String>,
+    aliases: Vec<String>,
+    code: Option<u8>,
+    tag: Option<String>,
+    default_code: Option<u32>,
+}
+
+impl ChipDraft {
+    fn finish(self) -> Result<&'static ChipFamily, AfptoolError> {
+        let line = self.line;
+        let missing = |key: &str| AfptoolError::Parse(format!("line {}: [[chip]] has no {}", line, key));
+        let name = self.name.ok_or_else(|| missing("name"))?;
+        let code = self.code.ok_or_else(|| missing("code"))?;
+        // Without a tag, the first three digits of the name stand in for it
+        let tag = self.tag.unwrap_or_else(|| {
+            let digits: String = name.chars().filter(char::is_ascii_digit).collect();
+            if digits.len() >= 3 { digits[..3].to_string() } else { String::new() }
+        });
+        // Loaded once per run and looked up for the rest of it
+        let aliases: Vec<&'static str> = self.aliases.into_iter().map(|alias| &*Box::leak(alias.into_boxed_str())).collect();
+        Ok(Box::leak(Box::new(ChipFamily {
+            name: Box::leak(name.into_boxed_str()),
+            aliases: Box::leak(aliases.into_boxed_slice()),
+            code,
+            tag: Box::leak(tag.into_boxed_str()),
+            typical_code: self.default_code.unwrap_or(CODE_FIELD),
+        })))
+    }
+}
+
+/// `line` up to a `#` that is not inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (at, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..at],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_chip_db(text: &str) -> Result<Vec<&'static ChipFamily>, AfptoolError> {
+    let mut chips = Vec::new();
+    let mut draft: Option<ChipDraft> = None;
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let error = |message: String| AfptoolError::Parse(format!("line {}: {}", number, message));
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "[[chip]]" {
+            if let Some(done) = draft.replace(ChipDraft { line: number, ..Default::default() }) {
+                chips.push(done.finish()?);
+            }
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| error(format!("expected [[chip]] or key = value, found {:?}", line)))?;
+        let chip = draft.as_mut().ok_or_else(|| error(format!("{} outside a [[chip]] table", key.trim())))?;
+        let value = parse_db_value(value.trim()).ok_or_else(|| error(format!("unreadable value for {}", key.trim())))?;
+        match (key.trim(), value) {
+            ("name", DbValue::Text(name)) => chip.name = Some(name),
+            ("aliases", DbValue::List(aliases)) => chip.aliases = aliases,
+            ("code", DbValue::Number(code)) if code <= 0xff => chip.code = Some(code as u8),
+            ("code", DbValue::Text(code)) if code.len() == 1 => chip.code = Some(code.as_bytes()[0]),
+            ("tag", DbValue::Text(tag)) if tag.len() <= 3 && tag.is_ascii() => chip.tag = Some(tag),
+            ("default_code", DbValue::Number(code)) => chip.default_code = Some(code),
+            ("default_code", DbValue::Text(code)) => {
+                let hex = code.trim_start_matches("0x").trim_start_matches("0X");
+                chip.default_code = Some(u32::from_str_radix(hex, 16).map_err(|_| error(format!("invalid hex default_code {:?}", code)))?);
+            }
+            (key @ ("name" | "aliases" | "code" | "tag" | "default_code"), _) => return Err(error(format!("wrong type for {}", key))),
+            (key, _) => return Err(error(format!("unknown key {}", key))),
+        }
+    }
+    if let Some(done) = draft {
+        chips.push(done.finish()?);
+    }
+    Ok(chips)
+}
+
+/// What to call a chip field in output names and messages: its family, or
+/// the code itself when the family is unknown.
+pub(crate) fn chip_label(code: u32) -> String {
+    chip_code_to_name(code).map(str::to_string).unwrap_or_else(|| format!("{:#x}", code))
+}
+
+/// Builds a chip field from a code byte and the up to three ASCII
+/// characters stored after it, given in reading order: `0x36` with `332`
+/// is RK3326's field. Without `family` the code is used as it is.
+pub fn chip_field(code: u32, family: Option<&str>) -> Result<u32> {
+    let Some(family) = family else {
+        return Ok(code);
+    };
+    if code > 0xff || family.is_empty() || family.len() > 3 || !family.is_ascii() {
+        return Err(AfptoolError::Invalid("A chip family override takes a one-byte code and up to three ASCII characters".to_string()));
+    }
+    Ok(family.bytes().rev().enumerate().fold(code, |field, (i, byte)| field | (byte as u32) << (8 * (i + 1))))
+}
+
+/// Whether two chip fields name the same family.
+pub(crate) fn same_chip(a: u32, b: u32) -> bool {
+    a == b || matches!((chip_family(a), chip_family(b)), (Some(x), Some(y)) if x == y)
+}
@@ -0,0 +1,148 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use crate::error::Result;
+use crate::chips::{chip_code_to_name, same_chip};
+use crate::image::{cstr_field, list_parts, locate_rkaf, read_rkaf_header, read_rkfw_header, rkfw_sections};
+use crate::layout::diff_layouts;
+use crate::paths::open_file;
+
+/// How a candidate image relates to the installed one, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Compatibility {
+    /// Same hardware and a compatible flash layout.
+    Safe,
+    /// Same hardware, but partitions move, shrink or disappear, so a direct
+    /// OTA would leave data in the wrong place.
+    NeedsMigration,
+    /// Built for another chip or board.
+    DifferentHardware,
+}
+
+impl fmt::Display for Compatibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Compatibility::Safe => "safe to upgrade directly",
+            Compatibility::NeedsMigration => "needs a layout migration",
+            Compatibility::DifferentHardware => "for different hardware",
+        })
+    }
+}
+
+/// Outcome of [`check_compat`], with one line per finding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatReport {
+    pub verdict: Compatibility,
+    pub findings: Vec<String>,
+}
+
+impl CompatReport {
+    fn note(&mut self, verdict: Compatibility, finding: String) {
+        self.verdict = self.verdict.max(verdict);
+        self.findings.push(finding);
+    }
+}
+
+/// Version and chip fields of a Rockchip loader (boot_merger) header.
+struct LoaderInfo {
+    version: u32,
+    chip: u32,
+}
+
+impl LoaderInfo {
+    fn version_text(&self) -> String {
+        format!("{:x}.{:02x}", (self.version >> 8) & 0xff, self.version & 0xff)
+    }
+}
+
+/// Reads the loader from the RKFW BOOT section, or from the bootloader
+/// partition of a bare RKAF image.
+fn read_loader(fp: &mut File) -> Result<Option<LoaderInfo>> {
+    let offset = match rkfw_sections(fp)? {
+        Some([(boot_offset, _), _]) => boot_offset,
+        None => match list_parts(fp)?.into_iter().find(|part| part.name == "bootloader") {
+            Some(part) => part.file_offset,
+            None => return Ok(None),
+        },
+    };
+    let mut header = [0u8; 0x19];
+    fp.seek(SeekFrom::Start(offset))?;
+    if fp.read_exact(&mut header).is_err() || !(&header[..4] == b"BOOT" || &header[..4] == b"LDR ") {
+        return Ok(None);
+    }
+    let field = |at: usize| u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]]);
+    Ok(Some(LoaderInfo { version: field(6), chip: field(21) }))
+}
+
+fn parse_version(version: &str) -> Vec<u32> {
+    version.split('.').map(|part| part.parse().unwrap_or(0)).collect()
+}
+
+/// Judges whether `candidate` can be flashed over a device running
+/// `installed`: chip family, model and machine id must match, the
+/// `mtdparts=` layout must not move or shrink existing partitions, and
+/// loader or firmware downgrades are pointed out.
+pub fn check_compat(installed_path: impl AsRef<Path>, candidate_path: impl AsRef<Path>) -> Result<CompatReport> {
+    let (installed_path, candidate_path) = (installed_path.as_ref(), candidate_path.as_ref());
+    let mut report = CompatReport { verdict: Compatibility::Safe, findings: Vec::new() };
+    let mut installed = open_file(installed_path)?;
+    let mut candidate = open_file(candidate_path)?;
+
+    let installed_rkfw = read_rkfw_header(&mut installed)?;
+    let candidate_rkfw = read_rkfw_header(&mut candidate)?;
+    match (&installed_rkfw, &candidate_rkfw) {
+        (Some(old), Some(new)) => {
+            let name = |code| chip_code_to_name(code).unwrap_or("unknown");
+            if !same_chip(old.chip_code, new.chip_code) {
+                report.note(Compatibility::DifferentHardware, format!(
+                    "Chip family differs: {} ({:#x}) vs {} ({:#x})",
+                    name(old.chip_code), old.chip_code, name(new.chip_code), new.chip_code
+                ));
+            }
+            match parse_version(&new.version).cmp(&parse_version(&old.version)) {
+                Ordering::Less => report.note(Compatibility::Safe, format!("Firmware version goes down: {} -> {}", old.version, new.version)),
+                Ordering::Equal => report.note(Compatibility::Safe, format!("Firmware version unchanged: {}", new.version)),
+                Ordering::Greater => {}
+            }
+        }
+        _ => report.note(Compatibility::Safe, "No RKFW header on both images, chip family not compared".to_string()),
+    }
+
+    let (installed_base, candidate_base) = (locate_rkaf(&mut installed)?, locate_rkaf(&mut candidate)?);
+    let old_header = read_rkaf_header(&mut installed, installed_base)?;
+    let new_header = read_rkaf_header(&mut candidate, candidate_base)?;
+    let (old_model, new_model) = (cstr_field(&old_header.model), cstr_field(&new_header.model));
+    if old_model != new_model {
+        report.note(Compatibility::DifferentHardware, format!("Model differs: {:?} vs {:?}", old_model, new_model));
+    }
+    // An empty id means the image accepts any device
+    let (old_id, new_id) = (old_header.id(), new_header.id());
+    if !old_id.is_empty() && !new_id.is_empty() && old_id != new_id {
+        report.note(Compatibility::DifferentHardware, format!("Machine id differs: {:?} vs {:?}", old_id, new_id));
+    }
+
+    match (read_loader(&mut installed)?, read_loader(&mut candidate)?) {
+        (Some(old), Some(new)) => {
+            if old.chip != new.chip {
+                report.note(Compatibility::DifferentHardware, format!("Loader chip differs: {:#010x} vs {:#010x}", old.chip, new.chip));
+            }
+            if new.version < old.version {
+                report.note(Compatibility::Safe, format!("Loader version goes down: {} -> {}", old.version_text(), new.version_text()));
+            }
+        }
+        _ => report.note(Compatibility::Safe, "Loader missing from one image, loader versions not compared".to_string()),
+    }
+
+    match diff_layouts(installed_path, candidate_path) {
+        Ok(changes) => {
+            for change in changes {
+                let verdict = if change.ota_unsafe() { Compatibility::NeedsMigration } else { Compatibility::Safe };
+                report.note(verdict, format!("Layout: {}", change));
+            }
+        }
+        Err(e) => report.note(Compatibility::NeedsMigration, format!("Layouts could not be compared: {}", e)),
+    }
+    Ok(report)
+}
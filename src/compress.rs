@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use crate::error::{IoContext, Result};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use crate::cancel::CancelToken;
+use crate::paths::open_file;
+
+/// Compressor extracted partitions can be written through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+}
+
+impl Compression {
+    /// Suffix added to the name of a compressed file, e.g. `system.img.gz`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => ".gz",
+        }
+    }
+}
+
+/// Splits a compressed file's extension off `path`, returning the name of
+/// the data inside and how it is compressed.
+pub(crate) fn split_compressed(path: &str) -> (&str, Option<Compression>) {
+    for compression in [Compression::Gzip] {
+        if let Some(base) = path.strip_suffix(compression.extension()) {
+            return (base, Some(compression));
+        }
+    }
+    (path, None)
+}
+
+/// How the file at `path` is compressed, judging by its extension.
+pub(crate) fn compression_of(path: &Path) -> Option<Compression> {
+    [Compression::Gzip].into_iter()
+        .find(|compression| path.as_os_str().as_encoded_bytes().ends_with(compression.extension().as_bytes()))
+}
+
+/// Decompresses the file at `path` into `out`, returning the number of bytes
+/// written.
+pub(crate) fn copy_decompressed(path: &Path, compression: Compression, out: &mut impl Write, cancel: &CancelToken) -> Result<u64> {
+    let input = BufReader::new(open_file(path)?);
+    let mut reader = match compression {
+        Compression::Gzip => MultiGzDecoder::new(input),
+    };
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut total = 0;
+    loop {
+        cancel.check()?;
+        let read = reader.read(&mut buffer).with_context(|| format!("Cannot decompress {}", path.display()))?;
+        if read == 0 {
+            return Ok(total);
+        }
+        out.write_all(&buffer[..read])?;
+        total += read as u64;
+    }
+}
+
+/// Size of the data in a compressed file. gzip only records it modulo
+/// 4 GiB, so the file is decompressed to count it.
+pub(crate) fn decompressed_size(path: &Path, compression: Compression, cancel: &CancelToken) -> Result<u64> {
+    copy_decompressed(path, compression, &mut std::io::sink(), cancel)
+}
+
+/// Passes writes through while hashing them.
+struct Md5Writer<W> {
+    inner: W,
+    context: md5::Context,
+}
+
+impl<W: Write> Write for Md5Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.context.consume(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+enum Sink {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<Md5Writer<BufWriter<File>>>),
+}
+
+/// A new file that data is written to as is or through a compressor.
+pub(crate) struct OutputFile(Sink);
+
+impl OutputFile {
+    pub(crate) fn create(path: &Path, capacity: usize, compression: Option<Compression>) -> Result<OutputFile> {
+        let file = BufWriter::with_capacity(capacity, File::create(path)?);
+        Ok(OutputFile(match compression {
+            None => Sink::Plain(file),
+            Some(Compression::Gzip) => Sink::Gzip(GzEncoder::new(
+                Md5Writer { inner: file, context: md5::Context::new() },
+                flate2::Compression::default(),
+            )),
+        }))
+    }
+
+    /// Flushes the file. For compressed output, returns the MD5 of the
+    /// compressed bytes, which differs from that of the data written.
+    pub(crate) fn finish(self) -> Result<Option<String>> {
+        match self.0 {
+            Sink::Plain(file) => {
+                file.into_inner().map_err(|e| e.into_error())?;
+                Ok(None)
+            }
+            Sink::Gzip(encoder) => {
+                let Md5Writer { inner, context } = encoder.finish()?;
+                inner.into_inner().map_err(|e| e.into_error())?;
+                Ok(Some(format!("{:x}", context.finalize())))
+            }
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.0 {
+            Sink::Plain(file) => file.write(buf),
+            Sink::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.0 {
+            Sink::Plain(file) => file.flush(),
+            Sink::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use crate::error::{AfptoolError, Result};
+use crate::chips::chip_name_to_code;
+use crate::image::rkfw_sections;
+use crate::pack::pack_rkfw_files;
+use crate::RKAF_SIGNATURE;
+use crate::paths::open_file;
+
+fn copy_region(fp: &mut File, offset: u64, len: u64, output_file: &Path) -> Result<()> {
+    let mut out = File::create(output_file)?;
+    fp.seek(SeekFrom::Start(offset))?;
+    if std::io::copy(&mut fp.take(len), &mut out)? != len {
+        return Err(AfptoolError::Parse("Insufficient length in container image file".to_string()));
+    }
+    Ok(())
+}
+
+/// Strips the RKFW wrapper, writing the embedded update.img and optionally the
+/// loader blob, without touching the partition data.
+pub fn unwrap_rkfw(input_file: impl AsRef<Path>, update_file: impl AsRef<Path>, loader_file: Option<&Path>) -> Result<()> {
+    let (input_file, update_file) = (input_file.as_ref(), update_file.as_ref());
+    let mut fp = open_file(input_file)?;
+    let file_size = fp.metadata()?.len();
+    let [(boot_offset, boot_size), (update_offset, update_size)] = rkfw_sections(&mut fp)?
+        .ok_or_else(|| AfptoolError::Parse(format!("{} is not an RKFW image", input_file.display())))?;
+
+    for (what, offset, size) in [("BOOT", boot_offset, boot_size), ("update.img", update_offset, update_size)] {
+        if offset + size > file_size {
+            return Err(AfptoolError::Parse(format!("{} section ({:#x}+{:#x}) extends past the end of {}", what, offset, size, input_file.display())));
+        }
+    }
+
+    let mut signature = [0u8; 4];
+    fp.seek(SeekFrom::Start(update_offset))?;
+    fp.read_exact(&mut signature)?;
+    if signature != *RKAF_SIGNATURE {
+        return Err(AfptoolError::Parse("cannot find embedded RKAF update.img".to_string()));
+    }
+
+    copy_region(&mut fp, update_offset, update_size, update_file)?;
+    if let Some(loader_file) = loader_file {
+        copy_region(&mut fp, boot_offset, boot_size, loader_file)?;
+    }
+    Ok(())
+}
+
+/// Wraps an existing update.img and loader blob into RKFW, computing only the
+/// outer MD5 trailer.
+pub fn wrap_rkaf(update_file: impl AsRef<Path>, loader_file: impl AsRef<Path>, output_file: impl AsRef<Path>, chip: &str, version: &str, timestamp: i64, code_hex: &str) -> Result<()> {
+    let (update_file, loader_file, output_file) = (update_file.as_ref(), loader_file.as_ref(), output_file.as_ref());
+    pack_rkfw_files(loader_file, update_file, output_file, (chip, chip_name_to_code(chip)?), version, timestamp, code_hex, &[])
+}
@@ -0,0 +1,148 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::error::{AfptoolError, IoContext, Result};
+use crate::cancel::CancelToken;
+use crate::image::find_part;
+use crate::paths::open_file;
+use crate::{tr, FLASH_SECTOR_SIZE};
+
+/// Optional behaviour for [`verify_device_with`].
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    /// Compare byte by byte and report the first difference instead of comparing MD5s.
+    pub bytewise: bool,
+    /// Stops the comparison between chunks of data.
+    pub cancel: CancelToken,
+}
+
+/// Reads a partition back from a device (or a raw dump of one) and compares
+/// it with the matching region of the firmware image.
+pub fn verify_device(image_path: impl AsRef<Path>, part_name: &str, device_path: impl AsRef<Path>, bytewise: bool) -> Result<()> {
+    verify_device_with(image_path, part_name, device_path, &VerifyOptions { bytewise, ..Default::default() })
+}
+
+pub fn verify_device_with(image_path: impl AsRef<Path>, part_name: &str, device_path: impl AsRef<Path>, options: &VerifyOptions) -> Result<()> {
+    let (image_path, device_path) = (image_path.as_ref(), device_path.as_ref());
+    let mut image = open_file(image_path)?;
+    let part = find_part(&mut image, part_name)?;
+
+    let mut device = File::open(device_path)
+        .with_context(|| format!("Cannot open device {}", device_path.display()))?;
+    let device_offset = part.flash_offset as u64 * FLASH_SECTOR_SIZE;
+
+    log::info!(
+        "{}",
+        tr!(
+            "device-verifying",
+            name = part.name,
+            size = part.byte_count,
+            device = device_path.display(),
+            sector = format!("{:#010x}", part.flash_offset)
+        )
+    );
+
+    image.seek(SeekFrom::Start(part.file_offset))?;
+    device.seek(SeekFrom::Start(device_offset))?;
+
+    if options.bytewise {
+        if let Some(offset) = first_mismatch(&mut image, &mut device, part.byte_count as u64, &options.cancel)? {
+            return Err(AfptoolError::Checksum(format!("Partition {} differs from device at byte offset {:#x}", part.name, offset)));
+        }
+    } else {
+        let image_digest = md5_region(&mut image, part.byte_count as u64, &options.cancel)?;
+        let device_digest = md5_region(&mut device, part.byte_count as u64, &options.cancel)?;
+        log::info!("{}", tr!("device-image-md5", md5 = format!("{:x}", image_digest)));
+        log::info!("{}", tr!("device-device-md5", md5 = format!("{:x}", device_digest)));
+        if image_digest != device_digest {
+            return Err(AfptoolError::Checksum(format!("Partition {} does not match device contents", part.name)));
+        }
+    }
+
+    log::info!("{}", tr!("device-ok", name = part.name));
+    Ok(())
+}
+
+/// Writes a partition from the image straight to a device (or an existing file),
+/// starting at its first byte, then optionally reads it back to check it.
+pub fn write_part_to_device(image_path: impl AsRef<Path>, part_name: &str, device_path: impl AsRef<Path>, verify: bool) -> Result<()> {
+    let (image_path, device_path) = (image_path.as_ref(), device_path.as_ref());
+    let mut image = open_file(image_path)?;
+    let part = find_part(&mut image, part_name)?;
+    if part.placeholder {
+        return Err(AfptoolError::Invalid(format!("Partition {} is a placeholder with no data in the image", part_name)));
+    }
+    let len = part.byte_count as u64;
+
+    // Never create the target: a typo in the device path must not end up as a new file
+    let mut device = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device_path)
+        .with_context(|| format!("Cannot open device {}", device_path.display()))?;
+    let device_size = device.seek(SeekFrom::End(0))?;
+    if device_size < len {
+        return Err(AfptoolError::Layout(format!(
+            "Partition {} is {} bytes, but {} holds only {} bytes",
+            part.name, len, device_path.display(), device_size
+        )));
+    }
+
+    log::info!("{}", tr!("device-writing", name = part.name, size = len, device = device_path.display()));
+
+    image.seek(SeekFrom::Start(part.file_offset))?;
+    device.seek(SeekFrom::Start(0))?;
+    if std::io::copy(&mut (&mut image).take(len), &mut device)? != len {
+        return Err(AfptoolError::Parse("Insufficient length in container image file".to_string()));
+    }
+    device.flush()?;
+    device.sync_all()?;
+
+    if verify {
+        image.seek(SeekFrom::Start(part.file_offset))?;
+        device.seek(SeekFrom::Start(0))?;
+        if let Some(offset) = first_mismatch(&mut image, &mut device, len, &CancelToken::default())? {
+            return Err(AfptoolError::Checksum(format!("Read-back of {} differs at byte offset {:#x}", device_path.display(), offset)));
+        }
+        log::info!("{}", tr!("device-ok", name = part.name));
+    }
+    Ok(())
+}
+
+fn md5_region(fp: &mut File, len: u64, cancel: &CancelToken) -> Result<md5::Digest> {
+    let mut context = md5::Context::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut remaining = len;
+
+    while remaining > 0 {
+        cancel.check()?;
+        let read_len = std::cmp::min(remaining as usize, buffer.len());
+        fp.read_exact(&mut buffer[..read_len])
+            .map_err(|_| AfptoolError::Parse(format!("Unexpected end of data while reading {} bytes", len)))?;
+        context.consume(&buffer[..read_len]);
+        remaining -= read_len as u64;
+    }
+
+    Ok(context.finalize())
+}
+
+fn first_mismatch(a: &mut File, b: &mut File, len: u64, cancel: &CancelToken) -> Result<Option<u64>> {
+    let mut buf_a = vec![0u8; 64 * 1024];
+    let mut buf_b = vec![0u8; 64 * 1024];
+    let mut position = 0u64;
+
+    while position < len {
+        cancel.check()?;
+        let read_len = std::cmp::min((len - position) as usize, buf_a.len());
+        a.read_exact(&mut buf_a[..read_len])?;
+        b.read_exact(&mut buf_b[..read_len])
+            .map_err(|_| AfptoolError::Layout("Device is smaller than the partition".to_string()))?;
+
+        if let Some(i) = buf_a[..read_len].iter().zip(&buf_b[..read_len]).position(|(x, y)| x != y) {
+            return Ok(Some(position + i as u64));
+        }
+        position += read_len as u64;
+    }
+
+    Ok(None)
+}
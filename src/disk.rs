@@ -0,0 +1,140 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::error::{AfptoolError, Result};
+use crate::image::list_parts;
+use crate::layout::load_parameter;
+use crate::parameter::ParameterFile;
+use crate::paths::{join_path, open_file};
+use crate::text::metadata_fields;
+use crate::FLASH_SECTOR_SIZE;
+
+/// Where a partition's data comes from: a file, an offset in it and a length.
+struct Source {
+    name: String,
+    path: PathBuf,
+    offset: u64,
+    len: u64,
+}
+
+fn tree_sources(dir: &Path) -> Result<Vec<Source>> {
+    let metadata = std::fs::read_to_string(join_path(dir, "partition-metadata.txt"))
+        .map_err(|_| AfptoolError::Layout(format!("Missing partition metadata in {}", dir.display())))?;
+
+    let mut sources = Vec::new();
+    for line in metadata.lines() {
+        let parts = metadata_fields(line);
+        if parts.len() < 7 {
+            continue;
+        }
+        let path = join_path(dir, parts[1]);
+        if let Ok(meta) = std::fs::metadata(&path) {
+            sources.push(Source {
+                name: parts[0].to_string(),
+                path,
+                offset: 0,
+                len: meta.len(),
+            });
+        }
+    }
+    Ok(sources)
+}
+
+fn image_sources(image_path: &Path) -> Result<Vec<Source>> {
+    let mut fp = open_file(image_path)?;
+    Ok(list_parts(&mut fp)?
+        .into_iter()
+        .map(|part| Source {
+            name: part.name,
+            path: image_path.to_path_buf(),
+            offset: part.file_offset,
+            len: part.byte_count,
+        })
+        .collect())
+}
+
+fn read_parameter(input: &Path, parameter_file: Option<&Path>, sources: &[Source]) -> Result<ParameterFile> {
+    if let Some(path) = parameter_file {
+        return ParameterFile::load(path);
+    }
+    if input.is_dir() {
+        let source = sources.iter().find(|source| source.name == "parameter");
+        let path = source.map_or_else(|| join_path(input, "parameter.txt"), |source| source.path.clone());
+        return Ok(ParameterFile::from_bytes(&std::fs::read(&path)
+            .map_err(|_| AfptoolError::not_found(format!("Cannot find parameter file in {}", input.display())))?));
+    }
+
+    load_parameter(input)
+}
+
+/// Copies `len` bytes to `out` at its current position, seeking over all-zero
+/// blocks so they stay holes in the output.
+fn copy_sparse(input: &mut impl Read, out: &mut File, len: u64) -> Result<()> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let read_len = std::cmp::min(remaining as usize, buffer.len());
+        input.read_exact(&mut buffer[..read_len])
+            .map_err(|_| AfptoolError::Parse("Insufficient length in container image file".to_string()))?;
+        if buffer[..read_len].iter().all(|&b| b == 0) {
+            out.seek(SeekFrom::Current(read_len as i64))?;
+        } else {
+            out.write_all(&buffer[..read_len])?;
+        }
+        remaining -= read_len as u64;
+    }
+    Ok(())
+}
+
+/// Lays out every partition of an update image or unpacked tree at its offset
+/// from parameter.txt's `mtdparts=` list, producing a raw (sparse) disk image.
+/// Returns the size of the disk image in bytes.
+pub fn assemble_disk(input: impl AsRef<Path>, parameter_file: Option<&Path>, output_file: impl AsRef<Path>, disk_size: Option<u64>) -> Result<u64> {
+    let (input, output_file) = (input.as_ref(), output_file.as_ref());
+    let sources = if input.is_dir() { tree_sources(input)? } else { image_sources(input)? };
+    let mtdparts = read_parameter(input, parameter_file, &sources)?.mtdparts()?;
+
+    let mut placed = Vec::new();
+    let mut end = 0u64;
+    for mtd in &mtdparts {
+        let Some(source) = sources.iter().find(|source| source.name == mtd.name) else {
+            continue;
+        };
+        let offset = mtd.offset * FLASH_SECTOR_SIZE;
+        if let Some(size) = mtd.size {
+            if source.len > size * FLASH_SECTOR_SIZE {
+                return Err(AfptoolError::Layout(format!(
+                    "Partition {} is {} bytes, but parameter reserves only {} bytes",
+                    mtd.name, source.len, size * FLASH_SECTOR_SIZE
+                )));
+            }
+            end = end.max(offset + size * FLASH_SECTOR_SIZE);
+        } else {
+            end = end.max(offset + source.len);
+        }
+        placed.push((offset, source));
+    }
+    if placed.is_empty() {
+        return Err(AfptoolError::Layout(format!("None of the mtdparts partitions are present in {}", input.display())));
+    }
+
+    let disk_size = match disk_size {
+        Some(size) if size < end => {
+            return Err(AfptoolError::Layout(format!("Disk size {} is smaller than the {} bytes the partitions need", size, end)));
+        }
+        Some(size) => size,
+        None => end,
+    };
+
+    let mut out = File::create(output_file)?;
+    out.set_len(disk_size)?;
+    for (offset, source) in placed {
+        log::info!("{:010x}-{:010x} {}", offset, offset + source.len, source.name);
+        let mut input = open_file(&source.path)?;
+        input.seek(SeekFrom::Start(source.offset))?;
+        out.seek(SeekFrom::Start(offset))?;
+        copy_sparse(&mut input, &mut out, source.len)?;
+    }
+    out.sync_all()?;
+    Ok(disk_size)
+}
@@ -0,0 +1,153 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::error::{AfptoolError, Result};
+use crate::cancel::CancelToken;
+use crate::check::{rkaf_crc, rkfw_md5, write_rkaf_crc, write_rkfw_md5, Md5Trailer};
+use crate::image::{cstr_field, locate_rkaf, read_rkaf_header, read_rkfw_header, RKFW_LARGE_MARKER_AT};
+use crate::pack::{parse_rkfw_version, put_rkfw_update_size, set_header_text, RESERVED_MARKER, SELF_MARKER};
+use crate::text::is_gbk;
+use crate::UPDATE_HEADER_SIZE;
+
+/// Header fields changed by [`edit_header`]; `None` leaves a field as it is.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderEdit {
+    pub model: Option<String>,
+    pub manufacturer: Option<String>,
+    /// `major.minor.build`, stored in the RKAF header and, for RKFW images,
+    /// in the outer header as well.
+    pub version: Option<String>,
+    /// Store model and manufacturer as GBK. Fields that were GBK already stay so.
+    pub gbk: bool,
+}
+
+/// Rewrites header fields of an RKFW or RKAF image in place and recomputes
+/// the RKAF checksum and, for RKFW images, the MD5 trailer. Partition data
+/// is neither read into memory nor moved.
+pub fn edit_header(image_path: impl AsRef<Path>, edit: &HeaderEdit) -> Result<()> {
+    let image_path = image_path.as_ref();
+    if edit.model.is_none() && edit.manufacturer.is_none() && edit.version.is_none() {
+        return Err(AfptoolError::Invalid("Nothing to change: give a model, manufacturer or version".to_string()));
+    }
+    let version = edit.version.as_deref().map(parse_rkfw_version).transpose()?;
+
+    let mut image = OpenOptions::new().read(true).write(true).open(image_path)?;
+    let base = locate_rkaf(&mut image)?;
+    let mut header = read_rkaf_header(&mut image, base)?;
+    for (field, text) in [(&mut header.model[..], &edit.model), (&mut header.manufacturer[..], &edit.manufacturer)] {
+        if let Some(text) = text {
+            let gbk = edit.gbk || is_gbk(field);
+            field.fill(0);
+            set_header_text(field, text, gbk)?;
+        }
+    }
+    if let Some((major, minor, build)) = version {
+        header.version = (major as u32) << 24 | (minor as u32) << 16 | build as u32;
+    }
+    image.seek(SeekFrom::Start(base))?;
+    image.write_all(&header.to_bytes())?;
+    write_rkaf_crc(&mut image, base, header.image_length()?, &CancelToken::default())?;
+
+    if let Some(rkfw) = read_rkfw_header(&mut image)? {
+        if let Some((major, minor, build)) = version {
+            let [build_low, build_high] = build.to_le_bytes();
+            image.seek(SeekFrom::Start(6))?;
+            image.write_all(&[build_low, build_high, minor, major])?;
+        }
+        write_rkfw_md5(&mut image, &rkfw)?;
+    }
+    Ok(())
+}
+
+/// Makes an image whose trailers no longer match its contents, after a hex
+/// edit or truncation, flashable again: the RKAF length is brought back to
+/// what the file holds (and `SELF` entries with it), the rkcrc32 and, for
+/// RKFW images, the update size and MD5 trailer are recomputed. Returns a
+/// line per change; none means the image was already consistent. Partition
+/// data that is missing from the file cannot be restored and is an error.
+pub fn fix_image(image_path: impl AsRef<Path>) -> Result<Vec<String>> {
+    let image_path = image_path.as_ref();
+    let mut image = OpenOptions::new().read(true).write(true).open(image_path)?;
+    let mut changes = Vec::new();
+    let base = locate_rkaf(&mut image)?;
+    let mut header = read_rkaf_header(&mut image, base)?;
+    let available = image.seek(SeekFrom::End(0))? - base;
+
+    let num_parts = (header.num_parts as usize).min(header.parts.len());
+    let is_marker = |path: &str| path == SELF_MARKER || path == RESERVED_MARKER;
+    let mut data_end = UPDATE_HEADER_SIZE as u64;
+    for part in header.parts[..num_parts].iter().filter(|part| !is_marker(&cstr_field(&part.full_path))) {
+        let end = part.offset() + part.byte_count();
+        if end > available {
+            return Err(AfptoolError::Parse(format!(
+                "Partition {} ends at {:#x}, past the {:#x} bytes of RKAF data left in the file; it cannot be restored",
+                cstr_field(&part.name), end, available
+            )));
+        }
+        data_end = data_end.max(end);
+    }
+
+    let mut length = header.image_length()?;
+    if length < data_end || length > available {
+        // Truncated past the old length, or a length that was damaged
+        changes.push(format!("RKAF length {:#x} -> {:#x}", length, data_end));
+        length = data_end;
+        header.set_image_length(length);
+        for part in header.parts[..num_parts].iter_mut() {
+            if cstr_field(&part.full_path) == SELF_MARKER {
+                part.set_byte_count(length + 4)?;
+            }
+        }
+        image.seek(SeekFrom::Start(base))?;
+        image.write_all(&header.to_bytes())?;
+    }
+
+    let (computed, stored) = rkaf_crc(&mut image, base, length, &CancelToken::default())?;
+    if stored != Some(computed) {
+        match stored {
+            Some(stored) => changes.push(format!("RKAF CRC {:#010x} -> {:#010x}", stored, computed)),
+            None => changes.push(format!("RKAF CRC {:#010x} appended", computed)),
+        }
+        write_rkaf_crc(&mut image, base, length, &CancelToken::default())?;
+    }
+
+    let Some(mut rkfw) = read_rkfw_header(&mut image)? else {
+        let end = length + 4;
+        if available > end {
+            changes.push(format!("{} bytes after the RKAF CRC dropped", available - end));
+        }
+        image.set_len(end)?;
+        return Ok(changes);
+    };
+    if rkfw.boot_offset as u64 + rkfw.boot_size as u64 > rkfw.update_offset as u64 {
+        return Err(AfptoolError::Parse(format!(
+            "RKFW BOOT section {:#x}+{:#x} overlaps the update image at {:#x}",
+            rkfw.boot_offset, rkfw.boot_size, rkfw.update_offset
+        )));
+    }
+    let update_size = length + 4;
+    if rkfw.update_size != update_size {
+        changes.push(format!("RKFW update size {:#x} -> {:#x}", rkfw.update_size, update_size));
+        let mut fields = [0u8; RKFW_LARGE_MARKER_AT + 6];
+        image.seek(SeekFrom::Start(0))?;
+        image.read_exact(&mut fields)?;
+        put_rkfw_update_size(&mut fields, update_size);
+        image.seek(SeekFrom::Start(0))?;
+        image.write_all(&fields)?;
+        rkfw.update_size = update_size;
+        // What follows the update image is no trailer any more
+        image.set_len(base + update_size)?;
+    }
+    match rkfw_md5(&mut image, &rkfw)? {
+        Md5Trailer::Match(_) => {}
+        Md5Trailer::Mismatch { stored, computed } => {
+            changes.push(format!("RKFW MD5 {} -> {}", stored, computed));
+            write_rkfw_md5(&mut image, &rkfw)?;
+        }
+        Md5Trailer::Missing(_) => {
+            write_rkfw_md5(&mut image, &rkfw)?;
+            changes.push("RKFW MD5 trailer rewritten".to_string());
+        }
+    }
+    Ok(changes)
+}
@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use crate::error::Result;
+use crate::hash::{md5_file_region, parallel_map};
+use crate::image::{cstr_field, list_parts, locate_rkaf, read_rkaf_header, read_rkfw_header, rkfw_sections, ImagePart};
+use crate::paths::open_file;
+use crate::text::clean_line;
+
+fn differ<T: std::fmt::Debug + PartialEq>(what: &str, a: T, b: T) -> Option<String> {
+    (a != b).then(|| format!("{} differs: {:?} vs {:?}", what, a, b))
+}
+
+/// Entries of an embedded package-file in a canonical order, since AFPTool
+/// and this tool may list the same files in a different sequence.
+fn package_entries(path: &Path, part: &ImagePart) -> Result<Vec<String>> {
+    let mut fp = open_file(path)?;
+    let mut data = vec![0u8; part.byte_count as usize];
+    fp.seek(SeekFrom::Start(part.file_offset))?;
+    fp.read_exact(&mut data)?;
+    let mut entries: Vec<String> = String::from_utf8_lossy(&data)
+        .lines()
+        .map(clean_line)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect();
+    entries.sort();
+    Ok(entries)
+}
+
+fn compare_rkfw(a: &mut File, a_path: &Path, b: &mut File, b_path: &Path) -> Result<Option<String>> {
+    let (a_header, b_header) = match (read_rkfw_header(a)?, read_rkfw_header(b)?) {
+        (None, None) => return Ok(None),
+        (Some(a_header), Some(b_header)) => (a_header, b_header),
+        (a_header, _) => {
+            let wrapped = if a_header.is_some() { "first" } else { "second" };
+            return Ok(Some(format!("Only the {} image has an RKFW wrapper", wrapped)));
+        }
+    };
+    if let Some(difference) = differ("RKFW version", &a_header.version, &b_header.version)
+        .or_else(|| differ("RKFW code", a_header.code, b_header.code))
+        .or_else(|| differ("RKFW chip code", a_header.chip_code, b_header.chip_code))
+    {
+        return Ok(Some(difference));
+    }
+
+    let [(a_boot_offset, a_boot_size), _] = rkfw_sections(a)?.unwrap_or_default();
+    let [(b_boot_offset, b_boot_size), _] = rkfw_sections(b)?.unwrap_or_default();
+    if let Some(difference) = differ("Loader size", a_boot_size, b_boot_size) {
+        return Ok(Some(difference));
+    }
+    if md5_file_region(a_path, a_boot_offset, a_boot_size)? != md5_file_region(b_path, b_boot_offset, b_boot_size)? {
+        return Ok(Some("Loader (BOOT) content differs".to_string()));
+    }
+    Ok(None)
+}
+
+/// Compares two RKFW or RKAF images at the format level: header fields that
+/// matter for flashing, the set of partitions with their flash layout, and
+/// the partition contents. Build dates, reserved bytes, padding and the order
+/// of data inside the image are ignored. Returns the first difference found,
+/// or `None` if the images are equivalent.
+pub fn compare_images(a_path: impl AsRef<Path>, b_path: impl AsRef<Path>) -> Result<Option<String>> {
+    let (a_path, b_path) = (a_path.as_ref(), b_path.as_ref());
+    let mut a = open_file(a_path)?;
+    let mut b = open_file(b_path)?;
+    if let Some(difference) = compare_rkfw(&mut a, a_path, &mut b, b_path)? {
+        return Ok(Some(difference));
+    }
+
+    let (a_base, b_base) = (locate_rkaf(&mut a)?, locate_rkaf(&mut b)?);
+    let a_header = read_rkaf_header(&mut a, a_base)?;
+    let b_header = read_rkaf_header(&mut b, b_base)?;
+    let (a_version, b_version) = (a_header.version, b_header.version);
+    if let Some(difference) = differ("Model", cstr_field(&a_header.model), cstr_field(&b_header.model))
+        .or_else(|| differ("Manufacturer", cstr_field(&a_header.manufacturer), cstr_field(&b_header.manufacturer)))
+        .or_else(|| differ("Machine id", a_header.id(), b_header.id()))
+        .or_else(|| differ("RKAF version", a_version, b_version))
+    {
+        return Ok(Some(difference));
+    }
+
+    let a_parts = list_parts(&mut a)?;
+    let b_parts = list_parts(&mut b)?;
+    let names = |parts: &[ImagePart]| {
+        let mut names: Vec<String> = parts.iter().map(|part| part.name.clone()).collect();
+        names.sort();
+        names
+    };
+    if let Some(difference) = differ("Partition list", names(&a_parts), names(&b_parts)) {
+        return Ok(Some(difference));
+    }
+
+    let mut pairs = Vec::new();
+    for a_part in &a_parts {
+        let b_part = b_parts.iter().find(|part| part.name == a_part.name).unwrap();
+        let field = |what: &str| format!("{} of {}", what, a_part.name);
+        if let Some(difference) = differ(&field("Path"), &a_part.full_path, &b_part.full_path)
+            .or_else(|| differ(&field("Flash offset"), a_part.flash_offset, b_part.flash_offset))
+            .or_else(|| differ(&field("Flash size"), a_part.flash_size, b_part.flash_size))
+            .or_else(|| differ(&field("Size"), a_part.byte_count, b_part.byte_count))
+        {
+            return Ok(Some(difference));
+        }
+        pairs.push((a_part, b_part));
+    }
+
+    let same = parallel_map(&pairs, |(a_part, b_part)| {
+        if a_part.name == "package-file" {
+            return Ok(package_entries(a_path, a_part)? == package_entries(b_path, b_part)?);
+        }
+        Ok(md5_file_region(a_path, a_part.file_offset, a_part.byte_count)?
+            == md5_file_region(b_path, b_part.file_offset, b_part.byte_count)?)
+    })?;
+    Ok(pairs
+        .iter()
+        .zip(same)
+        .find(|(_, same)| !same)
+        .map(|((part, _), _)| format!("Content of {} differs", part.name)))
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+use std::io;
+use thiserror::Error;
+use crate::cancel::Cancelled;
+
+/// Everything the library fails with, so callers can tell a damaged image
+/// from a bad input tree or a failed read without matching on message text.
+#[derive(Debug, Error)]
+pub enum AfptoolError {
+    /// The input is not a well-formed RKFW, RKAF or loader image, or a
+    /// metadata file in an unpacked tree can't be read.
+    #[error("{0}")]
+    Parse(String),
+    /// Data does not match the CRC, MD5 or device contents it was checked against.
+    #[error("{0}")]
+    Checksum(String),
+    /// package-file, partition metadata or mtdparts describe a layout that can't be built.
+    #[error("{0}")]
+    Layout(String),
+    /// An argument or option the operation can't use, such as an unknown
+    /// chip family or a malformed version.
+    #[error("{0}")]
+    Invalid(String),
+    /// A read or write failed. The message names the file when it is known;
+    /// the kind is that of the underlying error.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The operation was stopped through its [`CancelToken`](crate::CancelToken).
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+}
+
+pub type Result<T, E = AfptoolError> = std::result::Result<T, E>;
+
+/// Names the file or operation an I/O error happened on.
+pub(crate) trait IoContext<T> {
+    fn with_context<C: fmt::Display>(self, context: impl FnOnce() -> C) -> Result<T>;
+}
+
+impl<T> IoContext<T> for std::result::Result<T, io::Error> {
+    fn with_context<C: fmt::Display>(self, context: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|e| AfptoolError::Io(io::Error::new(e.kind(), format!("{}: {}", context(), e))))
+    }
+}
+
+impl From<std::num::ParseIntError> for AfptoolError {
+    fn from(e: std::num::ParseIntError) -> AfptoolError {
+        AfptoolError::Parse(format!("Invalid number: {}", e))
+    }
+}
+
+impl From<serde_json::Error> for AfptoolError {
+    fn from(e: serde_json::Error) -> AfptoolError {
+        match e.classify() {
+            serde_json::error::Category::Io => AfptoolError::Io(e.into()),
+            _ => AfptoolError::Parse(e.to_string()),
+        }
+    }
+}
+
+impl AfptoolError {
+    /// A file or entry the operation needs is missing.
+    pub(crate) fn not_found(message: impl Into<String>) -> AfptoolError {
+        AfptoolError::Io(io::Error::new(io::ErrorKind::NotFound, message.into()))
+    }
+
+    /// An input file grew or shrank while it was being read.
+    pub(crate) fn changed(message: impl Into<String>) -> AfptoolError {
+        AfptoolError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, message.into()))
+    }
+
+    /// A failure outside the image and its files, such as a hook that exited
+    /// with an error.
+    pub(crate) fn other(message: impl Into<String>) -> AfptoolError {
+        AfptoolError::Io(io::Error::other(message.into()))
+    }
+
+    /// The same error with `what` in front of its message.
+    pub(crate) fn within(self, what: impl fmt::Display) -> AfptoolError {
+        match self {
+            AfptoolError::Parse(message) => AfptoolError::Parse(format!("{}: {}", what, message)),
+            AfptoolError::Checksum(message) => AfptoolError::Checksum(format!("{}: {}", what, message)),
+            AfptoolError::Layout(message) => AfptoolError::Layout(format!("{}: {}", what, message)),
+            AfptoolError::Invalid(message) => AfptoolError::Invalid(format!("{}: {}", what, message)),
+            AfptoolError::Io(e) => AfptoolError::Io(io::Error::new(e.kind(), format!("{}: {}", what, e))),
+            AfptoolError::Cancelled(cancelled) => AfptoolError::Cancelled(cancelled),
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::fs::File;
+use crate::error::{AfptoolError, Result};
+use crate::sparse::Volume;
+
+const EXT4_MAGIC: u16 = 0xef53;
+const INCOMPAT_64BIT: u32 = 0x80;
+const EXTENTS_FL: u32 = 0x80000;
+const INLINE_DATA_FL: u32 = 0x10000000;
+const EXTENT_MAGIC: u16 = 0xf30a;
+const ROOT_INODE: u32 = 2;
+/// Largest file [`Ext4::read_file`] will load; build.prop and friends are far smaller.
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+struct Inode {
+    size: u64,
+    flags: u32,
+    block: [u8; 60],
+}
+
+/// Just enough of an ext2/3/4 reader to look files up by path and read them,
+/// for peeking into system and vendor partitions without mounting them.
+pub(crate) struct Ext4<'a> {
+    fp: &'a mut File,
+    volume: Volume,
+    block_size: u64,
+    inodes_per_group: u32,
+    inode_size: u64,
+    desc_size: u64,
+    desc_table: u64,
+    is_64bit: bool,
+}
+
+impl<'a> Ext4<'a> {
+    /// Opens the filesystem in `volume`, or returns `None` if it is not ext2/3/4.
+    pub fn open(fp: &'a mut File, volume: Volume) -> Result<Option<Ext4<'a>>> {
+        let mut superblock = [0u8; 1024];
+        if volume.read_at(fp, 1024, &mut superblock).is_err() || u16_at(&superblock, 56) != EXT4_MAGIC {
+            return Ok(None);
+        }
+
+        let log_block_size = u32_at(&superblock, 24);
+        if log_block_size > 6 {
+            return Err(AfptoolError::Parse("Invalid ext4 block size".to_string()));
+        }
+        let block_size = 1024u64 << log_block_size;
+        let first_data_block = u32_at(&superblock, 20) as u64;
+        let inode_size = if u32_at(&superblock, 76) == 0 { 128 } else { u16_at(&superblock, 88) as u64 };
+        let is_64bit = u32_at(&superblock, 96) & INCOMPAT_64BIT != 0;
+        let desc_size = if is_64bit { (u16_at(&superblock, 254) as u64).max(64) } else { 32 };
+
+        Ok(Some(Ext4 {
+            fp,
+            volume,
+            block_size,
+            inodes_per_group: u32_at(&superblock, 40).max(1),
+            inode_size: inode_size.max(128),
+            desc_size,
+            desc_table: (first_data_block + 1) * block_size,
+            is_64bit,
+        }))
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.volume.read_at(self.fp, offset, buf)
+    }
+
+    fn read_block(&mut self, block: u64) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; self.block_size as usize];
+        self.read_at(block * self.block_size, &mut data)?;
+        Ok(data)
+    }
+
+    fn inode(&mut self, number: u32) -> Result<Inode> {
+        let group = ((number - 1) / self.inodes_per_group) as u64;
+        let index = ((number - 1) % self.inodes_per_group) as u64;
+
+        let mut desc = vec![0u8; self.desc_size as usize];
+        self.read_at(self.desc_table + group * self.desc_size, &mut desc)?;
+        let mut table = u32_at(&desc, 8) as u64;
+        if self.is_64bit {
+            table |= (u32_at(&desc, 0x28) as u64) << 32;
+        }
+
+        let mut raw = [0u8; 128];
+        self.read_at(table * self.block_size + index * self.inode_size, &mut raw)?;
+        Ok(Inode {
+            size: u32_at(&raw, 4) as u64 | (u32_at(&raw, 108) as u64) << 32,
+            flags: u32_at(&raw, 32),
+            block: raw[40..100].try_into().unwrap(),
+        })
+    }
+
+    /// Physical blocks backing the file, in file order, as (logical, physical, count).
+    fn extents(&mut self, node: &[u8], out: &mut Vec<(u64, u64, u64)>) -> Result<()> {
+        if u16_at(node, 0) != EXTENT_MAGIC {
+            return Err(AfptoolError::Parse("Corrupt ext4 extent tree".to_string()));
+        }
+        let entries = u16_at(node, 2) as usize;
+        let depth = u16_at(node, 6);
+        for i in 0..entries {
+            let entry = node.get(12 + i * 12..24 + i * 12).ok_or_else(|| AfptoolError::Parse("Corrupt ext4 extent tree".to_string()))?;
+            if depth == 0 {
+                let len = u16_at(entry, 4) as u64;
+                // Lengths above 32768 mark uninitialized extents, which read as zeros
+                let (len, initialized) = if len > 32768 { (len - 32768, false) } else { (len, true) };
+                if initialized {
+                    let start = (u16_at(entry, 6) as u64) << 32 | u32_at(entry, 8) as u64;
+                    out.push((u32_at(entry, 0) as u64, start, len));
+                }
+            } else {
+                let leaf = (u16_at(entry, 8) as u64) << 32 | u32_at(entry, 4) as u64;
+                let child = self.read_block(leaf)?;
+                self.extents(&child, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Block map of a file without extents: direct blocks, then single, double
+    /// and triple indirect blocks.
+    fn mapped_blocks(&mut self, pointer: u64, level: u32, logical: &mut u64, wanted: u64, out: &mut Vec<(u64, u64, u64)>) -> Result<()> {
+        let per_block = self.block_size / 4;
+        if pointer == 0 {
+            *logical += per_block.pow(level);
+            return Ok(());
+        }
+        if level == 0 {
+            out.push((*logical, pointer, 1));
+            *logical += 1;
+            return Ok(());
+        }
+        let block = self.read_block(pointer)?;
+        for i in 0..per_block as usize {
+            if *logical >= wanted {
+                break;
+            }
+            self.mapped_blocks(u32_at(&block, i * 4) as u64, level - 1, logical, wanted, out)?;
+        }
+        Ok(())
+    }
+
+    fn read_inode_data(&mut self, inode: &Inode) -> Result<Vec<u8>> {
+        if inode.size > MAX_FILE_SIZE {
+            return Err(AfptoolError::Parse(format!("File too large to read ({} bytes)", inode.size)));
+        }
+        if inode.flags & INLINE_DATA_FL != 0 {
+            let len = std::cmp::min(inode.size as usize, inode.block.len());
+            return Ok(inode.block[..len].to_vec());
+        }
+
+        let blocks = inode.size.div_ceil(self.block_size);
+        let mut runs = Vec::new();
+        if inode.flags & EXTENTS_FL != 0 {
+            self.extents(&inode.block, &mut runs)?;
+        } else {
+            let mut logical = 0u64;
+            for (i, level) in [0u32; 12].into_iter().chain([1, 2, 3]).enumerate() {
+                if logical >= blocks {
+                    break;
+                }
+                self.mapped_blocks(u32_at(&inode.block, i * 4) as u64, level, &mut logical, blocks, &mut runs)?;
+            }
+        }
+
+        let mut data = vec![0u8; (blocks * self.block_size) as usize];
+        for (logical, physical, count) in runs {
+            if logical >= blocks {
+                continue;
+            }
+            let count = std::cmp::min(count, blocks - logical);
+            let start = (logical * self.block_size) as usize;
+            let end = start + (count * self.block_size) as usize;
+            self.read_at(physical * self.block_size, &mut data[start..end])?;
+        }
+        data.truncate(inode.size as usize);
+        Ok(data)
+    }
+
+    fn lookup(&mut self, directory: u32, name: &str) -> Result<Option<u32>> {
+        let inode = self.inode(directory)?;
+        let data = self.read_inode_data(&inode)?;
+        let mut offset = 0usize;
+        while offset + 8 <= data.len() {
+            let entry_inode = u32_at(&data, offset);
+            let rec_len = u16_at(&data, offset + 4) as usize;
+            let name_len = data[offset + 6] as usize;
+            if rec_len < 8 {
+                break;
+            }
+            if entry_inode != 0 && data.get(offset + 8..offset + 8 + name_len) == Some(name.as_bytes()) {
+                return Ok(Some(entry_inode));
+            }
+            offset += rec_len;
+        }
+        Ok(None)
+    }
+
+    /// Reads the file at `path` (relative to the filesystem root), or returns
+    /// `None` if it does not exist. Symlinks are not followed.
+    pub fn read_file(&mut self, path: &str) -> Result<Option<Vec<u8>>> {
+        let mut current = ROOT_INODE;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            match self.lookup(current, component)? {
+                Some(next) => current = next,
+                None => return Ok(None),
+            }
+        }
+        let inode = self.inode(current)?;
+        Ok(Some(self.read_inode_data(&inode)?))
+    }
+}
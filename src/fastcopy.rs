@@ -0,0 +1,65 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use crate::error::Result;
+use crate::cancel::CancelToken;
+use crate::timings::phase;
+
+/// Bytes copied between checks of the cancel token.
+const COPY_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Copies up to `len` bytes starting at `offset` in `input` to the current
+/// position of `out`, returning the number of bytes copied. On Linux the data
+/// is moved in the kernel with `copy_file_range` and never enters user space.
+pub(crate) fn copy_range(input: &File, offset: u64, len: u64, out: &mut File, cancel: &CancelToken) -> Result<u64> {
+    let _phase = phase("io");
+    #[cfg(target_os = "linux")]
+    if let Some(copied) = kernel_copy(input, offset, len, out, cancel)? {
+        return Ok(copied);
+    }
+
+    let mut reader = input;
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut copied = 0u64;
+    while copied < len {
+        cancel.check()?;
+        let chunk = std::io::copy(&mut reader.take(std::cmp::min(len - copied, COPY_CHUNK_SIZE)), out)?;
+        if chunk == 0 {
+            break;
+        }
+        copied += chunk;
+    }
+    Ok(copied)
+}
+
+/// `None` when the kernel or filesystem can't do it, before anything was written.
+#[cfg(target_os = "linux")]
+fn kernel_copy(input: &File, offset: u64, len: u64, out: &mut File, cancel: &CancelToken) -> Result<Option<u64>> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut off_in = offset as libc::loff_t;
+    let mut copied = 0u64;
+    while copied < len {
+        cancel.check()?;
+        let chunk = std::cmp::min(len - copied, COPY_CHUNK_SIZE) as usize;
+        // A null output offset writes at, and advances, out's file position
+        let result = unsafe {
+            libc::copy_file_range(input.as_raw_fd(), &mut off_in, out.as_raw_fd(), std::ptr::null_mut(), chunk, 0)
+        };
+        if result < 0 {
+            let error = std::io::Error::last_os_error();
+            let unsupported = matches!(
+                error.raw_os_error(),
+                Some(libc::ENOSYS | libc::EXDEV | libc::EINVAL | libc::EOPNOTSUPP | libc::EPERM)
+            );
+            if copied == 0 && unsupported {
+                return Ok(None);
+            }
+            return Err(error.into());
+        }
+        if result == 0 {
+            break;
+        }
+        copied += result as u64;
+    }
+    Ok(Some(copied))
+}
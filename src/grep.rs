@@ -0,0 +1,111 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use crate::error::{AfptoolError, Result};
+use flate2::read::MultiGzDecoder;
+use crate::image::list_parts;
+use crate::paths::open_file;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// One hit found by [`grep_image`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrepMatch {
+    pub part: String,
+    /// Index into the patterns passed to [`grep_image`].
+    pub pattern: usize,
+    /// Offset inside the partition, or inside its decompressed data.
+    pub offset: u64,
+    /// Absolute offset in the image file; `None` for matches in decompressed data.
+    pub file_offset: Option<u64>,
+}
+
+/// Encodes `text` as UTF-16LE, the form Windows tools and some bootloaders store strings in.
+pub fn utf16le_pattern(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+/// Parses a byte pattern such as `de ad be ef` or `deadbeef`.
+pub fn hex_pattern(text: &str) -> Result<Vec<u8>> {
+    let digits: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let digits = digits.trim_start_matches("0x");
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err(AfptoolError::Invalid(format!("Invalid hex pattern: {}", text)));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| AfptoolError::Invalid(format!("Invalid hex pattern: {}", text))))
+        .collect()
+}
+
+/// Streams `reader` looking for every pattern, keeping enough of each chunk's
+/// tail to catch matches that straddle two reads.
+fn scan(reader: &mut impl Read, patterns: &[Vec<u8>], mut on_match: impl FnMut(usize, u64)) -> Result<()> {
+    let overlap = patterns.iter().map(Vec::len).max().unwrap_or(1).saturating_sub(1);
+    let mut window: Vec<u8> = Vec::new();
+    let mut window_start = 0u64;
+    let mut buffer = vec![0u8; 1024 * 1024];
+
+    loop {
+        let read_bytes = reader.read(&mut buffer)?;
+        if read_bytes == 0 {
+            return Ok(());
+        }
+        let tail = window.len();
+        window.extend_from_slice(&buffer[..read_bytes]);
+
+        for (index, pattern) in patterns.iter().enumerate() {
+            if pattern.is_empty() {
+                continue;
+            }
+            for (position, candidate) in window.windows(pattern.len()).enumerate() {
+                // Matches lying wholly in the kept tail were reported last time round
+                if position + pattern.len() > tail && candidate == &pattern[..] {
+                    on_match(index, window_start + position as u64);
+                }
+            }
+        }
+
+        let keep = window.len().min(overlap);
+        window_start += (window.len() - keep) as u64;
+        window.drain(..window.len() - keep);
+    }
+}
+
+/// Searches every partition of an RKFW or RKAF image for the given byte
+/// patterns. With `decompress`, gzip-compressed partitions are searched in
+/// their decompressed form as well.
+pub fn grep_image(image_path: impl AsRef<Path>, patterns: &[Vec<u8>], part_filter: Option<&str>, decompress: bool) -> Result<Vec<GrepMatch>> {
+    let image_path = image_path.as_ref();
+    if patterns.iter().all(Vec::is_empty) {
+        return Err(AfptoolError::Invalid("No search pattern given".to_string()));
+    }
+
+    let mut fp = open_file(image_path)?;
+    let mut matches = Vec::new();
+    for part in list_parts(&mut fp)? {
+        if part_filter.is_some_and(|name| name != part.name) {
+            continue;
+        }
+
+        fp.seek(SeekFrom::Start(part.file_offset))?;
+        scan(&mut (&mut fp).take(part.byte_count), patterns, |pattern, offset| {
+            matches.push(GrepMatch {
+                part: part.name.clone(),
+                pattern,
+                offset,
+                file_offset: Some(part.file_offset + offset),
+            });
+        })?;
+
+        let mut magic = [0u8; 2];
+        fp.seek(SeekFrom::Start(part.file_offset))?;
+        if decompress && part.byte_count >= 2 && fp.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC {
+            fp.seek(SeekFrom::Start(part.file_offset))?;
+            let mut decoder = MultiGzDecoder::new((&mut fp).take(part.byte_count));
+            scan(&mut decoder, patterns, |pattern, offset| {
+                matches.push(GrepMatch { part: part.name.clone(), pattern, offset, file_offset: None });
+            })?;
+        }
+    }
+    Ok(matches)
+}
@@ -0,0 +1,67 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use crate::error::{AfptoolError, Result};
+use crate::image::list_parts;
+use crate::paths::open_file;
+use crate::timings::phase;
+
+/// Runs `f` over `items` on all available cores and returns the results in
+/// input order. Each call gets its own work item, so file-reading closures
+/// should open their own handle.
+pub(crate) fn parallel_map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> Result<R> + Sync) -> Result<Vec<R>> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get()).min(items.len());
+    if threads <= 1 {
+        return items.iter().map(&f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<R>>>> = Mutex::new((0..items.len()).map(|_| None).collect());
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                if index >= items.len() {
+                    break;
+                }
+                let result = f(&items[index]);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+
+    results.into_inner().unwrap().into_iter().map(|result| result.unwrap()).collect()
+}
+
+/// MD5 of `len` bytes at `offset` in the file at `path`, read through a fresh handle.
+pub(crate) fn md5_file_region(path: &Path, offset: u64, len: u64) -> Result<String> {
+    let mut fp = open_file(path)?;
+    fp.seek(SeekFrom::Start(offset))?;
+    let mut context = md5::Context::new();
+    let mut buffer = vec![0u8; 256 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let read_len = std::cmp::min(remaining as usize, buffer.len());
+        {
+            let _phase = phase("io");
+            fp.read_exact(&mut buffer[..read_len])
+                .map_err(|_| AfptoolError::Parse("Insufficient length in container image file".to_string()))?;
+        }
+        let _phase = phase("hash");
+        context.consume(&buffer[..read_len]);
+        remaining -= read_len as u64;
+    }
+    Ok(format!("{:x}", context.finalize()))
+}
+
+/// MD5 of every partition of an RKFW or RKAF image, as (name, digest) pairs,
+/// with the partitions hashed in parallel. `SELF` and `RESERVED` entries are
+/// left out.
+pub fn hash_image(image_path: impl AsRef<Path>) -> Result<Vec<(String, String)>> {
+    let image_path = image_path.as_ref();
+    let mut parts = list_parts(&mut open_file(image_path)?)?;
+    parts.retain(|part| !part.is_marker());
+    let digests = parallel_map(&parts, |part| md5_file_region(image_path, part.file_offset, part.byte_count))?;
+    Ok(parts.into_iter().map(|part| part.name).zip(digests).collect())
+}
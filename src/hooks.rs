@@ -0,0 +1,45 @@
+use std::path::Path;
+use std::process::Command;
+use crate::error::{AfptoolError, IoContext, Result};
+
+#[cfg(unix)]
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(windows)]
+fn shell_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+fn shell_command(command_line: &str) -> Command {
+    if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(command_line);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(command_line);
+        command
+    }
+}
+
+/// Runs each hook for one extracted partition. `{name}` and `{path}` in the hook
+/// are replaced with the quoted partition name and output path, which are also
+/// available as `AFPTOOL_PART_NAME` and `AFPTOOL_PART_PATH`.
+pub(crate) fn run_hooks(hooks: &[String], name: &str, path: &Path) -> Result<()> {
+    for hook in hooks {
+        let command_line = hook
+            .replace("{name}", &shell_quote(name))
+            .replace("{path}", &shell_quote(&path.to_string_lossy()));
+        let status = shell_command(&command_line)
+            .env("AFPTOOL_PART_NAME", name)
+            .env("AFPTOOL_PART_PATH", path)
+            .status()
+            .with_context(|| format!("Cannot run hook {:?}", hook))?;
+        if !status.success() {
+            return Err(AfptoolError::other(format!("Hook {:?} failed for {}: {}", hook, name, status)));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,334 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Language used for user-facing messages.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Lang {
+    En,
+    Zh,
+}
+
+const LANG_UNSET: u8 = 0;
+static CURRENT_LANG: AtomicU8 = AtomicU8::new(LANG_UNSET);
+
+impl Lang {
+    /// Parses a language name or POSIX locale such as `zh`, `en_US` or `zh_CN.UTF-8`.
+    pub fn from_locale(locale: &str) -> Option<Lang> {
+        let lang = locale
+            .split(['_', '-', '.', '@'])
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        match lang.as_str() {
+            "zh" => Some(Lang::Zh),
+            "en" | "c" | "posix" => Some(Lang::En),
+            _ => None,
+        }
+    }
+
+    fn from_env() -> Lang {
+        ["AFPTOOL_LANG", "LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|key| std::env::var(key).ok())
+            .find(|value| !value.is_empty())
+            .and_then(|value| Lang::from_locale(&value))
+            .unwrap_or(Lang::En)
+    }
+
+    fn catalog(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Lang::En => EN,
+            Lang::Zh => ZH,
+        }
+    }
+}
+
+/// Overrides the language detected from the environment.
+pub fn set_lang(lang: Lang) {
+    CURRENT_LANG.store(lang as u8 + 1, Ordering::Relaxed);
+}
+
+pub fn current_lang() -> Lang {
+    match CURRENT_LANG.load(Ordering::Relaxed) {
+        LANG_UNSET => {
+            let lang = Lang::from_env();
+            set_lang(lang);
+            lang
+        }
+        1 => Lang::En,
+        _ => Lang::Zh,
+    }
+}
+
+/// Looks up `key` in the current catalog and substitutes `{name}` placeholders.
+/// Keys missing from a translation fall back to English.
+pub fn translate(key: &str, args: &[(&str, &dyn Display)]) -> String {
+    let lookup = |catalog: &[(&str, &'static str)]| {
+        catalog.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+    };
+    let mut message = lookup(current_lang().catalog())
+        .or_else(|| lookup(EN))
+        .unwrap_or(key)
+        .to_string();
+
+    for (name, value) in args {
+        message = message.replace(&format!("{{{}}}", name), &value.to_string());
+    }
+    message
+}
+
+#[macro_export]
+macro_rules! tr {
+    ($key:expr) => {
+        $crate::i18n::translate($key, &[])
+    };
+    ($key:expr, $($name:ident = $value:expr),+ $(,)?) => {
+        $crate::i18n::translate($key, &[$((stringify!($name), &$value as &dyn std::fmt::Display)),+])
+    };
+}
+
+const EN: &[(&str, &str)] = &[
+    ("unpack-rkfw-detected", "RKFW signature detected"),
+    ("unpack-rkfp-detected", "RKFP signature detected"),
+    ("unpack-version", "version: {version}"),
+    ("unpack-code", "code field: {code}"),
+    ("unpack-boot-image", "{path} is an Android boot image (header version {version}), splitting it"),
+    ("unpack-code-usual", "code field is the usual one for this family, pack-rkfw needs no --code"),
+    ("unpack-code-unusual", "code field differs from the usual {typical} for this family, repack with --code {code} unless firmware-manifest.json is kept"),
+    ("unpack-date", "date: {date} (Unix timestamp: {timestamp})"),
+    ("unpack-new-chip", "You got a brand new chip ({code}), congratulations!!!"),
+    ("unpack-family", "family: {family}"),
+    ("unpack-filesize", "Filesize: {size}"),
+    ("unpack-bad-length", "RKAF header length {length} plus the CRC doesn't match the {size}-byte file; the CRC cannot be checked"),
+    ("unpack-bad-crc", "RKAF CRC mismatch: stored {stored}, computed {computed}"),
+    ("unpack-force-hint", " (use --force to unpack anyway)"),
+    ("unpack-md5-ok", "MD5: {md5} (intact)"),
+    ("unpack-md5-missing", "No MD5 trailer after the update image, integrity not checked"),
+    ("unpack-bad-md5", "RKFW MD5 mismatch: stored {stored}, computed {computed}"),
+    ("unpack-bad-boot", "No loader signature (BOOT or LDR) at BOOT offset {offset}, found {found}"),
+    ("unpack-lenient-hint", " (use --lenient to unpack anyway)"),
+    ("unpack-no-rkaf", "Cannot find embedded RKAF update.img at {offset}, found {found}"),
+    ("unpack-manufacturer", "manufacturer: {manufacturer}"),
+    ("unpack-model", "model: {model}"),
+    ("unpack-machine-id", "machine id: {id}"),
+    ("unpack-rkaf-header", "header version: {version}, unknown1: {unknown1}, length: {length} bytes, partitions: {parts}"),
+    ("unpack-gbk", "model/manufacturer are GBK-encoded; pack with --gbk to keep them that way"),
+    ("unpack-placeholder-skipped", "{name} is a placeholder with no data in the image, not extracted"),
+    ("unpack-metadata-saved", "Partition metadata saved to: {path}"),
+    ("unpack-rkfp-manifest-saved", "RKFP manifest saved to: {path}"),
+    ("unpack-package-file-written", "No package-file in the image, wrote one from the partition table: {path}"),
+    ("unpack-parm-stripped", "Stripped the PARM header and CRC from parameter, packing adds them back: {path}"),
+    ("unpack-zero-skipped", "{path} is all zero, not written"),
+    ("unpack-desparsed", "{path} is an Android sparse image, writing it expanded ({size} bytes)"),
+    ("pack-rkfw-from-tree", "No embedded-update.img in {dir}, packing one from its package-file"),
+    ("pack-dedup", "{path} has the same contents as {other}, stored once"),
+    ("pack-rkfw-success", "Successfully packed RKFW image:"),
+    ("pack-rkaf-success", "Successfully packed RKAF image:"),
+    ("pack-rkfp-success", "Successfully packed RKFP image:"),
+    ("pack-output", "  Output: {path}"),
+    ("pack-version", "  Version: {version}"),
+    ("pack-date", "  Date: {date}"),
+    ("pack-chip", "  Chip: {chip} (code: {code})"),
+    ("pack-boot-size", "  BOOT size: {size} bytes"),
+    ("pack-update-size", "  Update image size: {size} bytes"),
+    ("pack-md5", "  MD5: {md5}"),
+    ("pack-model", "  Model: {model}"),
+    ("pack-manufacturer", "  Manufacturer: {manufacturer}"),
+    ("pack-parts", "  Parts: {count}"),
+    ("pack-total-size", "  Total size: {size} bytes"),
+    ("device-verifying", "verifying {name} ({size} bytes) against {device} at sector {sector}"),
+    ("device-image-md5", "image MD5:  {md5}"),
+    ("device-device-md5", "device MD5: {md5}"),
+    ("device-ok", "{name}: OK"),
+    ("device-writing", "writing {name} ({size} bytes) to {device}"),
+    ("device-confirm", "This overwrites the start of {device} with partition {name} from {image}. Continue? [y/N] "),
+    ("tree-ok", "OK"),
+    ("tree-missing", "MISSING"),
+    ("tree-size-changed", "SIZE CHANGED (expected {expected}, found {actual})"),
+    ("tree-hash-changed", "CONTENT CHANGED"),
+    ("error", "Error: {message}"),
+    ("warning", "Warning: {message}"),
+    ("unsupported-lang", "Unsupported language: {lang}"),
+    ("aborted", "Aborted"),
+    ("batch-summary", "{unpacked} of {total} images unpacked"),
+    ("batch-failed", "{failed} of {total} images failed to unpack"),
+    ("info-android-boot", "Android boot image"),
+    ("info-android-boot-in", "{part} (Android boot image):"),
+    ("info-boot-header", "Header version: {version}, page size {page_size}"),
+    ("info-bytes", "{size} bytes"),
+    ("info-board-name", "Board name: {name}"),
+    ("info-os-version", "OS version: {version}, patch level {level}"),
+    ("info-cmdline", "Command line: {cmdline}"),
+    ("info-rkfw-version", "RKFW version: {version}"),
+    ("info-rkfw-code", "RKFW code: {code}"),
+    ("info-build-date", "Build date: {date}"),
+    ("info-chip", "Chip: {chip} ({code})"),
+    ("info-unknown", "unknown"),
+    ("info-update-image", "Update image: {offset} {size}"),
+    ("info-rkaf-version", "RKAF version: {version}"),
+    ("info-model", "Model: {model}"),
+    ("info-manufacturer", "Manufacturer: {manufacturer}"),
+    ("info-machine-id", "Machine id: {id}"),
+    ("info-no-build-prop", "No build.prop found in any ext4 partition"),
+    ("info-fingerprint", "Fingerprint: {value}"),
+    ("info-android-version", "Android version: {value}"),
+    ("info-security-patch", "Security patch: {value}"),
+    ("list-flash", "flash {offset}+{size}"),
+    ("list-placeholder", "(placeholder)"),
+    ("verify-pass", "ok"),
+    ("verify-fail", "FAIL"),
+    ("verify-passed", "All {total} checks passed"),
+    ("verify-failed", "{failed} of {total} checks failed"),
+    ("equal-same", "Images are equivalent"),
+    ("equal-differ", "Images differ: {difference}"),
+    ("layout-moved", "{name} moved {from} -> {to}"),
+    ("layout-resized", "{name} resized {from} -> {to}"),
+    ("layout-renamed", "{from} renamed to {to}"),
+    ("layout-diff-unsafe", "[unsafe for OTA]"),
+    ("layout-diff-summary", "{count} changes, {unsafe_count} unsafe for a direct OTA"),
+    ("compat-verdict", "Candidate is {verdict}"),
+    ("grep-no-matches", "No matches"),
+    ("size-bytes", "{size} bytes"),
+    ("timings-header", "Timings:"),
+    ("timings-spans", "{count} spans"),
+    ("timings-total", "total"),
+    ("boot-not-scrambled", "Loader entries are not RC4-scrambled, written as stored"),
+    ("boot-chip", "Chip: {chip}, released {date}"),
+    ("boot-crc-mismatch", "loader CRC does not match"),
+    ("boot-packed", "Packed {count} loader entries into {path}"),
+    ("resource-packed", "Packed {count} resource files into {path}"),
+    ("rkcrc-unwrapped", "{kind} CRC OK, data written to {path}"),
+    ("rkcrc-wrapped", "Wrapped {input} with a {kind} header into {path}"),
+    ("index-done", "{count} images indexed into {db}"),
+    ("disk-size", "{path}: {size} bytes"),
+    ("trim-file", "{path} {original} -> {trimmed} bytes ({saved} saved)"),
+    ("trim-total", "total: {saved} bytes saved"),
+    ("fix-nothing", "{image}: nothing to fix"),
+    ("build-variant", "{name}: {path}"),
+    ("logging-failed", "Cannot set up logging: {error}"),
+    ("wrap-no-code", "No usual code field for {chip}, pass --code"),
+    ("store-name-single", "--name can only be used with a single image"),
+];
+
+const ZH: &[(&str, &str)] = &[
+    ("unpack-rkfw-detected", "检测到 RKFW 签名"),
+    ("unpack-rkfp-detected", "检测到 RKFP 签名"),
+    ("unpack-version", "版本: {version}"),
+    ("unpack-code", "code 字段: {code}"),
+    ("unpack-boot-image", "{path} 是 Android 启动镜像（头版本 {version}），正在拆分"),
+    ("unpack-code-usual", "code 字段是该系列的常用值，pack-rkfw 无需 --code"),
+    ("unpack-code-unusual", "code 字段与该系列常用值 {typical} 不同，若不保留 firmware-manifest.json，重新打包时请加 --code {code}"),
+    ("unpack-date", "日期: {date}（Unix 时间戳: {timestamp}）"),
+    ("unpack-new-chip", "发现了一个全新的芯片（{code}），恭喜！！！"),
+    ("unpack-family", "芯片系列: {family}"),
+    ("unpack-filesize", "文件大小: {size}"),
+    ("unpack-bad-length", "RKAF 头部长度 {length} 加 CRC 与 {size} 字节的文件不符，无法校验 CRC"),
+    ("unpack-bad-crc", "RKAF CRC 不匹配: 记录值 {stored}，计算值 {computed}"),
+    ("unpack-force-hint", "（使用 --force 强制解包）"),
+    ("unpack-md5-ok", "MD5: {md5}（完整）"),
+    ("unpack-md5-missing", "更新映像后没有 MD5，未校验完整性"),
+    ("unpack-bad-md5", "RKFW MD5 不匹配: 记录值 {stored}，计算值 {computed}"),
+    ("unpack-bad-boot", "BOOT 偏移 {offset} 处没有引导程序签名（BOOT 或 LDR），而是 {found}"),
+    ("unpack-lenient-hint", "（使用 --lenient 仍然解包）"),
+    ("unpack-no-rkaf", "在 {offset} 处找不到内嵌的 RKAF update.img，而是 {found}"),
+    ("unpack-manufacturer", "制造商: {manufacturer}"),
+    ("unpack-model", "型号: {model}"),
+    ("unpack-machine-id", "机器 ID: {id}"),
+    ("unpack-rkaf-header", "头部版本: {version}，unknown1: {unknown1}，长度: {length} 字节，分区数: {parts}"),
+    ("unpack-gbk", "型号/制造商为 GBK 编码；打包时使用 --gbk 以保持原编码"),
+    ("unpack-placeholder-skipped", "{name} 是占位项，镜像中没有数据，未提取"),
+    ("unpack-metadata-saved", "分区元数据已保存到: {path}"),
+    ("unpack-rkfp-manifest-saved", "RKFP 清单已保存到: {path}"),
+    ("unpack-package-file-written", "映像中没有 package-file，已根据分区表生成: {path}"),
+    ("unpack-parm-stripped", "已去除 parameter 的 PARM 头和 CRC，打包时会重新加上: {path}"),
+    ("unpack-zero-skipped", "{path} 全部为零，未写出"),
+    ("unpack-desparsed", "{path} 是 Android 稀疏镜像，展开后写出（{size} 字节）"),
+    ("pack-rkfw-from-tree", "{dir} 中没有 embedded-update.img，按其 package-file 打包"),
+    ("pack-dedup", "{path} 与 {other} 内容相同，只存储一份"),
+    ("pack-rkfw-success", "RKFW 映像打包成功:"),
+    ("pack-rkaf-success", "RKAF 映像打包成功:"),
+    ("pack-rkfp-success", "RKFP 映像打包成功:"),
+    ("pack-output", "  输出: {path}"),
+    ("pack-version", "  版本: {version}"),
+    ("pack-date", "  日期: {date}"),
+    ("pack-chip", "  芯片: {chip}（代码: {code}）"),
+    ("pack-boot-size", "  BOOT 大小: {size} 字节"),
+    ("pack-update-size", "  更新映像大小: {size} 字节"),
+    ("pack-md5", "  MD5: {md5}"),
+    ("pack-model", "  型号: {model}"),
+    ("pack-manufacturer", "  制造商: {manufacturer}"),
+    ("pack-parts", "  分区数: {count}"),
+    ("pack-total-size", "  总大小: {size} 字节"),
+    ("device-verifying", "正在校验 {name}（{size} 字节），设备 {device}，扇区 {sector}"),
+    ("device-image-md5", "映像 MD5: {md5}"),
+    ("device-device-md5", "设备 MD5: {md5}"),
+    ("device-ok", "{name}: 正常"),
+    ("device-writing", "正在将 {name}（{size} 字节）写入 {device}"),
+    ("device-confirm", "这将用 {image} 中的分区 {name} 覆盖 {device} 的开头。是否继续？[y/N] "),
+    ("tree-ok", "正常"),
+    ("tree-missing", "缺失"),
+    ("tree-size-changed", "大小已改变（应为 {expected}，实际 {actual}）"),
+    ("tree-hash-changed", "内容已改变"),
+    ("error", "错误: {message}"),
+    ("warning", "警告: {message}"),
+    ("unsupported-lang", "不支持的语言: {lang}"),
+    ("aborted", "已取消"),
+    ("batch-summary", "已解包 {unpacked}/{total} 个映像"),
+    ("batch-failed", "{total} 个映像中有 {failed} 个解包失败"),
+    ("info-android-boot", "Android 启动镜像"),
+    ("info-android-boot-in", "{part}（Android 启动镜像）:"),
+    ("info-boot-header", "头版本: {version}，页大小 {page_size}"),
+    ("info-bytes", "{size} 字节"),
+    ("info-board-name", "板名: {name}"),
+    ("info-os-version", "系统版本: {version}，补丁级别 {level}"),
+    ("info-cmdline", "命令行: {cmdline}"),
+    ("info-rkfw-version", "RKFW 版本: {version}"),
+    ("info-rkfw-code", "RKFW code 字段: {code}"),
+    ("info-build-date", "构建日期: {date}"),
+    ("info-chip", "芯片: {chip}（{code}）"),
+    ("info-unknown", "未知"),
+    ("info-update-image", "更新映像: {offset} {size}"),
+    ("info-rkaf-version", "RKAF 版本: {version}"),
+    ("info-model", "型号: {model}"),
+    ("info-manufacturer", "制造商: {manufacturer}"),
+    ("info-machine-id", "机器 ID: {id}"),
+    ("info-no-build-prop", "所有 ext4 分区中都没有找到 build.prop"),
+    ("info-fingerprint", "指纹: {value}"),
+    ("info-android-version", "Android 版本: {value}"),
+    ("info-security-patch", "安全补丁: {value}"),
+    ("list-flash", "闪存 {offset}+{size}"),
+    ("list-placeholder", "（占位项）"),
+    ("verify-pass", "通过"),
+    ("verify-fail", "失败"),
+    ("verify-passed", "全部 {total} 项检查通过"),
+    ("verify-failed", "{total} 项检查中有 {failed} 项失败"),
+    ("equal-same", "两个映像等价"),
+    ("equal-differ", "映像不同: {difference}"),
+    ("layout-moved", "{name} 已移动 {from} -> {to}"),
+    ("layout-resized", "{name} 大小改变 {from} -> {to}"),
+    ("layout-renamed", "{from} 改名为 {to}"),
+    ("layout-diff-unsafe", "[不适合 OTA]"),
+    ("layout-diff-summary", "{count} 处变化，其中 {unsafe_count} 处不适合直接 OTA"),
+    ("compat-verdict", "候选映像: {verdict}"),
+    ("grep-no-matches", "没有匹配"),
+    ("size-bytes", "{size} 字节"),
+    ("timings-header", "耗时:"),
+    ("timings-spans", "{count} 段"),
+    ("timings-total", "合计"),
+    ("boot-not-scrambled", "loader 条目未经 RC4 加扰，按原样写出"),
+    ("boot-chip", "芯片: {chip}，发布时间 {date}"),
+    ("boot-crc-mismatch", "loader CRC 不匹配"),
+    ("boot-packed", "已将 {count} 个 loader 条目打包到 {path}"),
+    ("resource-packed", "已将 {count} 个资源文件打包到 {path}"),
+    ("rkcrc-unwrapped", "{kind} CRC 正确，数据已写入 {path}"),
+    ("rkcrc-wrapped", "已为 {input} 加上 {kind} 头并写入 {path}"),
+    ("index-done", "已将 {count} 个映像编入 {db}"),
+    ("disk-size", "{path}: {size} 字节"),
+    ("trim-file", "{path} {original} -> {trimmed} 字节（节省 {saved}）"),
+    ("trim-total", "合计节省 {saved} 字节"),
+    ("fix-nothing", "{image}: 无需修复"),
+    ("build-variant", "{name}: {path}"),
+    ("logging-failed", "无法设置日志: {error}"),
+    ("wrap-no-code", "{chip} 没有常用的 code 字段，请指定 --code"),
+    ("store-name-single", "--name 只能用于单个映像"),
+];
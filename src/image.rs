@@ -0,0 +1,233 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use crate::error::{AfptoolError, Result};
+use crate::paths::open_file;
+use serde::Serialize;
+use crate::chips::{chip_code_to_name, chip_name_to_code, same_chip};
+use crate::pack::{RESERVED_MARKER, SELF_MARKER};
+use crate::text::decode_text;
+use crate::timings::phase;
+use crate::{UpdateHeader, UpdatePart, RKAF_SIGNATURE, RKFP_SIGNATURE, RKFW_SIGNATURE, UPDATE_HEADER_SIZE};
+
+/// A partition entry resolved against the file it was read from.
+#[derive(Debug, Clone)]
+pub(crate) struct ImagePart {
+    pub name: String,
+    pub full_path: String,
+    pub flash_offset: u32,
+    pub flash_size: u32,
+    /// Absolute offset of the partition data inside the opened file.
+    pub file_offset: u64,
+    pub byte_count: u64,
+    /// Entry without data, see [`UpdatePart::is_placeholder`].
+    pub placeholder: bool,
+}
+
+impl ImagePart {
+    /// `SELF` and `RESERVED` entries, which describe the image rather than
+    /// hold partition data.
+    pub(crate) fn is_marker(&self) -> bool {
+        self.full_path == SELF_MARKER || self.full_path == RESERVED_MARKER
+    }
+}
+
+/// Bytes of the RKFW header that are read: everything up to the high half
+/// of the update image size.
+const RKFW_FIELDS_LEN: usize = 0x41;
+/// `HI` in the reserved area marks an RKFW image whose update image is past
+/// 4 GiB; the high 32 bits of its size follow, as in Rockchip's newer tools.
+pub(crate) const RKFW_LARGE_MARKER_AT: usize = 0x37;
+pub(crate) const RKFW_LARGE_MARKER: &[u8] = b"HI";
+
+/// The update image size of an RKFW header, with its high half when marked.
+pub(crate) fn rkfw_update_size(header: &[u8]) -> u64 {
+    let low = u32::from_le_bytes([header[0x25], header[0x26], header[0x27], header[0x28]]) as u64;
+    let at = RKFW_LARGE_MARKER_AT;
+    if header[at..at + 2] != *RKFW_LARGE_MARKER {
+        return low;
+    }
+    let high = u32::from_le_bytes([header[at + 2], header[at + 3], header[at + 4], header[at + 5]]) as u64;
+    high << 32 | low
+}
+
+/// Returns the offset of the RKAF update image inside `fp`, looking through
+/// the RKFW wrapper when there is one.
+pub(crate) fn locate_rkaf(fp: &mut (impl Read + Seek)) -> Result<u64> {
+    let mut buf = [0u8; RKFW_FIELDS_LEN];
+    fp.seek(SeekFrom::Start(0))?;
+    fp.read_exact(&mut buf[..4])?;
+
+    match &buf[..4] {
+        RKAF_SIGNATURE => Ok(0),
+        RKFW_SIGNATURE => {
+            fp.read_exact(&mut buf[4..])
+                .map_err(|_| AfptoolError::Parse("RKFW header is truncated".to_string()))?;
+            let offset = u32::from_le_bytes([buf[0x21], buf[0x22], buf[0x23], buf[0x24]]) as u64;
+            let file_len = fp.seek(SeekFrom::End(0))?;
+            if offset + UPDATE_HEADER_SIZE as u64 > file_len {
+                return Err(AfptoolError::Parse(format!(
+                    "RKFW header puts the update image at {:#x}, past the end of the {:#x}-byte file", offset, file_len
+                )));
+            }
+            Ok(offset)
+        }
+        RKFP_SIGNATURE => Err(rkfp_unsupported()),
+        signature => Err(AfptoolError::Parse(format!("Unknown signature: {:?}", signature))),
+    }
+}
+
+/// RKFP containers have their own entry table instead of an RKAF header, so
+/// commands that read the RKAF header refuse them rather than misread them.
+pub(crate) fn rkfp_unsupported() -> AfptoolError {
+    AfptoolError::Parse("RKFP containers can only be unpacked; this command reads RKFW and RKAF images".to_string())
+}
+
+/// Fields of the RKFW wrapper header.
+#[derive(Debug, Clone, Serialize)]
+pub struct RkfwHeader {
+    pub version: String,
+    pub code: u32,
+    /// Build date as `YYYY-MM-DD HH:MM:SS`, as stored in the header.
+    pub date: String,
+    pub timestamp: Option<i64>,
+    pub chip_code: u32,
+    pub boot_offset: u32,
+    pub boot_size: u32,
+    pub update_offset: u32,
+    pub update_size: u64,
+}
+
+pub(crate) fn read_rkfw_header(fp: &mut (impl Read + Seek)) -> Result<Option<RkfwHeader>> {
+    let _phase = phase("parse");
+    let mut buf = [0u8; RKFW_FIELDS_LEN];
+    fp.seek(SeekFrom::Start(0))?;
+    if fp.read_exact(&mut buf).is_err() || &buf[..4] != RKFW_SIGNATURE {
+        return Ok(None);
+    }
+
+    let year = u16::from_le_bytes([buf[0x0e], buf[0x0f]]);
+    let (month, day, hour, minute, second) = (buf[0x10], buf[0x11], buf[0x12], buf[0x13], buf[0x14]);
+    let timestamp = chrono::NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)
+        .and_then(|date| date.and_hms_opt(hour as u32, minute as u32, second as u32))
+        .map(|dt| dt.and_utc().timestamp());
+
+    Ok(Some(RkfwHeader {
+        version: format!("{}.{}.{}", buf[9], buf[8], u16::from_le_bytes([buf[6], buf[7]])),
+        code: u32::from_le_bytes([buf[0x0a], buf[0x0b], buf[0x0c], buf[0x0d]]),
+        date: format!("{}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second),
+        timestamp,
+        chip_code: u32::from_le_bytes([buf[0x15], buf[0x16], buf[0x17], buf[0x18]]),
+        boot_offset: u32::from_le_bytes([buf[0x19], buf[0x1a], buf[0x1b], buf[0x1c]]),
+        boot_size: u32::from_le_bytes([buf[0x1d], buf[0x1e], buf[0x1f], buf[0x20]]),
+        update_offset: u32::from_le_bytes([buf[0x21], buf[0x22], buf[0x23], buf[0x24]]),
+        update_size: rkfw_update_size(&buf),
+    }))
+}
+
+/// Reads the RKFW header of an image, or `None` for a bare RKAF image.
+pub fn read_rkfw_info(image_path: impl AsRef<Path>) -> Result<Option<RkfwHeader>> {
+    read_rkfw_header(&mut open_file(image_path)?)
+}
+
+/// Fails unless the image's RKFW header names the chip family `expected`, so
+/// an image for one SoC is never unpacked, checked or flashed as another's.
+pub fn check_chip(image_path: impl AsRef<Path>, expected: &str) -> Result<()> {
+    let image_path = image_path.as_ref();
+    check_chip_of(&mut open_file(image_path)?, &image_path.display().to_string(), expected)
+}
+
+/// [`check_chip`] for an image already open as `fp`, called `name` in errors.
+pub(crate) fn check_chip_of(fp: &mut (impl Read + Seek), name: &str, expected: &str) -> Result<()> {
+    let expected_code = chip_name_to_code(expected)?;
+    let header = read_rkfw_header(fp)?
+        .ok_or_else(|| AfptoolError::Parse(format!("{} has no RKFW header, so its chip family cannot be checked", name)))?;
+    if !same_chip(header.chip_code, expected_code) {
+        let actual = chip_code_to_name(header.chip_code).unwrap_or("unknown");
+        return Err(AfptoolError::Invalid(format!(
+            "{} is for {} (chip code {:#x}), not {} ({:#x})",
+            name, actual, header.chip_code, expected, expected_code
+        )));
+    }
+    Ok(())
+}
+
+/// BOOT and embedded update.img regions (offset, size) of an RKFW image.
+pub(crate) fn rkfw_sections(fp: &mut (impl Read + Seek)) -> Result<Option<[(u64, u64); 2]>> {
+    let mut buf = [0u8; RKFW_FIELDS_LEN];
+    fp.seek(SeekFrom::Start(0))?;
+    if fp.read_exact(&mut buf).is_err() || &buf[..4] != RKFW_SIGNATURE {
+        return Ok(None);
+    }
+
+    let field = |offset: usize| u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]]) as u64;
+    Ok(Some([(field(0x19), field(0x1d)), (field(0x21), rkfw_update_size(&buf))]))
+}
+
+pub(crate) fn read_rkaf_header(fp: &mut (impl Read + Seek), base: u64) -> Result<UpdateHeader> {
+    let _phase = phase("parse");
+    let mut buf = vec![0u8; UPDATE_HEADER_SIZE];
+    fp.seek(SeekFrom::Start(base))?;
+    fp.read_exact(&mut buf)?;
+
+    if buf[..4] != *RKAF_SIGNATURE {
+        return Err(AfptoolError::Parse(format!("Cannot find RKAF update image at offset {:#x}", base)));
+    }
+    UpdateHeader::from_bytes(&buf)
+}
+
+/// Reads the RKAF header of an RKFW or RKAF image.
+pub fn read_update_header(image_path: impl AsRef<Path>) -> Result<UpdateHeader> {
+    read_update_header_from_reader(&mut open_file(image_path)?)
+}
+
+/// [`read_update_header`] for an image that is not a file on disk.
+pub fn read_update_header_from_reader(reader: &mut (impl Read + Seek)) -> Result<UpdateHeader> {
+    let base = locate_rkaf(reader)?;
+    read_rkaf_header(reader, base)
+}
+
+pub(crate) fn cstr_field(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    decode_text(&bytes[..len])
+}
+
+fn resolve_part(part: &UpdatePart, base: u64) -> Result<ImagePart> {
+    let name = cstr_field(&part.name);
+    // Callers work out where the data ends, so that has to fit too
+    let file_offset = base.checked_add(part.offset())
+        .filter(|offset| offset.checked_add(part.byte_count()).is_some())
+        .ok_or_else(|| AfptoolError::Parse(format!(
+            "Partition {} at {:#x}+{:#x} ends past the largest possible image", name, part.offset(), part.byte_count()
+        )))?;
+    Ok(ImagePart {
+        name,
+        full_path: cstr_field(&part.full_path),
+        flash_offset: part.flash_offset,
+        flash_size: part.flash_size,
+        file_offset,
+        byte_count: part.byte_count(),
+        placeholder: part.is_placeholder(),
+    })
+}
+
+/// Lists the partitions of an RKFW or RKAF image without extracting anything.
+pub(crate) fn list_parts(fp: &mut (impl Read + Seek)) -> Result<Vec<ImagePart>> {
+    let _phase = phase("parse");
+    let base = locate_rkaf(fp)?;
+    let header = read_rkaf_header(fp, base)?;
+    let num_parts = header.num_parts;
+    let parts = header.parts;
+
+    parts
+        .iter()
+        .take(num_parts as usize)
+        .map(|part| resolve_part(part, base))
+        .collect()
+}
+
+pub(crate) fn find_part(fp: &mut (impl Read + Seek), name: &str) -> Result<ImagePart> {
+    list_parts(fp)?
+        .into_iter()
+        .find(|part| part.name == name)
+        .ok_or_else(|| AfptoolError::Invalid(format!("Cannot find partition {} in image", name)))
+}
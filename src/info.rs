@@ -0,0 +1,51 @@
+use std::path::Path;
+use crate::error::Result;
+use crate::paths::open_file;
+use serde::Serialize;
+use crate::bootimg::{read_boot_image_at, BootImageInfo};
+use crate::image::{read_rkfw_info, read_update_header, RkfwHeader};
+use crate::list::{list_partitions, Partition};
+use crate::chips::chip_code_to_name;
+
+/// Everything `info` reports about an image, in a form that serializes to JSON.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageInfo {
+    /// Present only for RKFW images.
+    pub rkfw: Option<RkfwHeader>,
+    /// Chip family named by the RKFW chip code, when it is a known one.
+    pub chip: Option<String>,
+    /// RKAF version as `major.minor.build`.
+    pub version: String,
+    pub model: String,
+    pub manufacturer: String,
+    pub machine_id: String,
+    pub partitions: Vec<Partition>,
+    /// Partitions holding an Android boot image, such as boot and recovery.
+    pub boot_images: Vec<BootImageInfo>,
+}
+
+/// Reads the headers and partition table of an RKFW or RKAF image.
+pub fn image_info(image_path: impl AsRef<Path>) -> Result<ImageInfo> {
+    let image_path = image_path.as_ref();
+    let rkfw = read_rkfw_info(image_path)?;
+    let header = read_update_header(image_path)?;
+    let partitions = list_partitions(image_path)?;
+    let mut file = open_file(image_path)?;
+    let mut boot_images = Vec::new();
+    for part in partitions.iter().filter(|part| !part.placeholder) {
+        // A damaged boot header shouldn't hide the rest of the report
+        if let Ok(Some(boot)) = read_boot_image_at(&mut file, part.file_offset, part.byte_count, Some(part.name.clone())) {
+            boot_images.push(boot);
+        }
+    }
+    Ok(ImageInfo {
+        chip: rkfw.as_ref().and_then(|rkfw| chip_code_to_name(rkfw.chip_code)).map(str::to_string),
+        rkfw,
+        version: header.version_text(),
+        model: header.model_name(),
+        manufacturer: header.manufacturer_name(),
+        machine_id: header.id(),
+        partitions,
+        boot_images,
+    })
+}
@@ -0,0 +1,164 @@
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use crate::error::{AfptoolError, Result};
+use crate::image::{cstr_field, find_part};
+use crate::parameter::{MtdPart, ParameterFile};
+use crate::paths::{join_path, open_file};
+use crate::pack::{RESERVED_MARKER, SELF_MARKER};
+use crate::{tr, UpdateHeader, RKAF_SIGNATURE, RKFW_SIGNATURE};
+
+/// One difference between two flash layouts, with offsets and sizes in sectors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutChange {
+    Added(MtdPart),
+    Removed(MtdPart),
+    Moved { name: String, from: u64, to: u64 },
+    /// `None` is a partition that takes the rest of the flash.
+    Resized { name: String, from: Option<u64>, to: Option<u64> },
+    /// Same offset and size under a different name.
+    Renamed { from: String, to: String },
+}
+
+impl LayoutChange {
+    /// Whether the change breaks a direct OTA from the old layout to the new
+    /// one: data would end up in the wrong place, or a partition the updater
+    /// writes by name no longer exists. Adding a partition is harmless.
+    pub fn ota_unsafe(&self) -> bool {
+        !matches!(self, LayoutChange::Added(_))
+    }
+}
+
+fn size_text(size: Option<u64>) -> String {
+    size.map_or_else(|| "-".to_string(), |size| format!("{:#010x}", size))
+}
+
+impl fmt::Display for LayoutChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LayoutChange::Added(part) => write!(f, "+ {} ({}@{:#010x})", part.name, size_text(part.size), part.offset),
+            LayoutChange::Removed(part) => write!(f, "- {} ({}@{:#010x})", part.name, size_text(part.size), part.offset),
+            LayoutChange::Moved { name, from, to } => {
+                write!(f, "~ {}", tr!("layout-moved", name = name, from = format!("{:#010x}", from), to = format!("{:#010x}", to)))
+            }
+            LayoutChange::Resized { name, from, to } => {
+                write!(f, "~ {}", tr!("layout-resized", name = name, from = size_text(*from), to = size_text(*to)))
+            }
+            LayoutChange::Renamed { from, to } => write!(f, "~ {}", tr!("layout-renamed", from = from, to = to)),
+        }
+    }
+}
+
+/// Reads parameter.txt from a file, an unpacked tree or the parameter
+/// partition of an RKFW/RKAF image.
+pub(crate) fn load_parameter(input: &Path) -> Result<ParameterFile> {
+    if input.is_dir() {
+        return ParameterFile::load(join_path(input, "parameter.txt"));
+    }
+
+    let mut fp = open_file(input)?;
+    let mut magic = [0u8; 4];
+    if fp.read_exact(&mut magic).is_ok() && (magic == RKFW_SIGNATURE || magic == RKAF_SIGNATURE) {
+        let part = find_part(&mut fp, "parameter")?;
+        let mut data = vec![0u8; part.byte_count as usize];
+        fp.seek(SeekFrom::Start(part.file_offset))?;
+        fp.read_exact(&mut data)?;
+        return Ok(ParameterFile::from_bytes(&data));
+    }
+    ParameterFile::load(input)
+}
+
+/// Compares the `mtdparts=` layouts of two parameter files, unpacked trees or
+/// images, in the order of the new layout.
+pub fn diff_layouts(old_input: impl AsRef<Path>, new_input: impl AsRef<Path>) -> Result<Vec<LayoutChange>> {
+    let (old_input, new_input) = (old_input.as_ref(), new_input.as_ref());
+    let old = load_parameter(old_input)?.mtdparts()?;
+    let new = load_parameter(new_input)?.mtdparts()?;
+
+    let mut changes = Vec::new();
+    let mut unmatched_old: Vec<&MtdPart> = old.iter().filter(|part| !new.iter().any(|p| p.name == part.name)).collect();
+    for part in &new {
+        let Some(previous) = old.iter().find(|p| p.name == part.name) else {
+            // A partition under a new name in exactly the old place was renamed
+            match unmatched_old.iter().position(|p| p.offset == part.offset && p.size == part.size) {
+                Some(index) => {
+                    let previous = unmatched_old.remove(index);
+                    changes.push(LayoutChange::Renamed { from: previous.name.clone(), to: part.name.clone() });
+                }
+                None => changes.push(LayoutChange::Added(part.clone())),
+            }
+            continue;
+        };
+        if previous.offset != part.offset {
+            changes.push(LayoutChange::Moved { name: part.name.clone(), from: previous.offset, to: part.offset });
+        }
+        if previous.size != part.size {
+            changes.push(LayoutChange::Resized { name: part.name.clone(), from: previous.size, to: part.size });
+        }
+    }
+    changes.extend(unmatched_old.into_iter().cloned().map(LayoutChange::Removed));
+    Ok(changes)
+}
+
+/// Checks that the partitions of a header can be flashed as laid out: each
+/// file fits the flash_size reserved for it, its data starts on an
+/// `alignment` boundary in the image, and no two flash ranges overlap. A
+/// flash_size of 0 with a non-zero offset, or of `FLASH_SIZE_GROW`, is a
+/// partition taking the rest of the flash; entries with neither offset nor
+/// size are not written to flash. Every problem is
+/// listed in the error, one row per partition.
+pub(crate) fn check_flash_layout(header: &UpdateHeader, alignment: u32) -> Result<()> {
+    let parts: Vec<(String, u64, Option<u64>, u64, u64)> = header.parts[..header.num_parts as usize]
+        .iter()
+        .filter(|part| {
+            let full_path = cstr_field(&part.full_path);
+            full_path != SELF_MARKER && full_path != RESERVED_MARKER
+        })
+        .map(|part| {
+            let size = (part.flash_size > 0 && !part.grows()).then_some(part.flash_size as u64);
+            (cstr_field(&part.name), part.flash_offset as u64, size, part.offset(), part.byte_count())
+        })
+        .collect();
+    let on_flash = |offset: u64, size: Option<u64>| offset > 0 || size.is_some();
+    let end = |offset: u64, size: Option<u64>| size.map_or(u64::MAX, |size| offset + size);
+
+    let mut problems = Vec::new();
+    for (i, (name, offset, size, part_offset, byte_count)) in parts.iter().enumerate() {
+        let mut complain = |problem: String| problems.push((name.clone(), *offset, *size, problem));
+        if *byte_count > 0 && part_offset % alignment as u64 != 0 {
+            complain(format!("data at image offset {:#x} is not aligned to {} bytes", part_offset, alignment));
+        }
+        if let Some(size) = size {
+            if *byte_count > size * 512 {
+                complain(format!("{} bytes do not fit in {} bytes of flash", byte_count, size * 512));
+            }
+        }
+        if !on_flash(*offset, *size) {
+            continue;
+        }
+        for (other, other_offset, other_size, _, _) in &parts[..i] {
+            if other != name && on_flash(*other_offset, *other_size)
+                && *offset < end(*other_offset, *other_size) && *other_offset < end(*offset, *size)
+            {
+                complain(format!("overlaps {} ({})", other, range_text(*other_offset, *other_size)));
+            }
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let mut table = format!(
+        "Partition layout cannot be flashed ({} problem{}):\n  {:20} {:23} PROBLEM",
+        problems.len(), if problems.len() == 1 { "" } else { "s" }, "NAME", "FLASH (sectors)"
+    );
+    for (name, offset, size, problem) in &problems {
+        table.push_str(&format!("\n  {:20} {:23} {}", name, range_text(*offset, *size), problem));
+    }
+    Err(AfptoolError::Layout(table))
+}
+
+fn range_text(offset: u64, size: Option<u64>) -> String {
+    format!("{}@{:#010x}", size_text(size), offset)
+}
+
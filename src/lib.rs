@@ -1,14 +1,96 @@
-use std::mem;
+mod android;
+mod boot;
+mod bootimg;
+mod builder;
+mod cancel;
+mod cache;
+mod catalog;
+mod check;
+mod chips;
+mod compat;
+mod compress;
+mod convert;
+mod device;
+mod disk;
+mod edit;
+mod equal;
+mod error;
+mod ext4;
+mod fastcopy;
+mod grep;
+mod hash;
+mod hooks;
+pub mod i18n;
+mod image;
+mod info;
+mod layout;
+mod list;
+mod manifest;
 mod pack;
+mod parameter;
+mod paths;
+mod rkfp;
+mod resource;
+mod rkcrc;
+mod scan;
+mod sparse;
+mod store;
+mod template;
+mod text;
+mod timings;
+mod transplant;
+mod trim;
+mod tree;
 mod unpack;
+mod variants;
 
-pub use pack::{pack_rkfw, pack_rkaf, chip_name_to_code};
-pub use unpack::unpack_file;
+pub use android::{android_build_info, BuildInfo};
+pub use boot::{pack_boot, unpack_boot, BootLoader, EntryKind, LoaderEntry};
+pub use bootimg::{boot_image_info, BootImageInfo, BootSection};
+pub use builder::{PartData, RkafBuilder, RkafPart, RkafPayload, RkfwBuilder};
+pub use cancel::{CancelToken, Cancelled};
+pub use catalog::{index_dir, query_catalog, CatalogEntry, CatalogQuery};
+pub use check::{verify_image, verify_image_from_reader, ImageCheck};
+pub use chips::{chip_code_to_name, chip_default_code, chip_family, chip_field, chip_name_to_code, default_chip_db, load_chip_db, ChipFamily};
+pub use compat::{check_compat, CompatReport, Compatibility};
+pub use compress::Compression;
+pub use convert::{unwrap_rkfw, wrap_rkaf};
+pub use device::{verify_device, verify_device_with, write_part_to_device, VerifyOptions};
+pub use disk::assemble_disk;
+pub use edit::{edit_header, fix_image, HeaderEdit};
+pub use equal::compare_images;
+pub use error::{AfptoolError, Result};
+pub use grep::{grep_image, hex_pattern, utf16le_pattern, GrepMatch};
+pub use hash::hash_image;
+pub use image::{check_chip, read_rkfw_info, read_update_header, read_update_header_from_reader, RkfwHeader};
+pub use info::{image_info, ImageInfo};
+pub use i18n::{set_lang, Lang};
+pub use layout::{diff_layouts, LayoutChange};
+pub use list::{cat_partition, dd_command, list_partitions, list_partitions_from_reader, Partition};
+pub use manifest::{FirmwareManifest, ManifestSection, UpdateManifest};
+pub use pack::{default_timestamp, pack_rkfw, pack_rkfw_with, pack_rkaf, pack_rkaf_with, pack_rkaf_to_writer, parse_header_byte, parse_timestamp, RkafOptions, RkfwOptions};
+pub use parameter::{MtdPart, ParameterFile};
+pub use rkfp::pack_rkfp;
+pub use resource::{pack_resource, unpack_resource, ResourceFile, ResourceImage};
+pub use rkcrc::{rkcrc_unwrap_file, rkcrc_wrap_file, unwrap_rkcrc, wrap_rkcrc, CrcWrapper};
+pub use scan::{carve_hit, scan_dump, ImageKind, ScanHit};
+pub use store::{IngestStats, Store};
+pub use template::parse_define;
+pub use timings::{enable_timings, timings_report, PhaseTiming};
+pub use transplant::{replace_part, transplant_part};
+pub use trim::{trim_tree, TrimReport};
+pub use tree::{verify_tree, verify_tree_with};
+pub use unpack::{unpack_batch, unpack_file, unpack_file_with, unpack_from_reader, BatchEntry, ExtractedPart, UnpackOptions, UnpackReport};
+pub use variants::{build_variants, BuildManifest, Variant};
 
 pub const RKAFP_MAGIC: &str = "RKAF";
 pub const PARM_MAGIC: &str = "PARM";
 pub const MAX_PARTS: usize = 16;
 pub const MAX_NAME_LEN: usize = 32;
+pub const FLASH_SECTOR_SIZE: u64 = 512;
+/// `flash_size` of a partition that takes the rest of the flash, what vendor
+/// tools store for a `-` size in mtdparts.
+pub const FLASH_SIZE_GROW: u32 = 0xFFFF_FFFF;
 const MAX_FULL_PATH_LEN: usize = 60;
 const MAX_MODEL_LEN: usize = 34;
 const MAX_ID_LEN: usize = 30;
@@ -18,7 +100,6 @@ pub const RKFW_SIGNATURE: &[u8] = b"RKFW";
 pub const RKFP_SIGNATURE: &[u8] = b"RKFP";
 
 #[derive(Copy, Clone, Debug)]
-#[repr(C, packed)]
 pub struct UpdatePart {
     pub name: [u8; MAX_NAME_LEN],
     pub full_path: [u8; MAX_FULL_PATH_LEN],
@@ -29,8 +110,12 @@ pub struct UpdatePart {
     pub part_byte_count: u32,
 }
 
+/// Size of an [`UpdatePart`] entry in the RKAF header.
+const UPDATE_PART_SIZE: usize = MAX_NAME_LEN + MAX_FULL_PATH_LEN + 5 * 4;
+/// Size of the RKAF header on disk.
+pub const UPDATE_HEADER_SIZE: usize = 2048;
+
 #[derive(Copy, Clone, Debug)]
-#[repr(C, packed)]
 pub struct UpdateHeader {
     pub magic: [u8; 4],
     pub length: u32,
@@ -51,8 +136,8 @@ pub struct ParamHeader {
     length: u32,
 }
 
-impl UpdateHeader {
-    pub fn default() -> Self {
+impl Default for UpdateHeader {
+    fn default() -> Self {
         Self {
             magic: [0u8; 4],
             length: 0,
@@ -66,18 +151,246 @@ impl UpdateHeader {
             reserved: [0u8; 116],
         }
     }
+}
 
-    pub fn from_bytes(bytes: &[u8]) -> &UpdateHeader {
-        unsafe { mem::transmute(bytes.as_ptr()) }
+/// Reads little-endian fields one after another from a buffer whose length
+/// was checked up front.
+struct FieldReader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl FieldReader<'_> {
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let mut field = [0u8; N];
+        field.copy_from_slice(&self.bytes[self.at..self.at + N]);
+        self.at += N;
+        field
     }
 
-    pub fn to_bytes(&self) -> &[u8] {
-        unsafe { std::slice::from_raw_parts(self as *const _ as *const u8, mem::size_of::<UpdateHeader>()) }
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.array())
     }
 }
 
+impl UpdateHeader {
+    /// Decodes an RKAF header, rejecting buffers shorter than
+    /// [`UPDATE_HEADER_SIZE`], a magic other than `RKAF` and more than
+    /// [`MAX_PARTS`] partitions.
+    pub fn from_bytes(bytes: &[u8]) -> Result<UpdateHeader> {
+        if bytes.len() < UPDATE_HEADER_SIZE {
+            return Err(AfptoolError::Parse(format!(
+                "RKAF header needs {} bytes, only {} available", UPDATE_HEADER_SIZE, bytes.len()
+            )));
+        }
+        if &bytes[..4] != RKAF_SIGNATURE {
+            return Err(AfptoolError::Parse("Invalid header magic id".to_string()));
+        }
+
+        let mut reader = FieldReader { bytes, at: 0 };
+        let mut header = UpdateHeader {
+            magic: reader.array(),
+            length: reader.u32(),
+            model: reader.array(),
+            id: reader.array(),
+            manufacturer: reader.array(),
+            unknown1: reader.u32(),
+            version: reader.u32(),
+            num_parts: reader.u32(),
+            ..Default::default()
+        };
+        if header.num_parts as usize > MAX_PARTS {
+            return Err(AfptoolError::Parse(format!(
+                "RKAF header lists {} partitions, at most {} fit", header.num_parts, MAX_PARTS
+            )));
+        }
+        for part in header.parts.iter_mut() {
+            *part = UpdatePart {
+                name: reader.array(),
+                full_path: reader.array(),
+                flash_size: reader.u32(),
+                part_offset: reader.u32(),
+                flash_offset: reader.u32(),
+                padded_size: reader.u32(),
+                part_byte_count: reader.u32(),
+            };
+        }
+        header.reserved = reader.array();
+        Ok(header)
+    }
+
+    /// Encodes the header as the [`UPDATE_HEADER_SIZE`] bytes stored in an image.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(UPDATE_HEADER_SIZE);
+        bytes.extend_from_slice(&self.magic);
+        bytes.extend_from_slice(&self.length.to_le_bytes());
+        bytes.extend_from_slice(&self.model);
+        bytes.extend_from_slice(&self.id);
+        bytes.extend_from_slice(&self.manufacturer);
+        for value in [self.unknown1, self.version, self.num_parts] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for part in &self.parts {
+            let start = bytes.len();
+            bytes.extend_from_slice(&part.name);
+            bytes.extend_from_slice(&part.full_path);
+            for value in [part.flash_size, part.part_offset, part.flash_offset, part.padded_size, part.part_byte_count] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            debug_assert_eq!(bytes.len() - start, UPDATE_PART_SIZE);
+        }
+        bytes.extend_from_slice(&self.reserved);
+        bytes
+    }
+
+    /// The model name, without the leading space AFPTool stores it with.
+    pub fn model_name(&self) -> String {
+        image::cstr_field(&self.model).trim().to_string()
+    }
+
+    pub fn manufacturer_name(&self) -> String {
+        image::cstr_field(&self.manufacturer).trim().to_string()
+    }
+
+    /// The machine id, without the leading space AFPTool stores it with.
+    pub fn id(&self) -> String {
+        image::cstr_field(&self.id).trim().to_string()
+    }
+
+    /// Stores `id` the way AFPTool does: prefixed with a space, truncated to
+    /// fit and NUL-terminated.
+    pub fn set_id(&mut self, id: &str) {
+        self.id = [0u8; MAX_ID_LEN];
+        if id.is_empty() {
+            return;
+        }
+        let value = format!(" {}", id.trim_start());
+        let len = value.len().min(MAX_ID_LEN - 1);
+        self.id[..len].copy_from_slice(&value.as_bytes()[..len]);
+    }
+
+    /// The version field as `major.minor.build`.
+    pub fn version_text(&self) -> String {
+        let version = self.version;
+        format!("{}.{}.{}", version >> 24, (version >> 16) & 0xff, version & 0xffff)
+    }
+
+    /// Stores a `major.minor.build` version, as an RKFW header does.
+    pub fn set_version(&mut self, version: &str) -> Result<()> {
+        let (major, minor, build) = pack::parse_rkfw_version(version)?;
+        self.version = (major as u32) << 24 | (minor as u32) << 16 | build as u32;
+        Ok(())
+    }
+
+    /// The image length, header included and CRC excluded. `length` only
+    /// holds its low 32 bits, so past 4 GiB the rest comes from where the
+    /// partition data ends.
+    pub fn image_length(&self) -> Result<u64> {
+        let mut data_end = 0;
+        for part in self.parts[..(self.num_parts as usize).min(MAX_PARTS)].iter().filter(|part| part.offset() > 0) {
+            let end = part.offset().checked_add(part.byte_count()).ok_or_else(|| AfptoolError::Parse(format!(
+                "Partition {} ends past the largest possible image", image::cstr_field(&part.name)
+            )))?;
+            data_end = data_end.max(end);
+        }
+        if data_end <= u32::MAX as u64 {
+            return Ok(self.length as u64);
+        }
+        let length = (data_end & !0xffff_ffff) | self.length as u64;
+        Ok([length.checked_sub(1 << 32), Some(length), length.checked_add(1 << 32)].into_iter()
+            .flatten()
+            .min_by_key(|candidate| candidate.abs_diff(data_end))
+            .unwrap_or(length))
+    }
+
+    /// Stores the image length, keeping its low 32 bits as large images do.
+    pub fn set_image_length(&mut self, length: u64) {
+        self.length = length as u32;
+    }
+}
+
+/// Entries of images past 4 GiB keep the high halves of their data offset
+/// and size in the end of `full_path`, as Rockchip's newer tools do: an `H`
+/// at byte 50 followed by the offset's high 32 bits, and another at byte 55
+/// followed by the size's. Their paths are limited to 49 bytes.
+const LARGE_PATH_LEN: usize = 50;
+const LARGE_OFFSET_AT: usize = LARGE_PATH_LEN;
+const LARGE_SIZE_AT: usize = LARGE_PATH_LEN + 5;
+const LARGE_MARKER: u8 = b'H';
+
 impl UpdatePart {
-    pub fn default() -> Self {
+    /// An entry with no data in the image, standing in for a partition that
+    /// is flashed from elsewhere.
+    pub fn is_placeholder(&self) -> bool {
+        self.offset() == 0 && self.byte_count() == 0
+    }
+
+    /// Whether the partition takes the rest of the flash: a size of
+    /// [`FLASH_SIZE_GROW`], or 0 at a non-zero offset.
+    pub fn grows(&self) -> bool {
+        self.flash_size == FLASH_SIZE_GROW || (self.flash_size == 0 && self.flash_offset > 0)
+    }
+
+    /// Whether the path ends early enough to leave room for the high halves.
+    fn has_large_fields(&self) -> bool {
+        self.full_path[..LARGE_PATH_LEN].contains(&0)
+    }
+
+    fn high_half(&self, at: usize) -> u64 {
+        if !self.has_large_fields() || self.full_path[at] != LARGE_MARKER {
+            return 0;
+        }
+        u32::from_le_bytes([self.full_path[at + 1], self.full_path[at + 2], self.full_path[at + 3], self.full_path[at + 4]]) as u64
+    }
+
+    fn set_high_half(&mut self, at: usize, high: u32) -> Result<()> {
+        if !self.has_large_fields() {
+            if high == 0 {
+                return Ok(());
+            }
+            return Err(AfptoolError::Layout(format!(
+                "{}: data past 4 GiB needs a path of at most {} bytes", image::cstr_field(&self.name), LARGE_PATH_LEN - 1
+            )));
+        }
+        let field = &mut self.full_path[at..at + 5];
+        if high == 0 {
+            field.fill(0);
+        } else {
+            field[0] = LARGE_MARKER;
+            field[1..].copy_from_slice(&high.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    /// Offset of the data from the start of the RKAF header.
+    pub fn offset(&self) -> u64 {
+        self.high_half(LARGE_OFFSET_AT) << 32 | self.part_offset as u64
+    }
+
+    /// Size of the data in the image.
+    pub fn byte_count(&self) -> u64 {
+        self.high_half(LARGE_SIZE_AT) << 32 | self.part_byte_count as u64
+    }
+
+    /// Stores the data offset, using the large-image fields when it is past
+    /// 4 GiB; set `full_path` first.
+    pub fn set_offset(&mut self, offset: u64) -> Result<()> {
+        self.set_high_half(LARGE_OFFSET_AT, (offset >> 32) as u32)?;
+        self.part_offset = offset as u32;
+        Ok(())
+    }
+
+    /// Stores the data size like [`set_offset`](Self::set_offset).
+    /// `padded_size` keeps only its low 32 bits past 4 GiB.
+    pub fn set_byte_count(&mut self, byte_count: u64) -> Result<()> {
+        self.set_high_half(LARGE_SIZE_AT, (byte_count >> 32) as u32)?;
+        self.part_byte_count = byte_count as u32;
+        Ok(())
+    }
+}
+
+impl Default for UpdatePart {
+    fn default() -> Self {
         Self {
             name: [0u8; MAX_NAME_LEN],
             full_path: [0u8; MAX_FULL_PATH_LEN],
@@ -90,35 +403,3 @@ impl UpdatePart {
     }
 }
 
-pub fn info_and_fatal(is_fatal: bool, message: String) {
-    if is_fatal {
-        eprint!("rkunpack: fatal: ");
-    } else {
-        eprint!("rkunpack: info: ");
-    }
-    eprintln!("{}", message);
-    if is_fatal {
-        std::process::exit(1);
-    }
-}
-
-#[macro_export]
-macro_rules! info {
-    ($($arg:tt)*) => {
-        info_and_fatal(false, format!($($arg)*));
-    };
-}
-
-#[macro_export]
-macro_rules! fatal {
-    ($($arg:tt)*) => {
-        info_and_fatal(true, format!($($arg)*));
-    };
-}
-
-pub unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-    core::slice::from_raw_parts(
-        (p as *const T) as *const u8,
-        mem::size_of::<T>(),
-    )
-}
@@ -0,0 +1,107 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::error::{AfptoolError, Result};
+use crate::paths::open_file;
+use serde::Serialize;
+use crate::image::{find_part, list_parts};
+use crate::pack::{RESERVED_MARKER, SELF_MARKER};
+use crate::FLASH_SIZE_GROW;
+
+/// One partition entry of an image, as shown by `list`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Partition {
+    pub name: String,
+    pub full_path: String,
+    /// Absolute offset of the data in the image file.
+    pub file_offset: u64,
+    pub byte_count: u64,
+    /// Start and size on flash, in 512-byte sectors.
+    pub flash_offset: u32,
+    pub flash_size: u32,
+    /// No data in the image; the partition is flashed from elsewhere.
+    pub placeholder: bool,
+}
+
+impl Partition {
+    /// `SELF` and `RESERVED` entries, which describe the image rather than
+    /// hold partition data.
+    pub fn is_marker(&self) -> bool {
+        self.full_path == SELF_MARKER || self.full_path == RESERVED_MARKER
+    }
+
+    /// Whether the partition takes the rest of the flash: a size of
+    /// [`FLASH_SIZE_GROW`], or 0 at a non-zero offset.
+    pub fn grows(&self) -> bool {
+        self.flash_size == FLASH_SIZE_GROW || (self.flash_size == 0 && self.flash_offset > 0)
+    }
+}
+
+/// Lists the partitions of an RKFW or RKAF image.
+pub fn list_partitions(image_path: impl AsRef<Path>) -> Result<Vec<Partition>> {
+    list_partitions_from_reader(&mut open_file(image_path)?)
+}
+
+/// [`list_partitions`] for an image that is not a file on disk.
+pub fn list_partitions_from_reader(reader: &mut (impl Read + Seek)) -> Result<Vec<Partition>> {
+    Ok(list_parts(reader)?
+        .into_iter()
+        .map(|part| Partition {
+            name: part.name,
+            full_path: part.full_path,
+            file_offset: part.file_offset,
+            byte_count: part.byte_count,
+            flash_offset: part.flash_offset,
+            flash_size: part.flash_size,
+            placeholder: part.placeholder,
+        })
+        .collect())
+}
+
+/// Copies the exact bytes of partition `part_name` to `out`, returning how
+/// many were written.
+pub fn cat_partition(image_path: impl AsRef<Path>, part_name: &str, out: &mut impl Write) -> Result<u64> {
+    let mut image = open_file(image_path)?;
+    let part = find_part(&mut image, part_name)?;
+    if part.placeholder {
+        return Err(AfptoolError::Invalid(format!("Partition {} is a placeholder with no data in the image", part_name)));
+    }
+    let len = part.byte_count as u64;
+    image.seek(SeekFrom::Start(part.file_offset))?;
+    if std::io::copy(&mut image.take(len), out)? != len {
+        return Err(AfptoolError::Parse("Insufficient length in container image file".to_string()));
+    }
+    out.flush()?;
+    Ok(len)
+}
+
+fn shell_quote(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c)) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// Builds a plain POSIX `dd` command that copies `part` out of `image_path`.
+/// The block size is the largest power of two (up to 1 MiB) dividing both the
+/// offset and the length, so no GNU-only flags are needed.
+pub fn dd_command(image_path: &str, part: &Partition) -> String {
+    let len = part.byte_count;
+    let mut block_size = 1024 * 1024u64;
+    while block_size > 1 && (!part.file_offset.is_multiple_of(block_size) || !len.is_multiple_of(block_size)) {
+        block_size /= 2;
+    }
+
+    let output = match part.full_path.rsplit(['/', '\\']).next() {
+        Some(file_name) if !file_name.is_empty() => file_name.to_string(),
+        _ => format!("{}.img", part.name),
+    };
+    format!(
+        "dd if={} of={} bs={} skip={} count={}",
+        shell_quote(image_path),
+        shell_quote(&output),
+        block_size,
+        part.file_offset / block_size,
+        len / block_size
+    )
+}
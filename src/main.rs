@@ -1,73 +1,1234 @@
-use clap::{Parser, Subcommand};
-use afptool_rs::{unpack_file, pack_rkfw, pack_rkaf};
-use anyhow::Result;
+use std::path::{Path, PathBuf};
+use clap::{Parser, Subcommand, ValueEnum};
+use afptool_rs::{boot_image_info, BootImageInfo, chip_default_code, chip_field, chip_name_to_code, default_chip_db, default_timestamp, load_chip_db, parse_header_byte, parse_timestamp, edit_header, fix_image, HeaderEdit, unpack_batch, unpack_file_with, BatchEntry, UnpackOptions, pack_rkfw_with, RkfwOptions, pack_rkaf_with, pack_rkfp, RkafOptions, verify_device, verify_tree, verify_image, Store, unwrap_rkfw, wrap_rkaf, parse_define, index_dir, query_catalog, CatalogEntry, CatalogQuery, set_lang, Lang, build_variants, replace_part, transplant_part, write_part_to_device, list_partitions, cat_partition, dd_command, trim_tree, assemble_disk, hash_image, grep_image, hex_pattern, utf16le_pattern, android_build_info, compare_images, image_info, check_chip, diff_layouts, scan_dump, carve_hit, enable_timings, timings_report, check_compat, Compatibility, Compression, unpack_boot, pack_boot, unpack_resource, pack_resource, rkcrc_wrap_file, rkcrc_unwrap_file, CrcWrapper, AfptoolError, ImageKind, Partition, FLASH_SECTOR_SIZE, tr};
+use anyhow::{anyhow, Result};
 
 #[derive(Parser)]
 #[command(name = "afptool-rs")]
 #[command(about = "A Rust tool for packing and unpacking RockChip firmware images")]
 #[command(version)]
 struct Args {
+    #[arg(long, global = true, help = "Message language (en, zh); defaults to the system locale. Table headings, JSON and most error details stay in English")]
+    lang: Option<String>,
+
+    #[arg(long, global = true, help = "Print how long parsing, layout, I/O and hashing took")]
+    timings: bool,
+
+    #[arg(long, global = true, value_name = "FILE",
+          help = "TOML file of extra chip families; defaults to ~/.config/afptool-rs/chips.toml if present")]
+    chip_db: Option<String>,
+
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text,
+          help = "Output style for info and unpack; json prints one JSON document on stdout")]
+    format: Format,
+
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto,
+          help = "Color the partition tables of info and unpack")]
+    color: ColorChoice,
+
+    #[arg(short, long, conflicts_with = "verbose", help = "Only print warnings and errors on stderr")]
+    quiet: bool,
+
+    #[arg(short, long, action = clap::ArgAction::Count, help = "Print more detail on stderr; -vv for the most")]
+    verbose: u8,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to color text written to `stream`; `auto` colors a terminal
+    /// unless `NO_COLOR` is set.
+    fn enabled(self, stream: &impl std::io::IsTerminal) -> bool {
+        match self {
+            ColorChoice::Auto => stream.is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Compressor {
+    Gzip,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     Unpack {
+        #[arg(help = "Path to the firmware file (RKFW, RKAF or RKFP format), or a directory of them with --batch")]
+        input: PathBuf,
+
+        #[arg(help = "Directory where extracted files will be saved")]
+        output: PathBuf,
+
+        #[arg(long = "hook", value_name = "COMMAND",
+              help = "Command run after each partition is extracted; {name} and {path} are substituted")]
+        hooks: Vec<String>,
+
+        #[arg(long, conflicts_with = "update_only",
+              help = "Only extract the loader (BOOT) from an RKFW image")]
+        boot_only: bool,
+
+        #[arg(long, help = "Only extract the embedded update.img from an RKFW image")]
+        update_only: bool,
+
+        #[arg(long, help = "Don't write all-zero partitions; pack regenerates them")]
+        skip_zero: bool,
+
+        #[arg(long, help = "Check the extracted files before moving them into place")]
+        verify: bool,
+
+        #[arg(long, value_name = "CHIP", help = "Fail unless the image is for this chip family (e.g., RK3326)")]
+        expected_chip: Option<String>,
+
+        #[arg(long, help = "Unpack even if the image fails its length, CRC, MD5 or hash checks")]
+        force: bool,
+
+        #[arg(long, help = "Unpack an RKFW image whose BOOT section has no loader signature")]
+        lenient: bool,
+
+        #[arg(long, value_name = "PATTERNS", value_delimiter = ',',
+              help = "Only extract these partitions, by name or path; * and ? are wildcards")]
+        only: Vec<String>,
+
+        #[arg(long, requires = "only", help = "Write the repacking metadata even with --only")]
+        with_metadata: bool,
+
+        #[arg(short, long, help = "Also unpack the embedded update.img and split Android boot images")]
+        recursive: bool,
+
+        #[arg(long, help = "Expand Android sparse partitions to raw images")]
+        desparse: bool,
+
+        #[arg(long, value_enum, help = "Compress each extracted partition (e.g. system.img.gz)")]
+        compress: Option<Compressor>,
+
+        #[arg(long, help = "Unpack every image in the input directory into its own subdirectory of the output")]
+        batch: bool,
+    },
+
+    PackRkfw {
+        #[arg(help = "Directory containing BOOT and embedded-update.img, or BOOT and a package-file tree")]
+        input: PathBuf,
+
+        #[arg(help = "Output RKFW firmware image file path; {chip}, {version}, {date} and {code} are filled in from the header")]
+        output: PathBuf,
+
+        #[arg(short, long, help = "Chip family (e.g., RK3326, RK3399, RK3566, RK3588, PX30); default from firmware-manifest.json")]
+        chip: Option<String>,
+
+        #[arg(long, value_name = "CODE", conflicts_with = "chip", value_parser = parse_code_arg,
+              help = "Raw chip field as hex (e.g., 0x36), for a chip family not in the table")]
+        chip_code: Option<u32>,
+
+        #[arg(long, value_name = "ASCII", requires = "chip_code",
+              help = "ASCII digits stored after the chip code (e.g., 332 with --chip-code 0x36 for RK3326)")]
+        chip_family: Option<String>,
+
+        #[arg(short, long, help = "Version in format: major.minor.build (e.g., 8.1.0); default from firmware-manifest.json")]
+        version: Option<String>,
+
+        #[arg(short, long, value_parser = parse_timestamp_arg,
+              help = "Build date as a Unix timestamp or YYYY-MM-DD[THH:MM:SS] UTC; default from firmware-manifest.json, then SOURCE_DATE_EPOCH or now")]
+        timestamp: Option<i64>,
+
+        #[arg(long, help = "Code field as hex string (e.g., 0x02000000); default from firmware-manifest.json, then the chip family's usual value")]
+        code: Option<String>,
+
+        #[arg(long = "raw-header-byte", value_name = "OFFSET=VALUE", value_parser = parse_header_byte_arg,
+              help = "Set a header byte after the section table (0x29 to 0x65), e.g. 0x2d=0x02; may be repeated")]
+        header_bytes: Vec<(usize, u8)>,
+    },
+
+    PackRkaf {
+        #[arg(help = "Directory containing package-file and files to pack")]
+        input: PathBuf,
+
+        #[arg(help = "Output RKAF update image file path; {model}, {manufacturer}, {machine_id}, {version} and {date} are filled in from the header")]
+        output: PathBuf,
+
+        #[arg(short, long, help = "Model name (default: MACHINE_MODEL from parameter.txt)")]
+        model: Option<String>,
+
+        #[arg(short = 'M', long, help = "Manufacturer name (default: MANUFACTURER from parameter.txt)")]
+        manufacturer: Option<String>,
+
+        #[arg(long, help = "Reuse unchanged partitions from the previous pack of this directory")]
+        incremental: bool,
+
+        #[arg(short = 'D', long = "define", value_name = "KEY=VALUE", value_parser = parse_define_arg,
+              help = "Value for a ${KEY} placeholder in package-file (environment variables are used otherwise)")]
+        defines: Vec<(String, String)>,
+
+        #[arg(long, help = "Machine id for the header (default: MACHINE_ID from parameter.txt)")]
+        machine_id: Option<String>,
+
+        #[arg(long, value_name = "VERSION",
+              help = "Header version as major.minor.build (default: from update-manifest.json, else 1.0.0)")]
+        fw_version: Option<String>,
+
+        #[arg(long, help = "Store model and manufacturer in GBK, as Chinese vendor tools do")]
+        gbk: bool,
+
+        #[arg(long, help = "Write padding out as zeros instead of leaving holes in the output")]
+        no_sparse: bool,
+
+        #[arg(long, help = "Add the SELF and RESERVED entries stock flashing tools expect")]
+        self_entry: bool,
+
+        #[arg(long, help = "Take flash offsets and sizes from mtdparts in parameter.txt over partition-metadata.txt")]
+        mtdparts: bool,
+
+        #[arg(long, value_name = "PARTS", value_delimiter = ',',
+              help = "Store these partitions (by name) as Android sparse images, e.g. system,vendor")]
+        android_sparse: Vec<String>,
+
+        #[arg(long, help = "Store byte-identical files listed under different paths once")]
+        dedupe: bool,
+
+        #[arg(long, help = "Decompress .gz files named in package-file while packing")]
+        decompress: bool,
+    },
+
+    PackRkfp {
+        #[arg(help = "Directory unpacked from an RKFP image, with rkfp-manifest.json")]
+        input: PathBuf,
+
+        #[arg(help = "Output RKFP image file path")]
+        output: PathBuf,
+    },
+
+    VerifyDevice {
         #[arg(help = "Path to the firmware file (RKFW or RKAF format)")]
+        image: String,
+
+        #[arg(short, long, help = "Name of the partition to verify")]
+        part: String,
+
+        #[arg(short, long, help = "Block device (or raw dump) the firmware was flashed to")]
+        device: String,
+
+        #[arg(long, help = "Compare byte by byte instead of by MD5 hash")]
+        bytewise: bool,
+
+        #[arg(long, value_name = "CHIP", help = "Fail unless the image is for this chip family (e.g., RK3326)")]
+        expected_chip: Option<String>,
+    },
+
+    Hash {
+        #[arg(help = "Path to the firmware file (RKFW or RKAF format)")]
+        image: String,
+    },
+
+    Equal {
+        #[arg(help = "First firmware file (RKFW or RKAF format)")]
+        a: String,
+
+        #[arg(help = "Second firmware file (RKFW or RKAF format)")]
+        b: String,
+    },
+
+    LayoutDiff {
+        #[arg(help = "Old parameter.txt, unpacked tree or firmware image")]
+        old: String,
+
+        #[arg(help = "New parameter.txt, unpacked tree or firmware image")]
+        new: String,
+    },
+
+    UnpackBoot {
+        #[arg(help = "Loader file, or RKFW/RKAF image containing one")]
         input: String,
 
-        #[arg(help = "Directory where extracted files will be saved")]
+        #[arg(help = "Directory where the loader entries will be saved")]
         output: String,
+
+        #[arg(long, help = "Undo the RC4 scrambling of the entries; pack-boot scrambles them again")]
+        decrypt: bool,
     },
 
-    PackRkfw {
-        #[arg(help = "Directory containing BOOT and embedded-update.img files")]
+    PackBoot {
+        #[arg(help = "Directory written by unpack-boot")]
         input: String,
 
-        #[arg(help = "Output RKFW firmware image file path")]
+        #[arg(help = "Loader file to write, e.g. BOOT for pack-rkfw")]
         output: String,
+    },
 
-        #[arg(short, long, help = "Chip family (e.g., RK29XX, RK30XX, RK31XX, RK32XX, RK3368, RK3326, RK3562, RK3566, PX30)")]
-        chip: String,
+    UnpackResource {
+        #[arg(help = "resource.img, or RKFW/RKAF image with a resource partition")]
+        input: String,
 
-        #[arg(short, long, help = "Version in format: major.minor.build (e.g., 8.1.0)")]
-        version: String,
+        #[arg(help = "Directory where the resource files will be saved")]
+        output: String,
+    },
 
-        #[arg(short, long, help = "Unix timestamp for build date (e.g., 1731031994)")]
-        timestamp: i64,
+    PackResource {
+        #[arg(help = "Directory written by unpack-resource")]
+        input: String,
 
-        #[arg(long, help = "Code field as hex string (e.g., 0x02000000)")]
-        code: String,
+        #[arg(help = "Resource image to write")]
+        output: String,
     },
 
-    PackRkaf {
-        #[arg(help = "Directory containing package-file and files to pack")]
+    #[command(group(clap::ArgGroup::new("mode").required(true).args(["krnl", "parm", "unwrap"])))]
+    Rkcrc {
+        #[arg(help = "File to wrap, or KRNL/PARM image to unwrap")]
+        input: String,
+
+        #[arg(help = "Output file path")]
+        output: String,
+
+        #[arg(short, long, help = "Wrap with a KRNL header, as for kernel images")]
+        krnl: bool,
+
+        #[arg(short, long, help = "Wrap with a PARM header, as for parameter files")]
+        parm: bool,
+
+        #[arg(short, long, help = "Check the CRC of a KRNL or PARM image and write the data inside")]
+        unwrap: bool,
+    },
+
+    CompatCheck {
+        #[arg(help = "Image currently installed on the device")]
+        installed: String,
+
+        #[arg(help = "Candidate update image")]
+        candidate: String,
+    },
+
+    Scan {
+        #[arg(help = "Raw eMMC/NAND dump to search")]
+        dump: String,
+
+        #[arg(long, help = "Also list magics whose headers don't check out")]
+        all: bool,
+
+        #[arg(long, value_name = "DIR", help = "Copy every complete top-level image found into this directory")]
+        carve: Option<String>,
+    },
+
+    Grep {
+        #[arg(help = "Path to the firmware file (RKFW or RKAF format)")]
+        image: String,
+
+        #[arg(help = "Text to search for (a byte pattern with --hex)")]
+        pattern: String,
+
+        #[arg(long, help = "Treat the pattern as hex bytes, e.g. 'de ad be ef'")]
+        hex: bool,
+
+        #[arg(long, conflicts_with = "hex", help = "Also search for the UTF-16LE encoding of the text")]
+        utf16: bool,
+
+        #[arg(short, long, help = "Only search this partition")]
+        part: Option<String>,
+
+        #[arg(short = 'z', long, help = "Also search inside gzip-compressed partitions")]
+        decompress: bool,
+    },
+
+    VerifyTree {
+        #[arg(help = "Directory produced by unpack")]
+        dir: String,
+    },
+
+    Verify {
+        #[arg(help = "Path to the firmware file (RKFW or RKAF format)")]
+        image: String,
+    },
+
+    Convert {
+        #[command(subcommand)]
+        action: ConvertAction,
+    },
+
+    Index {
+        #[arg(help = "Directory to scan recursively for firmware images")]
+        dir: String,
+
+        #[arg(long, default_value = "catalog.json", help = "Catalog file to write")]
+        db: String,
+    },
+
+    Query {
+        #[arg(long, default_value = "catalog.json", help = "Catalog file written by index")]
+        db: String,
+
+        #[arg(short, long, help = "Chip family (e.g., RK3566)")]
+        chip: Option<String>,
+
+        #[arg(short, long, help = "Substring of the model name")]
+        model: Option<String>,
+
+        #[arg(short = 'M', long, help = "Substring of the manufacturer name")]
+        manufacturer: Option<String>,
+
+        #[arg(short, long, help = "Firmware version (e.g., 1.0.0)")]
+        version: Option<String>,
+    },
+
+    Info {
+        #[arg(help = "Path to the firmware file (RKFW or RKAF format), or an extracted Android boot image")]
+        image: String,
+
+        #[arg(long, help = "Also look inside ext4 system/vendor partitions for the Android build fingerprint")]
+        deep: bool,
+    },
+
+    List {
+        #[arg(help = "Path to the firmware file (RKFW or RKAF format)")]
+        image: String,
+
+        #[arg(long, help = "Print a dd command extracting each partition instead of a table")]
+        print_dd: bool,
+    },
+
+    Cat {
+        #[arg(help = "Path to the firmware file (RKFW or RKAF format)")]
+        image: String,
+
+        #[arg(help = "Name of the partition to write to stdout")]
+        part: String,
+    },
+
+    Extract {
+        #[arg(help = "Path to the firmware file (RKFW or RKAF format)")]
+        image: String,
+
+        #[arg(short, long, help = "Name of the partition to extract")]
+        part: String,
+
+        #[arg(long, help = "Block device (or existing file) to write the partition to")]
+        to_dev: String,
+
+        #[arg(long, help = "Read the device back and compare it after writing")]
+        verify: bool,
+
+        #[arg(short, long, help = "Do not ask for confirmation")]
+        yes: bool,
+
+        #[arg(long, value_name = "CHIP", help = "Fail unless the image is for this chip family (e.g., RK3326)")]
+        expected_chip: Option<String>,
+    },
+
+    AssembleDisk {
+        #[arg(help = "Update image (RKFW or RKAF) or unpacked firmware directory")]
+        input: String,
+
+        #[arg(help = "Raw disk image to create")]
+        output: String,
+
+        #[arg(short, long, help = "parameter.txt to take the partition layout from")]
+        parameter: Option<String>,
+
+        #[arg(long, help = "Total disk image size in bytes (default: end of the last partition)")]
+        size: Option<u64>,
+    },
+
+    Trim {
+        #[arg(help = "Unpacked firmware directory")]
+        dir: String,
+
+        #[arg(long, help = "Only report what would be trimmed")]
+        dry_run: bool,
+    },
+
+    Transplant {
+        #[arg(long = "from", help = "Image to take the partition from")]
+        donor: String,
+
+        #[arg(short, long, help = "Name of the partition to copy")]
+        part: String,
+
+        #[arg(help = "Image receiving the partition (RKFW or RKAF)")]
+        target: String,
+
+        #[arg(short, long, help = "Write the result here instead of replacing the target")]
+        output: Option<String>,
+    },
+
+    Replace {
+        #[arg(help = "Image to change (RKFW or RKAF)")]
+        image: String,
+
+        #[arg(help = "Name of the partition to replace")]
+        part: String,
+
+        #[arg(help = "File holding the new partition data")]
+        data: String,
+
+        #[arg(short, long, help = "Write the result here instead of changing the image")]
+        output: Option<String>,
+    },
+
+    Edit {
+        #[arg(help = "Image to change (RKFW or RKAF)")]
+        image: String,
+
+        #[arg(short, long, help = "New model name")]
+        model: Option<String>,
+
+        #[arg(short = 'M', long, help = "New manufacturer name")]
+        manufacturer: Option<String>,
+
+        #[arg(short, long, help = "New firmware version (e.g., 8.1.0), for the RKAF and any RKFW header")]
+        version: Option<String>,
+
+        #[arg(long, help = "Store model and manufacturer in GBK, as Chinese vendor tools do")]
+        gbk: bool,
+    },
+
+    Fix {
+        #[arg(help = "Image whose length fields and checksums should be recomputed (RKFW or RKAF)")]
+        image: String,
+    },
+
+    Build {
+        #[arg(help = "JSON build manifest listing the variants to pack")]
+        manifest: String,
+    },
+
+    Store {
+        #[arg(help = "Store directory")]
+        store: String,
+
+        #[command(subcommand)]
+        action: StoreAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConvertAction {
+    /// Strip the RKFW wrapper, leaving a bare update.img
+    Unwrap {
+        #[arg(help = "RKFW firmware image")]
         input: String,
 
         #[arg(help = "Output RKAF update image file path")]
+        update: String,
+
+        #[arg(short, long, help = "Also save the loader (BOOT) blob to this path")]
+        loader: Option<String>,
+    },
+
+    /// Wrap an update.img and loader into an RKFW image
+    Wrap {
+        #[arg(help = "RKAF update image")]
+        update: String,
+
+        #[arg(short, long, help = "Loader (BOOT) blob")]
+        loader: String,
+
+        #[arg(help = "Output RKFW firmware image file path; {chip}, {version}, {date} and {code} are filled in from the header")]
         output: String,
 
-        #[arg(short, long, help = "Model name")]
-        model: String,
+        #[arg(short, long, help = "Chip family (e.g., RK3326, RK3399, RK3566, RK3588, PX30)")]
+        chip: String,
+
+        #[arg(short, long, help = "Version in format: major.minor.build (e.g., 8.1.0)")]
+        version: String,
+
+        #[arg(short, long, value_parser = parse_timestamp_arg,
+              help = "Build date as a Unix timestamp or YYYY-MM-DD[THH:MM:SS] UTC; default SOURCE_DATE_EPOCH or now")]
+        timestamp: Option<i64>,
 
-        #[arg(short = 'M', long, help = "Manufacturer name")]
-        manufacturer: String,
+        #[arg(long, help = "Code field as hex string (e.g., 0x02000000); default the chip family's usual value")]
+        code: Option<String>,
     },
 }
 
-fn main() -> Result<()> {
+#[derive(Subcommand)]
+enum StoreAction {
+    Ingest {
+        #[arg(required = true, help = "Firmware images to add to the store")]
+        images: Vec<String>,
+
+        #[arg(short, long, help = "Name to store the image under (defaults to the file name)")]
+        name: Option<String>,
+    },
+
+    Materialize {
+        #[arg(help = "Name of the stored image")]
+        name: String,
+
+        #[arg(help = "Output image file path")]
+        output: String,
+    },
+
+    List,
+}
+
+fn parse_define_arg(define: &str) -> Result<(String, String), String> {
+    parse_define(define).map_err(|e| e.to_string())
+}
+
+fn parse_header_byte_arg(text: &str) -> Result<(usize, u8), String> {
+    parse_header_byte(text).map_err(|e| e.to_string())
+}
+
+fn parse_timestamp_arg(timestamp: &str) -> Result<i64, String> {
+    parse_timestamp(timestamp).map_err(|e| e.to_string())
+}
+
+fn parse_code_arg(code: &str) -> Result<u32, String> {
+    let hex = code.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(hex, 16).map_err(|_| format!("Invalid hex value: {}", code))
+}
+
+fn confirm(prompt: &str) -> Result<bool> {
+    use std::io::Write;
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+fn print_boot_image(boot: &BootImageInfo, indent: &str) {
+    println!("{}{}", indent, tr!("info-boot-header", version = boot.header_version, page_size = boot.page_size));
+    for section in &boot.sections {
+        println!("{}{:14} {:#010x} {}", indent, section.name, section.offset, tr!("info-bytes", size = section.size));
+    }
+    if !boot.name.is_empty() {
+        println!("{}{}", indent, tr!("info-board-name", name = boot.name));
+    }
+    if let (Some(version), Some(level)) = (&boot.os_version, &boot.os_patch_level) {
+        println!("{}{}", indent, tr!("info-os-version", version = version, level = level));
+    }
+    println!("{}{}", indent, tr!("info-cmdline", cmdline = boot.cmdline));
+}
+
+fn print_catalog(entries: &[CatalogEntry]) {
+    for entry in entries {
+        println!(
+            "{:40} {:5} {:8} {:10} {:19} {}",
+            entry.path,
+            entry.format,
+            entry.chip.as_deref().unwrap_or("-"),
+            entry.version.as_deref().unwrap_or("-"),
+            entry.date.as_deref().unwrap_or("-"),
+            entry.model
+        );
+    }
+}
+
+/// Width of the flash map bar, in characters.
+const FLASH_MAP_WIDTH: u64 = 64;
+
+/// Wraps `text` in an ANSI SGR sequence when coloring.
+fn paint(text: String, code: &str, color: bool) -> String {
+    match color {
+        true => format!("\x1b[{}m{}\x1b[0m", code, text),
+        false => text,
+    }
+}
+
+/// Aligned table of the partitions, with how full each flash partition is,
+/// followed by a bar showing where they sit on flash.
+fn partition_table(parts: &[Partition], color: bool) -> String {
+    let mut table = paint(
+        format!("{:20} {:>12} {:>12} {:>12} {:>12} {:>7}  PATH", "NAME", "FLASH OFFSET", "FLASH SIZE", "IMAGE OFFSET", "IMAGE SIZE", "FILL"),
+        "1",
+        color,
+    );
+    for part in parts {
+        let flash_size = match part.grows() {
+            true => "-".to_string(),
+            false => format!("{:#010x}", part.flash_size),
+        };
+        let fill = match part.flash_size {
+            _ if part.grows() => None,
+            0 => None,
+            size => Some(part.byte_count as f64 * 100.0 / (size as u64 * FLASH_SECTOR_SIZE) as f64),
+        };
+        let fill_text = format!("{:>7}", fill.map_or("-".to_string(), |fill| format!("{:.1}%", fill)));
+        let fill_text = match fill {
+            Some(fill) if fill > 100.0 => paint(fill_text, "31", color),
+            Some(fill) if fill >= 90.0 => paint(fill_text, "33", color),
+            _ => fill_text,
+        };
+        let row = match part.placeholder {
+            true => paint(
+                format!("{:20} {:>12} {:>12} {:>12} {:>12} {:>7}  {}", part.name, format!("{:#010x}", part.flash_offset), flash_size, "-", "placeholder", "-", part.full_path),
+                "2",
+                color,
+            ),
+            false => format!(
+                "{:20} {:>12} {:>12} {:>12} {:>12} {}  {}",
+                part.name, format!("{:#010x}", part.flash_offset), flash_size,
+                format!("{:#010x}", part.file_offset), format!("{:#010x}", part.byte_count), fill_text, part.full_path
+            ),
+        };
+        table.push('\n');
+        table.push_str(&row);
+    }
+    if let Some(map) = flash_map(parts) {
+        table.push_str("\n\n");
+        table.push_str(&map);
+    }
+    table
+}
+
+/// One-line picture of the flash layout, a letter per partition, with a
+/// legend. A partition that grows to the end of flash is drawn up to the
+/// end of the bar. `None` when no partition has flash space.
+fn flash_map(parts: &[Partition]) -> Option<String> {
+    let placed: Vec<&Partition> = parts.iter().filter(|part| part.flash_size > 0 || part.grows()).collect();
+    let fixed_end = placed.iter()
+        .filter(|part| !part.grows())
+        .map(|part| part.flash_offset as u64 + part.flash_size as u64)
+        .max()
+        .unwrap_or(0);
+    let grow_start = placed.iter().filter(|part| part.grows()).map(|part| part.flash_offset as u64).max();
+    // Give a grow partition an eighth of the bar past everything else
+    let end = match grow_start {
+        Some(start) => start.max(fixed_end) + (start.max(fixed_end) / 8).max(1),
+        None => fixed_end,
+    };
+    if placed.is_empty() || end == 0 {
+        return None;
+    }
+
+    let mut bar = vec!['.'; FLASH_MAP_WIDTH as usize];
+    let mut legend = Vec::new();
+    let symbols = ('A'..='Z').chain('a'..='z').chain('0'..='9').cycle();
+    for (part, symbol) in placed.iter().zip(symbols) {
+        let part_end = match part.grows() {
+            true => end,
+            false => part.flash_offset as u64 + part.flash_size as u64,
+        };
+        let first = (part.flash_offset as u64 * FLASH_MAP_WIDTH / end).min(FLASH_MAP_WIDTH - 1);
+        let last = (part_end * FLASH_MAP_WIDTH).div_ceil(end).clamp(first + 1, FLASH_MAP_WIDTH);
+        bar[first as usize..last as usize].fill(symbol);
+        legend.push(format!("{} {}", symbol, part.name));
+    }
+    let tail = if grow_start.is_some() { ">" } else { "|" };
+    Some(format!(
+        "Flash map ({:#x} sectors{}):\n|{}{}\n {}",
+        fixed_end.max(grow_start.unwrap_or(0)),
+        if grow_start.is_some() { ", last partition grows to the end" } else { "" },
+        bar.into_iter().collect::<String>(),
+        tail,
+        legend.join("  ")
+    ))
+}
+
+fn print_batch(entries: &[BatchEntry]) {
+    println!();
+    println!("{:32} {:5} {:10} {:>5} {:>12}  RESULT", "IMAGE", "TYPE", "VERSION", "PARTS", "BYTES");
+    for entry in entries {
+        match (&entry.report, &entry.error) {
+            (Some(report), _) => println!(
+                "{:32} {:5} {:10} {:>5} {:>12}  {}",
+                entry.input,
+                report.kind.to_string(),
+                report.version,
+                report.parts.len(),
+                report.parts.iter().map(|part| part.size).sum::<u64>(),
+                entry.output
+            ),
+            (None, error) => println!("{:32} {:5} {:10} {:>5} {:>12}  {}", entry.input, "-", "-", "-", "-", error.as_deref().unwrap_or("")),
+        }
+    }
+    let unpacked = entries.iter().filter(|entry| entry.report.is_some()).count();
+    println!("{}", tr!("batch-summary", unpacked = unpacked, total = entries.len()));
+}
+
+/// Writes log records to stderr, keeping stdout for command output.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            log::Level::Error => eprintln!("{}", tr!("error", message = record.args())),
+            log::Level::Warn => eprintln!("{}", tr!("warning", message = record.args())),
+            _ => eprintln!("{}", record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// A problem with the command line found after clap has parsed it.
+#[derive(Debug)]
+struct UsageError(String);
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// Process exit codes, so scripts can tell why a command failed. clap
+/// exits with [`EXIT_USAGE`] itself for arguments it rejects.
+const EXIT_FAILURE: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_PARSE: u8 = 3;
+const EXIT_CHECKSUM: u8 = 4;
+const EXIT_LAYOUT: u8 = 5;
+const EXIT_IO: u8 = 6;
+
+/// Exit code for `error`, going by the first cause in its chain that has one.
+fn exit_code(error: &anyhow::Error) -> u8 {
+    error.chain().find_map(|cause| {
+        if let Some(error) = cause.downcast_ref::<AfptoolError>() {
+            return Some(match error {
+                AfptoolError::Parse(_) => EXIT_PARSE,
+                AfptoolError::Checksum(_) => EXIT_CHECKSUM,
+                AfptoolError::Layout(_) => EXIT_LAYOUT,
+                AfptoolError::Invalid(_) => EXIT_USAGE,
+                AfptoolError::Io(_) => EXIT_IO,
+                AfptoolError::Cancelled(_) => EXIT_FAILURE,
+            });
+        }
+        if cause.is::<std::io::Error>() {
+            return Some(EXIT_IO);
+        }
+        cause.is::<UsageError>().then_some(EXIT_USAGE)
+    }).unwrap_or(EXIT_FAILURE)
+}
+
+fn main() -> std::process::ExitCode {
+    match try_main() {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(error) => {
+            // As returning the error from main would print it
+            eprintln!("{}", tr!("error", message = format!("{:?}", error)));
+            std::process::ExitCode::from(exit_code(&error))
+        }
+    }
+}
+
+fn try_main() -> Result<()> {
     let args = Args::parse();
 
-    match args.command {
-        Commands::Unpack { input, output } => {
-            unpack_file(&input, &output)?;
+    log::set_logger(&StderrLogger).map_err(|e| anyhow!(tr!("logging-failed", error = e)))?;
+    log::set_max_level(match (args.quiet, args.verbose) {
+        (true, _) => log::LevelFilter::Warn,
+        (false, 0) => log::LevelFilter::Info,
+        (false, 1) => log::LevelFilter::Debug,
+        (false, _) => log::LevelFilter::Trace,
+    });
+
+    if let Some(lang) = &args.lang {
+        set_lang(Lang::from_locale(lang).ok_or_else(|| UsageError(tr!("unsupported-lang", lang = lang)))?);
+    }
+
+    match (&args.chip_db, default_chip_db()) {
+        (Some(path), _) => {
+            load_chip_db(path)?;
+        }
+        (None, Some(path)) if path.is_file() => {
+            load_chip_db(&path)?;
+        }
+        _ => {}
+    }
+
+    if !args.timings {
+        return run(args.command, args.format, args.color);
+    }
+    enable_timings();
+    let started = std::time::Instant::now();
+    let result = run(args.command, args.format, args.color);
+    // Also reported when the command fails, since a slow failure is worth diagnosing too
+    eprintln!("{}", tr!("timings-header"));
+    for timing in timings_report() {
+        eprintln!("  {:8} {:>10.3}s  ({})", timing.name, timing.elapsed.as_secs_f64(), tr!("timings-spans", count = timing.count));
+    }
+    eprintln!("  {:8} {:>10.3}s", tr!("timings-total"), started.elapsed().as_secs_f64());
+    result
+}
+
+fn run(command: Commands, format: Format, color: ColorChoice) -> Result<()> {
+    let json = format == Format::Json;
+    match command {
+        Commands::Unpack { input, output, hooks, boot_only, update_only, skip_zero, verify, expected_chip, force, lenient, only, with_metadata, recursive, desparse, compress, batch } => {
+            let options = UnpackOptions {
+                hooks, boot_only, update_only, skip_zero, verify, expected_chip, quiet: json, force, lenient, only, with_metadata, recursive, desparse,
+                compress: compress.map(|Compressor::Gzip| Compression::Gzip),
+                ..Default::default()
+            };
+            if batch {
+                let entries = unpack_batch(&input, &output, &options)?;
+                let failed = entries.iter().filter(|entry| entry.error.is_some()).count();
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&entries)?);
+                } else {
+                    for entry in &entries {
+                        for warning in entry.report.iter().flat_map(|report| &report.warnings) {
+                            log::warn!("{}: {}", entry.input, warning);
+                        }
+                    }
+                    print_batch(&entries);
+                }
+                if failed > 0 {
+                    return Err(anyhow!(tr!("batch-failed", failed = failed, total = entries.len())));
+                }
+                return Ok(());
+            }
+            let unpacked = unpack_file_with(&input, &output, &options)?;
+            if !json {
+                for warning in &unpacked.warnings {
+                    log::warn!("{}", warning);
+                }
+            }
+            if !json && log::log_enabled!(log::Level::Info) {
+                // Only a summary, so an image whose table can't be listed again isn't an error
+                if let Ok(parts) = list_partitions(&input) {
+                    log::info!("\n{}", partition_table(&parts, color.enabled(&std::io::stderr())));
+                }
+            }
+            if json {
+                // RKFP has no RKFW/RKAF headers to report; its details are in the unpack report
+                let image = if unpacked.kind == ImageKind::Rkfp { None } else { Some(image_info(&input)?) };
+                let report = serde_json::json!({ "output": output.display().to_string(), "unpacked": unpacked, "image": image });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            }
+        }
+        Commands::PackRkfw{ input, output, chip, chip_code, chip_family, version, timestamp, code, header_bytes } => {
+            let chip_code = chip_code.map(|code| chip_field(code, chip_family.as_deref())).transpose()?;
+            pack_rkfw_with(&input, &output, &RkfwOptions { chip, chip_code, version, timestamp, code, header_bytes })?;
+        }
+        Commands::PackRkaf { input, output, model, manufacturer, incremental, defines, machine_id, fw_version, gbk, no_sparse, self_entry, mtdparts, android_sparse, dedupe, decompress } => {
+            let options = RkafOptions { incremental, defines, machine_id, version: fw_version, gbk, write_padding: no_sparse, self_entry, mtdparts_primary: mtdparts, sparse: android_sparse, dedupe, decompress, ..Default::default() };
+            pack_rkaf_with(&input, &output, model.as_deref().unwrap_or(""), manufacturer.as_deref().unwrap_or(""), &options)?;
+        }
+        Commands::PackRkfp { input, output } => {
+            pack_rkfp(&input, &output)?;
+        }
+        Commands::VerifyDevice { image, part, device, bytewise, expected_chip } => {
+            if let Some(chip) = &expected_chip {
+                check_chip(&image, chip)?;
+            }
+            verify_device(&image, &part, &device, bytewise)?;
+        }
+        Commands::Hash { image } => {
+            for (name, digest) in hash_image(&image)? {
+                println!("{}  {}", digest, name);
+            }
+        }
+        Commands::Equal { a, b } => match compare_images(&a, &b)? {
+            Some(difference) => return Err(anyhow!(tr!("equal-differ", difference = difference))),
+            None => println!("{}", tr!("equal-same")),
+        },
+        Commands::LayoutDiff { old, new } => {
+            let changes = diff_layouts(&old, &new)?;
+            for change in &changes {
+                match change.ota_unsafe() {
+                    true => println!("{}  {}", change, tr!("layout-diff-unsafe")),
+                    false => println!("{}", change),
+                }
+            }
+            let unsafe_changes = changes.iter().filter(|change| change.ota_unsafe()).count();
+            println!("{}", tr!("layout-diff-summary", count = changes.len(), unsafe_count = unsafe_changes));
+        }
+        Commands::UnpackBoot { input, output, decrypt } => {
+            let loader = unpack_boot(&input, &output, decrypt)?;
+            if decrypt && !loader.scrambled() {
+                println!("{}", tr!("boot-not-scrambled"));
+            }
+            println!("{}", tr!("boot-chip", chip = format!("{:#010x}", loader.chip), date = loader.release_time_text()));
+            for entry in &loader.entries {
+                println!("{:8} {:20} {} -> {}", entry.kind.to_string(), entry.name, tr!("size-bytes", size = format!("{:>8}", entry.data.len())), entry.file_name());
+            }
+            if !loader.crc_ok {
+                log::warn!("{}", tr!("boot-crc-mismatch"));
+            }
+        }
+        Commands::PackBoot { input, output } => {
+            let loader = pack_boot(&input, &output)?;
+            println!("{}", tr!("boot-packed", count = loader.entries.len(), path = output));
+        }
+        Commands::UnpackResource { input, output } => {
+            let resource = unpack_resource(&input, &output)?;
+            for file in &resource.files {
+                println!("{:40} {}", file.path, tr!("size-bytes", size = format!("{:>8}", file.data.len())));
+            }
+        }
+        Commands::PackResource { input, output } => {
+            let resource = pack_resource(&input, &output)?;
+            println!("{}", tr!("resource-packed", count = resource.files.len(), path = output));
+        }
+        Commands::Rkcrc { input, output, krnl, parm: _, unwrap } => {
+            if unwrap {
+                let kind = rkcrc_unwrap_file(&input, &output)?;
+                println!("{}", tr!("rkcrc-unwrapped", kind = kind, path = output));
+            } else {
+                let kind = if krnl { CrcWrapper::Krnl } else { CrcWrapper::Parm };
+                rkcrc_wrap_file(&input, &output, kind)?;
+                println!("{}", tr!("rkcrc-wrapped", input = input, kind = kind, path = output));
+            }
+        }
+        Commands::CompatCheck { installed, candidate } => {
+            let report = check_compat(&installed, &candidate)?;
+            for finding in &report.findings {
+                println!("{}", finding);
+            }
+            if report.verdict != Compatibility::Safe {
+                return Err(anyhow!(tr!("compat-verdict", verdict = report.verdict)));
+            }
+            println!("{}", tr!("compat-verdict", verdict = report.verdict));
+        }
+        Commands::Scan { dump, all, carve } => {
+            let hits = scan_dump(&dump)?;
+            if let Some(dir) = &carve {
+                std::fs::create_dir_all(dir)?;
+            }
+            for hit in hits.iter().filter(|hit| all || hit.plausible) {
+                let len = hit.len.map_or_else(|| "?".to_string(), |len| format!("{:#x}", len));
+                let inside = hit.inside.map_or_else(String::new, |outer| format!(" (inside {:#x})", outer));
+                println!("{:#012x} {:7} {:>12} {}{}", hit.offset, hit.kind.to_string(), len, hit.detail, inside);
+
+                if let (Some(dir), true, None, Some(_)) = (&carve, hit.plausible, hit.inside, hit.len) {
+                    let file_name = format!("{:010x}.{}.img", hit.offset, hit.kind.to_string().to_lowercase());
+                    let path = std::path::Path::new(dir).join(file_name).to_string_lossy().into_owned();
+                    carve_hit(&dump, hit, &path)?;
+                    println!("  -> {}", path);
+                }
+            }
+        }
+        Commands::Grep { image, pattern, hex, utf16, part, decompress } => {
+            let mut patterns = vec![if hex { hex_pattern(&pattern)? } else { pattern.as_bytes().to_vec() }];
+            if utf16 {
+                patterns.push(utf16le_pattern(&pattern));
+            }
+            let matches = grep_image(&image, &patterns, part.as_deref(), decompress)?;
+            for found in &matches {
+                let encoding = if found.pattern == 1 { " (UTF-16)" } else { "" };
+                match found.file_offset {
+                    Some(file_offset) => println!("{}+{:#x} (file {:#x}){}", found.part, found.offset, file_offset, encoding),
+                    None => println!("{}+{:#x} (decompressed){}", found.part, found.offset, encoding),
+                }
+            }
+            if matches.is_empty() {
+                return Err(anyhow!(tr!("grep-no-matches")));
+            }
+        }
+        Commands::VerifyTree { dir } => {
+            verify_tree(&dir)?;
+        }
+        Commands::Verify { image } => {
+            let checks = verify_image(&image)?;
+            for check in &checks {
+                let result = if check.passed { tr!("verify-pass") } else { tr!("verify-fail") };
+                println!("{:4} {:30} {}", result, check.name, check.detail);
+            }
+            let failed = checks.iter().filter(|check| !check.passed).count();
+            if failed > 0 {
+                return Err(AfptoolError::Checksum(tr!("verify-failed", failed = failed, total = checks.len())).into());
+            }
+            println!("{}", tr!("verify-passed", total = checks.len()));
+        }
+        Commands::Convert { action } => match action {
+            ConvertAction::Unwrap { input, update, loader } => {
+                unwrap_rkfw(&input, &update, loader.as_deref().map(Path::new))?;
+            }
+            ConvertAction::Wrap { update, loader, output, chip, version, timestamp, code } => {
+                let timestamp = match timestamp {
+                    Some(timestamp) => timestamp,
+                    None => default_timestamp()?,
+                };
+                let code = match code {
+                    Some(code) => code,
+                    None => chip_default_code(chip_name_to_code(&chip)?).map(|code| format!("{:#010x}", code))
+                        .ok_or_else(|| UsageError(tr!("wrap-no-code", chip = chip)))?,
+                };
+                wrap_rkaf(&update, &loader, &output, &chip, &version, timestamp, &code)?;
+            }
+        },
+        Commands::Index { dir, db } => {
+            let entries = index_dir(&dir, &db)?;
+            print_catalog(&entries);
+            println!("{}", tr!("index-done", count = entries.len(), db = db));
+        }
+        Commands::Query { db, chip, model, manufacturer, version } => {
+            let query = CatalogQuery { chip, model, manufacturer, version };
+            print_catalog(&query_catalog(&db, &query)?);
+        }
+        Commands::Info { image, deep } => {
+            if let Some(boot) = boot_image_info(&image)? {
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&boot)?);
+                } else {
+                    println!("{}", tr!("info-android-boot"));
+                    print_boot_image(&boot, "");
+                }
+                return Ok(());
+            }
+            let info = image_info(&image)?;
+            let builds = if deep { Some(android_build_info(&image)?) } else { None };
+            if json {
+                let mut report = serde_json::to_value(&info)?;
+                if let Some(builds) = builds {
+                    report["builds"] = serde_json::to_value(builds)?;
+                }
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                return Ok(());
+            }
+            if let Some(rkfw) = &info.rkfw {
+                let chip = info.chip.clone().unwrap_or_else(|| tr!("info-unknown"));
+                println!("{}", tr!("info-rkfw-version", version = rkfw.version));
+                println!("{}", tr!("info-rkfw-code", code = format!("{:#010x}", rkfw.code)));
+                println!("{}", tr!("info-build-date", date = rkfw.date));
+                println!("{}", tr!("info-chip", chip = chip, code = format!("{:#x}", rkfw.chip_code)));
+                println!("BOOT: {:#010x} {:#010x}", rkfw.boot_offset, rkfw.boot_size);
+                println!("{}", tr!("info-update-image", offset = format!("{:#010x}", rkfw.update_offset), size = format!("{:#010x}", rkfw.update_size)));
+            }
+            println!("{}", tr!("info-rkaf-version", version = info.version));
+            println!("{}", tr!("info-model", model = info.model));
+            println!("{}", tr!("info-manufacturer", manufacturer = info.manufacturer));
+            println!("{}", tr!("info-machine-id", id = info.machine_id));
+            println!();
+            println!("{}", partition_table(&info.partitions, color.enabled(&std::io::stdout())));
+            for boot in &info.boot_images {
+                println!("{}", tr!("info-android-boot-in", part = boot.part.as_deref().unwrap_or_default()));
+                print_boot_image(boot, "  ");
+            }
+            if let Some(builds) = builds {
+                if builds.is_empty() {
+                    println!("{}", tr!("info-no-build-prop"));
+                }
+                for build in builds {
+                    let show = |value: Option<String>| value.unwrap_or_else(|| tr!("info-unknown"));
+                    println!("{} ({}):", build.part, build.path);
+                    println!("  {}", tr!("info-fingerprint", value = show(build.fingerprint)));
+                    println!("  {}", tr!("info-android-version", value = show(build.android_version)));
+                    println!("  {}", tr!("info-security-patch", value = show(build.security_patch)));
+                }
+            }
+        }
+        Commands::List { image, print_dd } => {
+            for part in list_partitions(&image)?.into_iter().filter(|part| !part.is_marker()) {
+                if print_dd {
+                    if part.byte_count > 0 {
+                        println!("{}", dd_command(&image, &part));
+                    }
+                } else if part.placeholder {
+                    println!(
+                        "{:17} {:26} {} {}",
+                        "-", part.name, tr!("list-flash", offset = format!("{:#010x}", part.flash_offset), size = format!("{:#010x}", part.flash_size)), tr!("list-placeholder")
+                    );
+                } else {
+                    println!(
+                        "{:08x}-{:08x} {:26} {}",
+                        part.file_offset,
+                        part.byte_count,
+                        part.name,
+                        tr!("list-flash", offset = format!("{:#010x}", part.flash_offset), size = format!("{:#010x}", part.flash_size))
+                    );
+                }
+            }
+        }
+        Commands::Cat { image, part } => {
+            let mut stdout = std::io::BufWriter::with_capacity(1024 * 1024, std::io::stdout().lock());
+            match cat_partition(&image, &part, &mut stdout) {
+                // The reader went away (e.g. `| head`), which is not a failure
+                Err(AfptoolError::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        Commands::Extract { image, part, to_dev, verify, yes, expected_chip } => {
+            if let Some(chip) = &expected_chip {
+                check_chip(&image, chip)?;
+            }
+            if !yes && !confirm(&tr!("device-confirm", device = to_dev, name = part, image = image))? {
+                return Err(anyhow!(tr!("aborted")));
+            }
+            write_part_to_device(&image, &part, &to_dev, verify)?;
+        }
+        Commands::AssembleDisk { input, output, parameter, size } => {
+            let size = assemble_disk(&input, parameter.as_deref().map(Path::new), &output, size)?;
+            println!("{}", tr!("disk-size", path = output, size = size));
+        }
+        Commands::Trim { dir, dry_run } => {
+            let reports = trim_tree(&dir, dry_run)?;
+            for report in &reports {
+                println!(
+                    "{}",
+                    tr!(
+                        "trim-file",
+                        path = format!("{:30}", report.path),
+                        original = report.original_size,
+                        trimmed = report.trimmed_size,
+                        saved = report.saved()
+                    )
+                );
+            }
+            println!("{}", tr!("trim-total", saved = reports.iter().map(|r| r.saved()).sum::<u64>()));
+        }
+        Commands::Transplant { donor, part, target, output } => {
+            transplant_part(&donor, &part, &target, output.as_deref().map(Path::new))?;
+        }
+        Commands::Replace { image, part, data, output } => {
+            replace_part(&image, &part, &data, output.as_deref().map(Path::new))?;
+        }
+        Commands::Edit { image, model, manufacturer, version, gbk } => {
+            edit_header(&image, &HeaderEdit { model, manufacturer, version, gbk })?;
+        }
+        Commands::Fix { image } => {
+            let changes = fix_image(&image)?;
+            for change in &changes {
+                println!("{}", change);
+            }
+            if changes.is_empty() {
+                println!("{}", tr!("fix-nothing", image = image));
+            }
         }
-        Commands::PackRkfw{ input, output, chip, version, timestamp, code } => {
-            pack_rkfw(&input, &output, &chip, &version, timestamp, &code)?;
+        Commands::Build { manifest } => {
+            for (name, output) in build_variants(&manifest)? {
+                println!("{}", tr!("build-variant", name = name, path = output.display()));
+            }
         }
-        Commands::PackRkaf { input, output, model, manufacturer } => {
-            pack_rkaf(&input, &output, &model, &manufacturer)?;
+        Commands::Store { store, action } => {
+            let store = Store::open(&store)?;
+            match action {
+                StoreAction::Ingest { images, name } => {
+                    if name.is_some() && images.len() > 1 {
+                        return Err(UsageError(tr!("store-name-single")).into());
+                    }
+                    for image in &images {
+                        let stats = store.ingest(image, name.as_deref())?;
+                        println!(
+                            "{}: {} blobs ({} new), {} of {} bytes stored",
+                            stats.name, stats.blobs, stats.new_blobs, stats.stored_bytes, stats.total_bytes
+                        );
+                    }
+                }
+                StoreAction::Materialize { name, output } => {
+                    store.materialize(&name, &output)?;
+                }
+                StoreAction::List => {
+                    for name in store.list()? {
+                        println!("{}", name);
+                    }
+                }
+            }
         }
     }
 
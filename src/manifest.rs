@@ -0,0 +1,173 @@
+use std::path::Path;
+use crate::error::{AfptoolError, IoContext, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use crate::grep::hex_pattern;
+use crate::paths::join_path;
+use crate::UpdateHeader;
+
+/// Written next to BOOT and embedded-update.img by an RKFW unpack.
+pub(crate) const FIRMWARE_MANIFEST_FILE: &str = "firmware-manifest.json";
+/// Written next to partition-metadata.txt by an RKAF unpack.
+pub(crate) const UPDATE_MANIFEST_FILE: &str = "update-manifest.json";
+
+/// Offset of the RKFW header bytes after the section table, which
+/// [`FirmwareManifest::reserved`] keeps.
+pub(crate) const RKFW_RESERVED_AT: usize = 0x29;
+/// Size of the RKFW header, BOOT follows it.
+pub(crate) const RKFW_HEADER_SIZE: usize = 0x66;
+
+/// The RKFW header fields of an unpacked image, so `pack-rkfw` can rebuild it
+/// without being told them again.
+///
+/// ```json
+/// {
+///   "chip": "RK3562",
+///   "chip_code": 50,
+///   "version": "1.0.0",
+///   "timestamp": 1762435994,
+///   "code": "0x02000000",
+///   "boot": { "offset": 102, "size": 469440 },
+///   "update": { "offset": 469542, "size": 272773124 }
+/// }
+/// ```
+///
+/// `chip` is absent when the chip code is not a known family. The sections
+/// are informational; packing lays them out afresh. A recursive unpack also
+/// records the embedded image's `model` and `manufacturer`, which packing
+/// the expanded tree needs to rebuild it. `reserved` holds the header bytes
+/// from 0x29 on in hex (the flag at 0x2d among them), put back as they were
+/// apart from the large-image marker, which follows the update size.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirmwareManifest {
+    pub chip: Option<String>,
+    pub chip_code: u32,
+    pub version: String,
+    pub timestamp: i64,
+    pub code: String,
+    pub boot: ManifestSection,
+    pub update: ManifestSection,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reserved: Option<String>,
+}
+
+/// The RKAF header fields pack can't work out from the tree, so that an
+/// unpacked image repacks to the same header:
+///
+/// ```json
+/// {
+///   "version": "1.0.0",
+///   "machine_id": "007",
+///   "length": 1052672,
+///   "num_parts": 12,
+///   "unknown1": 0,
+///   "reserved": "0000…"
+/// }
+/// ```
+///
+/// `reserved` is the 116 bytes after the partition table, in hex. The
+/// machine id is used when neither the options nor parameter.txt give one;
+/// `length` and `num_parts` are informational, packing works them out afresh.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdateManifest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_parts: Option<u32>,
+    pub unknown1: u32,
+    pub reserved: String,
+}
+
+/// Byte range of a section in the RKFW image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestSection {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Reads the manifest `file` of an unpacked tree, or `None` if it has none.
+fn load_manifest<T: DeserializeOwned>(dir: &Path, file: &str) -> Result<Option<T>> {
+    let path = join_path(dir, file);
+    let data = match std::fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Cannot read {}", path.display())),
+    };
+    serde_json::from_str(&data).map(Some).map_err(|e| AfptoolError::Parse(format!("Invalid manifest {}: {}", path.display(), e)))
+}
+
+fn save_manifest(manifest: &impl Serialize, dir: &Path, file: &str) -> Result<()> {
+    std::fs::write(join_path(dir, file), serde_json::to_string_pretty(manifest)? + "\n")?;
+    Ok(())
+}
+
+impl FirmwareManifest {
+    /// Reads the manifest of an unpacked tree, or `None` if it has none.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Option<FirmwareManifest>> {
+        load_manifest(dir.as_ref(), FIRMWARE_MANIFEST_FILE)
+    }
+
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        save_manifest(self, dir, FIRMWARE_MANIFEST_FILE)
+    }
+
+    /// The recorded header bytes from [`RKFW_RESERVED_AT`] on.
+    pub(crate) fn reserved_bytes(&self) -> Result<Option<Vec<u8>>> {
+        self.reserved.as_deref().map(decode_hex).transpose()
+    }
+}
+
+impl UpdateManifest {
+    pub(crate) fn from_header(header: &UpdateHeader) -> UpdateManifest {
+        UpdateManifest {
+            version: Some(header.version_text()),
+            machine_id: Some(header.id()),
+            length: header.image_length().ok(),
+            num_parts: Some(header.num_parts),
+            unknown1: header.unknown1,
+            reserved: encode_hex(&header.reserved),
+        }
+    }
+
+    /// Puts the recorded fields back into `header`.
+    pub(crate) fn apply(&self, header: &mut UpdateHeader) -> Result<()> {
+        let reserved = decode_hex(&self.reserved)?;
+        if reserved.len() != header.reserved.len() {
+            return Err(AfptoolError::Parse(format!("{} reserved must be {} bytes, not {}", UPDATE_MANIFEST_FILE, header.reserved.len(), reserved.len())));
+        }
+        if let Some(version) = &self.version {
+            header.set_version(version)?;
+        }
+        header.unknown1 = self.unknown1;
+        header.reserved.copy_from_slice(&reserved);
+        Ok(())
+    }
+
+    /// Reads the manifest of an unpacked tree, or `None` if it has none.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Option<UpdateManifest>> {
+        load_manifest(dir.as_ref(), UPDATE_MANIFEST_FILE)
+    }
+
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        save_manifest(self, dir, UPDATE_MANIFEST_FILE)
+    }
+}
+
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Result<Vec<u8>> {
+    if text.is_empty() {
+        return Ok(Vec::new());
+    }
+    hex_pattern(text)
+}
@@ -1,15 +1,178 @@
 use std::fs::File;
-use std::io::{Read, Write, BufRead, BufReader};
+use std::io::{Read, Seek, SeekFrom, Write, BufRead, BufReader, BufWriter};
 use std::collections::HashMap;
-use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use crate::error::{AfptoolError, IoContext, Result};
 use chrono::{Datelike, Timelike};
-use crate::{UpdateHeader, UpdatePart, MAX_NAME_LEN, MAX_FULL_PATH_LEN, RKFW_SIGNATURE, RKAF_SIGNATURE};
+use crate::tr;
+use crate::cache::{stamp, CachedFile, PackCache};
+use crate::cancel::CancelToken;
+use crate::chips::{chip_default_code, chip_label, chip_name_to_code};
+use crate::compress::{compression_of, copy_decompressed, decompressed_size, split_compressed, Compression};
+use crate::fastcopy::copy_range;
+use crate::layout::check_flash_layout;
+use crate::hash::{md5_file_region, parallel_map};
+use crate::image::{RKFW_LARGE_MARKER, RKFW_LARGE_MARKER_AT};
+use crate::parameter::{MtdPart, ParameterFile};
+use crate::manifest::{FirmwareManifest, UpdateManifest, FIRMWARE_MANIFEST_FILE, RKFW_HEADER_SIZE, RKFW_RESERVED_AT};
+use crate::paths::{join_path, open_file, with_suffix};
+use crate::rkcrc::{wrap_rkcrc, CrcWrapper};
+use crate::sparse::{SparsePlan, SPARSE_MAGIC};
+use crate::template::{expand_output_name, expand_vars};
+use crate::text::{clean_line, encode_text, metadata_fields, normalize_path};
+use crate::timings::phase;
+use crate::{UpdateHeader, UpdatePart, FLASH_SIZE_GROW, MAX_NAME_LEN, MAX_FULL_PATH_LEN, MAX_PARTS, RKFW_SIGNATURE, RKAF_SIGNATURE, UPDATE_HEADER_SIZE};
+
+/// Optional behaviour for [`pack_rkaf_with`].
+#[derive(Debug, Clone, Default)]
+pub struct RkafOptions {
+    /// Reuse unchanged partition data from the previous pack of the same tree.
+    pub incremental: bool,
+    /// Values for `${NAME}` placeholders in package-file, checked before the environment.
+    pub defines: Vec<(String, String)>,
+    /// Files used instead of the ones listed in package-file, keyed by partition name.
+    pub overrides: Vec<(String, PathBuf)>,
+    /// Machine id for the header; taken from parameter.txt's MACHINE_ID when `None`.
+    pub machine_id: Option<String>,
+    /// Header version as `major.minor.build`; when `None`, the one recorded
+    /// in update-manifest.json, else 1.0.0.
+    pub version: Option<String>,
+    /// Store model and manufacturer in GBK, as Chinese vendor tools do.
+    pub gbk: bool,
+    /// Write padding and all-zero partitions out as zeros instead of leaving
+    /// holes, for filesystems or tools that don't cope with sparse files.
+    pub write_padding: bool,
+    /// Add the `SELF` entry describing the image itself and a `RESERVED`
+    /// placeholder, as the original afptool does, unless package-file
+    /// already lists them. Some stock upgrade tools expect both.
+    pub self_entry: bool,
+    /// Take flash offsets and sizes from the `mtdparts=` list in parameter.txt
+    /// even where partition-metadata.txt has them; otherwise mtdparts only
+    /// fills in partitions the metadata lacks.
+    pub mtdparts_primary: bool,
+    /// Partitions, by name, whose raw files are stored as Android sparse
+    /// images. Files that already are sparse are embedded as they are.
+    pub sparse: Vec<String>,
+    /// Store byte-identical files listed under different paths once, as
+    /// stock images do for backup and recovery pairs. Off by default so an
+    /// unpacked tree repacks to its original layout.
+    pub dedupe: bool,
+    /// Decompress `.gz` files named in package-file while packing and store
+    /// them under the path without the suffix. Without it they are stored
+    /// as they are; an uncompressed file missing next to its `.gz`, as
+    /// `unpack --compress` leaves them, is decompressed either way.
+    pub decompress: bool,
+    /// Stops the pack between chunks of data; the partial image is removed.
+    pub cancel: CancelToken,
+}
+
+/// Extra partition-metadata.txt column marking a partition whose data is all zero.
+pub(crate) const ZERO_MARKER: &str = "zero";
+
+/// Extra partition-metadata.txt column marking an entry with no data in the
+/// image (offset and size 0), written back as such when its file is absent.
+pub(crate) const PLACEHOLDER_MARKER: &str = "placeholder";
+
+/// Extra partition-metadata.txt column marking a parameter partition stored
+/// with a `PARM` header and CRC, which unpack strips and pack puts back.
+pub(crate) const PARM_MARKER: &str = "parm";
+/// Suffix of the PARM-wrapped blob unpack saves next to the stripped text.
+pub(crate) const PARM_BLOB_SUFFIX: &str = ".parm";
+
+/// Extra partition-metadata.txt column marking an Android sparse partition
+/// that unpack expanded to a raw image, so its size no longer matches.
+pub(crate) const DESPARSED_MARKER: &str = "desparsed";
+
+/// Flash size written as in mtdparts for a partition that takes the rest of
+/// the flash, accepted in partition-metadata.txt and package-file.
+const GROW_MARKER: &str = "-";
+
+/// package-file paths that name no file: `SELF` stands for the update image
+/// itself and `RESERVED` for an unused slot.
+pub(crate) const SELF_MARKER: &str = "SELF";
+pub(crate) const RESERVED_MARKER: &str = "RESERVED";
+
+/// Path of a laid-out input file with its offset and size in the image.
+type LaidOut = (PathBuf, (u64, u64));
+
+/// Where an input is read from: the file itself or, when it is missing, the
+/// `.gz` that `unpack --compress` wrote in its place.
+fn input_file(file_path: PathBuf) -> PathBuf {
+    let compressed = with_suffix(&file_path, Compression::Gzip.extension());
+    match stamp(&file_path).is_none() && stamp(&compressed).is_some() {
+        true => compressed,
+        false => file_path,
+    }
+}
+
+/// Input files already laid out, grouped by size, so a byte-identical file
+/// under another path can share their data region.
+#[derive(Default)]
+struct ContentIndex {
+    by_size: HashMap<u64, Vec<LaidOut>>,
+    digests: HashMap<PathBuf, String>,
+}
+
+impl ContentIndex {
+    fn digest(&mut self, path: &Path, size: u64) -> Result<String> {
+        if let Some(digest) = self.digests.get(path) {
+            return Ok(digest.clone());
+        }
+        let digest = md5_file_region(path, 0, size)?;
+        self.digests.insert(path.to_path_buf(), digest.clone());
+        Ok(digest)
+    }
+
+    /// Path and region of a laid-out file with the same contents as `path`.
+    /// Only files of equal size are hashed.
+    fn find(&mut self, path: &Path) -> Result<Option<LaidOut>> {
+        let Some(size) = stamp(path).map(|file_stamp| file_stamp.size) else {
+            return Ok(None);
+        };
+        let candidates = self.by_size.get(&size).cloned().unwrap_or_default();
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+        let digest = self.digest(path, size)?;
+        for (other, region) in candidates {
+            if self.digest(&other, size)? == digest {
+                return Ok(Some((other, region)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn add(&mut self, path: &Path, region: (u64, u64)) {
+        self.by_size.entry(region.1).or_default().push((path.to_path_buf(), region));
+    }
+}
+
+/// Where a partition's bytes come from when writing the image.
+pub(crate) enum DataSource {
+    Input(PathBuf),
+    PreviousOutput(u64),
+    Zero,
+    /// Built in memory, such as parameter.txt wrapped in its PARM header.
+    Bytes(Vec<u8>),
+    /// A raw image written out as an Android sparse image.
+    Sparse(SparsePlan),
+    /// A compressed input file, decompressed as it is copied.
+    Compressed(PathBuf, Compression),
+}
 
 #[derive(Debug, Clone)]
 struct PartitionMetadata {
     flash_size: u32,
     flash_offset: u32,
     padded_size: u32,
+    byte_count: u64,
+    /// Marked by unpack as all zero; the file may have been left out.
+    zero: bool,
+    placeholder: bool,
+    parm: bool,
+    /// Expanded from an Android sparse image by unpack, so the recorded
+    /// sizes are those of the sparse data, not of the file.
+    desparsed: bool,
 }
 
 // RockChip CRC-32 table
@@ -80,7 +243,7 @@ const RKCRC32_TABLE: [u32; 256] = [
     0xbcbb966d, 0xb87a9bda, 0xb5398d03, 0xb1f880b4,
 ];
 
-fn rkcrc32(mut crc: u32, data: &[u8]) -> u32 {
+pub(crate) fn rkcrc32(mut crc: u32, data: &[u8]) -> u32 {
     for &byte in data {
         let index = ((crc >> 24) ^ (byte as u32)) as usize;
         crc = (crc << 8) ^ RKCRC32_TABLE[index & 0xFF];
@@ -88,8 +251,91 @@ fn rkcrc32(mut crc: u32, data: &[u8]) -> u32 {
     crc
 }
 
-fn parse_partition_metadata(input_dir: &str) -> Result<HashMap<String, PartitionMetadata>> {
-    let metadata_path = format!("{}/partition-metadata.txt", input_dir);
+/// Passes writes through while keeping the rkcrc32 of everything written.
+pub(crate) struct CrcWriter<'a, W: Write> {
+    pub inner: &'a mut W,
+    pub crc: u32,
+}
+
+impl<'a, W: Write> CrcWriter<'a, W> {
+    pub fn new(inner: &'a mut W) -> CrcWriter<'a, W> {
+        CrcWriter { inner, crc: 0 }
+    }
+}
+
+impl<W: Write> Write for CrcWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc = rkcrc32(self.crc, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Product of two polynomials modulo the rkcrc32 polynomial.
+fn crc_multiply(a: u32, b: u32) -> u32 {
+    let mut product = 0u32;
+    for bit in (0..32).rev() {
+        product = (product << 1) ^ if product & 0x8000_0000 != 0 { RKCRC32_TABLE[1] } else { 0 };
+        if b & (1 << bit) != 0 {
+            product ^= a;
+        }
+    }
+    product
+}
+
+/// The CRC of A followed by B, given the CRCs of A and B and the length of B.
+/// rkcrc32 starts from zero and has no final XOR, so it is linear: appending
+/// `len` bytes multiplies A's CRC by x^(8 * len) before B's is added.
+fn rkcrc32_combine(crc_a: u32, crc_b: u32, len: u64) -> u32 {
+    let (mut power, mut base, mut remaining) = (1u32, 0x100u32, len);
+    while remaining > 0 {
+        if remaining & 1 != 0 {
+            power = crc_multiply(power, base);
+        }
+        base = crc_multiply(base, base);
+        remaining >>= 1;
+    }
+    crc_multiply(crc_a, power) ^ crc_b
+}
+
+/// Bytes of the image each thread checksums at a time.
+const CRC_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// rkcrc32 of the first `len` bytes of the file at `path`, with chunks read
+/// and checksummed on all cores and combined afterwards.
+fn rkcrc32_file(path: &Path, len: u64, cancel: &CancelToken) -> Result<u32> {
+    let chunks: Vec<(u64, u64)> = (0..len)
+        .step_by(CRC_CHUNK_SIZE as usize)
+        .map(|offset| (offset, std::cmp::min(CRC_CHUNK_SIZE, len - offset)))
+        .collect();
+    let crcs = parallel_map(&chunks, |&(offset, chunk_len)| {
+        let mut input = open_file(path)?;
+        input.seek(SeekFrom::Start(offset))?;
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut checksum = 0;
+        let mut remaining = chunk_len;
+        while remaining > 0 {
+            cancel.check()?;
+            let read_len = std::cmp::min(remaining as usize, buffer.len());
+            {
+                let _phase = phase("io");
+                input.read_exact(&mut buffer[..read_len])?;
+            }
+            let _phase = phase("hash");
+            checksum = rkcrc32(checksum, &buffer[..read_len]);
+            remaining -= read_len as u64;
+        }
+        Ok(checksum)
+    })?;
+    Ok(chunks.iter().zip(crcs).fold(0, |crc, (&(_, chunk_len), chunk_crc)| rkcrc32_combine(crc, chunk_crc, chunk_len)))
+}
+
+fn parse_partition_metadata(input_dir: &Path) -> Result<HashMap<String, PartitionMetadata>> {
+    let metadata_path = join_path(input_dir, "partition-metadata.txt");
     let mut metadata_map = HashMap::new();
 
     let file = match File::open(&metadata_path) {
@@ -100,23 +346,30 @@ fn parse_partition_metadata(input_dir: &str) -> Result<HashMap<String, Partition
     let reader = BufReader::new(file);
     for line in reader.lines() {
         let line = line?;
-        let line = line.trim();
-
-        if line.is_empty() {
-            continue;
-        }
-
-        let parts: Vec<&str> = line.split(',').collect();
+        let parts = metadata_fields(&line);
         if parts.len() >= 7 {
             let name = parts[0].to_string();
-            let flash_size = u32::from_str_radix(parts[2].trim_start_matches("0x"), 16)?;
+            let flash_size = match parts[2] {
+                GROW_MARKER => FLASH_SIZE_GROW,
+                size => u32::from_str_radix(size.trim_start_matches("0x"), 16)?,
+            };
             let flash_offset = u32::from_str_radix(parts[3].trim_start_matches("0x"), 16)?;
             let padded_size = u32::from_str_radix(parts[5].trim_start_matches("0x"), 16)?;
+            let byte_count = u64::from_str_radix(parts[6].trim_start_matches("0x"), 16)?;
+            let zero = parts.get(7) == Some(&ZERO_MARKER);
+            let placeholder = parts.get(7) == Some(&PLACEHOLDER_MARKER);
+            let parm = parts.get(7) == Some(&PARM_MARKER);
+            let desparsed = parts.get(7) == Some(&DESPARSED_MARKER);
 
             metadata_map.insert(name, PartitionMetadata {
                 flash_size,
                 flash_offset,
                 padded_size,
+                byte_count,
+                zero,
+                placeholder,
+                parm,
+                desparsed,
             });
         }
     }
@@ -124,47 +377,145 @@ fn parse_partition_metadata(input_dir: &str) -> Result<HashMap<String, Partition
     Ok(metadata_map)
 }
 
-pub fn pack_rkfw(input_dir: &str, output_file: &str, chip: &str, version: &str, timestamp: i64, code_hex: &str) -> Result<()> {
-    let hex_str = code_hex.trim_start_matches("0x").trim_start_matches("0X");
-    let code_value = u32::from_str_radix(hex_str, 16)
-        .map_err(|_| anyhow!("Invalid hex value for code field: {}", hex_str))?;
+/// RKFW header fields for [`pack_rkfw_with`]. Fields left `None` are taken
+/// from the firmware-manifest.json an unpack wrote into the input directory.
+#[derive(Debug, Clone, Default)]
+pub struct RkfwOptions {
+    pub chip: Option<String>,
+    /// Raw value for the chip field, for a family not in the table. Takes
+    /// precedence over [`chip`](Self::chip).
+    pub chip_code: Option<u32>,
+    pub version: Option<String>,
+    /// Build date; without it or a manifest, [`default_timestamp`] is used.
+    pub timestamp: Option<i64>,
+    /// Code field as hex, with or without `0x`.
+    pub code: Option<String>,
+    /// Raw `(offset, value)` bytes written into the header after the section
+    /// table (0x29 to 0x65), over those recorded in the manifest.
+    pub header_bytes: Vec<(usize, u8)>,
+}
+
+pub fn pack_rkfw(input_dir: impl AsRef<Path>, output_file: impl AsRef<Path>, chip: &str, version: &str, timestamp: i64, code_hex: &str) -> Result<()> {
+    let options = RkfwOptions {
+        chip: Some(chip.to_string()),
+        version: Some(version.to_string()),
+        timestamp: Some(timestamp),
+        code: Some(code_hex.to_string()),
+        ..Default::default()
+    };
+    pack_rkfw_with(input_dir, output_file, &options)
+}
+
+/// [`pack_rkfw`] with the header fields an unpack recorded in
+/// firmware-manifest.json, overridden by those set in `options`.
+pub fn pack_rkfw_with(input_dir: impl AsRef<Path>, output_file: impl AsRef<Path>, options: &RkfwOptions) -> Result<()> {
+    let (input_dir, output_file) = (input_dir.as_ref(), output_file.as_ref());
+    let chip_given = options.chip.is_some() || options.chip_code.is_some();
+    let manifest = match (chip_given, &options.version, options.timestamp, &options.code) {
+        (true, Some(_), Some(_), Some(_)) => None,
+        _ => FirmwareManifest::load(input_dir)?,
+    };
+    let missing = |flag: &str| AfptoolError::Invalid(format!("No {} given and no {} in {}", flag, FIRMWARE_MANIFEST_FILE, input_dir.display()));
+    // The manifest's code is used as recorded, so an unknown chip or one
+    // stored with its full ID repacks to the same bytes
+    let (chip, chip_code) = match (options.chip_code, &options.chip, &manifest) {
+        (Some(code), _, _) => (chip_label(code), code),
+        (None, Some(chip), _) => (chip.clone(), chip_name_to_code(chip)?),
+        (None, None, Some(manifest)) => (chip_label(manifest.chip_code), manifest.chip_code),
+        (None, None, None) => return Err(missing("--chip")),
+    };
+    let version = options.version.clone().or_else(|| manifest.as_ref().map(|m| m.version.clone())).ok_or_else(|| missing("--version"))?;
+    let timestamp = match options.timestamp.or(manifest.as_ref().map(|m| m.timestamp)) {
+        Some(timestamp) => timestamp,
+        None => default_timestamp()?,
+    };
+    let code_hex = options.code.clone()
+        .or_else(|| manifest.as_ref().map(|m| m.code.clone()))
+        .or_else(|| chip_default_code(chip_code).map(|code| format!("{:#010x}", code)))
+        .ok_or_else(|| missing("--code"))?;
+
+    let boot_path = join_path(input_dir, "BOOT");
+    let update_path = join_path(input_dir, "embedded-update.img");
+
+    if !boot_path.is_file() {
+        return Err(AfptoolError::not_found(format!("Cannot find BOOT file in {}", input_dir.display())));
+    }
+    let recorded = match manifest {
+        Some(manifest) => Some(manifest),
+        None => FirmwareManifest::load(input_dir)?,
+    };
+    let mut header_bytes: Vec<(usize, u8)> = recorded.as_ref().map(FirmwareManifest::reserved_bytes).transpose()?.flatten()
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| (RKFW_RESERVED_AT + i, value))
+        .collect();
+    for &(offset, value) in &options.header_bytes {
+        if !(RKFW_RESERVED_AT..RKFW_HEADER_SIZE).contains(&offset) {
+            return Err(AfptoolError::Invalid(format!("Header byte {:#x} is outside the raw area {:#x}..{:#x}", offset, RKFW_RESERVED_AT, RKFW_HEADER_SIZE)));
+        }
+        header_bytes.push((offset, value));
+    }
+    if update_path.is_file() {
+        return pack_rkfw_files(&boot_path, &update_path, output_file, (&chip, chip_code), &version, timestamp, &code_hex, &header_bytes);
+    }
+    if !join_path(input_dir, "package-file").is_file() {
+        return Err(AfptoolError::not_found(format!("Cannot find embedded-update.img or package-file in {}", input_dir.display())));
+    }
+
+    // A fully unpacked tree: build the update image first, next to the output
+    log::info!("{}", tr!("pack-rkfw-from-tree", dir = input_dir.display()));
+    let temp_update = with_suffix(output_file, ".rkaf.tmp");
+    let packed = File::create(&temp_update).map_err(AfptoolError::from).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        // Without a recorded model and manufacturer, parameter.txt supplies them
+        let model = recorded.as_ref().and_then(|manifest| manifest.model.clone()).unwrap_or_default();
+        let manufacturer = recorded.as_ref().and_then(|manifest| manifest.manufacturer.clone()).unwrap_or_default();
+        // The update image takes the firmware version unless it recorded its own
+        let update_version = match UpdateManifest::load(input_dir)? {
+            Some(UpdateManifest { version: Some(_), .. }) => None,
+            _ => Some(version.clone()),
+        };
+        pack_rkaf_to_writer(input_dir, &mut out, &model, &manufacturer, &RkafOptions { version: update_version, ..Default::default() })?;
+        out.into_inner().map_err(|e| e.into_error())?;
+        pack_rkfw_files(&boot_path, &temp_update, output_file, (&chip, chip_code), &version, timestamp, &code_hex, &header_bytes)
+    });
+    let _ = std::fs::remove_file(&temp_update);
+    packed
+}
 
+/// Parses the `major.minor.build` version stored in an RKFW header.
+pub(crate) fn parse_rkfw_version(version: &str) -> Result<(u8, u8, u16)> {
     let version_parts: Vec<&str> = version.split('.').collect();
     if version_parts.len() != 3 {
-        return Err(anyhow!("Version must be in format: major.minor.build (e.g., 8.1.0)"));
+        return Err(AfptoolError::Invalid("Version must be in format: major.minor.build (e.g., 8.1.0)".to_string()));
     }
 
     let major: u8 = version_parts[0].parse()
-        .map_err(|_| anyhow!("Invalid major version"))?;
+        .map_err(|_| AfptoolError::Invalid("Invalid major version".to_string()))?;
     let minor: u8 = version_parts[1].parse()
-        .map_err(|_| anyhow!("Invalid minor version"))?;
+        .map_err(|_| AfptoolError::Invalid("Invalid minor version".to_string()))?;
     let build: u16 = version_parts[2].parse()
-        .map_err(|_| anyhow!("Invalid build number"))?;
-
-    let chip_code = chip_name_to_code(chip)?;
-
-    let boot_path = format!("{}/BOOT", input_dir);
-    let update_path = format!("{}/embedded-update.img", input_dir);
-
-    let mut boot_data = Vec::new();
-    File::open(&boot_path)
-        .map_err(|_| anyhow!("Cannot find BOOT file in {}", input_dir))?
-        .read_to_end(&mut boot_data)?;
+        .map_err(|_| AfptoolError::Invalid("Invalid build number".to_string()))?;
+    Ok((major, minor, build))
+}
 
-    let mut update_data = Vec::new();
-    File::open(&update_path)
-        .map_err(|_| anyhow!("Cannot find embedded-update.img file in {}", input_dir))?
-        .read_to_end(&mut update_data)?;
+/// Builds the 0x66-byte RKFW header for a BOOT section of `boot_size` bytes
+/// followed by an RKAF image of `update_size` bytes. `raw` is written over
+/// the bytes after the section table, as `(offset, value)` pairs.
+pub(crate) fn rkfw_header(chip_code: u32, version: &str, timestamp: i64, code: u32, boot_size: u64, update_size: u64, raw: &[(usize, u8)]) -> Result<Vec<u8>> {
+    let (major, minor, build) = parse_rkfw_version(version)?;
 
-    if update_data.len() < 4 || &update_data[0..4] != b"RKAF" {
-        return Err(anyhow!("embedded-update.img must be a valid RKAF file"));
-    }
+    let datetime = chrono::DateTime::from_timestamp(timestamp, 0)
+        .ok_or_else(|| AfptoolError::Invalid("Invalid timestamp".to_string()))?
+        .naive_utc();
 
-    let header_size = 0x66;
+    let header_size = RKFW_HEADER_SIZE as u32;
     let boot_offset = header_size;
-    let boot_size = boot_data.len() as u32;
-    let update_offset = boot_offset + boot_size;
-    let update_size = update_data.len() as u32;
+    // Only the update image size has a high half
+    let (boot_size, update_offset) = u32::try_from(boot_size).ok()
+        .and_then(|boot_size| Some((boot_size, boot_offset.checked_add(boot_size)?)))
+        .ok_or_else(|| AfptoolError::Layout(format!("A {}-byte BOOT section does not fit the RKFW header", boot_size)))?;
 
     let mut header = vec![0u8; header_size as usize];
 
@@ -177,93 +528,195 @@ pub fn pack_rkfw(input_dir: &str, output_file: &str, chip: &str, version: &str,
     header[8] = minor;
     header[9] = major;
 
-    let code_bytes = code_value.to_le_bytes();
+    let code_bytes = code.to_le_bytes();
     header[0x0a] = code_bytes[0];
     header[0x0b] = code_bytes[1];
     header[0x0c] = code_bytes[2];
     header[0x0d] = code_bytes[3];
 
-    let datetime = chrono::DateTime::from_timestamp(timestamp, 0)
-        .ok_or_else(|| anyhow!("Invalid timestamp"))?
-        .naive_utc();
-
     let year = datetime.year() as u16;
-    let month = datetime.month() as u8;
-    let day = datetime.day() as u8;
-    let hour = datetime.hour() as u8;
-    let minute = datetime.minute() as u8;
-    let second = datetime.second() as u8;
-
     header[0x0e] = (year & 0xFF) as u8;
     header[0x0f] = ((year >> 8) & 0xFF) as u8;
-    header[0x10] = month;
-    header[0x11] = day;
-    header[0x12] = hour;
-    header[0x13] = minute;
-    header[0x14] = second;
-
-    header[0x15] = chip_code;
-
-    let chip_digits: Vec<u8> = chip.chars()
-        .filter(|c| c.is_numeric())
-        .map(|c| c as u8)
-        .collect();
+    header[0x10] = datetime.month() as u8;
+    header[0x11] = datetime.day() as u8;
+    header[0x12] = datetime.hour() as u8;
+    header[0x13] = datetime.minute() as u8;
+    header[0x14] = datetime.second() as u8;
 
-    if chip_digits.len() >= 3 {
-        header[0x16] = chip_digits[2];
-        header[0x17] = chip_digits[1];
-        header[0x18] = chip_digits[0];
-    }
+    put_u32_le(&mut header[0x15..], chip_code);
 
     put_u32_le(&mut header[0x19..], boot_offset);
     put_u32_le(&mut header[0x1d..], boot_size);
 
     put_u32_le(&mut header[0x21..], update_offset);
-    put_u32_le(&mut header[0x25..], update_size);
 
     // Padding
     header[0x2d] = 0x01;
+    for &(offset, value) in raw.iter().filter(|(offset, _)| (RKFW_RESERVED_AT..RKFW_HEADER_SIZE).contains(offset)) {
+        header[offset] = value;
+    }
+    put_rkfw_update_size(&mut header, update_size);
+    Ok(header)
+}
 
-    let mut file_data = Vec::new();
-    file_data.extend_from_slice(&header);
-    file_data.extend_from_slice(&boot_data);
-    file_data.extend_from_slice(&update_data);
+/// Stores the update image size in an RKFW header, with the `HI` marker and
+/// the high half when it is past 4 GiB.
+pub(crate) fn put_rkfw_update_size(header: &mut [u8], update_size: u64) {
+    put_u32_le(&mut header[0x25..], update_size as u32);
+    let marker = &mut header[RKFW_LARGE_MARKER_AT..RKFW_LARGE_MARKER_AT + 6];
+    if update_size > u32::MAX as u64 {
+        marker[..2].copy_from_slice(RKFW_LARGE_MARKER);
+        put_u32_le(&mut marker[2..], (update_size >> 32) as u32);
+    } else if marker[..2] == *RKFW_LARGE_MARKER {
+        marker.fill(0);
+    }
+}
 
-    let digest = md5::compute(&file_data);
-    let md5_hex = format!("{:x}", digest);
+/// Parses an `OFFSET=VALUE` header byte as given on the command line; both
+/// numbers are decimal or `0x` hex.
+pub fn parse_header_byte(text: &str) -> Result<(usize, u8)> {
+    let number = |value: &str| match value.trim().strip_prefix("0x").or_else(|| value.trim().strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.trim().parse().ok(),
+    };
+    let invalid = || AfptoolError::Invalid(format!("Invalid header byte {:?}, expected OFFSET=VALUE", text));
+    let (offset, value) = text.split_once('=').ok_or_else(invalid)?;
+    let offset = number(offset).and_then(|offset| usize::try_from(offset).ok()).ok_or_else(invalid)?;
+    let value = number(value).and_then(|value| u8::try_from(value).ok()).ok_or_else(invalid)?;
+    Ok((offset, value))
+}
 
-    let mut out_file = File::create(output_file)?;
-    out_file.write_all(&file_data)?;
-    out_file.write_all(md5_hex.as_bytes())?;
+/// Parses a build date given as a Unix timestamp or as a UTC date and time
+/// (`2024-11-08T12:00:00`, `2024-11-08 12:00:00` or `2024-11-08`).
+pub fn parse_timestamp(text: &str) -> Result<i64> {
+    let text = text.trim();
+    if let Ok(timestamp) = text.parse::<i64>() {
+        return Ok(timestamp);
+    }
+    ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S"].iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(text.trim_end_matches('Z'), format).ok())
+        .or_else(|| chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(0, 0, 0)))
+        .map(|datetime| datetime.and_utc().timestamp())
+        .ok_or_else(|| AfptoolError::Invalid(format!("Invalid timestamp {:?}: expected seconds since 1970 or YYYY-MM-DD[THH:MM:SS]", text)))
+}
 
-    let total_size = file_data.len() + md5_hex.len();
+/// The build date used when none is given: `SOURCE_DATE_EPOCH` when set,
+/// for reproducible builds, otherwise the current time.
+pub fn default_timestamp() -> Result<i64> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.trim().parse().map_err(|_| AfptoolError::Invalid(format!("Invalid SOURCE_DATE_EPOCH: {}", epoch))),
+        Err(_) => Ok(chrono::Utc::now().timestamp()),
+    }
+}
 
-    println!("Successfully packed RKFW image:");
-    println!("  Output: {}", output_file);
-    println!("  Version: {}.{}.{}", major, minor, build);
-    println!("  Date: {}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second);
-    println!("  Chip: {} (code: 0x{:02x})", chip, chip_code);
-    println!("  BOOT size: {} bytes", boot_size);
-    println!("  Update image size: {} bytes", update_size);
-    println!("  MD5: {}", md5_hex);
-    println!("  Total size: {} bytes", total_size);
+/// `timestamp` as a UTC date and time.
+fn utc_datetime(timestamp: i64) -> Result<chrono::NaiveDateTime> {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|datetime| datetime.naive_utc())
+        .ok_or_else(|| AfptoolError::Invalid("Invalid timestamp".to_string()))
+}
 
-    Ok(())
+/// Parses the RKFW code field, written in hex with or without `0x`.
+pub(crate) fn parse_code(code_hex: &str) -> Result<u32> {
+    let hex_str = code_hex.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(hex_str, 16)
+        .map_err(|_| AfptoolError::Invalid(format!("Invalid hex value for code field: {}", hex_str)))
 }
 
-pub fn chip_name_to_code(chip: &str) -> Result<u8> {
-    match chip.to_uppercase().as_str() {
-        "RK29XX" | "RK29" => Ok(0x50),
-        "RK30XX" | "RK30" => Ok(0x60),
-        "RK31XX" | "RK31" => Ok(0x70),
-        "RK32XX" | "RK32" => Ok(0x80),
-        "RK3368" => Ok(0x41),
-        "RK3326" => Ok(0x36),
-        "RK3562" => Ok(0x32),
-        "RK3566" => Ok(0x38),
-        "PX30" => Ok(0x30),
-        _ => Err(anyhow!("Unsupported chip family: {}", chip)),
+/// Wraps an RKAF update image and a loader blob into an RKFW image. `chip`
+/// is the name used in the output name and messages, and the chip field.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn pack_rkfw_files(boot_path: &Path, update_path: &Path, output_file: &Path, chip: (&str, u32), version: &str, timestamp: i64, code_hex: &str, raw: &[(usize, u8)]) -> Result<()> {
+    let code_value = parse_code(code_hex)?;
+    let (major, minor, build) = parse_rkfw_version(version)?;
+    let (chip, chip_code) = chip;
+
+    let datetime = utc_datetime(timestamp)?;
+
+    let output_file = &expand_output_name(output_file, &[
+        ("chip", chip.to_string()),
+        ("version", version.to_string()),
+        ("date", datetime.format("%Y%m%d").to_string()),
+        ("code", format!("{:08x}", code_value)),
+    ])?;
+
+    let mut boot_file = open_file(boot_path)?;
+    let mut update_file = open_file(update_path)?;
+
+    let mut update_magic = [0u8; 4];
+    if update_file.read_exact(&mut update_magic).is_err() || update_magic != *RKAF_SIGNATURE {
+        return Err(AfptoolError::Parse(format!("{} must be a valid RKAF file", update_path.display())));
+    }
+    update_file.seek(SeekFrom::Start(0))?;
+
+    let boot_size = boot_file.metadata()?.len();
+    let update_size = update_file.metadata()?.len();
+    let header = rkfw_header(chip_code, version, timestamp, code_value, boot_size, update_size, raw)?;
+
+    let mut out_file = File::create(output_file)?;
+    out_file.write_all(&header)?;
+
+    // The MD5 trailer covers everything before it. A second thread hashes each
+    // chunk while the next one is read and written, and hands the buffer back.
+    let (to_hasher, chunks) = std::sync::mpsc::sync_channel::<(Vec<u8>, usize)>(2);
+    let (to_reader, spare_buffers) = std::sync::mpsc::channel::<Vec<u8>>();
+    for _ in 0..3 {
+        to_reader.send(vec![0u8; 1024 * 1024]).expect("receiver is still held");
     }
+    let digest = std::thread::scope(|scope| -> Result<md5::Digest> {
+        let header = &header;
+        let hasher = scope.spawn(move || {
+            let mut context = md5::Context::new();
+            context.consume(header);
+            for (buffer, len) in chunks {
+                let _phase = phase("hash");
+                context.consume(&buffer[..len]);
+                // The reader may already have failed and gone away
+                let _ = to_reader.send(buffer);
+            }
+            context.finalize()
+        });
+
+        for (input, size) in [(&mut boot_file, boot_size), (&mut update_file, update_size)] {
+            let mut input = input.take(size);
+            let mut copied = 0u64;
+            loop {
+                let mut buffer = spare_buffers.recv().map_err(|_| AfptoolError::other("MD5 thread stopped"))?;
+                let io = phase("io");
+                let read_bytes = input.read(&mut buffer)?;
+                out_file.write_all(&buffer[..read_bytes])?;
+                drop(io);
+                copied += read_bytes as u64;
+                // Empty chunks are passed on too, so the buffer comes back
+                to_hasher.send((buffer, read_bytes)).map_err(|_| AfptoolError::other("MD5 thread stopped"))?;
+                if read_bytes == 0 {
+                    break;
+                }
+            }
+            if copied != size {
+                return Err(AfptoolError::changed("Input changed size while packing"));
+            }
+        }
+        drop(to_hasher);
+        hasher.join().map_err(|_| AfptoolError::other("MD5 thread panicked"))
+    })?;
+
+    let md5_hex = format!("{:x}", digest);
+    out_file.write_all(md5_hex.as_bytes())?;
+
+    let total_size = header.len() as u64 + boot_size + update_size + md5_hex.len() as u64;
+
+    log::info!("{}", tr!("pack-rkfw-success"));
+    log::info!("{}", tr!("pack-output", path = output_file.display()));
+    log::info!("{}", tr!("pack-version", version = format!("{}.{}.{}", major, minor, build)));
+    log::info!("{}", tr!("pack-date", date = datetime.format("%Y-%m-%d %H:%M:%S").to_string()));
+    log::info!("{}", tr!("pack-chip", chip = chip, code = format!("{:#x}", chip_code)));
+    log::info!("{}", tr!("pack-boot-size", size = boot_size));
+    log::info!("{}", tr!("pack-update-size", size = update_size));
+    log::info!("{}", tr!("pack-md5", md5 = md5_hex));
+    log::info!("{}", tr!("pack-total-size", size = total_size));
+
+    Ok(())
 }
 
 fn put_u32_le(slice: &mut [u8], value: u32) {
@@ -274,178 +727,588 @@ fn put_u32_le(slice: &mut [u8], value: u32) {
     slice[3] = bytes[3];
 }
 
-pub fn pack_rkaf(input_dir: &str, output_file: &str, model: &str, manufacturer: &str) -> Result<()> {
-    let package_file_path = format!("{}/package-file", input_dir);
+/// Packs the tree in `input_dir` into an RKAF image. An empty `model` or
+/// `manufacturer` is taken from `MACHINE_MODEL` or `MANUFACTURER` in
+/// parameter.txt.
+pub fn pack_rkaf(input_dir: impl AsRef<Path>, output_file: impl AsRef<Path>, model: &str, manufacturer: &str) -> Result<()> {
+    pack_rkaf_with(input_dir, output_file, model, manufacturer, &RkafOptions::default())
+}
+
+/// Whether the file at `path` already is an Android sparse image.
+fn is_sparse_file(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = open_file(path)?;
+    Ok(file.read_exact(&mut magic).is_ok() && u32::from_le_bytes(magic) == SPARSE_MAGIC)
+}
+
+/// Header and data placement of an RKAF image, computed before anything is written.
+pub(crate) struct RkafLayout {
+    pub header: UpdateHeader,
+    /// (source, size, padded size) of each distinct file, in image order.
+    pub data: Vec<(DataSource, u64, u64)>,
+    pub length: u64,
+    /// Earlier output that `DataSource::PreviousOutput` entries refer to.
+    previous_output: Option<PathBuf>,
+    cache: PackCache,
+}
+
+impl RkafLayout {
+    /// Offset of each entry in `data`.
+    pub fn offsets(&self) -> Vec<u64> {
+        let mut offset = self.header_len() as u64;
+        self.data
+            .iter()
+            .map(|(_, _, padded_size)| {
+                let current = offset;
+                offset += *padded_size;
+                current
+            })
+            .collect()
+    }
+
+    /// Values for the `{field}` placeholders of an output file name. The
+    /// RKAF header has no build time, so `{date}` is that of
+    /// [`default_timestamp`], in UTC like the RKFW one.
+    fn name_fields(&self) -> Result<Vec<(&'static str, String)>> {
+        Ok(vec![
+            ("model", self.header.model_name()),
+            ("manufacturer", self.header.manufacturer_name()),
+            ("machine_id", self.header.id()),
+            ("version", self.header.version_text()),
+            ("date", utc_datetime(default_timestamp()?)?.format("%Y%m%d").to_string()),
+        ])
+    }
+
+    pub fn header_len(&self) -> usize {
+        UPDATE_HEADER_SIZE.div_ceil(RKAF_ALIGNMENT) * RKAF_ALIGNMENT
+    }
+}
+
+pub(crate) const RKAF_ALIGNMENT: usize = 2048;
+
+/// Writes `len` zero bytes at the current position.
+fn write_zeros(out: &mut impl Write, len: u64, cancel: &CancelToken) -> Result<()> {
+    let _phase = phase("io");
+    let zeros = [0u8; 64 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        cancel.check()?;
+        let chunk = std::cmp::min(remaining, zeros.len() as u64) as usize;
+        out.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    Ok(())
+}
+
+pub fn pack_rkaf_with(input_dir: impl AsRef<Path>, output_file: impl AsRef<Path>, model: &str, manufacturer: &str, options: &RkafOptions) -> Result<()> {
+    let (input_dir, output_file) = (input_dir.as_ref(), output_file.as_ref());
+    let layout = layout_rkaf(input_dir, model, manufacturer, options)?;
+    let output_file = &expand_output_name(output_file, &layout.name_fields()?)?;
+
+    // The previous output may be the file being replaced, so write next to it first
+    let temp_output = with_suffix(output_file, ".tmp");
+    let written = write_rkaf_data(&layout, &temp_output, options)
+        .and_then(|_| finish_rkaf(&temp_output, output_file, &options.cancel));
+    if let Err(e) = written {
+        // Whether cancelled or failed, don't leave a partial image behind
+        let _ = std::fs::remove_file(&temp_output);
+        return Err(e);
+    }
+
+    if options.incremental {
+        let mut new_cache = layout.cache;
+        let output_stamp = stamp(output_file)
+            .ok_or_else(|| AfptoolError::not_found(format!("Cannot stat {}", output_file.display())))?;
+        new_cache.output = Some((output_file.clone(), output_stamp));
+        new_cache.save(input_dir)?;
+    }
+
+    let num_parts = layout.header.num_parts;
+
+    log::info!("{}", tr!("pack-rkaf-success"));
+    log::info!("{}", tr!("pack-output", path = output_file.display()));
+    log::info!("{}", tr!("pack-model", model = layout.header.model_name()));
+    log::info!("{}", tr!("pack-manufacturer", manufacturer = layout.header.manufacturer_name()));
+    log::info!("{}", tr!("pack-parts", count = num_parts));
+    log::info!("{}", tr!("pack-total-size", size = layout.length));
+
+    Ok(())
+}
+
+/// [`pack_rkaf_with`] writing the image to `out`, front to back, instead of
+/// to a file, e.g. into a `Vec<u8>` or a socket. Returns the number of bytes
+/// written. `options.incremental` has no effect, since there is no earlier
+/// output to reuse.
+pub fn pack_rkaf_to_writer(input_dir: impl AsRef<Path>, out: &mut impl Write, model: &str, manufacturer: &str, options: &RkafOptions) -> Result<u64> {
+    let input_dir = input_dir.as_ref();
+    let options = RkafOptions { incremental: false, ..options.clone() };
+    let layout = layout_rkaf(input_dir, model, manufacturer, &options)?;
+    let cancel = &options.cancel;
+
+    let mut out = CrcWriter::new(out);
+    let header = layout.header.to_bytes();
+    out.write_all(&header)?;
+    write_zeros(&mut out, (layout.header_len() - header.len()) as u64, cancel)?;
+    for (source, file_size, padded_size) in &layout.data {
+        cancel.check()?;
+        match source {
+            DataSource::Input(file_path) => {
+                let input = open_file(file_path)?;
+                let _phase = phase("io");
+                if std::io::copy(&mut input.take(*file_size), &mut out)? != *file_size {
+                    return Err(AfptoolError::changed("Input changed size while packing"));
+                }
+            }
+            DataSource::Zero => write_zeros(&mut out, *file_size, cancel)?,
+            DataSource::Bytes(data) => out.write_all(data)?,
+            DataSource::Sparse(plan) => plan.write_to(&mut out, cancel)?,
+            DataSource::Compressed(file_path, compression) => {
+                if copy_decompressed(file_path, *compression, &mut out, cancel)? != *file_size {
+                    return Err(AfptoolError::changed("Input changed size while packing"));
+                }
+            }
+            DataSource::PreviousOutput(_) => return Err(AfptoolError::not_found("Previous output image is no longer available")),
+        }
+        write_zeros(&mut out, *padded_size - *file_size, cancel)?;
+    }
+    let crc = out.crc;
+    out.inner.write_all(&crc.to_le_bytes())?;
+    out.inner.flush()?;
+    Ok(layout.length + 4)
+}
+
+/// Writes the header and partition data of `layout` to `temp_output`, without the checksum.
+fn write_rkaf_data(layout: &RkafLayout, temp_output: &Path, options: &RkafOptions) -> Result<()> {
+    let cancel = &options.cancel;
+    let mut out_file = File::create(temp_output)?;
+
+    out_file.write_all(&layout.header.to_bytes())?;
+
+    // Padding and zero partitions are skipped over and filled in by the final set_len
+    out_file.seek(SeekFrom::Start(layout.header_len() as u64))?;
+
+    let previous = match &layout.previous_output {
+        Some(path) => Some(open_file(path)?),
+        None => None,
+    };
+
+    let header_size = UPDATE_HEADER_SIZE as u64;
+    if options.write_padding {
+        out_file.seek(SeekFrom::Start(header_size))?;
+        write_zeros(&mut out_file, layout.header_len() as u64 - header_size, cancel)?;
+    }
+
+    for ((source, file_size, padded_size), offset) in layout.data.iter().zip(layout.offsets()) {
+        out_file.seek(SeekFrom::Start(offset))?;
+        let copied = match source {
+            DataSource::PreviousOutput(previous_offset) => {
+                let previous = previous.as_ref()
+                    .ok_or_else(|| AfptoolError::not_found("Previous output image is no longer available"))?;
+                copy_range(previous, *previous_offset, *file_size, &mut out_file, cancel)?
+            }
+            DataSource::Input(file_path) => {
+                let input = open_file(file_path)?;
+                copy_range(&input, 0, *file_size, &mut out_file, cancel)?
+            }
+            DataSource::Zero if options.write_padding => {
+                write_zeros(&mut out_file, *file_size, cancel)?;
+                *file_size
+            }
+            DataSource::Zero => *file_size,
+            DataSource::Bytes(data) => {
+                out_file.write_all(data)?;
+                data.len() as u64
+            }
+            DataSource::Sparse(plan) => {
+                let mut out = BufWriter::new(&mut out_file);
+                plan.write_to(&mut out, cancel)?;
+                out.flush()?;
+                plan.size
+            }
+            DataSource::Compressed(file_path, compression) => {
+                let mut out = BufWriter::new(&mut out_file);
+                let copied = copy_decompressed(file_path, *compression, &mut out, cancel)?;
+                out.flush()?;
+                copied
+            }
+        };
+        if copied != *file_size {
+            return Err(AfptoolError::changed("Input changed size while packing"));
+        }
+        if options.write_padding {
+            out_file.seek(SeekFrom::Start(offset + *file_size))?;
+            write_zeros(&mut out_file, *padded_size - *file_size, cancel)?;
+        }
+    }
+    out_file.set_len(layout.length)?;
+    Ok(())
+}
+
+/// Appends the RKAF checksum to a fully written temporary image and moves it into place.
+pub(crate) fn finish_rkaf(temp_output: &Path, output_file: &Path, cancel: &CancelToken) -> Result<()> {
+    append_rkaf_crc(temp_output, cancel)?;
+    std::fs::rename(temp_output, output_file)?;
+    Ok(())
+}
+
+pub(crate) fn append_rkaf_crc(path: &Path, cancel: &CancelToken) -> Result<()> {
+    let len = std::fs::metadata(path)?.len();
+    let checksum = rkcrc32_file(path, len, cancel)?;
+    let _phase = phase("io");
+    std::fs::OpenOptions::new().append(true).open(path)?.write_all(&checksum.to_le_bytes())?;
+    Ok(())
+}
+
+/// Stores the model or manufacturer in its header field with the leading space
+/// Rockchip's tools write, truncated to leave a terminating NUL.
+pub(crate) fn set_header_text(field: &mut [u8], text: &str, gbk: bool) -> Result<()> {
+    let text = if text.starts_with(' ') { text.to_string() } else { format!(" {}", text) };
+    let bytes = encode_text(&text, field.len() - 1, gbk)?;
+    field[..bytes.len()].copy_from_slice(&bytes);
+    Ok(())
+}
+
+/// filling the rest of the flash (`-`) gets size [`FLASH_SIZE_GROW`].
+/// filling the rest of the flash (`-`) gets size 0.
+fn mtd_placement(mtd: &MtdPart) -> Result<(u32, u32)> {
+    let field = |value: u64| u32::try_from(value)
+        .map_err(|_| AfptoolError::Layout(format!("mtdparts entry {} does not fit the RKAF header", mtd)));
+    let size = match mtd.size {
+        Some(size) => field(size)?,
+        None => FLASH_SIZE_GROW,
+    };
+    Ok((field(mtd.offset)?, size))
+}
+
+/// parameter.txt wrapped back into the PARM blob unpack took it from, or
+/// `None` for a file that still carries the header and is packed as it is.
+fn wrap_parameter(file_path: &Path) -> Result<Option<Vec<u8>>> {
+    let data = std::fs::read(file_path).with_context(|| format!("Cannot read {}", file_path.display()))?;
+    if CrcWrapper::detect(&data) == Some(CrcWrapper::Parm) {
+        return Ok(None);
+    }
+    wrap_rkcrc(CrcWrapper::Parm, &data).map(Some)
+}
+
+/// One entry of package-file.
+struct PackageEntry {
+    name: String,
+    path: String,
+    /// Optional third and fourth columns, in sectors as stored in the header.
+    flash_offset: Option<u32>,
+    flash_size: Option<u32>,
+}
+
+impl PackageEntry {
+    /// `SELF` and `RESERVED` entries, which name no file.
+    fn is_marker(&self) -> bool {
+        self.path == SELF_MARKER || self.path == RESERVED_MARKER
+    }
+}
+
+fn parse_package_number(value: &str) -> Result<u32> {
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| AfptoolError::Parse(format!("Invalid address or size: {}", value)))
+}
+
+/// Parses a package-file line as the original afptool writes it: `NAME PATH`
+/// separated by any mix of tabs and spaces, optionally followed by a flash
+/// address and size. Blank lines and `#` comments, whole-line or trailing,
+/// give `None`.
+fn parse_package_line(line: &str, defines: &[(String, String)]) -> Result<Option<PackageEntry>> {
+    let line = clean_line(line);
+    let comment = line.match_indices('#').map(|(at, _)| at).find(|&at| at == 0 || line[..at].ends_with(char::is_whitespace));
+    let line = match comment {
+        Some(0) => return Ok(None),
+        Some(at) => &line[..at],
+        None => line,
+    };
+    let line = expand_vars(line, defines)?;
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields.len() {
+        0 | 1 => Ok(None),
+        2..=4 => Ok(Some(PackageEntry {
+            name: fields[0].to_string(),
+            path: normalize_path(fields[1]),
+            flash_offset: fields.get(2).map(|value| parse_package_number(value)).transpose()?,
+            flash_size: fields.get(3).map(|&value| match value {
+                GROW_MARKER => Ok(FLASH_SIZE_GROW),
+                value => parse_package_number(value),
+            }).transpose()?,
+        })),
+        _ => Err(AfptoolError::Parse(format!("Expected NAME PATH [ADDRESS [SIZE]], found {} fields", fields.len()))),
+    }
+}
+
+/// Reads package-file and the partition metadata and lays out the image.
+pub(crate) fn layout_rkaf(input_dir: &Path, model: &str, manufacturer: &str, options: &RkafOptions) -> Result<RkafLayout> {
+    let _phase = phase("layout");
+    let package_file_path = join_path(input_dir, "package-file");
     let package_file = File::open(&package_file_path)
-        .map_err(|_| anyhow!("Cannot find package-file in {}", input_dir))?;
+        .map_err(|_| AfptoolError::not_found(format!("Cannot find package-file in {}", input_dir.display())))?;
 
     let reader = BufReader::new(package_file);
     let mut file_list = Vec::new();
 
-    for line in reader.lines() {
+    for (number, line) in reader.lines().enumerate() {
         let line = line?;
-        let line = line.trim();
-
-        if line.is_empty() || line.starts_with('#') {
-            continue;
-        }
-
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() >= 2 {
-            file_list.push((parts[0].to_string(), parts[1].to_string()));
+        if let Some(entry) = parse_package_line(&line, &options.defines)
+            .map_err(|e| AfptoolError::Layout(format!("package-file line {}: {}", number + 1, e)))? {
+            file_list.push(entry);
         }
     }
 
     if file_list.is_empty() {
-        return Err(anyhow!("No files found in package-file"));
+        return Err(AfptoolError::Layout("No files found in package-file".to_string()));
     }
-
-    let mut machine_id = String::new();
-    if let Ok(param_file) = File::open(format!("{}/parameter.txt", input_dir)) {
-        let reader = BufReader::new(param_file);
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                if line.starts_with("MACHINE_ID:") {
-                    machine_id = line.split(':').nth(1).unwrap_or("").trim().to_string();
-                    break;
-                }
+    if options.self_entry {
+        for (name, marker) in [("update", SELF_MARKER), ("backup", RESERVED_MARKER)] {
+            if !file_list.iter().any(|entry| entry.path == marker) {
+                file_list.push(PackageEntry { name: name.to_string(), path: marker.to_string(), flash_offset: None, flash_size: None });
             }
         }
     }
+    if file_list.len() > MAX_PARTS {
+        return Err(AfptoolError::Layout(format!(
+            "package-file lists {} entries, but an RKAF image holds at most {}", file_list.len(), MAX_PARTS
+        )));
+    }
 
-    let mut header = UpdateHeader::default();
-    header.magic.copy_from_slice(RKAF_SIGNATURE);
+    let override_for = |name: &str| {
+        options.overrides.iter().rev().find(|(key, _)| key == name).map(|(_, path)| path.clone())
+    };
+    for (name, _) in &options.overrides {
+        if !file_list.iter().any(|entry| &entry.name == name) {
+            return Err(AfptoolError::Layout(format!("Partition {} is not listed in package-file", name)));
+        }
+    }
 
-    let model_str = if model.starts_with(' ') {
-        model.to_string()
-    } else {
-        format!(" {}", model)
+    let parameter_path = input_file(override_for("parameter")
+        .unwrap_or_else(|| join_path(input_dir, "parameter.txt")));
+    let parameter = match compression_of(&parameter_path) {
+        Some(compression) => {
+            let mut data = Vec::new();
+            copy_decompressed(&parameter_path, compression, &mut data, &options.cancel)
+                .ok()
+                .map(|_| ParameterFile::from_bytes(&data))
+        }
+        None => ParameterFile::load(&parameter_path).ok(),
     };
-    let model_bytes = model_str.as_bytes();
-    let len = model_bytes.len().min(header.model.len() - 1);
-    header.model[..len].copy_from_slice(&model_bytes[..len]);
-
-    let manufacturer_str = if manufacturer.starts_with(' ') {
-        manufacturer.to_string()
-    } else {
-        format!(" {}", manufacturer)
+    let recorded = UpdateManifest::load(input_dir)?;
+    let machine_id = match &options.machine_id {
+        Some(machine_id) => machine_id.clone(),
+        None => parameter.as_ref().and_then(|parameter| parameter.machine_id()).map(str::to_string)
+            .or_else(|| recorded.as_ref().and_then(|recorded| recorded.machine_id.clone()))
+            .unwrap_or_default(),
     };
-    let manufacturer_bytes = manufacturer_str.as_bytes();
-    let len = manufacturer_bytes.len().min(header.manufacturer.len() - 1);
-    header.manufacturer[..len].copy_from_slice(&manufacturer_bytes[..len]);
-
-    if !machine_id.is_empty() {
-        let id_bytes = format!(" {}", machine_id);
-        let id_bytes = id_bytes.as_bytes();
-        let len = id_bytes.len().min(30 - 1); // MAX_ID_LEN is 30
-        unsafe {
-            let header_ptr = &header as *const UpdateHeader as *const u8;
-            let id_offset = 42; // id field offset in UpdateHeader
-            let id_ptr = header_ptr.add(id_offset) as *mut u8;
-            std::ptr::copy_nonoverlapping(id_bytes.as_ptr(), id_ptr, len);
+    // An empty model or manufacturer is taken from parameter.txt, as vendor builds expect
+    let from_parameter = |given: &str, key: &str, value: Option<&str>| -> Result<String> {
+        match (given, value) {
+            ("", Some(value)) => Ok(value.to_string()),
+            ("", None) => Err(AfptoolError::Layout(format!("No {} given and parameter.txt has no {}", key.to_lowercase().replace('_', " "), key))),
+            (given, _) => Ok(given.to_string()),
         }
-    }
+    };
+    let model = from_parameter(model, "MACHINE_MODEL", parameter.as_ref().and_then(|parameter| parameter.machine_model()))?;
+    let manufacturer = from_parameter(manufacturer, "MANUFACTURER", parameter.as_ref().and_then(|parameter| parameter.manufacturer()))?;
+
+    let mut header = UpdateHeader::default();
+    header.magic.copy_from_slice(RKAF_SIGNATURE);
+
+    set_header_text(&mut header.model, &model, options.gbk)?;
+    set_header_text(&mut header.manufacturer, &manufacturer, options.gbk)?;
+    header.set_id(&machine_id);
 
     header.num_parts = file_list.len() as u32;
     header.version = 0x01000000; // Version
+    if let Some(recorded) = &recorded {
+        recorded.apply(&mut header)?;
+    }
+    if let Some(version) = &options.version {
+        header.set_version(version)?;
+    }
 
     let partition_metadata = parse_partition_metadata(input_dir)?;
-    if partition_metadata.is_empty() {
-        return Err(anyhow!("Missing partition metadata"));
+    let mtdparts = parameter.as_ref().and_then(|parameter| parameter.mtdparts().ok());
+    if partition_metadata.is_empty() && mtdparts.is_none() && file_list.iter().any(|entry| !entry.is_marker() && entry.flash_offset.is_none()) {
+        return Err(AfptoolError::Layout("Missing partition metadata".to_string()));
     }
 
-    let header_size = std::mem::size_of::<UpdateHeader>();
-    let sector_size = 2048;
-    let mut current_offset = ((header_size + sector_size - 1) / sector_size) * sector_size;
+    let header_size = UPDATE_HEADER_SIZE;
+    let sector_size = RKAF_ALIGNMENT;
+    let mut current_offset = (header_size.div_ceil(sector_size) * sector_size) as u64;
 
-    let mut file_data_map: HashMap<String, (Vec<u8>, u32, u32)> = HashMap::new();
+    let cache = if options.incremental { PackCache::load(input_dir) } else { PackCache::default() };
+    let previous_output = cache.previous_output();
+    let mut new_cache = PackCache::default();
+
+    let mut file_layout: HashMap<PathBuf, (u64, u64)> = HashMap::new();
+    let mut contents = ContentIndex::default();
+    // Wrapped and sparse partitions are stored differently from their files
+    let shareable = |name: &String, compression: Option<Compression>| {
+        options.dedupe && !options.sparse.contains(name) && !partition_metadata.get(name).is_some_and(|meta| meta.parm)
+            && compression.is_none()
+    };
     let mut file_data_list = Vec::new();
 
-    for (i, (name, path)) in file_list.iter().enumerate() {
-        let (file_offset, file_size, _padded_size) = if let Some((data, offset, padded)) = file_data_map.get(path) {
-            // File already loaded, reuse offset
-            (*offset, data.len() as u32, *padded)
+    let mut self_entries = Vec::new();
+    for (i, entry) in file_list.iter().enumerate() {
+        let (name, path) = (&entry.name, &entry.path);
+        let listed_path = override_for(name).unwrap_or_else(|| join_path(input_dir, path));
+        let file_path = input_file(listed_path.clone());
+        // A listed .gz is only inflated when asked; the fallback always is
+        let compression = compression_of(&file_path).filter(|_| options.decompress || file_path != listed_path);
+        let placeholder = partition_metadata.get(name).is_some_and(|meta| meta.placeholder) && stamp(&file_path).is_none();
+        let (file_offset, file_size) = if entry.is_marker() && override_for(name).is_none() {
+            // SELF is filled in with the image length once it is known
+            if path == SELF_MARKER {
+                self_entries.push(i);
+            }
+            (0, 0)
+        } else if placeholder {
+            (0, 0)
+        } else if let Some((offset, size)) = file_layout.get(&file_path) {
+            // File already laid out, reuse offset
+            (*offset, *size)
+        } else if let Some((other, region)) = match shareable(name, compression) {
+            true => contents.find(&file_path)?,
+            false => None,
+        } {
+            log::info!("{}", tr!("pack-dedup", path = path, other = other.display()));
+            file_layout.insert(file_path.clone(), region);
+            region
         } else {
-            // Load file for first time
-            let file_path = format!("{}/{}", input_dir, path);
-            let mut file_data = Vec::new();
-
-            File::open(&file_path)
-                .map_err(|e| anyhow!("Cannot open {}: {}", file_path, e))?
-                .read_to_end(&mut file_data)?;
-
-            let file_size = file_data.len() as u32;
-            let padded_size = ((file_size + sector_size as u32 - 1) / sector_size as u32) * sector_size as u32;
-            let file_offset = current_offset as u32;
+            let file_stamp = stamp(&file_path);
+            let zero_size = partition_metadata
+                .get(name)
+                .filter(|meta| meta.zero && file_stamp.is_none())
+                .map(|meta| meta.byte_count);
+
+            let compression = file_stamp.and(compression);
+            if compression.is_some() && (options.sparse.contains(name) || partition_metadata.get(name).is_some_and(|meta| meta.parm)) {
+                return Err(AfptoolError::Layout(format!("Partition {} cannot be packed from the compressed file {}", name, file_path.display())));
+            }
+            let file_size = match (file_stamp, zero_size, compression) {
+                (Some(_), _, Some(compression)) => decompressed_size(&file_path, compression, &options.cancel)?,
+                (Some(file_stamp), _, None) => file_stamp.size,
+                // Left out at unpack because it was all zero
+                (None, Some(size), _) => size,
+                (None, None, _) => return Err(AfptoolError::not_found(format!("Cannot open {}", file_path.display()))),
+            };
+            let wrapped = match (file_stamp, partition_metadata.get(name)) {
+                (Some(_), Some(meta)) if meta.parm => wrap_parameter(&file_path)?,
+                _ => None,
+            };
+            let sparse = match file_stamp {
+                Some(_) if wrapped.is_none() && options.sparse.contains(name) && !is_sparse_file(&file_path)? => {
+                    Some(SparsePlan::new(&file_path, &options.cancel)?)
+                }
+                _ => None,
+            };
+            let file_size = wrapped.as_ref().map_or(file_size, |data| data.len() as u64);
+            let file_size = sparse.as_ref().map_or(file_size, |plan| plan.size);
+            let padded_size = file_size.div_ceil(sector_size as u64) * sector_size as u64;
+            let file_offset = current_offset;
+
+            let incremental = options.incremental && override_for(name).is_none() && wrapped.is_none() && sparse.is_none();
+            let mut source = match (zero_size, wrapped, sparse, compression) {
+                (Some(_), _, _, _) => DataSource::Zero,
+                (None, Some(data), _, _) => DataSource::Bytes(data),
+                (None, None, Some(plan), _) => DataSource::Sparse(plan),
+                (None, None, None, Some(compression)) => DataSource::Compressed(file_path.clone(), compression),
+                (None, None, None, None) => DataSource::Input(file_path.clone()),
+            };
+            if matches!(source, DataSource::Input(_)) && shareable(name, compression) {
+                contents.add(&file_path, (file_offset, file_size));
+            }
+            match file_stamp {
+                Some(file_stamp) if incremental => {
+                    let transform = compression.map_or("raw", |compression| compression.extension().trim_start_matches('.'));
+                    let (md5, unchanged) = cache.check(path, &file_path, file_stamp, transform)?;
+                    if let (true, Some(_)) = (unchanged, previous_output) {
+                        source = DataSource::PreviousOutput(cache.files[path].offset);
+                    }
+                    new_cache.files.insert(path.clone(), CachedFile {
+                        stamp: file_stamp,
+                        md5,
+                        offset: file_offset,
+                        transform: transform.to_string(),
+                    });
+                }
+                _ => {}
+            }
 
-            file_data_map.insert(path.clone(), (file_data.clone(), file_offset, padded_size));
-            file_data_list.push((path.clone(), file_data));
+            file_layout.insert(file_path.clone(), (file_offset, file_size));
+            file_data_list.push((source, file_size, padded_size));
 
-            current_offset += padded_size as usize;
+            current_offset += padded_size;
 
-            (file_offset, file_size, padded_size)
+            (file_offset, file_size)
         };
 
+        log::debug!("{:08x}-{:08x} {} from {}", file_offset, file_size, name, file_path.display());
         let mut part = UpdatePart::default();
 
         let name_bytes = name.as_bytes();
         let len = name_bytes.len().min(MAX_NAME_LEN - 1);
         part.name[..len].copy_from_slice(&name_bytes[..len]);
 
-        let path_bytes = path.as_bytes();
+        // A compressed input is stored under the name of the data it holds
+        let path_bytes = match options.decompress {
+            true => split_compressed(path).0.as_bytes(),
+            false => path.as_bytes(),
+        };
         let len = path_bytes.len().min(MAX_FULL_PATH_LEN - 1);
         part.full_path[..len].copy_from_slice(&path_bytes[..len]);
 
-        if let Some(meta) = partition_metadata.get(name) {
-            part.flash_size = meta.flash_size;
-            part.flash_offset = meta.flash_offset;
-            part.padded_size = meta.padded_size;
-        } else {
-            return Err(anyhow!("Missing partition metadata for {:}", name));
+        // Flash placement comes from the package-file columns, then
+        // partition-metadata.txt, then mtdparts (ahead of the metadata with
+        // `mtdparts_primary`). Entries mtdparts doesn't name are not on flash.
+        let meta = partition_metadata.get(name);
+        let mtd = mtdparts.as_ref().map(|parts| parts.iter().find(|part| &part.name == name));
+        // The recorded padded size is kept unless the file outgrew it, as an
+        // expanded sparse image does. Past 4 GiB only the low half is kept
+        let padded_size = file_size.div_ceil(sector_size as u64) * sector_size as u64;
+        part.padded_size = match meta {
+            Some(meta) if !meta.desparsed && meta.padded_size as u64 >= padded_size => meta.padded_size,
+            _ => padded_size as u32,
+        };
+        match (meta, mtd) {
+            (Some(meta), Some(Some(_))) if !options.mtdparts_primary => (part.flash_offset, part.flash_size) = (meta.flash_offset, meta.flash_size),
+            (_, Some(Some(mtd))) => (part.flash_offset, part.flash_size) = mtd_placement(mtd)?,
+            (Some(meta), _) => (part.flash_offset, part.flash_size) = (meta.flash_offset, meta.flash_size),
+            (None, Some(None)) => {}
+            (None, None) if entry.is_marker() || entry.flash_offset.is_some() => {}
+            (None, None) => return Err(AfptoolError::Layout(format!("Missing partition metadata for {}", name))),
+        }
+        if let Some(flash_offset) = entry.flash_offset {
+            part.flash_offset = flash_offset;
+        }
+        if let Some(flash_size) = entry.flash_size {
+            part.flash_size = flash_size;
         }
 
-        part.part_offset = file_offset;
-        part.part_byte_count = file_size;
+        part.set_offset(file_offset)?;
+        part.set_byte_count(file_size)?;
 
         header.parts[i] = part;
     }
 
-    header.length = current_offset as u32;
-
-    let mut out_file = File::create(output_file)?;
-
-    out_file.write_all(header.to_bytes())?;
-
-    let header_padding = sector_size - header_size;
-    out_file.write_all(&vec![0u8; header_padding])?;
-
-    for (path, file_data) in file_data_list.iter() {
-        out_file.write_all(file_data)?;
-
-        // Pad file
-        let (_data, _offset, padded_size) = file_data_map.get(path).unwrap();
-        let padding_size = *padded_size as usize - file_data.len();
-        if padding_size > 0 {
-            out_file.write_all(&vec![0u8; padding_size])?;
-        }
+    header.set_image_length(current_offset);
+    // The image as a whole, trailing CRC included
+    for i in self_entries {
+        header.parts[i].set_byte_count(current_offset + 4)?;
     }
-
-    let file_content = std::fs::read(output_file)?;
-    let checksum = rkcrc32(0, &file_content);
-
-    let mut out_file = std::fs::OpenOptions::new()
-        .append(true)
-        .open(output_file)?;
-    out_file.write_all(&checksum.to_le_bytes())?;
-
-    let num_parts = header.num_parts;
-
-    println!("Successfully packed RKAF image:");
-    println!("  Output: {}", output_file);
-    println!("  Model: {}", model);
-    println!("  Manufacturer: {}", manufacturer);
-    println!("  Parts: {}", num_parts);
-    println!("  Total size: {} bytes", current_offset);
-
-    Ok(())
+    check_flash_layout(&header, sector_size as u32)?;
+
+    Ok(RkafLayout {
+        header,
+        data: file_data_list,
+        length: current_offset,
+        previous_output: previous_output.map(Path::to_path_buf),
+        cache: new_cache,
+    })
 }
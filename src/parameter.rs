@@ -0,0 +1,213 @@
+use std::fmt;
+use std::path::Path;
+use crate::error::{AfptoolError, IoContext, Result};
+use crate::PARM_MAGIC;
+
+/// A partition from the `mtdparts=` list in the CMDLINE, in 512-byte sectors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MtdPart {
+    pub name: String,
+    pub offset: u64,
+    /// `None` for a partition declared as `-`, which takes the rest of the flash.
+    pub size: Option<u64>,
+    /// Anything after the name inside the parentheses, such as `grow` or `bootable`.
+    pub flags: Option<String>,
+}
+
+impl fmt::Display for MtdPart {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.size {
+            Some(size) => write!(f, "{:#010x}", size)?,
+            None => write!(f, "-")?,
+        }
+        write!(f, "@{:#010x}({}", self.offset, self.name)?;
+        if let Some(flags) = &self.flags {
+            write!(f, ":{}", flags)?;
+        }
+        write!(f, ")")
+    }
+}
+
+fn parse_hex(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+    u64::from_str_radix(digits, 16).map_err(|_| AfptoolError::Parse(format!("Invalid number in mtdparts: {}", value)))
+}
+
+fn parse_mtd_entry(entry: &str) -> Result<MtdPart> {
+    let invalid = || AfptoolError::Layout(format!("Invalid mtdparts entry: {}", entry));
+    let (size, rest) = entry.split_once('@').ok_or_else(invalid)?;
+    let (offset, name) = rest.split_once('(').ok_or_else(invalid)?;
+    let name = name.strip_suffix(')').ok_or_else(invalid)?;
+    let (name, flags) = match name.split_once(':') {
+        Some((name, flags)) => (name, Some(flags.to_string())),
+        None => (name, None),
+    };
+    Ok(MtdPart {
+        name: name.to_string(),
+        offset: parse_hex(offset)?,
+        size: if size.trim() == "-" { None } else { Some(parse_hex(size)?) },
+        flags,
+    })
+}
+
+/// Splits a line into key and value if it looks like `KEY: value`.
+fn split_entry(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.split_once(':')?;
+    let key = key.trim();
+    let is_key = !key.is_empty() && key.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+    is_key.then_some((key, value))
+}
+
+/// A parsed parameter.txt. Lines are kept as written, so comments, unknown
+/// keys, key order and line endings all survive a load/edit/save round trip;
+/// only lines changed through [`ParameterFile::set`] are rewritten.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParameterFile {
+    lines: Vec<String>,
+    bom: bool,
+    crlf: bool,
+    trailing_newline: bool,
+}
+
+impl ParameterFile {
+    pub fn parse(text: &str) -> ParameterFile {
+        let bom = text.starts_with('\u{feff}');
+        let text = text.trim_start_matches('\u{feff}');
+        let trailing_newline = text.ends_with('\n');
+        let body = text.strip_suffix('\n').unwrap_or(text);
+        let crlf = text.contains("\r\n");
+        let lines = if text.is_empty() {
+            Vec::new()
+        } else {
+            body.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line).to_string()).collect()
+        };
+        ParameterFile { lines, bom, crlf, trailing_newline }
+    }
+
+    /// Parses the contents of a parameter partition, dropping the `PARM`
+    /// header and trailing CRC if it carries them.
+    pub fn from_bytes(data: &[u8]) -> ParameterFile {
+        if data.len() >= 8 && &data[..4] == PARM_MAGIC.as_bytes() {
+            let len = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+            let end = (8 + len).min(data.len());
+            return ParameterFile::parse(&String::from_utf8_lossy(&data[8..end]));
+        }
+        ParameterFile::parse(&String::from_utf8_lossy(data))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<ParameterFile> {
+        let path = path.as_ref();
+        let data = std::fs::read(path).with_context(|| format!("Cannot read parameter file {}", path.display()))?;
+        Ok(ParameterFile::from_bytes(&data))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    /// Keys in the order they appear.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().filter_map(|line| split_entry(line)).map(|(key, _)| key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| split_entry(line))
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value.trim())
+    }
+
+    /// Sets `key` in place, keeping the spacing after the colon, or appends it
+    /// if it is not present yet.
+    pub fn set(&mut self, key: &str, value: &str) {
+        for line in &mut self.lines {
+            if let Some((name, old)) = split_entry(line) {
+                if name == key {
+                    let spacing = &old[..old.len() - old.trim_start().len()];
+                    *line = format!("{}:{}{}", key, spacing, value);
+                    return;
+                }
+            }
+        }
+        self.lines.push(format!("{}:{}", key, value));
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let index = self.lines.iter().position(|line| split_entry(line).is_some_and(|(name, _)| name == key))?;
+        let line = self.lines.remove(index);
+        split_entry(&line).map(|(_, value)| value.trim().to_string())
+    }
+
+    pub fn firmware_version(&self) -> Option<&str> {
+        self.get("FIRMWARE_VER")
+    }
+
+    pub fn machine_model(&self) -> Option<&str> {
+        self.get("MACHINE_MODEL")
+    }
+
+    pub fn machine_id(&self) -> Option<&str> {
+        self.get("MACHINE_ID")
+    }
+
+    pub fn manufacturer(&self) -> Option<&str> {
+        self.get("MANUFACTURER")
+    }
+
+    pub fn cmdline(&self) -> Option<&str> {
+        self.get("CMDLINE")
+    }
+
+    /// The `mtdparts=` list from the CMDLINE.
+    pub fn mtdparts(&self) -> Result<Vec<MtdPart>> {
+        let list = self
+            .cmdline()
+            .and_then(|cmdline| cmdline.split_whitespace().find_map(|word| word.strip_prefix("mtdparts=")))
+            .ok_or_else(|| AfptoolError::Parse("No mtdparts= found in parameter file".to_string()))?;
+        let list = list.split_once(':').map_or(list, |(_, parts)| parts);
+        list.split(',').map(parse_mtd_entry).collect()
+    }
+
+    /// Replaces the `mtdparts=` list in the CMDLINE, keeping the flash device
+    /// name in front of it and the rest of the command line.
+    pub fn set_mtdparts(&mut self, parts: &[MtdPart]) -> Result<()> {
+        let cmdline = self.cmdline().ok_or_else(|| AfptoolError::Parse("No CMDLINE in parameter file".to_string()))?;
+        let list = parts.iter().map(MtdPart::to_string).collect::<Vec<_>>().join(",");
+        let mut found = false;
+        let words: Vec<String> = cmdline
+            .split(' ')
+            .map(|word| match word.strip_prefix("mtdparts=") {
+                Some(old) => {
+                    found = true;
+                    match old.split_once(':') {
+                        Some((device, _)) => format!("mtdparts={}:{}", device, list),
+                        None => format!("mtdparts={}", list),
+                    }
+                }
+                None => word.to_string(),
+            })
+            .collect();
+        if !found {
+            return Err(AfptoolError::Parse("No mtdparts= found in parameter file".to_string()));
+        }
+        self.set("CMDLINE", &words.join(" "));
+        Ok(())
+    }
+}
+
+impl fmt::Display for ParameterFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.bom {
+            write!(f, "\u{feff}")?;
+        }
+        let line_ending = if self.crlf { "\r\n" } else { "\n" };
+        write!(f, "{}", self.lines.join(line_ending))?;
+        if self.trailing_newline {
+            write!(f, "{}", line_ending)?;
+        }
+        Ok(())
+    }
+}
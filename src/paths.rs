@@ -0,0 +1,90 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use crate::error::{IoContext, Result};
+
+/// Joins a path as stored in an image, package-file or metadata file (with
+/// `/` or `\` separators) onto a directory from the command line, using the
+/// platform's own separator. On Windows, paths beyond the 260-character limit
+/// get the `\\?\` prefix so deep trees and long partition names still work.
+pub(crate) fn join_path(dir: impl AsRef<Path>, relative: &str) -> PathBuf {
+    let mut path = dir.as_ref().to_path_buf();
+    path.extend(relative.split(['/', '\\']).filter(|component| !component.is_empty() && *component != "."));
+    long_path(path)
+}
+
+/// Whether a path read from an image stays inside the directory it is joined
+/// onto. Absolute paths, drive letters (any `:`, which Windows also reads as a
+/// stream name) and `..` components are refused, as is a path naming no file.
+pub(crate) fn is_contained(relative: &str) -> bool {
+    let mut components = relative.split(['/', '\\']).filter(|component| !component.is_empty() && *component != ".").peekable();
+    !relative.starts_with(['/', '\\'])
+        && components.peek().is_some()
+        && !components.any(|component| component == ".." || component.contains(':'))
+}
+
+/// `path` with `suffix` appended to its file name, such as the `.tmp` file
+/// an output is written to first.
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// The bytes of `path`, for records such as the pack cache that must give
+/// back the same path. On Unix any path round-trips; elsewhere a path that is
+/// not valid Unicode comes back changed and so never matches.
+#[cfg(unix)]
+pub(crate) fn path_to_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn path_to_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(text) => Cow::Borrowed(text.as_bytes()),
+        Cow::Owned(text) => Cow::Owned(text.into_bytes()),
+    }
+}
+
+/// Reverses [`path_to_bytes`].
+#[cfg(unix)]
+pub(crate) fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Opens an input file, naming it in the error if that fails.
+pub(crate) fn open_file(path: impl AsRef<Path>) -> Result<File> {
+    let path = path.as_ref();
+    File::open(path).with_context(|| format!("Cannot open {}", path.display()))
+}
+
+#[cfg(windows)]
+fn long_path(path: PathBuf) -> PathBuf {
+    const MAX_PATH: usize = 260;
+    let text = path.as_os_str().to_string_lossy();
+    if text.len() < MAX_PATH || text.starts_with(r"\\?\") {
+        return path;
+    }
+    // The prefix turns off all normalization, so the path must be absolute first
+    let Ok(absolute) = std::path::absolute(&path) else {
+        return path;
+    };
+    let absolute = absolute.to_string_lossy();
+    match absolute.strip_prefix(r"\\") {
+        Some(unc) => PathBuf::from(format!(r"\\?\UNC\{}", unc)),
+        None => PathBuf::from(format!(r"\\?\{}", absolute)),
+    }
+}
+
+#[cfg(not(windows))]
+fn long_path(path: PathBuf) -> PathBuf {
+    path
+}
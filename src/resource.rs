@@ -0,0 +1,198 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::error::{AfptoolError, IoContext, Result};
+use crate::image::list_parts;
+use crate::paths::{is_contained, join_path, open_file};
+use crate::text::clean_line;
+use crate::{RKAF_SIGNATURE, RKFW_SIGNATURE};
+
+const RSCE_MAGIC: &[u8; 4] = b"RSCE";
+const ENTRY_TAG: &[u8; 4] = b"ENTR";
+/// Header, table entries and file data are all laid out in 512-byte blocks.
+const BLOCK_SIZE: usize = 512;
+const MAX_PATH_LEN: usize = 256;
+
+/// File describing an unpacked resource image, written next to its files.
+pub(crate) const RESOURCE_METADATA_FILE: &str = "resource-metadata.txt";
+
+/// One file stored in a resource image, such as `rk-kernel.dtb` or `logo.bmp`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceFile {
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+/// A Rockchip resource image (`RSCE`), which carries the kernel device tree
+/// and boot logos: a one-block header, one block per file in the index
+/// table, then each file's data starting on a block boundary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceImage {
+    pub version: u16,
+    pub table_version: u16,
+    pub files: Vec<ResourceFile>,
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+impl ResourceImage {
+    pub fn parse(data: &[u8]) -> Result<ResourceImage> {
+        if data.len() < 15 || &data[..4] != RSCE_MAGIC {
+            return Err(AfptoolError::Parse("Not a resource image (no RSCE header)".to_string()));
+        }
+        let (table_offset, entry_size) = (data[9] as usize * BLOCK_SIZE, data[10] as usize * BLOCK_SIZE);
+        let count = u32_at(data, 11) as usize;
+        if entry_size < MAX_PATH_LEN + 12 {
+            return Err(AfptoolError::Parse(format!("Resource table entries of {} bytes are too small", entry_size)));
+        }
+
+        let mut files = Vec::with_capacity(count.min(1024));
+        for index in 0..count {
+            let at = table_offset + index * entry_size;
+            let entry = data.get(at..at + entry_size)
+                .ok_or_else(|| AfptoolError::Parse(format!("Resource table entry {} is past the end of the image", index)))?;
+            if &entry[..4] != ENTRY_TAG {
+                return Err(AfptoolError::Parse(format!("Resource table entry {} has no ENTR tag", index)));
+            }
+            let path_field = &entry[4..4 + MAX_PATH_LEN];
+            let path = String::from_utf8_lossy(&path_field[..path_field.iter().position(|&b| b == 0).unwrap_or(MAX_PATH_LEN)]).into_owned();
+            let offset = u32_at(entry, 4 + MAX_PATH_LEN) as usize * BLOCK_SIZE;
+            let size = u32_at(entry, 8 + MAX_PATH_LEN) as usize;
+            let content = data.get(offset..offset + size).ok_or_else(|| AfptoolError::Parse(format!(
+                "Resource {} at {:#x}+{:#x} runs past the end of the {:#x}-byte image", path, offset, size, data.len()
+            )))?;
+            files.push(ResourceFile { path, data: content.to_vec() });
+        }
+        Ok(ResourceImage { version: u16::from_le_bytes([data[4], data[5]]), table_version: u16::from_le_bytes([data[6], data[7]]), files })
+    }
+
+    /// The image as `resource_tool` lays it out: header, table, then the files in order.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let padded = |len: usize| len.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        let mut out = vec![0u8; BLOCK_SIZE * (1 + self.files.len())];
+        out[..4].copy_from_slice(RSCE_MAGIC);
+        out[4..6].copy_from_slice(&self.version.to_le_bytes());
+        out[6..8].copy_from_slice(&self.table_version.to_le_bytes());
+        out[8..11].copy_from_slice(&[1, 1, 1]);
+        out[11..15].copy_from_slice(&(self.files.len() as u32).to_le_bytes());
+
+        for (index, file) in self.files.iter().enumerate() {
+            if file.path.len() >= MAX_PATH_LEN {
+                return Err(AfptoolError::Layout(format!("Resource path {} is longer than {} bytes", file.path, MAX_PATH_LEN - 1)));
+            }
+            let block = u32::try_from(out.len() / BLOCK_SIZE).ok();
+            let size = u32::try_from(file.data.len()).ok();
+            let (Some(block), Some(size)) = (block, size) else {
+                return Err(AfptoolError::Layout("Resource image is too large".to_string()));
+            };
+            let entry = &mut out[BLOCK_SIZE * (1 + index)..BLOCK_SIZE * (2 + index)];
+            entry[..4].copy_from_slice(ENTRY_TAG);
+            entry[4..4 + file.path.len()].copy_from_slice(file.path.as_bytes());
+            entry[4 + MAX_PATH_LEN..8 + MAX_PATH_LEN].copy_from_slice(&block.to_le_bytes());
+            entry[8 + MAX_PATH_LEN..12 + MAX_PATH_LEN].copy_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&file.data);
+            out.resize(padded(out.len()), 0);
+        }
+        Ok(out)
+    }
+
+    /// Writes every file under `dst_path` with a resource-metadata.txt
+    /// recording the header versions and the file order:
+    ///
+    /// ```text
+    /// VERSION: 0
+    /// TABLE_VERSION: 0
+    /// FILE: rk-kernel.dtb
+    /// FILE: logo.bmp
+    /// ```
+    pub fn save_dir(&self, dst_path: impl AsRef<Path>) -> Result<()> {
+        let dst_path = dst_path.as_ref();
+        std::fs::create_dir_all(dst_path)?;
+        let mut metadata = File::create(join_path(dst_path, RESOURCE_METADATA_FILE))?;
+        writeln!(metadata, "VERSION: {}", self.version)?;
+        writeln!(metadata, "TABLE_VERSION: {}", self.table_version)?;
+        for file in &self.files {
+            if !is_contained(&file.path) || file.path == RESOURCE_METADATA_FILE {
+                return Err(AfptoolError::Parse(format!("Resource has unsafe path {:?}", file.path)));
+            }
+            let path = join_path(dst_path, &file.path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, &file.data)?;
+            writeln!(metadata, "FILE: {}", file.path)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a resource image unpacked by [`ResourceImage::save_dir`],
+    /// picking up any files that were replaced since.
+    pub fn load_dir(input_dir: impl AsRef<Path>) -> Result<ResourceImage> {
+        let input_dir = input_dir.as_ref();
+        let metadata_path = join_path(input_dir, RESOURCE_METADATA_FILE);
+        let metadata = std::fs::read_to_string(&metadata_path)
+            .with_context(|| format!("Cannot read {}", metadata_path.display()))?;
+        let mut image = ResourceImage { version: 0, table_version: 0, files: Vec::new() };
+        for line in metadata.lines().map(clean_line) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once(':')
+                .ok_or_else(|| AfptoolError::Parse(format!("Invalid line in {}: {}", RESOURCE_METADATA_FILE, line)))?;
+            let value = value.trim();
+            let number = || value.parse::<u16>().map_err(|_| AfptoolError::Parse(format!("Invalid {} in {}: {}", key.trim(), RESOURCE_METADATA_FILE, value)));
+            match key.trim() {
+                "VERSION" => image.version = number()?,
+                "TABLE_VERSION" => image.table_version = number()?,
+                "FILE" => {
+                    let path = join_path(input_dir, value);
+                    let data = std::fs::read(&path).with_context(|| format!("Cannot read {}", path.display()))?;
+                    image.files.push(ResourceFile { path: value.to_string(), data });
+                }
+                other => return Err(AfptoolError::Parse(format!("Unknown key {} in {}", other, RESOURCE_METADATA_FILE))),
+            }
+        }
+        Ok(image)
+    }
+}
+
+/// Reads the resource image out of a resource.img file or the resource
+/// partition of an RKFW or RKAF image.
+fn read_resource_blob(input_path: &Path) -> Result<Vec<u8>> {
+    let mut fp = open_file(input_path)?;
+    let mut magic = [0u8; 4];
+    fp.read_exact(&mut magic)?;
+    let (offset, len) = match &magic[..] {
+        RKFW_SIGNATURE | RKAF_SIGNATURE => {
+            let part = list_parts(&mut fp)?
+                .into_iter()
+                .find(|part| part.name == "resource")
+                .ok_or_else(|| AfptoolError::Parse(format!("{} has no resource partition", input_path.display())))?;
+            (part.file_offset, part.byte_count)
+        }
+        _ => (0, fp.metadata()?.len()),
+    };
+    let mut data = vec![0u8; len as usize];
+    fp.seek(SeekFrom::Start(offset))?;
+    fp.read_exact(&mut data)
+        .map_err(|_| AfptoolError::Parse(format!("Resource image in {} is truncated", input_path.display())))?;
+    Ok(data)
+}
+
+/// Extracts the files of a resource image into `dst_path`. `input_path` may
+/// be a resource.img or an RKFW or RKAF image with a resource partition.
+pub fn unpack_resource(input_path: impl AsRef<Path>, dst_path: impl AsRef<Path>) -> Result<ResourceImage> {
+    let input_path = input_path.as_ref();
+    let image = ResourceImage::parse(&read_resource_blob(input_path)?)?;
+    image.save_dir(dst_path)?;
+    Ok(image)
+}
+
+/// Rebuilds a resource image from a directory written by [`unpack_resource`].
+pub fn pack_resource(input_dir: impl AsRef<Path>, output_path: impl AsRef<Path>) -> Result<ResourceImage> {
+    let image = ResourceImage::load_dir(input_dir)?;
+    std::fs::write(output_path.as_ref(), image.to_bytes()?)?;
+    Ok(image)
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+use std::path::Path;
+use crate::error::{AfptoolError, IoContext, Result};
+use crate::pack::rkcrc32;
+
+/// Header Rockchip's rkcrc tool puts in front of a kernel or parameter
+/// image: a magic, the data length, then the data and its rkcrc32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrcWrapper {
+    Krnl,
+    Parm,
+}
+
+impl CrcWrapper {
+    pub fn magic(self) -> &'static [u8; 4] {
+        match self {
+            CrcWrapper::Krnl => b"KRNL",
+            CrcWrapper::Parm => b"PARM",
+        }
+    }
+
+    /// The wrapper of `data`, if it starts with a known magic.
+    pub fn detect(data: &[u8]) -> Option<CrcWrapper> {
+        [CrcWrapper::Krnl, CrcWrapper::Parm].into_iter().find(|kind| data.starts_with(kind.magic()))
+    }
+}
+
+impl fmt::Display for CrcWrapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CrcWrapper::Krnl => "KRNL",
+            CrcWrapper::Parm => "PARM",
+        })
+    }
+}
+
+/// Wraps `data` with the given header and a trailing rkcrc32 of the data.
+pub fn wrap_rkcrc(kind: CrcWrapper, data: &[u8]) -> Result<Vec<u8>> {
+    let len = u32::try_from(data.len()).map_err(|_| AfptoolError::Layout(format!("{} bytes are too many for a {} header", data.len(), kind)))?;
+    let mut wrapped = Vec::with_capacity(data.len() + 12);
+    wrapped.extend_from_slice(kind.magic());
+    wrapped.extend_from_slice(&len.to_le_bytes());
+    wrapped.extend_from_slice(data);
+    wrapped.extend_from_slice(&rkcrc32(0, data).to_le_bytes());
+    Ok(wrapped)
+}
+
+/// Returns the wrapper and the data inside a KRNL or PARM image, after
+/// checking the stored length and CRC. Padding after the CRC is ignored.
+pub fn unwrap_rkcrc(data: &[u8]) -> Result<(CrcWrapper, &[u8])> {
+    let kind = CrcWrapper::detect(data)
+        .ok_or_else(|| AfptoolError::Parse("No KRNL or PARM header".to_string()))?;
+    let len = data.get(4..8).map(|field| u32::from_le_bytes([field[0], field[1], field[2], field[3]]) as usize);
+    let end = len.and_then(|len| len.checked_add(8)).filter(|end| end + 4 <= data.len()).ok_or_else(|| {
+        AfptoolError::Parse(format!("{} header claims more data than the {}-byte file holds", kind, data.len()))
+    })?;
+    let stored = u32::from_le_bytes([data[end], data[end + 1], data[end + 2], data[end + 3]]);
+    let computed = rkcrc32(0, &data[8..end]);
+    if stored != computed {
+        return Err(AfptoolError::Checksum(format!(
+            "{} CRC mismatch: stored {:#010x}, computed {:#010x}", kind, stored, computed
+        )));
+    }
+    Ok((kind, &data[8..end]))
+}
+
+/// Writes `input` wrapped with a KRNL or PARM header to `output`.
+pub fn rkcrc_wrap_file(input: impl AsRef<Path>, output: impl AsRef<Path>, kind: CrcWrapper) -> Result<()> {
+    let input = input.as_ref();
+    let data = std::fs::read(input).with_context(|| format!("Cannot read {}", input.display()))?;
+    std::fs::write(output, wrap_rkcrc(kind, &data)?)?;
+    Ok(())
+}
+
+/// Writes the data inside the KRNL or PARM image `input` to `output` and
+/// returns which header it had.
+pub fn rkcrc_unwrap_file(input: impl AsRef<Path>, output: impl AsRef<Path>) -> Result<CrcWrapper> {
+    let input = input.as_ref();
+    let data = std::fs::read(input).with_context(|| format!("Cannot read {}", input.display()))?;
+    let (kind, inner) = unwrap_rkcrc(&data)?;
+    std::fs::write(output, inner)?;
+    Ok(kind)
+}
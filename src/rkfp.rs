@@ -0,0 +1,505 @@
+//! RKFP containers, the successor to RKAF used by newer Rockchip firmware.
+//!
+//! All fields are little-endian. The container starts with a 0x200-byte header:
+//!
+//! | offset | field                                               |
+//! |--------|-----------------------------------------------------|
+//! | 0x00   | `RKFP`                                              |
+//! | 0x04   | header size (0x200)                                 |
+//! | 0x08   | version, `major << 24 \| minor << 16 \| build`       |
+//! | 0x0c   | entry count                                         |
+//! | 0x10   | entry table offset                                  |
+//! | 0x14   | entry size (0x80)                                   |
+//! | 0x18   | hash area offset                                    |
+//! | 0x1c   | hash size (16, MD5)                                 |
+//! | 0x20   | GPT offset (u64)                                    |
+//! | 0x28   | GPT size (u64), 0 when the image carries no GPT     |
+//! | 0x30   | image length (u64)                                  |
+//! | 0x38   | disk size in sectors the GPT was laid out for (u64) |
+//! | 0x40   | model, 64 bytes                                     |
+//! | 0x80   | manufacturer, 64 bytes                              |
+//!
+//! Each entry holds a 32-byte name, a 64-byte path, then the data offset and
+//! size in bytes and the flash offset and size in 512-byte sectors (all u64);
+//! a flash size of `u64::MAX` is the partition that grows to the end of the
+//! disk. The hash area is the MD5 of the entry table followed by the MD5 of
+//! each entry's data, and the GPT area is the protective MBR, GPT header and
+//! partition entries to write at the start of the disk.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::error::{AfptoolError, IoContext, Result};
+use crate::hooks::run_hooks;
+use crate::image::cstr_field;
+use crate::paths::{is_contained, join_path};
+use crate::scan::ImageKind;
+use crate::timings::phase;
+use crate::tr;
+use crate::tree::record_checksums;
+use crate::unpack::{extract_file, say, ExtractedPart, OutputDir, UnpackOptions, UnpackReport};
+use crate::RKFP_SIGNATURE;
+
+pub(crate) const RKFP_HEADER_SIZE: usize = 0x200;
+pub(crate) const RKFP_MAX_ENTRIES: u32 = 128;
+const RKFP_MANIFEST: &str = "rkfp-manifest.json";
+const ENTRY_SIZE: usize = 0x80;
+const HASH_SIZE: usize = 16;
+const SECTOR_SIZE: usize = 512;
+/// Protective MBR, GPT header and 128 partition entries of 128 bytes.
+const GPT_SIZE: usize = 34 * SECTOR_SIZE;
+const GPT_HEADER_SIZE: usize = 92;
+const FLASH_SIZE_GROW: u64 = u64::MAX;
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+/// Linux filesystem data, 0FC63DAF-8483-4772-8E79-3D69D8477DE4, in on-disk byte order.
+const LINUX_DATA_GUID: [u8; 16] = [
+    0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4,
+];
+
+/// What unpack records about the container besides the partition files, and
+/// what pack-rkfp needs to rebuild it.
+#[derive(Debug, Serialize, Deserialize)]
+struct RkfpManifest {
+    version: String,
+    model: String,
+    manufacturer: String,
+    /// Disk size the GPT is laid out for, in sectors; 0 for the smallest disk
+    /// that holds every partition.
+    disk_sectors: u64,
+    partitions: Vec<RkfpPartition>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RkfpPartition {
+    name: String,
+    path: String,
+    /// In 512-byte sectors.
+    flash_offset: u64,
+    /// In 512-byte sectors; `null` for the partition that grows to the end of the disk.
+    flash_size: Option<u64>,
+}
+
+struct RkfpHeader {
+    version: u32,
+    entry_count: u32,
+    entry_table_offset: u64,
+    hash_area_offset: u64,
+    gpt_offset: u64,
+    gpt_size: u64,
+    image_length: u64,
+    disk_sectors: u64,
+    model: String,
+    manufacturer: String,
+}
+
+struct RkfpEntry {
+    name: String,
+    path: String,
+    data_offset: u64,
+    data_size: u64,
+    flash_offset: u64,
+    flash_size: u64,
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn version_string(version: u32) -> String {
+    format!("{}.{}.{}", version >> 24, (version >> 16) & 0xff, version & 0xffff)
+}
+
+/// Fails unless `len` bytes at `offset` lie inside an image of `filesize` bytes.
+fn check_range(offset: u64, len: u64, filesize: u64, what: &str) -> Result<()> {
+    match offset.checked_add(len) {
+        Some(end) if end <= filesize => Ok(()),
+        _ => Err(AfptoolError::Parse(format!("RKFP {} at {:#x}+{:#x} runs past the end of the image", what, offset, len))),
+    }
+}
+
+fn parse_header(buf: &[u8], filesize: u64) -> Result<RkfpHeader> {
+    if &buf[..4] != RKFP_SIGNATURE {
+        return Err(AfptoolError::Parse("Invalid header magic id".to_string()));
+    }
+    if u32_at(buf, 0x04) as usize != RKFP_HEADER_SIZE || u32_at(buf, 0x14) as usize != ENTRY_SIZE || u32_at(buf, 0x1c) as usize != HASH_SIZE {
+        return Err(AfptoolError::Parse("Unsupported RKFP header, entry or hash size".to_string()));
+    }
+    let header = RkfpHeader {
+        version: u32_at(buf, 0x08),
+        entry_count: u32_at(buf, 0x0c),
+        entry_table_offset: u32_at(buf, 0x10) as u64,
+        hash_area_offset: u32_at(buf, 0x18) as u64,
+        gpt_offset: u64_at(buf, 0x20),
+        gpt_size: u64_at(buf, 0x28),
+        image_length: u64_at(buf, 0x30),
+        disk_sectors: u64_at(buf, 0x38),
+        model: cstr_field(&buf[0x40..0x80]),
+        manufacturer: cstr_field(&buf[0x80..0xc0]),
+    };
+    if header.entry_count > RKFP_MAX_ENTRIES {
+        return Err(AfptoolError::Parse(format!("RKFP entry count {} is over the limit of {}", header.entry_count, RKFP_MAX_ENTRIES)));
+    }
+    if header.gpt_size != 0 && header.gpt_size != GPT_SIZE as u64 {
+        return Err(AfptoolError::Parse(format!("RKFP GPT area is {:#x} bytes, expected {:#x}", header.gpt_size, GPT_SIZE)));
+    }
+    if header.image_length > filesize {
+        return Err(AfptoolError::Parse("Insufficient length in container image file".to_string()));
+    }
+    let entries = header.entry_count as u64;
+    check_range(header.entry_table_offset, entries * ENTRY_SIZE as u64, filesize, "entry table")?;
+    check_range(header.hash_area_offset, (entries + 1) * HASH_SIZE as u64, filesize, "hash area")?;
+    check_range(header.gpt_offset, header.gpt_size, filesize, "GPT")?;
+    Ok(header)
+}
+
+fn parse_entry(buf: &[u8], filesize: u64) -> Result<RkfpEntry> {
+    let entry = RkfpEntry {
+        name: cstr_field(&buf[0x00..0x20]),
+        path: cstr_field(&buf[0x20..0x60]),
+        data_offset: u64_at(buf, 0x60),
+        data_size: u64_at(buf, 0x68),
+        flash_offset: u64_at(buf, 0x70),
+        flash_size: u64_at(buf, 0x78),
+    };
+    check_range(entry.data_offset, entry.data_size, filesize, &entry.name)?;
+    Ok(entry)
+}
+
+fn read_at(fp: &mut (impl Read + Seek), offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    fp.seek(SeekFrom::Start(offset))?;
+    fp.read_exact(&mut buf)
+        .map_err(|_| AfptoolError::Parse("Insufficient length in container image file".to_string()))?;
+    Ok(buf)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// Checks the CRCs of the GPT header and its partition entries.
+fn check_gpt(gpt: &[u8]) -> Result<()> {
+    let header = &gpt[SECTOR_SIZE..2 * SECTOR_SIZE];
+    if &header[..8] != b"EFI PART" || u32_at(header, 12) as usize != GPT_HEADER_SIZE {
+        return Err(AfptoolError::Parse("RKFP GPT area holds no GPT header".to_string()));
+    }
+    let mut unsummed = header[..GPT_HEADER_SIZE].to_vec();
+    unsummed[16..20].fill(0);
+    if crc32(&unsummed) != u32_at(header, 16) {
+        return Err(AfptoolError::Checksum("RKFP GPT header CRC mismatch".to_string()));
+    }
+    if crc32(&gpt[2 * SECTOR_SIZE..]) != u32_at(header, 88) {
+        return Err(AfptoolError::Checksum("RKFP GPT partition entries CRC mismatch".to_string()));
+    }
+    Ok(())
+}
+
+/// Extracts every entry of an RKFP container into `dst`, after checking
+/// the entry table and each entry's data against the hash area, and writes
+/// rkfp-manifest.json for repacking.
+/// Refuses a damaged container unless `--force` is given, in which case the
+/// mismatch becomes a warning.
+fn damaged(options: &UnpackOptions, warnings: &mut Vec<String>, mismatch: String) -> Result<()> {
+    if !options.force {
+        return Err(AfptoolError::Checksum(format!("{}{}", mismatch, tr!("unpack-force-hint"))));
+    }
+    warnings.push(mismatch);
+    Ok(())
+}
+
+pub(crate) fn unpack_rkfp(fp: &mut (impl Read + Seek), dst: OutputDir, options: &UnpackOptions) -> Result<UnpackReport> {
+    let parsing = phase("parse");
+    let filesize = fp.seek(SeekFrom::End(0))?;
+    let header = parse_header(&read_at(fp, 0, RKFP_HEADER_SIZE)?, filesize)?;
+    let table = read_at(fp, header.entry_table_offset, header.entry_count as usize * ENTRY_SIZE)?;
+    let hashes = read_at(fp, header.hash_area_offset, (header.entry_count as usize + 1) * HASH_SIZE)?;
+    let mut warnings = Vec::new();
+    if md5::compute(&table).0 != hashes[..HASH_SIZE] {
+        damaged(options, &mut warnings, "RKFP entry table does not match its MD5".to_string())?;
+    }
+    let entries = table.chunks(ENTRY_SIZE)
+        .map(|entry| parse_entry(entry, filesize))
+        .collect::<Result<Vec<_>>>()?;
+    if header.gpt_size > 0 {
+        match check_gpt(&read_at(fp, header.gpt_offset, GPT_SIZE)?) {
+            Err(AfptoolError::Checksum(mismatch)) => damaged(options, &mut warnings, mismatch)?,
+            result => result?,
+        }
+    }
+    drop(parsing);
+
+    say!(options, "{}", tr!("unpack-rkfp-detected"));
+    say!(options, "{}", tr!("unpack-version", version = version_string(header.version)));
+    say!(options, "{}", tr!("unpack-filesize", size = filesize));
+    say!(options, "{}", tr!("unpack-manufacturer", manufacturer = header.manufacturer));
+    say!(options, "{}", tr!("unpack-model", model = header.model));
+
+    let mut report = UnpackReport {
+        kind: ImageKind::Rkfp,
+        version: version_string(header.version),
+        chip: None,
+        timestamp: None,
+        model: Some(header.model.clone()),
+        manufacturer: Some(header.manufacturer.clone()),
+        update: None,
+        parts: Vec::new(),
+        warnings,
+    };
+    let mut checksums = Vec::new();
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if !is_contained(&entry.path) {
+            return Err(AfptoolError::Parse(format!(
+                "Partition {} has unsafe path {:?} that would leave the output directory", entry.name, entry.path
+            )));
+        }
+        let output_path = dst.join(&entry.path);
+        if let Some(parent) = output_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let digest = extract_file(fp, entry.data_offset, entry.data_size, dst, &entry.path, options)?;
+        let expected = &hashes[(i + 1) * HASH_SIZE..(i + 2) * HASH_SIZE];
+        if digest != format!("{:x}", md5::Digest(expected.try_into().unwrap())) {
+            damaged(options, &mut report.warnings, format!("{} does not match its MD5 in the RKFP hash area", entry.path))?;
+        }
+        checksums.push((entry.path.clone(), digest.clone()));
+        report.parts.push(ExtractedPart {
+            name: entry.name.clone(),
+            path: entry.path.clone(),
+            offset: entry.data_offset,
+            size: entry.data_size,
+            md5: digest,
+        });
+        run_hooks(&options.hooks, &entry.name, &output_path)?;
+
+        partitions.push(RkfpPartition {
+            name: entry.name.clone(),
+            path: entry.path.clone(),
+            flash_offset: entry.flash_offset,
+            flash_size: (entry.flash_size != FLASH_SIZE_GROW).then_some(entry.flash_size),
+        });
+    }
+    record_checksums(dst.path, &checksums)?;
+
+    let manifest = RkfpManifest {
+        version: version_string(header.version),
+        model: header.model,
+        manufacturer: header.manufacturer,
+        disk_sectors: header.disk_sectors,
+        partitions,
+    };
+    std::fs::write(dst.join(RKFP_MANIFEST), serde_json::to_string_pretty(&manifest)?)?;
+    say!(options, "\n{}", tr!("unpack-rkfp-manifest-saved", path = dst.shown(RKFP_MANIFEST)));
+
+    Ok(report)
+}
+
+fn align(len: u64) -> u64 {
+    len.div_ceil(SECTOR_SIZE as u64) * SECTOR_SIZE as u64
+}
+
+fn parse_version(version: &str) -> Result<u32> {
+    let parts: Vec<&str> = version.split('.').collect();
+    if parts.len() != 3 {
+        return Err(AfptoolError::Parse("Invalid version format. Expected: major.minor.build".to_string()));
+    }
+    let major: u8 = parts[0].parse().map_err(|_| AfptoolError::Parse("Invalid major version".to_string()))?;
+    let minor: u8 = parts[1].parse().map_err(|_| AfptoolError::Parse("Invalid minor version".to_string()))?;
+    let build: u16 = parts[2].parse().map_err(|_| AfptoolError::Parse("Invalid build version".to_string()))?;
+    Ok((major as u32) << 24 | (minor as u32) << 16 | build as u32)
+}
+
+fn put_text(field: &mut [u8], text: &str, what: &str) -> Result<()> {
+    // One byte is kept for the terminating NUL
+    if text.len() >= field.len() {
+        return Err(AfptoolError::Invalid(format!("{} {:?} is longer than {} bytes", what, text, field.len() - 1)));
+    }
+    field[..text.len()].copy_from_slice(text.as_bytes());
+    Ok(())
+}
+
+/// A GUID derived from `seed`, so that packing the same tree twice gives the same image.
+fn guid(seed: &str) -> [u8; 16] {
+    let mut guid = md5::compute(seed).0;
+    // Version 4, RFC 4122 variant; the version is in the high byte of the little-endian third field
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    guid
+}
+
+/// The smallest disk, in sectors, that holds every partition and the backup GPT.
+fn min_disk_sectors(partitions: &[RkfpPartition], sizes: &[u64]) -> u64 {
+    let backup = (GPT_SIZE / SECTOR_SIZE) as u64 - 1;
+    partitions.iter().zip(sizes)
+        .map(|(part, &size)| part.flash_offset.saturating_add(part.flash_size.unwrap_or(align(size) / SECTOR_SIZE as u64)))
+        .max()
+        .unwrap_or(0)
+        .saturating_add(backup)
+}
+
+/// Lays out a protective MBR, GPT header and partition entries for
+/// `partitions` on a disk of `disk_sectors` sectors.
+fn build_gpt(partitions: &[RkfpPartition], disk_sectors: u64, disk_seed: &str) -> Result<Vec<u8>> {
+    let first_usable = (GPT_SIZE / SECTOR_SIZE) as u64;
+    // The backup entries and header take the last 33 sectors
+    let last_usable = disk_sectors.checked_sub(first_usable)
+        .ok_or_else(|| AfptoolError::Layout(format!("A disk of {:#x} sectors cannot hold a GPT", disk_sectors)))?;
+    let mut gpt = vec![0u8; GPT_SIZE];
+
+    // Protective MBR: one 0xee partition covering the disk
+    let mbr = &mut gpt[..SECTOR_SIZE];
+    mbr[446 + 1..446 + 4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    mbr[446 + 4] = 0xee;
+    mbr[446 + 5..446 + 8].copy_from_slice(&[0xff, 0xff, 0xff]);
+    mbr[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+    mbr[446 + 12..446 + 16].copy_from_slice(&((disk_sectors - 1).min(u32::MAX as u64) as u32).to_le_bytes());
+    mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    for (i, part) in partitions.iter().enumerate() {
+        let last = match part.flash_size {
+            Some(size) => part.flash_offset.checked_add(size).and_then(|end| end.checked_sub(1)),
+            None => Some(last_usable),
+        };
+        let last = match last {
+            Some(last) if part.flash_offset >= first_usable && last >= part.flash_offset && last <= last_usable => last,
+            _ => return Err(AfptoolError::Layout(format!("{} at sector {:#x} does not fit on a disk of {:#x} sectors", part.name, part.flash_offset, disk_sectors))),
+        };
+        let entry = &mut gpt[2 * SECTOR_SIZE + i * 128..2 * SECTOR_SIZE + (i + 1) * 128];
+        entry[..16].copy_from_slice(&LINUX_DATA_GUID);
+        entry[16..32].copy_from_slice(&guid(&part.name));
+        entry[32..40].copy_from_slice(&part.flash_offset.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+        for (j, unit) in part.name.encode_utf16().take(36).enumerate() {
+            entry[56 + j * 2..58 + j * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    let entries_crc = crc32(&gpt[2 * SECTOR_SIZE..]);
+
+    let header = &mut gpt[SECTOR_SIZE..2 * SECTOR_SIZE];
+    header[..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+    header[24..32].copy_from_slice(&1u64.to_le_bytes());
+    header[32..40].copy_from_slice(&(disk_sectors - 1).to_le_bytes());
+    header[40..48].copy_from_slice(&first_usable.to_le_bytes());
+    header[48..56].copy_from_slice(&last_usable.to_le_bytes());
+    header[56..72].copy_from_slice(&guid(disk_seed));
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&(RKFP_MAX_ENTRIES).to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+    let header_crc = crc32(&header[..GPT_HEADER_SIZE]);
+    header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+    Ok(gpt)
+}
+
+/// Copies `input` to the current position of `output`, returning its MD5.
+fn copy_hashed(input: &mut File, output: &mut File) -> Result<[u8; 16]> {
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    let mut context = md5::Context::new();
+    loop {
+        let read_len = input.read(&mut buffer)?;
+        if read_len == 0 {
+            return Ok(context.finalize().0);
+        }
+        output.write_all(&buffer[..read_len])?;
+        context.consume(&buffer[..read_len]);
+    }
+}
+
+/// Builds an RKFP container from a directory unpacked from one: the files
+/// listed in rkfp-manifest.json, with the entry table, hash area and a GPT
+/// generated from the manifest's flash layout.
+pub fn pack_rkfp(input_dir: impl AsRef<Path>, output_file: impl AsRef<Path>) -> Result<()> {
+    let (input_dir, output_file) = (input_dir.as_ref(), output_file.as_ref());
+    let manifest_path = join_path(input_dir, RKFP_MANIFEST);
+    let manifest_text = std::fs::read_to_string(&manifest_path)
+        .with_context(|| format!("Cannot read {}", manifest_path.display()))?;
+    let manifest: RkfpManifest = serde_json::from_str(&manifest_text)
+        .map_err(|e| AfptoolError::from(e).within(manifest_path.display()))?;
+    let version = parse_version(&manifest.version)?;
+    let count = manifest.partitions.len();
+    if count == 0 || count > RKFP_MAX_ENTRIES as usize {
+        return Err(AfptoolError::Layout(format!("An RKFP image holds 1 to {} partitions, the manifest lists {}", RKFP_MAX_ENTRIES, count)));
+    }
+
+    let table_offset = RKFP_HEADER_SIZE as u64;
+    let hash_offset = table_offset + (count * ENTRY_SIZE) as u64;
+    let gpt_offset = align(hash_offset + ((count + 1) * HASH_SIZE) as u64);
+    let mut data_offset = gpt_offset + GPT_SIZE as u64;
+
+    let mut fp_out = File::create(output_file)?;
+    let mut table = vec![0u8; count * ENTRY_SIZE];
+    let mut hashes = vec![0u8; (count + 1) * HASH_SIZE];
+    let mut sizes = Vec::with_capacity(count);
+    for (i, part) in manifest.partitions.iter().enumerate() {
+        let input_path = join_path(input_dir, &part.path);
+        let mut fp_in = File::open(&input_path)
+            .with_context(|| format!("Cannot open {}", input_path.display()))?;
+        let size = fp_in.metadata()?.len();
+        if part.flash_size.is_some_and(|flash_size| size > flash_size.saturating_mul(SECTOR_SIZE as u64)) {
+            return Err(AfptoolError::Layout(format!("{} is {} bytes, more than its partition holds", part.path, size)));
+        }
+
+        fp_out.seek(SeekFrom::Start(data_offset))?;
+        let digest = copy_hashed(&mut fp_in, &mut fp_out)?;
+        hashes[(i + 1) * HASH_SIZE..(i + 2) * HASH_SIZE].copy_from_slice(&digest);
+
+        let entry = &mut table[i * ENTRY_SIZE..(i + 1) * ENTRY_SIZE];
+        put_text(&mut entry[0x00..0x20], &part.name, "Partition name")?;
+        put_text(&mut entry[0x20..0x60], &part.path, "Partition path")?;
+        entry[0x60..0x68].copy_from_slice(&data_offset.to_le_bytes());
+        entry[0x68..0x70].copy_from_slice(&size.to_le_bytes());
+        entry[0x70..0x78].copy_from_slice(&part.flash_offset.to_le_bytes());
+        entry[0x78..0x80].copy_from_slice(&part.flash_size.unwrap_or(FLASH_SIZE_GROW).to_le_bytes());
+
+        sizes.push(size);
+        data_offset += align(size);
+    }
+    // Pads the last entry's data to a whole sector
+    fp_out.set_len(data_offset)?;
+    hashes[..HASH_SIZE].copy_from_slice(&md5::compute(&table).0);
+
+    let disk_sectors = match manifest.disk_sectors {
+        0 => min_disk_sectors(&manifest.partitions, &sizes),
+        sectors => sectors,
+    };
+    let gpt = build_gpt(&manifest.partitions, disk_sectors, &format!("{}/{}", manifest.manufacturer, manifest.model))?;
+
+    let mut header = vec![0u8; RKFP_HEADER_SIZE];
+    header[..4].copy_from_slice(RKFP_SIGNATURE);
+    header[0x04..0x08].copy_from_slice(&(RKFP_HEADER_SIZE as u32).to_le_bytes());
+    header[0x08..0x0c].copy_from_slice(&version.to_le_bytes());
+    header[0x0c..0x10].copy_from_slice(&(count as u32).to_le_bytes());
+    header[0x10..0x14].copy_from_slice(&(table_offset as u32).to_le_bytes());
+    header[0x14..0x18].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+    header[0x18..0x1c].copy_from_slice(&(hash_offset as u32).to_le_bytes());
+    header[0x1c..0x20].copy_from_slice(&(HASH_SIZE as u32).to_le_bytes());
+    header[0x20..0x28].copy_from_slice(&gpt_offset.to_le_bytes());
+    header[0x28..0x30].copy_from_slice(&(GPT_SIZE as u64).to_le_bytes());
+    header[0x30..0x38].copy_from_slice(&data_offset.to_le_bytes());
+    header[0x38..0x40].copy_from_slice(&disk_sectors.to_le_bytes());
+    put_text(&mut header[0x40..0x80], &manifest.model, "Model")?;
+    put_text(&mut header[0x80..0xc0], &manifest.manufacturer, "Manufacturer")?;
+
+    for (offset, bytes) in [(0, &header), (table_offset, &table), (hash_offset, &hashes), (gpt_offset, &gpt)] {
+        fp_out.seek(SeekFrom::Start(offset))?;
+        fp_out.write_all(bytes)?;
+    }
+
+    println!("{}", tr!("pack-rkfp-success"));
+    println!("{}", tr!("pack-output", path = output_file.display()));
+    println!("{}", tr!("pack-version", version = version_string(version)));
+    println!("{}", tr!("pack-model", model = manifest.model));
+    println!("{}", tr!("pack-manufacturer", manufacturer = manifest.manufacturer));
+    println!("{}", tr!("pack-parts", count = count));
+    println!("{}", tr!("pack-total-size", size = data_offset));
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::error::{AfptoolError, Result};
+use crate::paths::open_file;
+use serde::Serialize;
+use crate::cancel::CancelToken;
+use crate::fastcopy::copy_range;
+use crate::image::rkfw_update_size;
+use crate::rkfp::{RKFP_HEADER_SIZE, RKFP_MAX_ENTRIES};
+use crate::{UpdateHeader, MAX_PARTS, RKAF_SIGNATURE, RKFP_SIGNATURE, RKFW_SIGNATURE, UPDATE_HEADER_SIZE};
+
+const LOADER_SIGNATURES: [&[u8]; 2] = [b"BOOT", b"LDR "];
+const LOADER_HEADER_SIZE: usize = 0x66;
+const LOADER_ENTRY_SIZE: usize = 0x39;
+const SCAN_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Kind of image a [`ScanHit`] looks like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ImageKind {
+    Rkfw,
+    Rkaf,
+    Rkfp,
+    /// A Rockchip loader (`BOOT` or `LDR ` header), as written by boot_merger.
+    Loader,
+}
+
+impl fmt::Display for ImageKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ImageKind::Rkfw => "RKFW",
+            ImageKind::Rkaf => "RKAF",
+            ImageKind::Rkfp => "RKFP",
+            ImageKind::Loader => "loader",
+        })
+    }
+}
+
+/// A firmware magic found in a raw dump.
+#[derive(Debug, Clone)]
+pub struct ScanHit {
+    pub kind: ImageKind,
+    pub offset: u64,
+    /// Size of the image if its header gives one that fits in the dump.
+    pub len: Option<u64>,
+    /// Whether the header around the magic makes sense, not just the magic.
+    pub plausible: bool,
+    /// What the checks found, e.g. the partition count or why the header was rejected.
+    pub detail: String,
+    /// Offset of an earlier hit whose image contains this one.
+    pub inside: Option<u64>,
+}
+
+fn read_at(fp: &mut File, offset: u64, buf: &mut [u8]) -> bool {
+    fp.seek(SeekFrom::Start(offset)).is_ok() && fp.read_exact(buf).is_ok()
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u64 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap()) as u64
+}
+
+/// Checks the header at `offset` and works out the image length.
+/// Returns (length, plausible, detail).
+fn check(fp: &mut File, kind: ImageKind, offset: u64, dump_len: u64) -> (Option<u64>, bool, String) {
+    let remaining = dump_len - offset;
+    match kind {
+        ImageKind::Rkfw => {
+            let mut header = [0u8; 0x41];
+            if !read_at(fp, offset, &mut header) {
+                return (None, false, "truncated header".into());
+            }
+            let (update_offset, update_size) = (u32_at(&header, 0x21), rkfw_update_size(&header));
+            let len = update_offset + update_size + 32;
+            let mut magic = [0u8; 4];
+            if header[4] as usize != LOADER_HEADER_SIZE || len > remaining {
+                (None, false, "header does not fit in the dump".into())
+            } else if !read_at(fp, offset + update_offset, &mut magic) || magic != RKAF_SIGNATURE {
+                (None, false, "no RKAF image at the update offset".into())
+            } else {
+                (Some(len), true, format!("chip code {:#x}, update image at +{:#x}", u32_at(&header, 0x15), update_offset))
+            }
+        }
+        ImageKind::Rkaf => {
+            let mut header = [0u8; UPDATE_HEADER_SIZE];
+            if !read_at(fp, offset, &mut header) {
+                return (None, false, "truncated header".into());
+            }
+            let num_parts = u32_at(&header, 0x88);
+            if num_parts == 0 || num_parts > MAX_PARTS as u64 {
+                return (None, false, format!("implausible partition count {}", num_parts));
+            }
+            // Past 4 GiB the length field alone is not enough
+            let len = match UpdateHeader::from_bytes(&header).and_then(|header| header.image_length()) {
+                Ok(length) => length.saturating_add(4),
+                Err(e) => return (None, false, e.to_string()),
+            };
+            if len > remaining {
+                (None, false, format!("length {:#x} runs past the end of the dump", len))
+            } else {
+                (Some(len), true, format!("{} partitions", num_parts))
+            }
+        }
+        ImageKind::Rkfp => {
+            let mut header = [0u8; 0x38];
+            if !read_at(fp, offset, &mut header) {
+                return (None, false, "truncated header".into());
+            }
+            let len = u64::from_le_bytes(header[0x30..0x38].try_into().unwrap());
+            let entry_count = u32_at(&header, 0x0c);
+            if u32_at(&header, 4) != RKFP_HEADER_SIZE as u64 || entry_count > RKFP_MAX_ENTRIES as u64 {
+                (None, false, "implausible RKFP header".into())
+            } else if len > remaining {
+                (None, false, format!("length {:#x} runs past the end of the dump", len))
+            } else {
+                (Some(len), true, format!("{} partitions", entry_count))
+            }
+        }
+        ImageKind::Loader => {
+            let mut header = [0u8; LOADER_HEADER_SIZE];
+            if !read_at(fp, offset, &mut header) {
+                return (None, false, "truncated header".into());
+            }
+            if u16::from_le_bytes([header[4], header[5]]) as usize != LOADER_HEADER_SIZE {
+                return (None, false, "wrong header size".into());
+            }
+            // code471, code472 and loader entry tables: count, offset, entry size
+            let mut end = LOADER_HEADER_SIZE as u64;
+            let mut entries = 0;
+            for table in [25usize, 31, 37] {
+                let (count, table_offset, entry_size) = (header[table], u32_at(&header, table + 1), header[table + 5]);
+                if count > 0 && entry_size as usize != LOADER_ENTRY_SIZE {
+                    return (None, false, "wrong entry size".into());
+                }
+                for i in 0..count as u64 {
+                    let mut entry = [0u8; LOADER_ENTRY_SIZE];
+                    if !read_at(fp, offset + table_offset + i * LOADER_ENTRY_SIZE as u64, &mut entry) {
+                        return (None, false, "entry table outside the dump".into());
+                    }
+                    end = end.max(u32_at(&entry, 45) + u32_at(&entry, 49));
+                    entries += 1;
+                }
+            }
+            let len = end + 4;
+            if entries == 0 || len > remaining {
+                (None, false, "no entries that fit in the dump".into())
+            } else {
+                (Some(len), true, format!("{} entries, chip {:#010x}", entries, u32_at(&header, 21)))
+            }
+        }
+    }
+}
+
+fn kind_of(magic: &[u8]) -> Option<ImageKind> {
+    match magic {
+        m if m == RKFW_SIGNATURE => Some(ImageKind::Rkfw),
+        m if m == RKAF_SIGNATURE => Some(ImageKind::Rkaf),
+        m if m == RKFP_SIGNATURE => Some(ImageKind::Rkfp),
+        m if LOADER_SIGNATURES.contains(&m) => Some(ImageKind::Loader),
+        _ => None,
+    }
+}
+
+/// Searches a raw eMMC/NAND dump for RKFW, RKAF, RKFP and loader headers.
+/// Every magic is reported; `plausible` tells the real images from stray
+/// occurrences of the same four bytes.
+pub fn scan_dump(dump_path: impl AsRef<Path>) -> Result<Vec<ScanHit>> {
+    let mut fp = open_file(&dump_path)?;
+    let dump_len = fp.metadata()?.len();
+    let mut reader = open_file(&dump_path)?;
+
+    let mut hits: Vec<ScanHit> = Vec::new();
+    let mut buffer = vec![0u8; SCAN_CHUNK_SIZE + 3];
+    let mut carried = 0usize;
+    let mut chunk_start = 0u64;
+    loop {
+        let read_bytes = reader.read(&mut buffer[carried..])?;
+        if read_bytes == 0 {
+            break;
+        }
+        let filled = carried + read_bytes;
+        let window = &buffer[..filled];
+
+        // Every magic starts with R, B or L, so let memchr find the candidates
+        for position in memchr::memchr3_iter(b'R', b'B', b'L', window) {
+            let Some(kind) = window.get(position..position + 4).and_then(kind_of) else {
+                continue;
+            };
+            let offset = chunk_start + position as u64;
+            let (len, plausible, detail) = check(&mut fp, kind, offset, dump_len);
+            let inside = hits
+                .iter()
+                .rev()
+                .find(|hit| hit.len.is_some_and(|hit_len| offset < hit.offset + hit_len))
+                .map(|hit| hit.offset);
+            hits.push(ScanHit { kind, offset, len, plausible, detail, inside });
+        }
+
+        // Keep the last three bytes so a magic split across reads is still found
+        carried = filled.min(3);
+        buffer.copy_within(filled - carried..filled, 0);
+        chunk_start += (filled - carried) as u64;
+    }
+    Ok(hits)
+}
+
+/// Copies the image found by [`scan_dump`] out of the dump.
+pub fn carve_hit(dump_path: impl AsRef<Path>, hit: &ScanHit, output_path: impl AsRef<Path>) -> Result<()> {
+    let len = hit.len.ok_or_else(|| AfptoolError::Invalid(format!("Size of the {} image at {:#x} is unknown", hit.kind, hit.offset)))?;
+    let input = open_file(dump_path)?;
+    let mut out = File::create(output_path)?;
+    if copy_range(&input, hit.offset, len, &mut out, &CancelToken::default())? != len {
+        return Err(AfptoolError::Parse(format!("Dump ended inside the {} image at {:#x}", hit.kind, hit.offset)));
+    }
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,270 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::error::{AfptoolError, Result};
+use crate::cancel::CancelToken;
+use crate::paths::open_file;
+
+pub(crate) const SPARSE_MAGIC: u32 = 0xed26ff3a;
+const CHUNK_RAW: u16 = 0xcac1;
+const CHUNK_FILL: u16 = 0xcac2;
+const CHUNK_DONT_CARE: u16 = 0xcac3;
+const CHUNK_CRC32: u16 = 0xcac4;
+
+#[derive(Debug, Clone, Copy)]
+enum ChunkData {
+    /// Offset of the chunk's data in the image file.
+    Raw(u64),
+    Fill([u8; 4]),
+    DontCare,
+}
+
+#[derive(Debug, Clone)]
+struct Chunk {
+    /// Offset and length in the expanded image, in bytes.
+    start: u64,
+    len: u64,
+    data: ChunkData,
+}
+
+/// Chunk map of an Android sparse image stored somewhere in a file.
+#[derive(Debug, Clone)]
+pub(crate) struct SparseImage {
+    chunks: Vec<Chunk>,
+    /// Size of the expanded image, in bytes.
+    pub size: u64,
+}
+
+impl SparseImage {
+    /// Parses the sparse header and chunk list at `offset`, or returns `None`
+    /// if the region does not start with the sparse magic.
+    pub fn parse(fp: &mut (impl Read + Seek), offset: u64, len: u64) -> Result<Option<SparseImage>> {
+        let mut header = [0u8; 28];
+        fp.seek(SeekFrom::Start(offset))?;
+        if len < header.len() as u64 || fp.read_exact(&mut header).is_err() {
+            return Ok(None);
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        if u32_at(0) != SPARSE_MAGIC {
+            return Ok(None);
+        }
+
+        let (file_header_size, chunk_header_size) = (u16_at(8) as u64, u16_at(10) as u64);
+        let block_size = u32_at(12) as u64;
+        let total_blocks = u32_at(16) as u64;
+        let total_chunks = u32_at(20);
+        if chunk_header_size < 12 || block_size == 0 {
+            return Err(AfptoolError::Parse("Invalid sparse image header".to_string()));
+        }
+
+        let mut chunks = Vec::with_capacity(total_chunks as usize);
+        let mut position = offset + file_header_size;
+        let mut start = 0u64;
+        for _ in 0..total_chunks {
+            let mut chunk = [0u8; 12];
+            fp.seek(SeekFrom::Start(position))?;
+            fp.read_exact(&mut chunk).map_err(|_| AfptoolError::Parse("Truncated sparse image".to_string()))?;
+            let kind = u16::from_le_bytes([chunk[0], chunk[1]]);
+            let blocks = u32::from_le_bytes(chunk[4..8].try_into().unwrap()) as u64;
+            let total_size = u32::from_le_bytes(chunk[8..12].try_into().unwrap()) as u64;
+            let data_offset = position + chunk_header_size;
+
+            let data = match kind {
+                CHUNK_RAW => ChunkData::Raw(data_offset),
+                CHUNK_FILL => {
+                    let mut fill = [0u8; 4];
+                    fp.seek(SeekFrom::Start(data_offset))?;
+                    fp.read_exact(&mut fill)?;
+                    ChunkData::Fill(fill)
+                }
+                CHUNK_DONT_CARE => ChunkData::DontCare,
+                CHUNK_CRC32 => {
+                    position += total_size;
+                    continue;
+                }
+                _ => return Err(AfptoolError::Parse(format!("Unknown sparse chunk type {:#06x}", kind))),
+            };
+            chunks.push(Chunk { start, len: blocks * block_size, data });
+            start += blocks * block_size;
+            position += total_size;
+            if position > offset + len {
+                return Err(AfptoolError::Parse("Truncated sparse image".to_string()));
+            }
+        }
+
+        if start > total_blocks * block_size {
+            return Err(AfptoolError::Parse(format!("Sparse image chunks cover more than its {} blocks", total_blocks)));
+        }
+        Ok(Some(SparseImage { chunks, size: total_blocks * block_size }))
+    }
+
+    /// Fills `buf` with the expanded image's bytes at `offset`.
+    pub fn read_at(&self, fp: &mut (impl Read + Seek), offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0usize;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let index = self.chunks.partition_point(|chunk| chunk.start + chunk.len <= position);
+            let Some(chunk) = self.chunks.get(index) else {
+                // Blocks after the last chunk are not stored and read as zero
+                if position + (buf.len() - done) as u64 > self.size {
+                    return Err(AfptoolError::Parse("Read past the end of sparse image".to_string()));
+                }
+                buf[done..].fill(0);
+                break;
+            };
+            let within = position - chunk.start;
+            let count = std::cmp::min((chunk.len - within) as usize, buf.len() - done);
+            let target = &mut buf[done..done + count];
+            match chunk.data {
+                ChunkData::Raw(data_offset) => {
+                    fp.seek(SeekFrom::Start(data_offset + within))?;
+                    fp.read_exact(target)?;
+                }
+                ChunkData::Fill(fill) => {
+                    for (i, byte) in target.iter_mut().enumerate() {
+                        *byte = fill[(within as usize + i) % 4];
+                    }
+                }
+                ChunkData::DontCare => target.fill(0),
+            }
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+/// A partition's contents, stored either as-is or as an Android sparse image.
+pub(crate) enum Volume {
+    Plain { offset: u64, len: u64 },
+    Sparse(SparseImage),
+}
+
+impl Volume {
+    pub fn open(fp: &mut File, offset: u64, len: u64) -> Result<Volume> {
+        Ok(match SparseImage::parse(fp, offset, len)? {
+            Some(sparse) => Volume::Sparse(sparse),
+            None => Volume::Plain { offset, len },
+        })
+    }
+
+    pub fn read_at(&self, fp: &mut File, offset: u64, buf: &mut [u8]) -> Result<()> {
+        match self {
+            Volume::Plain { offset: base, len } => {
+                if offset + buf.len() as u64 > *len {
+                    return Err(AfptoolError::Parse("Read past the end of partition".to_string()));
+                }
+                fp.seek(SeekFrom::Start(base + offset))?;
+                fp.read_exact(buf)?;
+                Ok(())
+            }
+            Volume::Sparse(sparse) => sparse.read_at(fp, offset, buf),
+        }
+    }
+}
+
+/// Block size of the sparse images pack writes, as the vendor tools use.
+const PACK_BLOCK_SIZE: u64 = 4096;
+/// Longest raw chunk written, keeping its byte count well inside 32 bits.
+const MAX_RAW_BLOCKS: u64 = 0x10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlannedChunk {
+    Raw { first_block: u64, blocks: u64 },
+    Fill { value: [u8; 4], blocks: u64 },
+}
+
+/// How a raw image is stored as an Android sparse image: blocks that repeat
+/// one 4-byte value become fill chunks, the rest raw chunks.
+#[derive(Debug, Clone)]
+pub(crate) struct SparsePlan {
+    path: PathBuf,
+    raw_size: u64,
+    chunks: Vec<PlannedChunk>,
+    /// Size of the sparse image, in bytes.
+    pub size: u64,
+}
+
+/// The 4-byte value a block repeats, if it is one.
+fn fill_value(block: &[u8]) -> Option<[u8; 4]> {
+    let value = [block[0], block[1], block[2], block[3]];
+    block.chunks_exact(4).all(|word| word == value).then_some(value)
+}
+
+impl SparsePlan {
+    /// Reads the file at `path` once to lay out its chunks. A last partial
+    /// block is padded with zeros, as `img2simg` does.
+    pub fn new(path: &Path, cancel: &CancelToken) -> Result<SparsePlan> {
+        let mut input = open_file(path)?;
+        let raw_size = input.metadata()?.len();
+        let mut chunks: Vec<PlannedChunk> = Vec::new();
+        let mut block = vec![0u8; PACK_BLOCK_SIZE as usize];
+        for index in 0..raw_size.div_ceil(PACK_BLOCK_SIZE) {
+            cancel.check()?;
+            let len = (raw_size - index * PACK_BLOCK_SIZE).min(PACK_BLOCK_SIZE) as usize;
+            block[len..].fill(0);
+            input.read_exact(&mut block[..len])?;
+            match (fill_value(&block), chunks.last_mut()) {
+                (Some(value), Some(PlannedChunk::Fill { value: last, blocks })) if *last == value => *blocks += 1,
+                (Some(value), _) => chunks.push(PlannedChunk::Fill { value, blocks: 1 }),
+                (None, Some(PlannedChunk::Raw { blocks, .. })) if *blocks < MAX_RAW_BLOCKS => *blocks += 1,
+                (None, _) => chunks.push(PlannedChunk::Raw { first_block: index, blocks: 1 }),
+            }
+        }
+        let size = 28 + chunks.iter().map(|chunk| match chunk {
+            PlannedChunk::Raw { blocks, .. } => 12 + blocks * PACK_BLOCK_SIZE,
+            PlannedChunk::Fill { .. } => 12 + 4,
+        }).sum::<u64>();
+        Ok(SparsePlan { path: path.to_path_buf(), raw_size, chunks, size })
+    }
+
+    /// Writes the sparse image, reading the raw data from the file again.
+    pub fn write_to(&self, out: &mut impl Write, cancel: &CancelToken) -> Result<()> {
+        let mut input = open_file(&self.path)?;
+        if input.metadata()?.len() != self.raw_size {
+            return Err(AfptoolError::changed("Input changed size while packing"));
+        }
+        let total_blocks = self.raw_size.div_ceil(PACK_BLOCK_SIZE) as u32;
+        let mut header = Vec::with_capacity(28);
+        header.extend_from_slice(&SPARSE_MAGIC.to_le_bytes());
+        for half in [1u16, 0, 28, 12] {
+            header.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [PACK_BLOCK_SIZE as u32, total_blocks, self.chunks.len() as u32, 0] {
+            header.extend_from_slice(&word.to_le_bytes());
+        }
+        out.write_all(&header)?;
+
+        let mut buffer = vec![0u8; 1024 * 1024];
+        for chunk in &self.chunks {
+            cancel.check()?;
+            let (kind, blocks, data_len) = match chunk {
+                PlannedChunk::Raw { blocks, .. } => (CHUNK_RAW, *blocks, blocks * PACK_BLOCK_SIZE),
+                PlannedChunk::Fill { blocks, .. } => (CHUNK_FILL, *blocks, 4),
+            };
+            out.write_all(&kind.to_le_bytes())?;
+            out.write_all(&[0, 0])?;
+            out.write_all(&(blocks as u32).to_le_bytes())?;
+            out.write_all(&(12 + data_len as u32).to_le_bytes())?;
+            match chunk {
+                PlannedChunk::Fill { value, .. } => out.write_all(value)?,
+                PlannedChunk::Raw { first_block, blocks } => {
+                    let start = first_block * PACK_BLOCK_SIZE;
+                    let stored = (self.raw_size - start).min(blocks * PACK_BLOCK_SIZE);
+                    input.seek(SeekFrom::Start(start))?;
+                    let mut remaining = stored;
+                    while remaining > 0 {
+                        cancel.check()?;
+                        let len = remaining.min(buffer.len() as u64) as usize;
+                        input.read_exact(&mut buffer[..len])?;
+                        out.write_all(&buffer[..len])?;
+                        remaining -= len as u64;
+                    }
+                    // Zero padding of a last partial block
+                    out.write_all(&vec![0u8; (data_len - stored) as usize])?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
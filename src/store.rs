@@ -0,0 +1,190 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use crate::error::{AfptoolError, Result};
+use crate::image::{list_parts, rkfw_sections};
+use crate::paths::open_file;
+
+const MANIFEST_EXTENSION: &str = "manifest";
+
+/// A content-addressed store of partition blobs. Each ingested image is kept as a
+/// manifest listing the blobs that make it up, in file order, so identical
+/// partitions shared by many images are stored once.
+pub struct Store {
+    root: PathBuf,
+}
+
+/// Statistics returned by [`Store::ingest`].
+#[derive(Debug, Clone, Default)]
+pub struct IngestStats {
+    pub name: String,
+    pub total_bytes: u64,
+    pub stored_bytes: u64,
+    pub blobs: usize,
+    pub new_blobs: usize,
+}
+
+impl Store {
+    pub fn open(root: impl AsRef<Path>) -> Result<Store> {
+        let root = root.as_ref().to_path_buf();
+        std::fs::create_dir_all(root.join("blobs"))?;
+        std::fs::create_dir_all(root.join("images"))?;
+        Ok(Store { root })
+    }
+
+    fn blob_path(&self, digest: &str) -> PathBuf {
+        self.root.join("blobs").join(&digest[..2]).join(digest)
+    }
+
+    fn manifest_path(&self, name: &str) -> PathBuf {
+        self.root.join("images").join(format!("{}.{}", name, MANIFEST_EXTENSION))
+    }
+
+    /// Splits an image into partition blobs (plus the header and padding between
+    /// them) and stores every blob not already present.
+    pub fn ingest(&self, image_path: impl AsRef<Path>, name: Option<&str>) -> Result<IngestStats> {
+        let image_path = image_path.as_ref();
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => image_path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .ok_or_else(|| AfptoolError::Invalid(format!("Cannot derive an image name from {}", image_path.display())))?,
+        };
+        if name.is_empty() || name.contains(['/', '\\']) {
+            return Err(AfptoolError::Invalid(format!("Invalid image name: {}", name)));
+        }
+
+        let mut fp = open_file(image_path)?;
+        let total_bytes = fp.metadata()?.len();
+        let segments = split_segments(&mut fp, total_bytes)?;
+
+        let mut stats = IngestStats { name: name.clone(), total_bytes, ..Default::default() };
+        let mut manifest = format!("# afptool-rs store manifest\nsize {}\n", total_bytes);
+
+        for (offset, len) in segments {
+            let digest = hash_region(&mut fp, offset, len)?;
+            let blob_path = self.blob_path(&digest);
+            if !blob_path.exists() {
+                std::fs::create_dir_all(blob_path.parent().unwrap())?;
+                let temp_path = blob_path.with_extension("tmp");
+                let mut out = File::create(&temp_path)?;
+                fp.seek(SeekFrom::Start(offset))?;
+                std::io::copy(&mut (&mut fp).take(len), &mut out)?;
+                std::fs::rename(&temp_path, &blob_path)?;
+                stats.new_blobs += 1;
+                stats.stored_bytes += len;
+            }
+            stats.blobs += 1;
+            manifest.push_str(&format!("{} {}\n", digest, len));
+        }
+
+        std::fs::write(self.manifest_path(&name), manifest)?;
+        Ok(stats)
+    }
+
+    /// Rebuilds an ingested image byte for byte, checking each blob's hash.
+    pub fn materialize(&self, name: &str, output_file: impl AsRef<Path>) -> Result<()> {
+        let manifest = File::open(self.manifest_path(name))
+            .map_err(|_| AfptoolError::not_found(format!("Image {} is not in the store", name)))?;
+        let mut out = File::create(output_file)?;
+        let mut expected_size = None;
+        let mut written = 0u64;
+
+        for line in BufReader::new(manifest).lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => {}
+                [comment, ..] if comment.starts_with('#') => {}
+                ["size", size] => expected_size = Some(size.parse::<u64>()?),
+                [digest, len] if digest.len() == 32 && digest.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    let len: u64 = len.parse()?;
+                    let blob = File::open(self.blob_path(digest))
+                        .map_err(|_| AfptoolError::not_found(format!("Blob {} is missing from the store", digest)))?;
+                    let mut reader = HashingReader { inner: blob.take(len), context: md5::Context::new() };
+                    if std::io::copy(&mut reader, &mut out)? != len {
+                        return Err(AfptoolError::Checksum(format!("Blob {} is truncated", digest)));
+                    }
+                    if format!("{:x}", reader.context.finalize()) != *digest {
+                        return Err(AfptoolError::Checksum(format!("Blob {} is corrupt", digest)));
+                    }
+                    written += len;
+                }
+                _ => return Err(AfptoolError::Parse(format!("Malformed manifest line: {}", line))),
+            }
+        }
+
+        if expected_size.is_some_and(|size| size != written) {
+            return Err(AfptoolError::Parse(format!("Manifest for {} is incomplete", name)));
+        }
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(self.root.join("images"))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == MANIFEST_EXTENSION) {
+                if let Some(stem) = path.file_stem() {
+                    names.push(stem.to_string_lossy().to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+struct HashingReader<R> {
+    inner: R,
+    context: md5::Context,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.context.consume(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Covers the whole file with (offset, len) segments, one per BOOT section and
+/// partition, with whatever lies between them as separate segments.
+fn split_segments(fp: &mut File, file_size: u64) -> Result<Vec<(u64, u64)>> {
+    let mut regions: Vec<(u64, u64)> = list_parts(fp)?
+        .iter()
+        .map(|part| (part.file_offset, part.byte_count))
+        .collect();
+    if let Some([boot, _]) = rkfw_sections(fp)? {
+        regions.push(boot);
+    }
+    regions.retain(|&(offset, len)| len > 0 && offset + len <= file_size);
+    regions.sort();
+    regions.dedup();
+
+    let mut segments = Vec::new();
+    let mut position = 0u64;
+    for (offset, len) in regions {
+        if offset < position {
+            // Overlapping entries are left inside the previous segment
+            continue;
+        }
+        if offset > position {
+            segments.push((position, offset - position));
+        }
+        segments.push((offset, len));
+        position = offset + len;
+    }
+    if position < file_size {
+        segments.push((position, file_size - position));
+    }
+    Ok(segments)
+}
+
+fn hash_region(fp: &mut File, offset: u64, len: u64) -> Result<String> {
+    fp.seek(SeekFrom::Start(offset))?;
+    let mut reader = HashingReader { inner: fp.take(len), context: md5::Context::new() };
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    Ok(format!("{:x}", reader.context.finalize()))
+}
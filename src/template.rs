@@ -0,0 +1,72 @@
+use std::path::{Path, PathBuf};
+use crate::error::{AfptoolError, Result};
+
+/// Replaces `${NAME}` placeholders with values from `defines`, falling back to the
+/// environment. Unknown names are an error so typos don't end up in file paths.
+pub(crate) fn expand_vars(text: &str, defines: &[(String, String)]) -> Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| AfptoolError::Parse(format!("Unterminated placeholder in: {}", text)))?;
+        let name = &after[..end];
+
+        let value = defines
+            .iter()
+            .rev()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+            .or_else(|| std::env::var(name).ok())
+            .ok_or_else(|| AfptoolError::Invalid(format!("Undefined variable ${{{}}} (use --define {}=...)", name, name)))?;
+        result.push_str(&value);
+        rest = &after[end + 1..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Replaces `{field}` placeholders in an output file name with values from
+/// the header being written, e.g. `update_{model}_{version}_{date}.img`.
+/// Characters that don't belong in a file name are replaced by `_`. A name
+/// that is not valid UTF-8 is used as it is, as long as it has no placeholder.
+pub(crate) fn expand_output_name(template: &Path, fields: &[(&str, String)]) -> Result<PathBuf> {
+    let Some(template) = template.to_str() else {
+        if template.as_os_str().as_encoded_bytes().contains(&b'{') {
+            return Err(AfptoolError::Invalid(format!("Output name with placeholders is not valid UTF-8: {}", template.display())));
+        }
+        return Ok(template.to_path_buf());
+    };
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| AfptoolError::Invalid(format!("Unterminated placeholder in output name: {}", template)))?;
+        let name = &after[..end];
+        let value = fields.iter().find(|(key, _)| *key == name).map(|(_, value)| value).ok_or_else(|| {
+            let known: Vec<String> = fields.iter().map(|(key, _)| format!("{{{}}}", key)).collect();
+            AfptoolError::Invalid(format!("Unknown placeholder {{{}}} in output name (available: {})", name, known.join(", ")))
+        })?;
+        result.extend(value.trim().chars().map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' }));
+        rest = &after[end + 1..];
+    }
+
+    result.push_str(rest);
+    Ok(PathBuf::from(result))
+}
+
+/// Parses a `KEY=VALUE` definition as given on the command line.
+pub fn parse_define(define: &str) -> Result<(String, String)> {
+    match define.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(AfptoolError::Invalid(format!("Invalid definition {:?}, expected KEY=VALUE", define))),
+    }
+}
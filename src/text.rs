@@ -0,0 +1,89 @@
+use crate::error::{AfptoolError, Result};
+
+/// Trims whitespace (including a stray `\r`) and a leading UTF-8 BOM, both of
+/// which turn up in tree files edited on Windows.
+pub(crate) fn clean_line(line: &str) -> &str {
+    line.trim_start_matches('\u{feff}').trim()
+}
+
+/// Uses `/` as the path separator, as stored in the image.
+pub(crate) fn normalize_path(path: &str) -> String {
+    path.trim().replace('\\', "/")
+}
+
+/// Splits a partition-metadata.txt line into trimmed fields.
+pub(crate) fn metadata_fields(line: &str) -> Vec<&str> {
+    clean_line(line).split(',').map(str::trim).collect()
+}
+
+/// Decodes a header string. Chinese vendor tools often write model and
+/// manufacturer in GBK, so bytes that aren't UTF-8 are tried as GBK before
+/// falling back to a lossy conversion.
+pub(crate) fn decode_text(bytes: &[u8]) -> String {
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return text.to_string();
+    }
+    encoding_rs::GBK
+        .decode_without_bom_handling_and_without_replacement(bytes)
+        .map(|text| text.into_owned())
+        .unwrap_or_else(|| String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Whether a header string is stored in GBK rather than UTF-8.
+pub(crate) fn is_gbk(bytes: &[u8]) -> bool {
+    std::str::from_utf8(bytes).is_err()
+        && encoding_rs::GBK.decode_without_bom_handling_and_without_replacement(bytes).is_some()
+}
+
+/// Encodes a header string as UTF-8 or GBK in at most `max_len` bytes,
+/// dropping whole characters from the end so no character is cut in half.
+pub(crate) fn encode_text(text: &str, max_len: usize, gbk: bool) -> Result<Vec<u8>> {
+    let mut text = text;
+    loop {
+        let bytes = if gbk {
+            let (bytes, _, unmappable) = encoding_rs::GBK.encode(text);
+            if unmappable {
+                return Err(AfptoolError::Invalid(format!("{:?} cannot be represented in GBK", text)));
+            }
+            bytes.into_owned()
+        } else {
+            text.as_bytes().to_vec()
+        };
+        if bytes.len() <= max_len {
+            return Ok(bytes);
+        }
+        let mut chars = text.chars();
+        chars.next_back();
+        text = chars.as_str();
+    }
+}
+
+/// Matches `text` against a shell-style pattern where `*` stands for any run
+/// of characters and `?` for a single one.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was and how much of the text it has taken so far
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, taken)) => {
+                    backtrack = Some((star, taken + 1));
+                    p = star + 1;
+                    t = taken + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,82 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TOTALS: Mutex<Vec<PhaseTiming>> = Mutex::new(Vec::new());
+
+thread_local! {
+    /// Phases open on this thread, so a phase entered again inside itself
+    /// (an I/O helper called from an I/O loop) is only counted once.
+    static OPEN: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Time spent in one phase over the whole run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhaseTiming {
+    pub name: &'static str,
+    pub elapsed: Duration,
+    /// How many times the phase was entered.
+    pub count: u64,
+}
+
+/// Starts recording phase timings. Until it is called, [`phase`] costs one
+/// atomic load.
+pub fn enable_timings() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Per-phase totals in the order the phases were first entered. A phase
+/// includes any other phase entered inside it (layout includes hashing the
+/// inputs for `--incremental`), and phases run on several threads at once add
+/// up, so totals can exceed the wall-clock time.
+pub fn timings_report() -> Vec<PhaseTiming> {
+    TOTALS.lock().unwrap().clone()
+}
+
+/// Marks the rest of the enclosing scope as part of `name` ("parse", "layout",
+/// "io" or "hash").
+pub(crate) fn phase(name: &'static str) -> Phase {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Phase { name, start: None };
+    }
+    let nested = OPEN.with(|open| {
+        let mut open = open.borrow_mut();
+        let nested = open.contains(&name);
+        open.push(name);
+        nested
+    });
+    Phase { name, start: (!nested).then(Instant::now) }
+}
+
+pub(crate) struct Phase {
+    name: &'static str,
+    start: Option<Instant>,
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        OPEN.with(|open| {
+            let mut open = open.borrow_mut();
+            if let Some(index) = open.iter().rposition(|name| *name == self.name) {
+                open.remove(index);
+            }
+        });
+        let Some(start) = self.start else {
+            return;
+        };
+        let elapsed = start.elapsed();
+        let mut totals = TOTALS.lock().unwrap();
+        match totals.iter_mut().find(|timing| timing.name == self.name) {
+            Some(timing) => {
+                timing.elapsed += elapsed;
+                timing.count += 1;
+            }
+            None => totals.push(PhaseTiming { name: self.name, elapsed, count: 1 }),
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::error::{AfptoolError, IoContext, Result};
+use crate::cancel::CancelToken;
+use crate::check::{write_rkaf_crc, write_rkfw_md5};
+use crate::image::{cstr_field, find_part, locate_rkaf, read_rkaf_header, read_rkfw_header, rkfw_sections};
+use crate::pack::{append_rkaf_crc, put_rkfw_update_size, RKAF_ALIGNMENT};
+use crate::paths::{open_file, with_suffix};
+use crate::{UpdateHeader, UpdatePart, FLASH_SECTOR_SIZE, UPDATE_HEADER_SIZE};
+
+fn copy_exact(fp: &mut File, offset: u64, len: u64, out: &mut impl Write) -> Result<()> {
+    fp.seek(SeekFrom::Start(offset))?;
+    if std::io::copy(&mut fp.take(len), out)? != len {
+        return Err(AfptoolError::Parse("Insufficient length in container image file".to_string()));
+    }
+    Ok(())
+}
+
+/// New data for one partition: `size` bytes at `offset` in `file`.
+struct Replacement<'a> {
+    file: &'a mut File,
+    offset: u64,
+    size: u64,
+}
+
+fn aligned(size: u64) -> u64 {
+    size.div_ceil(RKAF_ALIGNMENT as u64) * RKAF_ALIGNMENT as u64
+}
+
+/// Index of partition `part_name` in `header`, after checking that `new_size`
+/// bytes fit its flash partition.
+fn replaced_index(header: &UpdateHeader, part_name: &str, new_size: u64) -> Result<usize> {
+    let num_parts = (header.num_parts as usize).min(header.parts.len());
+    let index = header.parts[..num_parts]
+        .iter()
+        .position(|part| cstr_field(&part.name) == part_name)
+        .ok_or_else(|| AfptoolError::Invalid(format!("Cannot find partition {} in target image", part_name)))?;
+    let flash_size = header.parts[index].flash_size as u64 * FLASH_SECTOR_SIZE;
+    if flash_size > 0 && !header.parts[index].grows() && new_size > flash_size {
+        return Err(AfptoolError::Layout(format!(
+            "Partition {} is now {} bytes, but the target reserves only {} bytes on flash",
+            part_name, new_size, flash_size
+        )));
+    }
+    Ok(index)
+}
+
+/// Gives `part` the new data size, keeping an explicitly larger padded size.
+fn resize_part(part: &mut UpdatePart, new_size: u64) -> Result<()> {
+    // Only follow the new size when the old value was just the aligned data length
+    if part.padded_size == aligned(part.byte_count()) as u32 {
+        part.padded_size = aligned(new_size) as u32;
+    }
+    part.set_byte_count(new_size)
+}
+
+/// Writes the target's RKAF image to `rkaf_output` with one partition's data
+/// replaced, moving the following partitions as needed.
+fn rebuild_rkaf(target: &mut File, base: u64, part_name: &str, data: Replacement, rkaf_output: &Path) -> Result<()> {
+    let mut header = read_rkaf_header(target, base)?;
+    let new_size = data.size;
+    let index = replaced_index(&header, part_name, new_size)?;
+    let num_parts = (header.num_parts as usize).min(header.parts.len());
+    let header_len = UPDATE_HEADER_SIZE.div_ceil(RKAF_ALIGNMENT) * RKAF_ALIGNMENT;
+
+    let mut parts = header.parts;
+    let replaced = (parts[index].offset(), parts[index].byte_count());
+
+    // Distinct data regions after the header, in file order, mapped to their new offsets
+    let mut regions: BTreeMap<(u64, u64), u64> = BTreeMap::new();
+    for part in &parts[..num_parts] {
+        if part.byte_count() > 0 && part.offset() >= header_len as u64 {
+            regions.insert((part.offset(), part.byte_count()), 0);
+        }
+    }
+
+    let mut out = File::create(rkaf_output)?;
+    out.write_all(&vec![0u8; header_len])?;
+    let mut offset = header_len as u64;
+    for (&(old_offset, old_size), new_offset) in regions.iter_mut() {
+        *new_offset = offset;
+        let size = if (old_offset, old_size) == replaced {
+            copy_exact(data.file, data.offset, new_size, &mut out)?;
+            new_size
+        } else {
+            copy_exact(target, base + old_offset, old_size, &mut out)?;
+            old_size
+        };
+        let padded = aligned(size);
+        out.write_all(&vec![0u8; (padded - size) as usize])?;
+        offset += padded;
+    }
+
+    for part in parts[..num_parts].iter_mut() {
+        let key = (part.offset(), part.byte_count());
+        if let Some(&new_offset) = regions.get(&key) {
+            part.set_offset(new_offset)?;
+            if key == replaced {
+                resize_part(part, new_size)?;
+            }
+        }
+    }
+    header.parts = parts;
+    header.set_image_length(offset);
+
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&header.to_bytes())?;
+    drop(out);
+    append_rkaf_crc(rkaf_output, &CancelToken::default())
+}
+
+/// Rewrites `target_file` to `output_file` with new data for `part_name`,
+/// keeping an RKFW header and loader as they are apart from the update size.
+fn rebuild_image(target_file: &Path, output_file: &Path, part_name: &str, data: Replacement) -> Result<()> {
+    let mut target = open_file(target_file)?;
+    let base = locate_rkaf(&mut target)?;
+
+    let temp_output = with_suffix(output_file, ".tmp");
+    match rkfw_sections(&mut target)? {
+        None => rebuild_rkaf(&mut target, base, part_name, data, &temp_output)?,
+        Some([_, (update_offset, _)]) => {
+            let rkaf_output = with_suffix(output_file, ".rkaf.tmp");
+            rebuild_rkaf(&mut target, base, part_name, data, &rkaf_output)?;
+            let update_size = std::fs::metadata(&rkaf_output)?.len();
+
+            let mut prefix = vec![0u8; update_offset as usize];
+            target.seek(SeekFrom::Start(0))?;
+            target.read_exact(&mut prefix)?;
+            put_rkfw_update_size(&mut prefix, update_size);
+
+            let mut context = md5::Context::new();
+            let mut out = File::create(&temp_output)?;
+            context.consume(&prefix);
+            out.write_all(&prefix)?;
+
+            let mut rkaf = open_file(&rkaf_output)?;
+            let mut buffer = vec![0u8; 1024 * 1024];
+            loop {
+                let read_bytes = rkaf.read(&mut buffer)?;
+                if read_bytes == 0 {
+                    break;
+                }
+                context.consume(&buffer[..read_bytes]);
+                out.write_all(&buffer[..read_bytes])?;
+            }
+            out.write_all(format!("{:x}", context.finalize()).as_bytes())?;
+            drop(rkaf);
+            std::fs::remove_file(&rkaf_output)?;
+        }
+    }
+
+    drop(target);
+    std::fs::rename(&temp_output, output_file)?;
+    Ok(())
+}
+
+/// Copies partition `part_name` from `donor_file` into `target_file`, adjusting
+/// the layout, the RKAF checksum and, for RKFW targets, the MD5 trailer. The
+/// result replaces the target unless `output_file` is given.
+pub fn transplant_part(donor_file: impl AsRef<Path>, part_name: &str, target_file: impl AsRef<Path>, output_file: Option<&Path>) -> Result<()> {
+    let (donor_file, target_file) = (donor_file.as_ref(), target_file.as_ref());
+    let output_file = output_file.unwrap_or(target_file);
+    let mut donor = open_file(donor_file)?;
+    let donor_part = find_part(&mut donor, part_name)?;
+    let data = Replacement { file: &mut donor, offset: donor_part.file_offset, size: donor_part.byte_count };
+    rebuild_image(target_file, output_file, part_name, data)
+}
+
+/// Writes the contents of `data_file` over one partition of `image_file`.
+/// When the new data fits the space the old data took up, only that space,
+/// the RKAF header and the checksums are rewritten in place; otherwise the
+/// image is laid out again as [`transplant_part`] does. The result replaces
+/// the image unless `output_file` is given.
+pub fn replace_part(image_file: impl AsRef<Path>, part_name: &str, data_file: impl AsRef<Path>, output_file: Option<&Path>) -> Result<()> {
+    let (image_file, data_file) = (image_file.as_ref(), data_file.as_ref());
+    let output_file = output_file.unwrap_or(image_file);
+    let mut data = File::open(data_file).with_context(|| format!("Cannot read {}", data_file.display()))?;
+    let size = data.metadata()?.len();
+
+    let mut image = open_file(image_file)?;
+    let base = locate_rkaf(&mut image)?;
+    let header = read_rkaf_header(&mut image, base)?;
+    let index = replaced_index(&header, part_name, size)?;
+    drop(image);
+    let room = match room_in_place(&header, index) {
+        Some(room) if size <= room => room,
+        _ => return rebuild_image(image_file, output_file, part_name, Replacement { file: &mut data, offset: 0, size }),
+    };
+
+    if output_file != image_file {
+        std::fs::copy(image_file, output_file)?;
+    }
+    let mut image = std::fs::OpenOptions::new().read(true).write(true).open(output_file)?;
+    let mut header = header;
+    let part = &mut header.parts[index];
+    // Clear what is left of the old data so no stale bytes stay in the image
+    let old_end = aligned(part.byte_count());
+    image.seek(SeekFrom::Start(base + part.offset()))?;
+    if std::io::copy(&mut data, &mut image)? != size {
+        return Err(AfptoolError::changed(format!("{} changed while it was being read", data_file.display())));
+    }
+    let clear = old_end.min(room).saturating_sub(size);
+    std::io::copy(&mut std::io::repeat(0).take(clear), &mut image)?;
+    resize_part(part, size)?;
+
+    image.seek(SeekFrom::Start(base))?;
+    image.write_all(&header.to_bytes())?;
+    write_rkaf_crc(&mut image, base, header.image_length()?, &CancelToken::default())?;
+    if let Some(rkfw) = read_rkfw_header(&mut image)? {
+        write_rkfw_md5(&mut image, &rkfw)?;
+    }
+    Ok(())
+}
+
+/// Bytes partition `index` may take at its current offset without reaching
+/// the next partition's data or the end of the image, or `None` when its
+/// data is shared with another entry or in the header area.
+fn room_in_place(header: &UpdateHeader, index: usize) -> Option<u64> {
+    let num_parts = (header.num_parts as usize).min(header.parts.len());
+    let part = &header.parts[index];
+    let header_len = UPDATE_HEADER_SIZE.div_ceil(RKAF_ALIGNMENT) * RKAF_ALIGNMENT;
+    if part.offset() < header_len as u64 {
+        return None;
+    }
+    let mut end = header.image_length().ok()?;
+    for (i, other) in header.parts[..num_parts].iter().enumerate() {
+        if i == index || other.byte_count() == 0 {
+            continue;
+        }
+        if other.offset() == part.offset() {
+            return None;
+        }
+        if other.offset() > part.offset() {
+            end = end.min(other.offset());
+        }
+    }
+    end.checked_sub(part.offset())
+}
@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use crate::error::{AfptoolError, Result};
+use crate::cancel::CancelToken;
+use crate::hash::parallel_map;
+use crate::pack::{DESPARSED_MARKER, PARM_BLOB_SUFFIX, PARM_MARKER};
+use crate::paths::{join_path, open_file};
+use crate::text::{clean_line, metadata_fields, normalize_path};
+use crate::tr;
+
+pub(crate) const CHECKSUMS_FILE: &str = "checksums.md5";
+
+/// Merges `entries` (relative path, MD5 hex) into the tree's checksum file, which is
+/// kept in `md5sum` format so it can also be checked with `md5sum -c`.
+pub(crate) fn record_checksums(dst_path: &Path, entries: &[(String, String)]) -> Result<()> {
+    let checksums_path = join_path(dst_path, CHECKSUMS_FILE);
+    let mut checksums = read_checksums(&checksums_path)?;
+    for (path, digest) in entries {
+        checksums.insert(path.clone(), digest.clone());
+    }
+
+    let mut file = File::create(&checksums_path)?;
+    for (path, digest) in &checksums {
+        writeln!(file, "{}  {}", digest, path)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_checksums(checksums_path: &Path) -> Result<BTreeMap<String, String>> {
+    let mut checksums = BTreeMap::new();
+    let file = match File::open(checksums_path) {
+        Ok(f) => f,
+        Err(_) => return Ok(checksums),
+    };
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if let Some((digest, path)) = clean_line(&line).split_once("  ") {
+            checksums.insert(normalize_path(path), digest.to_string());
+        }
+    }
+    Ok(checksums)
+}
+
+/// Sizes recorded in partition-metadata.txt, keyed by partition path.
+fn read_metadata_sizes(dir: &Path) -> Result<HashMap<String, u64>> {
+    let mut sizes = HashMap::new();
+    let file = match File::open(join_path(dir, "partition-metadata.txt")) {
+        Ok(f) => f,
+        Err(_) => return Ok(sizes),
+    };
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let parts = metadata_fields(&line);
+        if parts.len() >= 7 {
+            let size = u64::from_str_radix(parts[6].trim_start_matches("0x"), 16)?;
+            if parts.get(7) == Some(&DESPARSED_MARKER) {
+                // Expanded on unpack, so only the checksum applies
+                continue;
+            }
+            if parts.get(7) == Some(&PARM_MARKER) {
+                // The text without its 8-byte header and 4-byte CRC, and the blob as stored
+                sizes.insert(format!("{}{}", normalize_path(parts[1]), PARM_BLOB_SUFFIX), size);
+                sizes.insert(normalize_path(parts[1]), size.saturating_sub(12));
+            } else {
+                sizes.insert(normalize_path(parts[1]), size);
+            }
+        }
+    }
+    Ok(sizes)
+}
+
+pub(crate) fn md5_file(path: &Path) -> Result<String> {
+    md5_file_with(path, &CancelToken::default())
+}
+
+fn md5_file_with(path: &Path, cancel: &CancelToken) -> Result<String> {
+    let mut file = open_file(path)?;
+    let mut context = md5::Context::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        cancel.check()?;
+        let read_bytes = file.read(&mut buffer)?;
+        if read_bytes == 0 {
+            break;
+        }
+        context.consume(&buffer[..read_bytes]);
+    }
+    Ok(format!("{:x}", context.finalize()))
+}
+
+/// Checks every file recorded by unpack against its recorded size and MD5.
+pub fn verify_tree(dir: impl AsRef<Path>) -> Result<()> {
+    verify_tree_with(dir, &CancelToken::default())
+}
+
+/// [`verify_tree`] that stops early once `cancel` is triggered.
+pub fn verify_tree_with(dir: impl AsRef<Path>, cancel: &CancelToken) -> Result<()> {
+    let dir = dir.as_ref();
+    let checksums = read_checksums(&join_path(dir, CHECKSUMS_FILE))?;
+    if checksums.is_empty() {
+        return Err(AfptoolError::not_found(format!("No {} found in {}", CHECKSUMS_FILE, dir.display())));
+    }
+    let sizes = read_metadata_sizes(dir)?;
+
+    // Files are hashed in parallel, then reported in order
+    let entries: Vec<(&String, &String)> = checksums.iter().collect();
+    let statuses = parallel_map(&entries, |&(path, digest)| {
+        let full_path = join_path(dir, path);
+        Ok(match std::fs::metadata(&full_path) {
+            Err(_) => Some(tr!("tree-missing")),
+            Ok(meta) => match sizes.get(path) {
+                Some(&size) if size != meta.len() => {
+                    Some(tr!("tree-size-changed", expected = size, actual = meta.len()))
+                }
+                _ if md5_file_with(&full_path, cancel)? != *digest => Some(tr!("tree-hash-changed")),
+                _ => None,
+            },
+        })
+    })?;
+
+    let mut drifted = 0;
+    for ((path, _), status) in entries.into_iter().zip(statuses) {
+        match status {
+            Some(status) => {
+                drifted += 1;
+                log::warn!("{:30} {}", path, status);
+            }
+            None => log::info!("{:30} {}", path, tr!("tree-ok")),
+        }
+    }
+
+    if drifted > 0 {
+        return Err(AfptoolError::Checksum(format!("{} of {} files in {} have drifted", drifted, checksums.len(), dir.display())));
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::error::Result;
+use crate::paths::join_path;
+use crate::tree::{md5_file, record_checksums};
+use crate::text::{metadata_fields, normalize_path};
+use crate::FLASH_SECTOR_SIZE;
+
+/// Size change of one partition file made by [`trim_tree`].
+#[derive(Debug, Clone)]
+pub struct TrimReport {
+    pub path: String,
+    pub original_size: u64,
+    pub trimmed_size: u64,
+}
+
+impl TrimReport {
+    pub fn saved(&self) -> u64 {
+        self.original_size - self.trimmed_size
+    }
+}
+
+fn read_at(fp: &mut File, offset: u64, buf: &mut [u8]) -> bool {
+    fp.seek(SeekFrom::Start(offset)).is_ok() && fp.read_exact(buf).is_ok()
+}
+
+/// Size a filesystem at the start of the file claims for itself, so trimming
+/// never cuts into it even where its tail happens to be zero.
+fn filesystem_end(fp: &mut File) -> u64 {
+    let mut superblock = [0u8; 64];
+    if read_at(fp, 1024, &mut superblock) && superblock[56..58] == [0x53, 0xef] {
+        // ext2/3/4: s_blocks_count_lo and s_log_block_size
+        let blocks = u32::from_le_bytes(superblock[4..8].try_into().unwrap()) as u64;
+        let log_block_size = u32::from_le_bytes(superblock[24..28].try_into().unwrap());
+        return blocks << (10 + log_block_size.min(6));
+    }
+
+    let mut squashfs = [0u8; 48];
+    if read_at(fp, 0, &mut squashfs) && &squashfs[..4] == b"hsqs" {
+        return u64::from_le_bytes(squashfs[40..48].try_into().unwrap());
+    }
+
+    let mut sparse = [0u8; 4];
+    if read_at(fp, 0, &mut sparse) && sparse == [0x3a, 0xff, 0x26, 0xed] {
+        // Android sparse images are left alone
+        return u64::MAX;
+    }
+    0
+}
+
+/// Offset just past the last non-zero byte of the file.
+fn data_end(fp: &mut File, len: u64) -> Result<u64> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut end = len;
+    while end > 0 {
+        let chunk = std::cmp::min(end, buffer.len() as u64);
+        fp.seek(SeekFrom::Start(end - chunk))?;
+        fp.read_exact(&mut buffer[..chunk as usize])?;
+        if let Some(i) = buffer[..chunk as usize].iter().rposition(|&b| b != 0) {
+            return Ok(end - chunk + i as u64 + 1);
+        }
+        end -= chunk;
+    }
+    Ok(0)
+}
+
+/// Truncates trailing zero padding from the partition files of an unpacked tree,
+/// keeping whole sectors and any filesystem's declared size, and updates
+/// partition-metadata.txt and the checksum file to match.
+pub fn trim_tree(dir: impl AsRef<Path>, dry_run: bool) -> Result<Vec<TrimReport>> {
+    let dir = dir.as_ref();
+    let metadata_path = join_path(dir, "partition-metadata.txt");
+    let metadata = std::fs::read_to_string(&metadata_path)?;
+    let mut reports = Vec::new();
+    let mut new_sizes = HashMap::new();
+
+    for line in metadata.lines() {
+        let parts = metadata_fields(line);
+        if parts.len() < 7 {
+            continue;
+        }
+        let path = normalize_path(parts[1]);
+        let full_path = Path::new(dir).join(&path);
+        let Ok(mut fp) = std::fs::OpenOptions::new().read(true).write(!dry_run).open(&full_path) else {
+            continue;
+        };
+
+        let original_size = fp.metadata()?.len();
+        let end = data_end(&mut fp, original_size)?.div_ceil(FLASH_SECTOR_SIZE) * FLASH_SECTOR_SIZE;
+        let trimmed_size = end.max(filesystem_end(&mut fp)).min(original_size);
+        if trimmed_size == original_size {
+            continue;
+        }
+
+        if !dry_run {
+            fp.set_len(trimmed_size)?;
+            new_sizes.insert(path.clone(), trimmed_size);
+        }
+        reports.push(TrimReport { path, original_size, trimmed_size });
+    }
+
+    if !new_sizes.is_empty() {
+        let mut file = File::create(&metadata_path)?;
+        for line in metadata.lines() {
+            let mut parts: Vec<String> = metadata_fields(line).into_iter().map(str::to_string).collect();
+            if let Some(size) = parts.get(1).and_then(|path| new_sizes.get(&normalize_path(path))) {
+                if parts.len() >= 7 {
+                    parts[6] = format!("{:#010x}", size);
+                }
+            }
+            writeln!(file, "{}", parts.join(","))?;
+        }
+
+        let mut checksums = Vec::new();
+        for path in new_sizes.keys() {
+            checksums.push((path.clone(), md5_file(&Path::new(dir).join(path))?));
+        }
+        record_checksums(dir, &checksums)?;
+    }
+
+    Ok(reports)
+}
@@ -1,30 +1,333 @@
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{Read, Seek, Write};
-use std::path::Path;
-use anyhow::{anyhow, Result};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::error::{AfptoolError, IoContext, Result};
 use chrono::NaiveDateTime;
-use crate::{RKAF_SIGNATURE, RKFW_SIGNATURE, UpdateHeader, RKAFP_MAGIC};
+use serde::Serialize;
+use crate::tr;
+use crate::cancel::CancelToken;
+use crate::chips::{chip_code_to_name, chip_default_code};
+use crate::bootimg::read_boot_image_at;
+use crate::check::{rkaf_crc, rkfw_md5, Md5Trailer};
+use crate::compress::{Compression, OutputFile};
+use crate::tree::{read_checksums, record_checksums, verify_tree_with, CHECKSUMS_FILE};
+use crate::hooks::run_hooks;
+use crate::image::{check_chip_of, cstr_field, read_rkfw_header, rkfw_update_size};
+use crate::manifest::{encode_hex, FirmwareManifest, ManifestSection, UpdateManifest, RKFW_HEADER_SIZE, RKFW_RESERVED_AT};
+use crate::pack::{DESPARSED_MARKER, PARM_BLOB_SUFFIX, PARM_MARKER, PLACEHOLDER_MARKER, RESERVED_MARKER, SELF_MARKER, ZERO_MARKER};
+use crate::paths::{is_contained, join_path, open_file};
+use crate::rkfp::unpack_rkfp;
+use crate::rkcrc::{unwrap_rkcrc, CrcWrapper};
+use crate::scan::ImageKind;
+use crate::sparse::SparseImage;
+use crate::text::{glob_match, is_gbk};
+use crate::timings::phase;
+use crate::{RKAF_SIGNATURE, RKFP_SIGNATURE, RKFW_SIGNATURE, UpdateHeader, UPDATE_HEADER_SIZE};
+
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+const WRITE_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+/// Largest parameter partition checked for a PARM header; real ones are a few KiB.
+const MAX_PARAMETER_SIZE: u64 = 1024 * 1024;
+
+/// Optional behaviour for [`unpack_file_with`].
+#[derive(Debug, Clone, Default)]
+pub struct UnpackOptions {
+    /// Shell commands run after each partition is extracted, with `{name}` and
+    /// `{path}` replaced by the partition name and output path.
+    pub hooks: Vec<String>,
+    /// Only extract the loader (BOOT) from an RKFW image.
+    pub boot_only: bool,
+    /// Only extract the embedded update.img from an RKFW image.
+    pub update_only: bool,
+    /// Don't write partitions that are entirely zero; they are marked in
+    /// partition-metadata.txt and regenerated by pack.
+    pub skip_zero: bool,
+    /// Check the extracted files against their checksums before moving them into place.
+    pub verify: bool,
+    /// Refuse to unpack unless the image is for this chip family.
+    pub expected_chip: Option<String>,
+    /// Stops the unpack between chunks of data; nothing is left in the destination.
+    pub cancel: CancelToken,
+    /// Don't log the header fields and partitions as they are extracted.
+    pub quiet: bool,
+    /// Unpack an image whose RKAF CRC or RKFW MD5 doesn't check out instead
+    /// of refusing it.
+    pub force: bool,
+    /// Unpack an RKFW image whose BOOT section doesn't start with a loader
+    /// signature, as some vendor images don't.
+    pub lenient: bool,
+    /// Only extract partitions whose name or path matches one of these
+    /// patterns (`*` and `?` wildcards). For an RKFW image, `BOOT` and
+    /// `embedded-update.img` select those sections; other patterns are
+    /// looked up among the partitions of the embedded update.img.
+    pub only: Vec<String>,
+    /// Write partition-metadata.txt, package-file and firmware-manifest.json
+    /// even when [`only`](Self::only) selects part of the image.
+    pub with_metadata: bool,
+    /// Unpack the embedded update.img of an RKFW image into the same
+    /// directory instead of writing it out, and split Android boot images
+    /// into their kernel, ramdisk and other sections under `<path>.d/`.
+    pub recursive: bool,
+    /// Expand partitions stored as Android sparse images to raw images
+    /// that can be mounted directly.
+    pub desparse: bool,
+    /// Write each extracted partition through this compressor, adding its
+    /// extension to the file name (`system.img.gz`). package-file is left
+    /// as is.
+    pub compress: Option<Compression>,
+}
+
+impl UnpackOptions {
+    fn selects(&self, name: &str, path: &str) -> bool {
+        self.only.is_empty() || self.only.iter().any(|pattern| glob_match(pattern, name) || glob_match(pattern, path))
+    }
+
+    /// Whether the files needed to pack the tree again are written.
+    fn writes_metadata(&self) -> bool {
+        self.only.is_empty() || self.with_metadata
+    }
+}
+
+/// What [`unpack_file_with`] found in an image and wrote out.
+#[derive(Debug, Clone, Serialize)]
+pub struct UnpackReport {
+    /// [`ImageKind::Rkfw`], [`ImageKind::Rkaf`] or [`ImageKind::Rkfp`].
+    pub kind: ImageKind,
+    /// RKFW firmware version, or the RKAF header version, as `major.minor.build`.
+    pub version: String,
+    /// Chip family from the RKFW header, when it is a known one.
+    pub chip: Option<String>,
+    /// RKFW build time as a Unix timestamp.
+    pub timestamp: Option<i64>,
+    /// RKAF model and manufacturer.
+    pub model: Option<String>,
+    pub manufacturer: Option<String>,
+    /// The other RKAF header fields, as written to update-manifest.json.
+    pub update: Option<UpdateManifest>,
+    /// Files written, in image order; skipped placeholder and all-zero
+    /// partitions are not listed.
+    pub parts: Vec<ExtractedPart>,
+    /// Problems the image was unpacked despite, such as a missing MD5
+    /// trailer or, with [`force`](UnpackOptions::force), a bad CRC.
+    pub warnings: Vec<String>,
+}
+
+/// One file written by an unpack.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExtractedPart {
+    pub name: String,
+    /// Path relative to the destination directory, as stored in the image
+    /// plus the extension of any compression.
+    pub path: String,
+    /// Offset of the data in the image file.
+    pub offset: u64,
+    pub size: u64,
+    /// MD5 of the data, before any compression.
+    pub md5: String,
+}
+
+/// Logs a progress line unless [`UnpackOptions::quiet`] is set.
+macro_rules! say {
+    ($options:expr, $($arg:tt)*) => {
+        if !$options.quiet {
+            log::info!($($arg)*);
+        }
+    };
+}
+pub(crate) use say;
+
+pub fn unpack_file(file_path: impl AsRef<Path>, dst_path: impl AsRef<Path>) -> Result<UnpackReport> {
+    unpack_file_with(file_path, dst_path, &UnpackOptions::default())
+}
+
+/// Extracts into a staging directory next to `dst_path` and only moves the
+/// result into place once everything (hooks and verification included) has
+/// succeeded, so a failed unpack never leaves a half-written tree behind.
+pub fn unpack_file_with(file_path: impl AsRef<Path>, dst_path: impl AsRef<Path>, options: &UnpackOptions) -> Result<UnpackReport> {
+    let file_path = file_path.as_ref();
+    let name = file_path.display().to_string();
+    unpack_named(&mut open_file(file_path)?, &name, dst_path.as_ref(), options)
+}
+
+/// [`unpack_file_with`] for an image that is not a file on disk, such as a
+/// download buffered in memory.
+pub fn unpack_from_reader(reader: &mut (impl Read + Seek), dst_path: impl AsRef<Path>, options: &UnpackOptions) -> Result<UnpackReport> {
+    unpack_named(reader, "Image", dst_path.as_ref(), options)
+}
+
+/// Unpacks `reader`, called `name` in error messages.
+fn unpack_named(reader: &mut (impl Read + Seek), name: &str, dst_path: &Path, options: &UnpackOptions) -> Result<UnpackReport> {
+    if options.boot_only && options.update_only {
+        return Err(AfptoolError::Invalid("Only one of boot-only and update-only can be requested".to_string()));
+    }
+    if let Some(chip) = &options.expected_chip {
+        check_chip_of(reader, name, chip)?;
+    }
+
+    let dst = dst_path;
+    let staging = match dst.file_name() {
+        Some(name) => {
+            let mut staging_name = OsString::from(".");
+            staging_name.push(name);
+            staging_name.push(format!(".partial-{}", std::process::id()));
+            dst.with_file_name(staging_name)
+        }
+        // No name to derive a sibling from (e.g. "."), so extract in place
+        None => return unpack_into(reader, OutputDir { path: dst, shown: dst }, options),
+    };
+    if let Some(parent) = staging.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    log::debug!("Extracting into {}", staging.display());
+    let result = unpack_into(reader, OutputDir { path: &staging, shown: dst }, options)
+        .and_then(|report| move_into_place(&staging, dst).map(|_| report));
+    if staging.exists() {
+        let _ = std::fs::remove_dir_all(&staging);
+    }
+    result
+}
+
+/// Outcome of unpacking one image of an [`unpack_batch`].
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchEntry {
+    /// Image file, relative to the batch input directory.
+    pub input: String,
+    /// Directory the image was unpacked into.
+    pub output: String,
+    /// What was unpacked, when the image was.
+    pub report: Option<UnpackReport>,
+    /// Why the image could not be unpacked.
+    pub error: Option<String>,
+}
+
+/// Unpacks every RKFW, RKAF and RKFP image directly inside `src_dir` into a
+/// subdirectory of `dst_path` named after the image file without its
+/// extension. Other files are left alone, and an image that fails to unpack
+/// is recorded in its entry rather than stopping the rest.
+pub fn unpack_batch(src_dir: impl AsRef<Path>, dst_path: impl AsRef<Path>, options: &UnpackOptions) -> Result<Vec<BatchEntry>> {
+    let (src_dir, dst_path) = (src_dir.as_ref(), dst_path.as_ref());
+    let mut images = Vec::new();
+    let mut children: Vec<_> = std::fs::read_dir(src_dir)
+        .with_context(|| format!("Cannot read directory {}", src_dir.display()))?
+        .collect::<std::io::Result<_>>()?;
+    children.sort_by_key(|entry| entry.file_name());
+    for child in children {
+        let mut signature = [0u8; 4];
+        let recognized = child.file_type()?.is_file()
+            && File::open(child.path()).and_then(|mut fp| fp.read_exact(&mut signature)).is_ok()
+            && [RKFW_SIGNATURE, RKAF_SIGNATURE, RKFP_SIGNATURE].contains(&&signature[..]);
+        if recognized {
+            images.push(PathBuf::from(child.file_name()));
+        }
+    }
+
+    let stem = |name: &Path| name.file_stem().map_or(name.as_os_str(), |stem| stem).to_owned();
+    let mut entries = Vec::new();
+    for name in &images {
+        options.cancel.check()?;
+        // update.img and update.bin would share a directory, so both keep their extension
+        let subdir = match images.iter().filter(|other| stem(other) == stem(name)).count() {
+            1 => stem(name),
+            _ => name.clone().into_os_string(),
+        };
+        let output = dst_path.join(subdir);
+        let (report, error) = match unpack_file_with(src_dir.join(name), &output, options) {
+            Ok(report) => (Some(report), None),
+            Err(e @ AfptoolError::Cancelled(_)) => return Err(e),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        entries.push(BatchEntry { input: name.display().to_string(), output: output.display().to_string(), report, error });
+    }
+    Ok(entries)
+}
+
+/// Where an unpack writes, and the directory its messages name: the output
+/// directory itself while the files go to a staging directory beside it.
+#[derive(Clone, Copy)]
+pub(crate) struct OutputDir<'a> {
+    pub(crate) path: &'a Path,
+    pub(crate) shown: &'a Path,
+}
 
-pub fn unpack_file(file_path: &str, dst_path: &str) -> Result<()> {
-    let mut file = File::open(file_path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
+impl OutputDir<'_> {
+    pub(crate) fn join(&self, relative: &str) -> PathBuf {
+        join_path(self.path, relative)
+    }
+
+    pub(crate) fn shown(&self, relative: &str) -> String {
+        join_path(self.shown, relative).display().to_string()
+    }
+}
+
+fn unpack_into(file: &mut (impl Read + Seek), dst: OutputDir, options: &UnpackOptions) -> Result<UnpackReport> {
+    let mut buffer = [0u8; RKFW_HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    let header_len = file.read(&mut buffer)?;
+    if header_len < 4 {
+        return Err(AfptoolError::Parse("File is too small to be a firmware image".to_string()));
+    }
 
     let signature = &buffer[0..4];
-    match signature {
-        RKAF_SIGNATURE => unpack_rkafp(file_path, dst_path)?,
-        RKFW_SIGNATURE => unpack_rkfw(&buffer, dst_path)?,
+    let report = match signature {
+        RKAF_SIGNATURE => unpack_rkafp(file, dst, options)?,
+        RKFW_SIGNATURE if header_len < RKFW_HEADER_SIZE => {
+            return Err(AfptoolError::Parse(format!("RKFW header is truncated: {} of {} bytes", header_len, RKFW_HEADER_SIZE)));
+        }
+        RKFW_SIGNATURE => unpack_rkfw(file, &buffer, dst, options)?,
+        RKFP_SIGNATURE => unpack_rkfp(file, dst, options)?,
         _ => {
-            return Err(anyhow!("Unknown signature: {:?}", signature));
+            return Err(AfptoolError::Parse(format!("Unknown signature: {:?}", signature)));
         }
+    };
+    let unmatched: Vec<&str> = options.only.iter()
+        .filter(|pattern| !report.parts.iter().any(|part| glob_match(pattern, &part.name) || glob_match(pattern, &part.path)))
+        .map(String::as_str)
+        .collect();
+    if !unmatched.is_empty() {
+        return Err(AfptoolError::Invalid(format!("Nothing extracted for --only {}", unmatched.join(","))));
     }
-    Ok(())
+
+    if options.verify {
+        verify_tree_with(dst.path, &options.cancel)?;
+    }
+    Ok(report)
 }
 
-fn unpack_rkfw(buf: &[u8], dst_path: &str) -> Result<()> {
-    let mut chip: Option<&str> = None;
+/// Moves a finished staging tree to `dst`, merging it into an existing
+/// directory the same way unpacking there directly would.
+fn move_into_place(staging: &Path, dst: &Path) -> Result<()> {
+    if !dst.exists() {
+        std::fs::rename(staging, dst)?;
+        return Ok(());
+    }
+
+    let checksums = read_checksums(&staging.join(CHECKSUMS_FILE))?;
+    std::fs::remove_file(staging.join(CHECKSUMS_FILE)).ok();
+    merge_dir(staging, dst)?;
+    record_checksums(dst, &checksums.into_iter().collect::<Vec<_>>())
+}
+
+fn merge_dir(src: &Path, dst: &Path) -> Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() && target.is_dir() {
+            merge_dir(&entry.path(), &target)?;
+        } else {
+            if target.is_dir() {
+                std::fs::remove_dir_all(&target)?;
+            }
+            std::fs::rename(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
 
-    println!("RKFW signature detected");
+fn unpack_rkfw(fp: &mut (impl Read + Seek), buf: &[u8], dst: OutputDir, options: &UnpackOptions) -> Result<UnpackReport> {
+    say!(options, "{}", tr!("unpack-rkfw-detected"));
 
     let version_str = format!(
         "{}.{}.{}",
@@ -32,10 +335,21 @@ fn unpack_rkfw(buf: &[u8], dst_path: &str) -> Result<()> {
         buf[8],
         ((buf[7] as u16) << 8) + buf[6] as u16
     );
-    println!("version: {}", version_str);
+    say!(options, "{}", tr!("unpack-version", version = version_str));
+    let mut report = UnpackReport {
+        kind: ImageKind::Rkfw,
+        version: version_str,
+        chip: None,
+        timestamp: None,
+        model: None,
+        manufacturer: None,
+        update: None,
+        parts: Vec::new(),
+        warnings: Vec::new(),
+    };
 
     let code = u32::from_le_bytes([buf[0x0a], buf[0x0b], buf[0x0c], buf[0x0d]]);
-    println!("code field: 0x{:08x}", code);
+    say!(options, "{}", tr!("unpack-code", code = format!("0x{:08x}", code)));
 
     let year = ((buf[0x0f] as u16) << 8) | (buf[0x0e] as u16);
     let month = buf[0x10];
@@ -45,141 +359,404 @@ fn unpack_rkfw(buf: &[u8], dst_path: &str) -> Result<()> {
     let second = buf[0x14];
 
     let date = chrono::NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)
-        .ok_or_else(|| anyhow!("Invalid date"))?;
+        .ok_or_else(|| AfptoolError::Parse("Invalid date in RKFW header".to_string()))?;
     let time = chrono::NaiveTime::from_hms_opt(hour as u32, minute as u32, second as u32)
-        .ok_or_else(|| anyhow!("Invalid time"))?;
+        .ok_or_else(|| AfptoolError::Parse("Invalid time in RKFW header".to_string()))?;
     let dt = NaiveDateTime::new(date, time);
     let unix_timestamp = dt.and_utc().timestamp();
-
-    println!(
-        "date: {}-{:02}-{:02} {:02}:{:02}:{:02} (Unix timestamp: {})",
-        year, month, day, hour, minute, second, unix_timestamp
+    report.timestamp = Some(unix_timestamp);
+
+    say!(
+        options,
+        "{}",
+        tr!(
+            "unpack-date",
+            date = format!("{}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second),
+            timestamp = unix_timestamp
+        )
     );
 
-    match buf[0x15] {
-        0x50 => chip = Some("RK29xx"),
-        0x60 => chip = Some("RK30xx"),
-        0x70 => chip = Some("RK31xx"),
-        0x80 => chip = Some("RK32xx"),
-        0x41 => chip = Some("RK3368"),
-        0x36 => chip = Some("RK3326"),
-        0x32 => chip = Some("RK3562"),
-        0x38 => chip = Some("RK3566"),
-        0x30 => chip = Some("PX30"),
-        _ => println!(
-            "You got a brand new chip ({:#x}), congratulations!!!",
-            buf[0x15]
-        ),
+    let chip_code = get_u32_le(&buf[0x15..]);
+    let chip = chip_code_to_name(chip_code);
+    if chip.is_none() {
+        say!(options, "{}", tr!("unpack-new-chip", code = format!("{:#x}", chip_code)));
     }
 
+    report.chip = chip.map(str::to_string);
     let chip_name = chip.unwrap_or("unknown");
-    println!("family: {}", chip_name);
+    say!(options, "{}", tr!("unpack-family", family = chip_name));
+    match chip_default_code(chip_code) {
+        Some(typical) if typical == code => say!(options, "{}", tr!("unpack-code-usual")),
+        Some(typical) => say!(options, "{}", tr!("unpack-code-unusual", typical = format!("0x{:08x}", typical), code = format!("0x{:08x}", code))),
+        None => {}
+    }
+
+    if let Some(header) = read_rkfw_header(fp)? {
+        match rkfw_md5(fp, &header)? {
+            Md5Trailer::Match(md5) => say!(options, "{}", tr!("unpack-md5-ok", md5 = md5)),
+            Md5Trailer::Missing(_) => report.warnings.push(tr!("unpack-md5-missing")),
+            Md5Trailer::Mismatch { stored, computed } => {
+                let damage = tr!("unpack-bad-md5", stored = stored, computed = computed);
+                if !options.force {
+                    return Err(AfptoolError::Checksum(format!("{}{}", damage, tr!("unpack-force-hint"))));
+                }
+                report.warnings.push(damage);
+            }
+        }
+    }
+
+    let file_len = fp.seek(SeekFrom::End(0))?;
+    for (section, offset_at) in [("BOOT", 0x19), ("embedded-update.img", 0x21)] {
+        let (offset, size) = (get_u32_le(&buf[offset_at..]) as u64, get_u32_le(&buf[offset_at + 4..]) as u64);
+        if offset + size > file_len {
+            return Err(AfptoolError::Parse(format!(
+                "RKFW header puts {} at {:#x}+{:#x}, past the end of the {:#x}-byte image", section, offset, size, file_len
+            )));
+        }
+    }
 
     let ioff = get_u32_le(&buf[0x19..]);
     let isize: u32 = get_u32_le(&buf[0x1d..]);
 
-    // if &buf[ioff as usize..ioff as usize + 4] != b"BOOT" {
-    //     panic!("cannot find BOOT signature");
-    // }
+    let signature = read_signature(fp, ioff as u64)?;
+    if signature.as_slice() != b"BOOT" && signature.as_slice() != b"LDR " {
+        let damage = tr!("unpack-bad-boot", offset = format!("{:#x}", ioff), found = describe_signature(&signature));
+        if !options.lenient {
+            return Err(AfptoolError::Parse(format!("{}{}", damage, tr!("unpack-lenient-hint"))));
+        }
+        report.warnings.push(damage);
+    }
 
-    println!(
-        "{:08x}-{:08x} {:26} (size: {})",
-        ioff,
-        ioff + isize - 1,
-        "BOOT",
-        isize
-    );
-    std::fs::create_dir_all(dst_path)?;
-    write_file(
-        &Path::new(&format!("{}/BOOT", dst_path)),
-        &buf[ioff as usize..ioff as usize + (isize as usize)],
-    )?;
+    std::fs::create_dir_all(dst.path)?;
+    let mut checksums = Vec::new();
+
+    if !options.update_only && options.selects("BOOT", "BOOT") {
+        say!(
+            options,
+            "{:08x}-{:08x} {:26} (size: {})",
+            ioff,
+            (ioff as u64 + isize as u64).saturating_sub(1),
+            "BOOT",
+            isize
+        );
+        let boot_path = dst.join("BOOT");
+        let boot_digest = copy_out(fp, ioff as u64, isize as u64, &boot_path, &options.cancel)?;
+        checksums.push(("BOOT".to_string(), boot_digest.clone()));
+        report.parts.push(ExtractedPart {
+            name: "BOOT".to_string(),
+            path: "BOOT".to_string(),
+            offset: ioff as u64,
+            size: isize as u64,
+            md5: boot_digest,
+        });
+        run_hooks(&options.hooks, "BOOT", &boot_path)?;
+    }
 
     let ioff = get_u32_le(&buf[0x21..]);
-    let isize = get_u32_le(&buf[0x25..]);
+    let isize = rkfw_update_size(buf);
+
+    let selects_update = !options.boot_only && options.selects("embedded-update.img", "embedded-update.img");
+    // A recursive unpack expands the embedded image unless it was asked for by name
+    let wants_partitions = !options.boot_only && if selects_update {
+        options.recursive && options.only.is_empty()
+    } else {
+        options.only.iter().any(|pattern| !glob_match(pattern, "BOOT") && !glob_match(pattern, "embedded-update.img"))
+    };
+    if selects_update || wants_partitions {
+        let signature = read_signature(fp, ioff as u64)?;
+        if signature.as_slice() != RKAF_SIGNATURE {
+            return Err(AfptoolError::Parse(tr!(
+                "unpack-no-rkaf", offset = format!("{:#x}", ioff), found = describe_signature(&signature)
+            )));
+        }
+    }
+    if wants_partitions {
+        // Pull the selected partitions straight out of the embedded image
+        let inner = unpack_rkafp(&mut Window::new(fp, ioff as u64, isize), dst, options)?;
+        (report.model, report.manufacturer, report.update) = (inner.model, inner.manufacturer, inner.update);
+        report.warnings.extend(inner.warnings);
+        for mut part in inner.parts {
+            part.offset += ioff as u64;
+            report.parts.push(part);
+        }
+    } else if selects_update {
+        say!(
+            options,
+            "{:08x}-{:08x} {:26} (size: {})",
+            ioff,
+            (ioff as u64 + isize).saturating_sub(1),
+            "embedded-update.img",
+            isize
+        );
+        let update_path = dst.join("embedded-update.img");
+        let update_digest = copy_out(fp, ioff as u64, isize, &update_path, &options.cancel)?;
+        checksums.push(("embedded-update.img".to_string(), update_digest.clone()));
+        report.parts.push(ExtractedPart {
+            name: "embedded-update.img".to_string(),
+            path: "embedded-update.img".to_string(),
+            offset: ioff as u64,
+            size: isize,
+            md5: update_digest,
+        });
+        run_hooks(&options.hooks, "embedded-update.img", &update_path)?;
+    }
 
-    if &buf[ioff as usize..ioff as usize + 4] != b"RKAF" {
-        panic!("cannot find embedded RKAF update.img");
+    let section = |offset_at: usize, size: u64| ManifestSection {
+        offset: get_u32_le(&buf[offset_at..]) as u64,
+        size,
+    };
+    let manifest = FirmwareManifest {
+        chip: report.chip.clone(),
+        chip_code,
+        version: report.version.clone(),
+        timestamp: unix_timestamp,
+        code: format!("0x{:08x}", code),
+        boot: section(0x19, get_u32_le(&buf[0x1d..]) as u64),
+        update: section(0x21, isize),
+        model: report.model.clone(),
+        manufacturer: report.manufacturer.clone(),
+        reserved: Some(encode_hex(&buf[RKFW_RESERVED_AT..RKFW_HEADER_SIZE])),
+    };
+    if options.writes_metadata() {
+        manifest.save(dst.path)?;
     }
 
-    println!(
-        "{:08x}-{:08x} {:26} (size: {})",
-        ioff,
-        ioff + isize - 1,
-        "embedded-update.img",
-        isize
-    );
-    write_file(
-        &Path::new(&format!("{}/embedded-update.img", dst_path)),
-        &buf[ioff as usize..ioff as usize + isize as usize],
-    )?;
-    Ok(())
+    record_checksums(dst.path, &checksums)?;
+    Ok(report)
 }
 
-fn extract_file(fp: &mut File, offset: u64, len: u64, full_path: &str) -> Result<()> {
-    println!("{:08x}-{:08x} {}", offset, len, full_path);
-    let mut buffer = vec![0u8; 16 * 1024];
-    let mut fp_out = File::create(full_path)?;
+/// The text of a parameter partition stored as a `PARM` header, the text and
+/// its CRC with nothing after them, so that packing wraps it back to the
+/// same bytes. Anything else is `None` and extracted as it is.
+fn read_parm_text(fp: &mut (impl Read + Seek), offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+    if !(12..=MAX_PARAMETER_SIZE).contains(&len) {
+        return Ok(None);
+    }
+    let mut data = vec![0u8; len as usize];
+    fp.seek(SeekFrom::Start(offset))?;
+    fp.read_exact(&mut data)?;
+    Ok(match unwrap_rkcrc(&data) {
+        Ok((CrcWrapper::Parm, text)) if text.len() + 12 == data.len() => Some(text.to_vec()),
+        _ => None,
+    })
+}
+
+/// A byte range of another reader, seen as a file of its own, so an
+/// embedded update.img can be read in place.
+struct Window<'a, R> {
+    inner: &'a mut R,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<'a, R: Read + Seek> Window<'a, R> {
+    fn new(inner: &'a mut R, start: u64, len: u64) -> Self {
+        Window { inner, start, len, pos: 0 }
+    }
+}
+
+impl<R: Read + Seek> Read for Window<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.len.saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        if available == 0 {
+            return Ok(0);
+        }
+        self.inner.seek(SeekFrom::Start(self.start + self.pos))?;
+        let read = self.inner.read(&mut buf[..available])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for Window<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = target.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start of the window"))?;
+        Ok(self.pos)
+    }
+}
+
+/// Up to four bytes at `offset`, fewer when the file ends first.
+fn read_signature(fp: &mut (impl Read + Seek), offset: u64) -> Result<Vec<u8>> {
+    let mut signature = Vec::with_capacity(4);
+    fp.seek(SeekFrom::Start(offset))?;
+    fp.take(4).read_to_end(&mut signature)?;
+    Ok(signature)
+}
+
+/// Shows bytes found where a signature was expected, e.g. `"MZ\0\0" (4d 5a 00 00)`.
+fn describe_signature(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "the end of the file".to_string();
+    }
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{:?} ({})", String::from_utf8_lossy(bytes), hex.join(" "))
+}
+
+fn is_zero_region(fp: &mut (impl Read + Seek), offset: u64, len: u64, cancel: &CancelToken) -> Result<bool> {
+    let _phase = phase("io");
+    let mut buffer = vec![0u8; 64 * 1024];
+    fp.seek(SeekFrom::Start(offset))?;
+
+    let mut remaining = len;
+    while remaining > 0 {
+        cancel.check()?;
+        let read_len = std::cmp::min(remaining as usize, buffer.len());
+        fp.read_exact(&mut buffer[..read_len])
+            .map_err(|_| AfptoolError::Parse("Insufficient length in container image file".to_string()))?;
+        if buffer[..read_len].iter().any(|&b| b != 0) {
+            return Ok(false);
+        }
+        remaining -= read_len as u64;
+    }
+    Ok(true)
+}
+
+pub(crate) fn extract_file(fp: &mut (impl Read + Seek), offset: u64, len: u64, dst: OutputDir, path: &str, options: &UnpackOptions) -> Result<String> {
+    say!(options, "{:08x}-{:08x} {}", offset, len, dst.shown(path));
+    copy_out(fp, offset, len, &dst.join(path), &options.cancel)
+}
+
+/// Copies `len` bytes at `offset` into a new file, returning their MD5.
+fn copy_out(fp: &mut (impl Read + Seek), offset: u64, len: u64, full_path: &Path, cancel: &CancelToken) -> Result<String> {
+    // Several reads are gathered into each write, which matters on network shares and spinning disks
+    let fp_out = OutputFile::create(full_path, WRITE_BUFFER_SIZE, None)?;
+    copy_into(fp, offset, len, fp_out, cancel).map(|(digest, _)| digest)
+}
+
+/// Copies `len` bytes at `offset` into `fp_out`, returning their MD5 and
+/// that of the file when it is compressed.
+fn copy_into(fp: &mut (impl Read + Seek), offset: u64, len: u64, mut fp_out: OutputFile, cancel: &CancelToken) -> Result<(String, Option<String>)> {
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    let mut context = md5::Context::new();
 
     fp.seek(std::io::SeekFrom::Start(offset))?;
 
     let mut remaining = len;
 
     while remaining > 0 {
+        cancel.check()?;
         let read_len = std::cmp::min(remaining as usize, buffer.len());
-        let read_bytes = fp.read(&mut buffer[..read_len])?;
-
-        if read_bytes != read_len {
-            return Err(anyhow!("Insufficient length in container image file"));
+        {
+            let _phase = phase("io");
+            fp.read_exact(&mut buffer[..read_len])
+                .map_err(|_| AfptoolError::Parse("Insufficient length in container image file".to_string()))?;
+            fp_out.write_all(&buffer[..read_len])?;
         }
-
-        fp_out.write_all(&buffer[..read_len])?;
+        let _phase = phase("hash");
+        context.consume(&buffer[..read_len]);
 
         remaining -= read_len as u64;
     }
+    let _phase = phase("io");
+    let file_digest = fp_out.finish()?;
 
-    Ok(())
+    Ok((format!("{:x}", context.finalize()), file_digest))
 }
 
-fn unpack_rkafp(file_path: &str, dst_path: &str) -> Result<()> {
-    use std::mem;
+/// Writes the expanded contents of a sparse image into `fp_out`, returning
+/// their MD5 and that of the file when it is compressed.
+fn desparse_out(fp: &mut (impl Read + Seek), sparse: &SparseImage, mut fp_out: OutputFile, cancel: &CancelToken) -> Result<(String, Option<String>)> {
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    let mut context = md5::Context::new();
+    let mut offset = 0;
+    while offset < sparse.size {
+        cancel.check()?;
+        let chunk = &mut buffer[..(sparse.size - offset).min(READ_BUFFER_SIZE as u64) as usize];
+        sparse.read_at(fp, offset, chunk)?;
+        context.consume(&*chunk);
+        fp_out.write_all(chunk)?;
+        offset += chunk.len() as u64;
+    }
+    let file_digest = fp_out.finish()?;
+    Ok((format!("{:x}", context.finalize()), file_digest))
+}
 
-    let mut fp = File::open(file_path)?;
-    let mut buf = vec![0u8; mem::size_of::<UpdateHeader>()];
+fn unpack_rkafp(fp: &mut (impl Read + Seek), dst: OutputDir, options: &UnpackOptions) -> Result<UnpackReport> {
+    let parsing = phase("parse");
+    fp.seek(SeekFrom::Start(0))?;
+    let mut buf = vec![0u8; UPDATE_HEADER_SIZE];
     fp.read_exact(&mut buf)?;
-    let header = UpdateHeader::from_bytes(buf.as_mut());
-    let magic_str = std::str::from_utf8(&header.magic)?;
-    if magic_str != RKAFP_MAGIC {
-        return Err(anyhow!("Invalid header magic id"));
+    let header = UpdateHeader::from_bytes(&buf)?;
+    drop(parsing);
+
+    let filesize = fp.seek(SeekFrom::End(0))?;
+    say!(options, "{}", tr!("unpack-filesize", size = filesize));
+    let length = header.image_length()?;
+    // A length that doesn't match the file means the image was cut short or
+    // padded, and a CRC that doesn't match means the data is damaged
+    let damage = if filesize.checked_sub(4) != Some(length) {
+        let mismatch = tr!("unpack-bad-length", length = length, size = filesize);
+        if !options.force {
+            return Err(AfptoolError::Parse(format!("{}{}", mismatch, tr!("unpack-force-hint"))));
+        }
+        Some(mismatch)
+    } else {
+        match rkaf_crc(fp, 0, length, &options.cancel)? {
+            (computed, Some(stored)) if stored == computed => None,
+            (computed, stored) => {
+                let mismatch = tr!(
+                    "unpack-bad-crc",
+                    stored = format!("{:#010x}", stored.unwrap_or(0)),
+                    computed = format!("{:#010x}", computed)
+                );
+                if !options.force {
+                    return Err(AfptoolError::Checksum(format!("{}{}", mismatch, tr!("unpack-force-hint"))));
+                }
+                Some(mismatch)
+            }
+        }
+    };
+    std::fs::create_dir_all(dst.path)?;
+    if options.writes_metadata() {
+        std::fs::create_dir_all(dst.join("Image"))?;
     }
+    let manufacturer = cstr_field(&header.manufacturer);
+    let model = cstr_field(&header.model);
 
-    let filesize = fp.metadata()?.len();
-    println!("Filesize: {}", filesize);
-    if filesize - 4 != header.length as u64 {
-        eprintln!("update_header.length cannot be correct, cannot check CRC");
+    say!(options, "{}", tr!("unpack-manufacturer", manufacturer = manufacturer));
+    say!(options, "{}", tr!("unpack-model", model = model));
+    if is_gbk(&header.manufacturer) || is_gbk(&header.model) {
+        say!(options, "{}", tr!("unpack-gbk"));
     }
-    std::fs::create_dir_all(format!("{}/Image", dst_path))?;
-    // 安全地从null-terminated字符串中提取文本
-    let manufacturer = std::ffi::CStr::from_bytes_until_nul(&header.manufacturer)
-        .map(|s| s.to_string_lossy())
-        .unwrap_or_else(|_| "unknown".into());
-    let model = std::ffi::CStr::from_bytes_until_nul(&header.model)
-        .map(|s| s.to_string_lossy())
-        .unwrap_or_else(|_| "unknown".into());
-
-    println!("manufacturer: {}", manufacturer);
-    println!("model: {}", model);
+    say!(options, "{}", tr!("unpack-machine-id", id = header.id()));
+    say!(options, "{}", tr!(
+        "unpack-rkaf-header",
+        version = header.version_text(),
+        unknown1 = format!("{:#010x}", header.unknown1),
+        length = length,
+        parts = header.num_parts
+    ));
+    let mut report = UnpackReport {
+        kind: ImageKind::Rkaf,
+        version: header.version_text(),
+        chip: None,
+        timestamp: None,
+        model: Some(header.model_name()),
+        manufacturer: Some(header.manufacturer_name()),
+        update: Some(UpdateManifest::from_header(&header)),
+        parts: Vec::new(),
+        warnings: damage.into_iter().collect(),
+    };
 
     // Save partition metadata for repacking
-    let metadata_path = format!("{}/partition-metadata.txt", dst_path);
-    let mut metadata_file = File::create(&metadata_path)?;
+    let metadata_path = dst.join("partition-metadata.txt");
+    let mut metadata_file = match options.writes_metadata() {
+        true => Some(BufWriter::new(File::create(&metadata_path)?)),
+        false => None,
+    };
+    let mut checksums = Vec::new();
 
     for i in 0..header.num_parts {
         let part = &header.parts[i as usize];
-        // 安全地提取路径字符串
         if let Ok(cstr_path) = std::ffi::CStr::from_bytes_until_nul(&part.full_path) {
             let part_full_path = cstr_path.to_string_lossy();
-            if part_full_path == "SELF" || part_full_path == "RESERVED" {
+            if part_full_path == SELF_MARKER || part_full_path == RESERVED_MARKER {
                 continue;
             }
 
@@ -188,37 +765,204 @@ fn unpack_rkafp(file_path: &str, dst_path: &str) -> Result<()> {
             } else {
                 String::new()
             };
+            let selected = options.selects(&part_name, &part_full_path);
+            if !selected && metadata_file.is_none() {
+                continue;
+            }
 
             let flash_size = part.flash_size;
             let flash_offset = part.flash_offset;
-            let part_offset = part.part_offset;
+            let part_offset = part.offset();
             let padded_size = part.padded_size;
-            let part_byte_count = part.part_byte_count;
+            let part_byte_count = part.byte_count();
+
+            let placeholder = part.is_placeholder();
+            if part_offset.checked_add(part_byte_count).is_none_or(|end| end > filesize) {
+                return Err(AfptoolError::Parse(format!(
+                    "Partition {} at {:#x}+{:#x} runs past the end of the {:#x}-byte image",
+                    part_name, part_offset, part_byte_count, filesize
+                )));
+            }
+            let zero = part_byte_count > 0 && is_zero_region(fp, part_offset, part_byte_count, &options.cancel)?;
+            let parameter_text = match part_name.as_str() {
+                "parameter" if !zero => read_parm_text(fp, part_offset, part_byte_count)?,
+                _ => None,
+            };
+            let sparse = match options.desparse && selected && !zero && !placeholder {
+                true => SparseImage::parse(fp, part_offset, part_byte_count).map_err(|e| e.within(&part_full_path))?,
+                false => None,
+            };
+            let marker = if placeholder {
+                format!(",{}", PLACEHOLDER_MARKER)
+            } else if zero {
+                format!(",{}", ZERO_MARKER)
+            } else if parameter_text.is_some() {
+                format!(",{}", PARM_MARKER)
+            } else if sparse.is_some() {
+                format!(",{}", DESPARSED_MARKER)
+            } else {
+                String::new()
+            };
+            if let Some(metadata_file) = metadata_file.as_mut() {
+                writeln!(
+                    metadata_file,
+                    "{},{},{:#010x},{:#010x},{:#010x},{:#010x},{:#010x}{}",
+                    part_name,
+                    part_full_path,
+                    flash_size,
+                    flash_offset,
+                    part_offset,
+                    padded_size,
+                    part_byte_count,
+                    marker
+                )?;
+            }
 
-            writeln!(
-                metadata_file,
-                "{},{},{:#010x},{:#010x},{:#010x},{:#010x},{:#010x}",
-                part_name,
-                part_full_path,
-                flash_size,
-                flash_offset,
-                part_offset,
-                padded_size,
-                part_byte_count
-            )?;
+            if !selected {
+                continue;
+            }
+            if placeholder {
+                say!(options, "{}", tr!("unpack-placeholder-skipped", name = part_name));
+                continue;
+            }
+
+            if zero && options.skip_zero {
+                say!(options, "{}", tr!("unpack-zero-skipped", path = part_full_path));
+                continue;
+            }
 
-            let part_full_path = format!("{}/{}", dst_path, part_full_path);
-            extract_file(
-                &mut fp,
-                part.part_offset as u64,
-                part.part_byte_count as u64,
-                &part_full_path,
-            )?;
+            if !is_contained(&part_full_path) {
+                return Err(AfptoolError::Parse(format!(
+                    "Partition {} has unsafe path {:?} that would leave the output directory", part_name, part_full_path
+                )));
+            }
+            let output_path = dst.join(&part_full_path);
+            if let Some(parent) = output_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            if let Some(text) = parameter_text {
+                // The blob as stored, next to the text that pack wraps again
+                let blob_path = format!("{}{}", part_full_path, PARM_BLOB_SUFFIX);
+                let blob_digest = extract_file(fp, part_offset, part_byte_count, dst, &blob_path, options)?;
+                checksums.push((blob_path.clone(), blob_digest.clone()));
+                report.parts.push(ExtractedPart {
+                    name: part_name.clone(),
+                    path: blob_path,
+                    offset: part_offset,
+                    size: part_byte_count,
+                    md5: blob_digest,
+                });
+                std::fs::write(&output_path, &text)?;
+                say!(options, "{}", tr!("unpack-parm-stripped", path = dst.shown(&part_full_path)));
+                let mut context = md5::Context::new();
+                context.consume(&text);
+                let digest = format!("{:x}", context.finalize());
+                checksums.push((part_full_path.to_string(), digest.clone()));
+                report.parts.push(ExtractedPart {
+                    name: part_name.clone(),
+                    path: part_full_path.to_string(),
+                    offset: part_offset + 8,
+                    size: text.len() as u64,
+                    md5: digest,
+                });
+                run_hooks(&options.hooks, &part_name, &output_path)?;
+                continue;
+            }
+            // package-file stays plain, as packing reads it first
+            let compression = options.compress.filter(|_| part_full_path != "package-file");
+            let (stored_path, output_path) = match compression {
+                Some(compression) => {
+                    let stored_path = format!("{}{}", part_full_path, compression.extension());
+                    let output_path = dst.join(&stored_path);
+                    (stored_path, output_path)
+                }
+                None => (part_full_path.to_string(), output_path),
+            };
+            let fp_out = OutputFile::create(&output_path, WRITE_BUFFER_SIZE, compression)?;
+            let (digest, file_digest) = match &sparse {
+                Some(sparse) => {
+                    say!(options, "{}", tr!("unpack-desparsed", path = stored_path, size = sparse.size));
+                    desparse_out(fp, sparse, fp_out, &options.cancel)?
+                }
+                None => {
+                    // The summary table after the unpack gives the layout; the ranges are detail
+                    if !options.quiet {
+                        log::debug!("{:08x}-{:08x} {}", part_offset, part_byte_count, dst.shown(&stored_path));
+                    }
+                    copy_into(fp, part_offset, part_byte_count, fp_out, &options.cancel)?
+                }
+            };
+            checksums.push((stored_path.clone(), file_digest.unwrap_or_else(|| digest.clone())));
+            report.parts.push(ExtractedPart {
+                name: part_name.clone(),
+                path: stored_path,
+                offset: part_offset,
+                size: sparse.as_ref().map_or(part_byte_count, |sparse| sparse.size),
+                md5: digest,
+            });
+            run_hooks(&options.hooks, &part_name, &output_path)?;
+            if options.recursive {
+                let sections = split_boot_image(fp, (part_offset, part_byte_count), (&part_name, &part_full_path), dst, options)?;
+                for section in sections {
+                    checksums.push((section.path.clone(), section.md5.clone()));
+                    report.parts.push(section);
+                }
+            }
         }
     }
 
-    println!("\nPartition metadata saved to: {}", metadata_path);
+    record_checksums(dst.path, &checksums)?;
+    let Some(mut metadata_file) = metadata_file else {
+        return Ok(report);
+    };
+    metadata_file.flush()?;
+    UpdateManifest::from_header(&header).save(dst.path)?;
+
+    // Images built by afptool carry their package-file; others get one
+    // rebuilt from the header so the tree can be packed again as it is
+    let entries: Vec<(String, String)> = header.parts[..header.num_parts as usize]
+        .iter()
+        .map(|part| (cstr_field(&part.name), cstr_field(&part.full_path)))
+        .collect();
+    if !entries.iter().any(|(_, full_path)| full_path == "package-file") {
+        write_package_file(&dst.join("package-file"), &entries)?;
+        say!(options, "{}", tr!("unpack-package-file-written", path = dst.shown("package-file")));
+    }
+
+    say!(options, "\n{}", tr!("unpack-metadata-saved", path = dst.shown("partition-metadata.txt")));
 
+    Ok(report)
+}
+
+/// Writes each section of a partition holding an Android boot image to
+/// `<path>.d/<section>`; other partitions are left alone.
+fn split_boot_image(fp: &mut (impl Read + Seek), (offset, len): (u64, u64), (name, path): (&str, &str), dst: OutputDir, options: &UnpackOptions) -> Result<Vec<ExtractedPart>> {
+    let Some(boot) = read_boot_image_at(fp, offset, len, None).map_err(|e| e.within(path))? else {
+        return Ok(Vec::new());
+    };
+    say!(options, "{}", tr!("unpack-boot-image", path = path, version = boot.header_version));
+    let dir = format!("{}.d", path);
+    std::fs::create_dir_all(dst.join(&dir))?;
+    let mut parts = Vec::new();
+    for section in boot.sections {
+        let section_path = format!("{}/{}", dir, section.name);
+        let at = offset + section.offset;
+        let md5 = extract_file(fp, at, section.size, dst, &section_path, options)?;
+        parts.push(ExtractedPart { name: format!("{}/{}", name, section.name), path: section_path, offset: at, size: section.size, md5 });
+    }
+    Ok(parts)
+}
+
+/// Writes a package-file listing `entries` (name, path) in image order, in
+/// the layout afptool's own package-file uses.
+fn write_package_file(path: &Path, entries: &[(String, String)]) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "# NAME\tRelative path")?;
+    writeln!(out, "#")?;
+    for (name, full_path) in entries {
+        writeln!(out, "{}\t{}", name, full_path)?;
+    }
+    out.flush()?;
     Ok(())
 }
 
@@ -226,8 +970,4 @@ fn get_u32_le(slice: &[u8]) -> u32 {
     u32::from_le_bytes([slice[0], slice[1], slice[2], slice[3]])
 }
 
-fn write_file(path: &Path, buffer: &[u8]) -> Result<()> {
-    let mut file = File::create(path)?;
-    file.write_all(buffer)?;
-    Ok(())
-}
+
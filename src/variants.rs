@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::error::{AfptoolError, IoContext, Result};
+use serde::Deserialize;
+use crate::cancel::CancelToken;
+use crate::compress::copy_decompressed;
+use crate::pack::{finish_rkaf, layout_rkaf, DataSource, RkafOptions};
+use crate::paths::with_suffix;
+
+/// A build manifest describing several RKAF images packed from one input tree.
+///
+/// ```json
+/// {
+///   "input": "tree",
+///   "model": "RK3326",
+///   "manufacturer": "RK3326",
+///   "variants": [
+///     { "name": "board-a", "output": "out/board-a.img" },
+///     { "name": "board-b", "output": "out/board-b.img",
+///       "files": { "parameter": "boards/b/parameter.txt" } }
+///   ]
+/// }
+/// ```
+///
+/// Relative paths are resolved against the manifest's directory.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BuildManifest {
+    pub input: PathBuf,
+    #[serde(default)]
+    pub model: String,
+    #[serde(default)]
+    pub manufacturer: String,
+    #[serde(default)]
+    pub defines: BTreeMap<String, String>,
+    pub variants: Vec<Variant>,
+}
+
+/// One output image of a [`BuildManifest`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct Variant {
+    pub name: String,
+    pub output: PathBuf,
+    pub model: Option<String>,
+    pub manufacturer: Option<String>,
+    /// Replacement files keyed by partition name.
+    #[serde(default)]
+    pub files: BTreeMap<String, PathBuf>,
+    /// Extra `${NAME}` values, taking precedence over the manifest's.
+    #[serde(default)]
+    pub defines: BTreeMap<String, String>,
+}
+
+impl BuildManifest {
+    pub fn load(path: impl AsRef<Path>) -> Result<BuildManifest> {
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read build manifest {}", path.display()))?;
+        let mut manifest: BuildManifest = serde_json::from_str(&data)
+            .map_err(|e| AfptoolError::Parse(format!("Invalid build manifest {}: {}", path.display(), e)))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        let resolve = |p: &Path| base.join(p);
+        manifest.input = resolve(&manifest.input);
+        for variant in &mut manifest.variants {
+            variant.output = resolve(&variant.output);
+            for file in variant.files.values_mut() {
+                *file = resolve(file);
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+/// Packs every variant of a build manifest. Files shared between variants are
+/// read once and written to all outputs that contain them.
+pub fn build_variants(manifest_path: impl AsRef<Path>) -> Result<Vec<(String, PathBuf)>> {
+    let manifest_path = manifest_path.as_ref();
+    let manifest = BuildManifest::load(manifest_path)?;
+    if manifest.variants.is_empty() {
+        return Err(AfptoolError::Layout(format!("Build manifest {} lists no variants", manifest_path.display())));
+    }
+
+    let mut layouts = Vec::new();
+    for variant in &manifest.variants {
+        let mut defines: Vec<(String, String)> = manifest.defines.clone().into_iter().collect();
+        defines.extend(variant.defines.clone());
+        let options = RkafOptions {
+            defines,
+            overrides: variant.files.clone().into_iter().collect(),
+            ..Default::default()
+        };
+        let model = variant.model.as_deref().unwrap_or(&manifest.model);
+        let manufacturer = variant.manufacturer.as_deref().unwrap_or(&manifest.manufacturer);
+        let layout = layout_rkaf(&manifest.input, model, manufacturer, &options)
+            .map_err(|e| e.within(format!("Variant {}", variant.name)))?;
+        layouts.push(layout);
+    }
+
+    let temp_outputs: Vec<PathBuf> = manifest.variants.iter().map(|v| with_suffix(&v.output, ".tmp")).collect();
+    let mut outputs = Vec::new();
+    for (layout, temp_output) in layouts.iter().zip(&temp_outputs) {
+        if let Some(parent) = temp_output.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut out_file = File::create(temp_output)?;
+        out_file.write_all(&layout.header.to_bytes())?;
+        // Header and file padding are left to the zero fill of set_len
+        out_file.set_len(layout.length)?;
+        outputs.push(out_file);
+    }
+
+    // Input file -> (output index, offset) of every place it is written
+    let mut targets: BTreeMap<&Path, Vec<(usize, u64)>> = BTreeMap::new();
+    let mut sizes: HashMap<&Path, u64> = HashMap::new();
+    for (index, layout) in layouts.iter().enumerate() {
+        for ((source, file_size, _), offset) in layout.data.iter().zip(layout.offsets()) {
+            let file_path = match source {
+                DataSource::Input(file_path) => file_path,
+                // Already there from the zero fill
+                DataSource::Zero => continue,
+                DataSource::Bytes(data) => {
+                    outputs[index].seek(SeekFrom::Start(offset))?;
+                    outputs[index].write_all(data)?;
+                    continue;
+                }
+                DataSource::Sparse(plan) => {
+                    outputs[index].seek(SeekFrom::Start(offset))?;
+                    let mut out = BufWriter::new(&mut outputs[index]);
+                    plan.write_to(&mut out, &CancelToken::default())?;
+                    out.flush()?;
+                    continue;
+                }
+                DataSource::Compressed(file_path, compression) => {
+                    outputs[index].seek(SeekFrom::Start(offset))?;
+                    let mut out = BufWriter::new(&mut outputs[index]);
+                    copy_decompressed(file_path, *compression, &mut out, &CancelToken::default())?;
+                    out.flush()?;
+                    continue;
+                }
+                DataSource::PreviousOutput(_) => {
+                    return Err(AfptoolError::Invalid("Incremental data cannot be used in a multi-variant build".to_string()));
+                }
+            };
+            targets.entry(file_path).or_default().push((index, offset));
+            sizes.insert(file_path, *file_size);
+        }
+    }
+
+    let mut buffer = vec![0u8; 1024 * 1024];
+    for (file_path, places) in &targets {
+        for (index, offset) in places {
+            outputs[*index].seek(SeekFrom::Start(*offset))?;
+        }
+        let mut input = File::open(file_path)
+            .with_context(|| format!("Cannot open {}", file_path.display()))?
+            .take(sizes[file_path]);
+        let mut copied = 0u64;
+        loop {
+            let read_bytes = input.read(&mut buffer)?;
+            if read_bytes == 0 {
+                break;
+            }
+            for (index, _) in places {
+                outputs[*index].write_all(&buffer[..read_bytes])?;
+            }
+            copied += read_bytes as u64;
+        }
+        if copied != sizes[file_path] {
+            return Err(AfptoolError::changed("Input changed size while packing"));
+        }
+    }
+    drop(outputs);
+
+    let mut built = Vec::new();
+    for (variant, temp_output) in manifest.variants.iter().zip(&temp_outputs) {
+        finish_rkaf(temp_output, &variant.output, &CancelToken::default())?;
+        built.push((variant.name.clone(), variant.output.clone()));
+    }
+    Ok(built)
+}
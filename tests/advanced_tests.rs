@@ -116,7 +116,7 @@ mod advanced_tests {
         let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
         cmd.assert()
             .failure()
-            .stderr(predicate::str::contains("the following required arguments were not provided:"));
+            .stderr(predicate::str::contains("Usage: afptool-rs"));
     }
 
     #[test]
@@ -138,9 +138,9 @@ mod advanced_tests {
         
         cmd.assert()
            .success()
-           .stdout(predicate::str::contains("RKFW signature detected"))
-           .stdout(predicate::str::contains("version: 8.1.0"))
-           .stdout(predicate::str::contains("family: PX30"));
+           .stderr(predicate::str::contains("RKFW signature detected"))
+           .stderr(predicate::str::contains("version: 8.1.0"))
+           .stderr(predicate::str::contains("family: PX30"));
         
         // 验证文件是否被正确提取
         assert!(output_dir.join("BOOT").exists());
@@ -169,9 +169,9 @@ mod advanced_tests {
         // 执行命令并检查输出
         cmd.assert()
            .success()
-           .stdout(predicate::str::contains("Filesize:"))
-           .stdout(predicate::str::contains("manufacturer: RK3326"))
-           .stdout(predicate::str::contains("model: RK3326"));
+           .stderr(predicate::str::contains("Filesize:"))
+           .stderr(predicate::str::contains("manufacturer: RK3326"))
+           .stderr(predicate::str::contains("model: RK3326"));
         
         Ok(())
     }
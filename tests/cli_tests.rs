@@ -84,6 +84,20 @@ mod integration_tests {
         data
     }
     
+    /// 创建可用 pack-rkaf 打包的最小目录：package-file、parameter.txt、3000 字节的 boot.img 和分区元数据
+    fn create_rkaf_tree(dir: &Path) -> std::io::Result<()> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tboot.img\n")?;
+        fs::write(dir.join("parameter.txt"), "FIRMWARE_VER: 1.0\nMACHINE_ID: 007\n")?;
+        fs::write(dir.join("boot.img"), vec![7u8; 3000])?;
+        fs::write(
+            dir.join("partition-metadata.txt"),
+            "package-file,package-file,0x00000000,0x00000000,0x00000000,0x00000800,0x00000000\n\
+             parameter,parameter.txt,0x00000000,0x00000000,0x00000000,0x00000800,0x00000000\n\
+             boot,boot.img,0x00000020,0x00000010,0x00000000,0x00001800,0x00000000\n",
+        )
+    }
+    
     #[test]
     fn test_cli_help() {
         let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
@@ -91,11 +105,215 @@ mod integration_tests {
         cmd.assert()
             .success()
             .stdout(predicate::str::contains("afptool-rs"))
-            .stdout(predicate::str::contains("A Rust tool for unpacking RockChip firmware images"))
+            .stdout(predicate::str::contains("A Rust tool for packing and unpacking RockChip firmware images"));
+
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.args(["unpack", "--help"]);
+        cmd.assert()
+            .success()
             .stdout(predicate::str::contains("Path to the firmware file"))
             .stdout(predicate::str::contains("Directory where extracted files will be saved"));
     }
     
+    #[test]
+    fn test_cli_lang_zh() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let input_file = temp_dir.path().join("mock.rkfw");
+        let mut data = create_mock_rkfw_file();
+        // 2024-11-08 12:00:00
+        data[0x0e..0x15].copy_from_slice(&[0xe8, 0x07, 11, 8, 12, 0, 0]);
+        fs::write(&input_file, data)?;
+
+        let mut cmd = Command::cargo_bin("afptool-rs")?;
+        cmd.args(["--lang", "zh", "unpack"])
+            .arg(&input_file)
+            .arg(temp_dir.path().join("out"));
+        cmd.assert()
+            .success()
+            .stderr(predicate::str::contains("检测到 RKFW 签名"))
+            .stderr(predicate::str::contains("芯片系列: PX30"));
+
+        // info 和 verify 的标签与总结也会翻译
+        let tree = temp_dir.path().join("tree");
+        create_rkaf_tree(&tree)?;
+        let image = temp_dir.path().join("update.img");
+        afptool_rs::pack_rkaf(tree.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "Rockchip")?;
+        Command::cargo_bin("afptool-rs")?.args(["--lang", "zh", "info"]).arg(&image).assert()
+            .success()
+            .stdout(predicate::str::contains("型号: RK3326"))
+            .stdout(predicate::str::contains("机器 ID: 007"));
+        Command::cargo_bin("afptool-rs")?.args(["--lang", "zh", "verify"]).arg(&image).assert()
+            .success()
+            .stdout(predicate::str::contains("项检查通过"));
+        Command::cargo_bin("afptool-rs")?.args(["--lang", "zh", "info"]).arg(temp_dir.path().join("missing.img")).assert()
+            .code(6)
+            .stderr(predicate::str::starts_with("错误: "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cli_format_json() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        create_rkaf_tree(&tree)?;
+        let image = temp_dir.path().join("update.img");
+        afptool_rs::pack_rkaf(tree.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "Rockchip")?;
+
+        let output = Command::cargo_bin("afptool-rs")?.args(["--format", "json", "info"]).arg(&image).output()?;
+        assert!(output.status.success());
+        let info: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(info["model"], "RK3326");
+        assert_eq!(info["machine_id"], "007");
+        assert!(info["rkfw"].is_null());
+        let boot = info["partitions"].as_array().unwrap().iter().find(|part| part["name"] == "boot").unwrap();
+        assert_eq!(boot["byte_count"], 3000);
+
+        // unpack 只输出一个 JSON 文档，不再打印进度
+        let output = Command::cargo_bin("afptool-rs")?
+            .args(["--format", "json", "unpack"])
+            .arg(&image)
+            .arg(temp_dir.path().join("out"))
+            .output()?;
+        assert!(output.status.success());
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(report["image"]["manufacturer"], "Rockchip");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cli_format_json_rkfp() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir_all(tree.join("Image"))?;
+        fs::write(tree.join("Image/boot.img"), vec![7u8; 3000])?;
+        fs::write(
+            tree.join("rkfp-manifest.json"),
+            r#"{"version":"1.0.2","model":"RK3588","manufacturer":"Rockchip","disk_sectors":0,
+                "partitions":[{"name":"boot","path":"Image/boot.img","flash_offset":8192,"flash_size":null}]}"#,
+        )?;
+        let image = temp_dir.path().join("update.img");
+        Command::cargo_bin("afptool-rs")?.arg("pack-rkfp").arg(&tree).arg(&image).assert().success();
+
+        // RKFP 没有 RKFW/RKAF 头，image 为 null，信息都在 unpacked 里
+        let output = Command::cargo_bin("afptool-rs")?
+            .args(["--format", "json", "unpack"])
+            .arg(&image)
+            .arg(temp_dir.path().join("out"))
+            .output()?;
+        assert!(output.status.success());
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        assert!(report["image"].is_null());
+        assert_eq!(report["unpacked"]["model"], "RK3588");
+        assert_eq!(report["unpacked"]["parts"][0]["size"], 3000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cli_verbosity() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        create_rkaf_tree(&tree)?;
+        let image = temp_dir.path().join("update.img");
+
+        // 进度信息写到 stderr，stdout 留给命令输出
+        let output = Command::cargo_bin("afptool-rs")?.arg("pack-rkaf").arg(&tree).arg(&image).args(["-m", "RK3326", "-M", "Rockchip"]).output()?;
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8(output.stderr)?;
+        assert!(stderr.contains("Model: RK3326") && !stderr.contains("boot from"));
+
+        // -v 加上每个分区的布局，-q 只留警告和错误
+        let output = Command::cargo_bin("afptool-rs")?.args(["-v", "pack-rkaf"]).arg(&tree).arg(&image).args(["-m", "RK3326", "-M", "Rockchip"]).output()?;
+        assert!(String::from_utf8(output.stderr)?.contains("boot from"));
+        let output = Command::cargo_bin("afptool-rs")?.args(["-q", "unpack"]).arg(&image).arg(temp_dir.path().join("out")).output()?;
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty() && output.stderr.is_empty());
+
+        // 提示的是最终输出目录里的路径，而不是临时目录
+        let out = temp_dir.path().join("out2");
+        let output = Command::cargo_bin("afptool-rs")?.arg("unpack").arg(&image).arg(&out).output()?;
+        let stderr = String::from_utf8(output.stderr)?;
+        assert!(stderr.contains(&format!("Partition metadata saved to: {}", out.join("partition-metadata.txt").display())), "{}", stderr);
+        assert!(!stderr.contains(".partial-"), "{}", stderr);
+
+        // info 以表格列出分区，并画出 flash 布局
+        let output = Command::cargo_bin("afptool-rs")?.args(["--color", "never", "info"]).arg(&image).output()?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.contains("boot                   0x00000010   0x00000020   0x00001800   0x00000bb8   18.3%  boot.img"));
+        assert!(stdout.contains("|.....................AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA|\n A boot"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cli_exit_codes() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        create_rkaf_tree(&tree)?;
+        let image = temp_dir.path().join("update.img");
+        afptool_rs::pack_rkaf(tree.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "Rockchip")?;
+        let out = temp_dir.path().join("out");
+
+        // 参数错误 2，未知签名 3，校验和不符 4，布局错误 5，I/O 错误 6
+        Command::cargo_bin("afptool-rs")?.args(["--lang", "xx", "info"]).arg(&image).assert().code(2);
+        let junk = temp_dir.path().join("junk.img");
+        fs::write(&junk, vec![0x55u8; 4096])?;
+        Command::cargo_bin("afptool-rs")?.arg("unpack").arg(&junk).arg(&out).assert().code(3);
+        let mut damaged = fs::read(&image)?;
+        let last = damaged.len() - 10;
+        damaged[last] ^= 0xff;
+        let damaged_image = temp_dir.path().join("damaged.img");
+        fs::write(&damaged_image, damaged)?;
+        Command::cargo_bin("afptool-rs")?.arg("unpack").arg(&damaged_image).arg(&out).assert().code(4);
+        // 截断的映像长度不符，不加 --force 时按解析错误拒绝
+        let truncated = fs::read(&image)?;
+        fs::write(&damaged_image, &truncated[..truncated.len() - 100])?;
+        Command::cargo_bin("afptool-rs")?.arg("unpack").arg(&damaged_image).arg(&out).assert().code(3)
+            .stderr(predicate::str::contains("--force"));
+        fs::write(tree.join("boot.img"), vec![7u8; 64 * 1024])?;
+        Command::cargo_bin("afptool-rs")?.arg("pack-rkaf").arg(&tree).arg(temp_dir.path().join("big.img"))
+            .args(["-m", "RK3326", "-M", "Rockchip"]).assert().code(5);
+        // 分区数据超出目标镜像的闪存分区大小
+        Command::cargo_bin("afptool-rs")?.arg("replace").arg(&image).arg("boot").arg(tree.join("boot.img"))
+            .arg("-o").arg(temp_dir.path().join("replaced.img"))
+            .assert().code(5).stderr(predicate::str::contains("reserves only"));
+        Command::cargo_bin("afptool-rs")?.arg("unpack").arg(temp_dir.path().join("missing.img")).arg(&out).assert().code(6);
+        // package-file 列出的分区文件缺失
+        fs::remove_file(tree.join("boot.img"))?;
+        Command::cargo_bin("afptool-rs")?.arg("pack-rkaf").arg(&tree).arg(temp_dir.path().join("missing-part.img"))
+            .args(["-m", "RK3326", "-M", "Rockchip"])
+            .assert().code(6).stderr(predicate::str::contains("boot.img"));
+        let rkfw_dir = temp_dir.path().join("rkfw");
+        fs::create_dir_all(&rkfw_dir)?;
+        fs::copy(&image, rkfw_dir.join("embedded-update.img"))?;
+        Command::cargo_bin("afptool-rs")?.arg("pack-rkfw").arg(&rkfw_dir).arg(temp_dir.path().join("rkfw.img"))
+            .args(["-c", "RK3326", "-v", "1.0.0"])
+            .assert().code(6).stderr(predicate::str::contains("BOOT"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cli_output_name_date() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let tree = temp_dir.path().join("tree");
+        create_rkaf_tree(&tree)?;
+
+        // RKAF 头部没有时间，{date} 取 SOURCE_DATE_EPOCH 的 UTC 日期（2024-11-08 23:59:59 UTC）
+        Command::cargo_bin("afptool-rs")?
+            .env("SOURCE_DATE_EPOCH", "1731110399")
+            .env("TZ", "Asia/Shanghai")
+            .arg("pack-rkaf").arg(&tree).arg(temp_dir.path().join("update_{date}.img"))
+            .args(["-m", "RK3326", "-M", "Rockchip"])
+            .assert().success();
+        assert!(temp_dir.path().join("update_20241108.img").is_file());
+
+        Ok(())
+    }
+
     #[test]
     #[ignore] // 默认忽略此测试，因为它需要构建可执行文件
     fn test_cli_unpack_rkfw() -> Result<(), Box<dyn std::error::Error>> {
@@ -109,9 +327,9 @@ mod integration_tests {
         // 执行命令并检查输出
         cmd.assert()
             .success()
-            .stdout(predicate::str::contains("RKFW signature detected"))
-            .stdout(predicate::str::contains("version: 8.1.0"))
-            .stdout(predicate::str::contains("family: PX30"));
+            .stderr(predicate::str::contains("RKFW signature detected"))
+            .stderr(predicate::str::contains("version: 8.1.0"))
+            .stderr(predicate::str::contains("family: PX30"));
         
         // 检查文件是否被正确提取
         let boot_file = Path::new(&output_dir).join("BOOT");
@@ -1,9 +1,12 @@
 #[cfg(test)]
 mod tests {
     use std::fs::{self, File};
-    use std::io::Write;
+    use std::io::{Read, Seek, SeekFrom, Write};
     use std::path::Path;
-    use afptool_rs::{RKAF_SIGNATURE, RKFW_SIGNATURE, UpdateHeader};
+    use afptool_rs::{RKAF_SIGNATURE, RKFW_SIGNATURE, UpdateHeader, UPDATE_HEADER_SIZE, pack_rkfw, pack_rkaf, pack_rkaf_with, pack_rkfp, RkafOptions, unpack_file, unpack_file_with, UnpackOptions, verify_device, verify_tree, Lang, Store};
+    use afptool_rs::{index_dir, query_catalog, CatalogQuery, unwrap_rkfw, wrap_rkaf, build_variants, replace_part, transplant_part, write_part_to_device, list_partitions, dd_command, trim_tree, assemble_disk, hash_image, grep_image, hex_pattern, utf16le_pattern, android_build_info, MtdPart, ParameterFile, compare_images, read_update_header, check_chip, diff_layouts, LayoutChange, scan_dump, carve_hit, ImageKind, enable_timings, timings_report, check_compat, Compatibility, CancelToken, verify_device_with, VerifyOptions, unpack_boot, pack_boot, BootLoader, EntryKind, read_rkfw_info, verify_image, AfptoolError, RkafBuilder, RkafPart, RkfwBuilder, RkafPayload, PartData, pack_rkaf_to_writer, list_partitions_from_reader, verify_image_from_reader, unpack_from_reader, image_info, FirmwareManifest, pack_rkfw_with, RkfwOptions, wrap_rkcrc, unwrap_rkcrc, rkcrc_wrap_file, rkcrc_unwrap_file, CrcWrapper, cat_partition, edit_header, fix_image, HeaderEdit, chip_name_to_code, chip_code_to_name, chip_field, chip_default_code, load_chip_db, UpdatePart, FLASH_SIZE_GROW, parse_timestamp, default_timestamp, boot_image_info, ResourceImage, ResourceFile, unpack_resource, pack_resource, Compression, unpack_batch, parse_header_byte, UpdateManifest};
+    use afptool_rs::i18n::translate;
+    use tempfile::TempDir;
 
     // 创建模拟的 RKFW 文件用于测试
     fn create_mock_rkfw() -> Vec<u8> {
@@ -91,15 +94,1717 @@ mod tests {
         data
     }
 
+    // 创建一个可用 pack_rkaf 打包的解包目录
+    fn create_rkaf_tree(dir: &Path) {
+        fs::write(dir.join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tboot.img\n").unwrap();
+        fs::write(dir.join("parameter.txt"), "FIRMWARE_VER: 1.0\nMACHINE_ID: 007\n").unwrap();
+        let boot: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("boot.img"), boot).unwrap();
+        fs::write(
+            dir.join("partition-metadata.txt"),
+            "package-file,package-file,0x00000000,0x00000000,0x00000000,0x00000800,0x00000000\n\
+             parameter,parameter.txt,0x00000000,0x00000000,0x00000000,0x00000800,0x00000000\n\
+             boot,boot.img,0x00000020,0x00000010,0x00000000,0x00001800,0x00000000\n",
+        ).unwrap();
+    }
+
+    /// 放大 create_rkaf_tree 中 boot 的 flash 分区（扇区数），以容纳更大的 boot.img
+    fn set_boot_flash_size(dir: &Path, sectors: u32) {
+        let metadata = fs::read_to_string(dir.join("partition-metadata.txt")).unwrap();
+        let metadata: String = metadata.lines().map(|line| match line.strip_prefix("boot,boot.img,") {
+            Some(rest) => format!("boot,boot.img,{:#010x}{}\n", sectors, &rest[10..]),
+            None => format!("{}\n", line),
+        }).collect();
+        fs::write(dir.join("partition-metadata.txt"), metadata).unwrap();
+    }
+
+    #[test]
+    fn test_verify_device() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path().to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 模拟设备：boot 分区位于第 0x10 扇区
+        let boot = fs::read(temp_dir.path().join("boot.img")).unwrap();
+        let mut device = vec![0u8; 0x10 * 512];
+        device.extend_from_slice(&boot);
+        let device_path = temp_dir.path().join("device.bin");
+        fs::write(&device_path, &device).unwrap();
+
+        let image = image.to_str().unwrap();
+        let device_str = device_path.to_str().unwrap();
+        assert!(verify_device(image, "boot", device_str, false).is_ok());
+        assert!(verify_device(image, "boot", device_str, true).is_ok());
+
+        device[0x10 * 512 + 100] ^= 0xff;
+        fs::write(&device_path, &device).unwrap();
+        assert!(verify_device(image, "boot", device_str, false).is_err());
+        let err = verify_device(image, "boot", device_str, true).unwrap_err();
+        assert!(err.to_string().contains("0x64"));
+        assert!(verify_device(image, "missing", device_str, false).is_err());
+    }
+
+    #[test]
+    fn test_verify_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        let out = temp_dir.path().join("out");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(src.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        unpack_file(image.to_str().unwrap(), out.to_str().unwrap()).unwrap();
+        let out_str = out.to_str().unwrap();
+        assert!(out.join("checksums.md5").exists());
+        assert!(verify_tree(out_str).is_ok());
+
+        // 修改内容但保持大小不变
+        let mut boot = fs::read(out.join("boot.img")).unwrap();
+        boot[0] ^= 0xff;
+        fs::write(out.join("boot.img"), &boot).unwrap();
+        assert!(verify_tree(out_str).is_err());
+
+        boot[0] ^= 0xff;
+        fs::write(out.join("boot.img"), &boot).unwrap();
+        assert!(verify_tree(out_str).is_ok());
+
+        fs::remove_file(out.join("parameter.txt")).unwrap();
+        assert!(verify_tree(out_str).is_err());
+    }
+
+    #[test]
+    fn test_pack_rkaf_incremental() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let image = temp_dir.path().join("update.img");
+        let options = RkafOptions { incremental: true, ..Default::default() };
+        let (src_str, image_str) = (src.to_str().unwrap(), image.to_str().unwrap());
+
+        pack_rkaf_with(src_str, image_str, "RK3326", "RK3326", &options).unwrap();
+        assert!(src.join(".afptool-pack-cache").exists());
+        let first = fs::read(&image).unwrap();
+
+        // 内容改变但大小和修改时间不变：缓存认为未改变，应从上一次输出复制旧数据
+        let boot_path = src.join("boot.img");
+        let mtime = fs::metadata(&boot_path).unwrap().modified().unwrap();
+        let mut boot = fs::read(&boot_path).unwrap();
+        boot[0] ^= 0xff;
+        fs::write(&boot_path, &boot).unwrap();
+        File::options().write(true).open(&boot_path).unwrap().set_modified(mtime).unwrap();
+
+        pack_rkaf_with(src_str, image_str, "RK3326", "RK3326", &options).unwrap();
+        assert_eq!(fs::read(&image).unwrap(), first);
+
+        // 修改时间改变后重新读取输入
+        File::options().write(true).open(&boot_path).unwrap()
+            .set_modified(mtime + std::time::Duration::from_secs(10)).unwrap();
+        pack_rkaf_with(src_str, image_str, "RK3326", "RK3326", &options).unwrap();
+        let third = fs::read(&image).unwrap();
+        assert_ne!(third, first);
+        assert_eq!(third.len(), first.len());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // 目录和文件名不是 UTF-8 时，打包、增量缓存和解包都应正常工作
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join(OsStr::from_bytes(b"src\xff"));
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let image = temp_dir.path().join(OsStr::from_bytes(b"update\xfe.img"));
+        let options = RkafOptions { incremental: true, ..Default::default() };
+        pack_rkaf_with(&src, &image, "RK3326", "RK3326", &options).unwrap();
+        let first = fs::read(&image).unwrap();
+
+        // 缓存按原始字节记录输出路径，第二次打包能找回上一次的输出
+        let cache = fs::read(src.join(".afptool-pack-cache")).unwrap();
+        assert!(cache.windows(b"update\xfe.img".len()).any(|w| w == b"update\xfe.img"));
+        pack_rkaf_with(&src, &image, "RK3326", "RK3326", &options).unwrap();
+        assert_eq!(fs::read(&image).unwrap(), first);
+
+        let out = temp_dir.path().join(OsStr::from_bytes(b"out\xfd"));
+        let options = UnpackOptions { verify: true, ..Default::default() };
+        unpack_file_with(&image, &out, &options).unwrap();
+        assert_eq!(fs::read(out.join("boot.img")).unwrap(), fs::read(src.join("boot.img")).unwrap());
+        assert!(verify_tree(&out).is_ok());
+    }
+
+    #[test]
+    fn test_store_dedup_and_materialize() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let first = temp_dir.path().join("first.img");
+        let second = temp_dir.path().join("second.img");
+        pack_rkaf(src.to_str().unwrap(), first.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        fs::write(src.join("parameter.txt"), "FIRMWARE_VER: 1.1\nMACHINE_ID: 007\n").unwrap();
+        pack_rkaf(src.to_str().unwrap(), second.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let store = Store::open(temp_dir.path().join("store").to_str().unwrap()).unwrap();
+        let stats = store.ingest(first.to_str().unwrap(), None).unwrap();
+        assert_eq!(stats.new_blobs, stats.blobs);
+
+        // 只有 parameter 和头部不同，boot 等分区应被复用
+        let stats = store.ingest(second.to_str().unwrap(), None).unwrap();
+        assert!(stats.new_blobs < stats.blobs);
+        assert_eq!(store.list().unwrap(), vec!["first", "second"]);
+
+        for name in ["first", "second"] {
+            let restored = temp_dir.path().join(format!("{}.restored", name));
+            store.materialize(name, restored.to_str().unwrap()).unwrap();
+            assert_eq!(
+                fs::read(&restored).unwrap(),
+                fs::read(temp_dir.path().join(format!("{}.img", name))).unwrap()
+            );
+        }
+        assert!(store.materialize("third", "unused").is_err());
+    }
+
+    #[test]
+    fn test_index_and_query_catalog() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        let library = temp_dir.path().join("library");
+        fs::create_dir(&src).unwrap();
+        fs::create_dir_all(library.join("vendor")).unwrap();
+        create_rkaf_tree(&src);
+        pack_rkaf(src.to_str().unwrap(), library.join("vendor/x96.img").to_str().unwrap(), "X96 Max", "Vendor").unwrap();
+        pack_rkaf(src.to_str().unwrap(), library.join("other.img").to_str().unwrap(), "Other", "Vendor").unwrap();
+        fs::write(library.join("notes.txt"), "not an image").unwrap();
+
+        let db = temp_dir.path().join("catalog.json");
+        let entries = index_dir(library.to_str().unwrap(), db.to_str().unwrap()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|e| e.partitions.contains(&"boot".to_string())));
+
+        let query = CatalogQuery { model: Some("x96".to_string()), ..Default::default() };
+        let found = query_catalog(db.to_str().unwrap(), &query).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].model, "X96 Max");
+        assert!(found[0].path.ends_with("x96.img"));
+
+        // RKAF 映像没有芯片信息
+        let query = CatalogQuery { chip: Some("RK3566".to_string()), ..Default::default() };
+        assert!(query_catalog(db.to_str().unwrap(), &query).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pack_rkaf_package_file_variables() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        fs::rename(src.join("boot.img"), src.join("boot-x96.img")).unwrap();
+        fs::write(src.join("package-file"), "package-file\tpackage-file\nboot\tboot-${BOARD}.img\n").unwrap();
+        let image = temp_dir.path().join("update.img");
+        let (src_str, image_str) = (src.to_str().unwrap(), image.to_str().unwrap());
+
+        // 未定义的变量应报错
+        let err = pack_rkaf(src_str, image_str, "RK3326", "RK3326").unwrap_err();
+        assert!(err.to_string().contains("BOARD"));
+
+        let options = RkafOptions { defines: vec![("BOARD".to_string(), "x96".to_string())], ..Default::default() };
+        pack_rkaf_with(src_str, image_str, "RK3326", "RK3326", &options).unwrap();
+        let data = fs::read(&image).unwrap();
+        assert!(data.windows(12).any(|w| w == b"boot-x96.img"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unpack_hooks() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(src.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let log = temp_dir.path().join("hook.log");
+        let out = temp_dir.path().join("out dir");
+        let options = UnpackOptions {
+            hooks: vec![format!("echo {{name}} $(wc -c < {{path}}) >> '{}'", log.display())],
+            ..Default::default()
+        };
+        unpack_file_with(image.to_str().unwrap(), out.to_str().unwrap(), &options).unwrap();
+        let log = fs::read_to_string(&log).unwrap();
+        assert!(log.lines().any(|line| line == "boot 5000"));
+        assert_eq!(log.lines().count(), 3);
+
+        // 钩子失败时解包应报错
+        let options = UnpackOptions { hooks: vec!["false".to_string()], ..Default::default() };
+        assert!(unpack_file_with(image.to_str().unwrap(), out.to_str().unwrap(), &options).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unpack_staging() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(src.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let work = temp_dir.path().join("work");
+        fs::create_dir(&work).unwrap();
+
+        // 失败时不留下半成品目录
+        let out = work.join("out");
+        let options = UnpackOptions { hooks: vec!["test {name} != boot".to_string()], ..Default::default() };
+        assert!(unpack_file_with(image.to_str().unwrap(), out.to_str().unwrap(), &options).is_err());
+        assert_eq!(fs::read_dir(&work).unwrap().count(), 0);
+
+        let options = UnpackOptions { verify: true, ..Default::default() };
+        unpack_file_with(image.to_str().unwrap(), out.to_str().unwrap(), &options).unwrap();
+        assert!(out.join("boot.img").exists());
+        assert_eq!(fs::read_dir(&work).unwrap().count(), 1);
+
+        // 解包到已有目录时合并，原有文件保留
+        fs::write(out.join("notes.txt"), "keep").unwrap();
+        unpack_file_with(image.to_str().unwrap(), out.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read_to_string(out.join("notes.txt")).unwrap(), "keep");
+        assert!(verify_tree(out.to_str().unwrap()).is_ok());
+        assert_eq!(fs::read_dir(&work).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_convert_wrap_unwrap() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let update = temp_dir.path().join("update.img");
+        let loader = temp_dir.path().join("loader.bin");
+        let firmware = temp_dir.path().join("firmware.img");
+        pack_rkaf(src.to_str().unwrap(), update.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        fs::write(&loader, b"BOOT loader blob").unwrap();
+
+        wrap_rkaf(update.to_str().unwrap(), loader.to_str().unwrap(), firmware.to_str().unwrap(),
+                  "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+
+        let update_out = temp_dir.path().join("update.out");
+        let loader_out = temp_dir.path().join("loader.out");
+        unwrap_rkfw(firmware.to_str().unwrap(), update_out.to_str().unwrap(), Some(loader_out.as_path())).unwrap();
+        assert_eq!(fs::read(&update_out).unwrap(), fs::read(&update).unwrap());
+        assert_eq!(fs::read(&loader_out).unwrap(), fs::read(&loader).unwrap());
+
+        // 非 RKFW 输入
+        assert!(unwrap_rkfw(update.to_str().unwrap(), update_out.to_str().unwrap(), None).is_err());
+    }
+
+    #[test]
+    fn test_unpack_rkfw_boot_only() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let update = temp_dir.path().join("update.img");
+        let loader = temp_dir.path().join("loader.bin");
+        let firmware = temp_dir.path().join("firmware.img");
+        pack_rkaf(src.to_str().unwrap(), update.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        fs::write(&loader, b"BOOT loader blob").unwrap();
+        wrap_rkaf(update.to_str().unwrap(), loader.to_str().unwrap(), firmware.to_str().unwrap(),
+                  "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+
+        let out = temp_dir.path().join("boot-only");
+        let options = UnpackOptions { boot_only: true, ..Default::default() };
+        unpack_file_with(firmware.to_str().unwrap(), out.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(out.join("BOOT")).unwrap(), fs::read(&loader).unwrap());
+        assert!(!out.join("embedded-update.img").exists());
+
+        let out = temp_dir.path().join("update-only");
+        let options = UnpackOptions { update_only: true, ..Default::default() };
+        unpack_file_with(firmware.to_str().unwrap(), out.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(out.join("embedded-update.img")).unwrap(), fs::read(&update).unwrap());
+        assert!(!out.join("BOOT").exists());
+
+        // 两个选项不能同时使用
+        let options = UnpackOptions { boot_only: true, update_only: true, ..Default::default() };
+        assert!(unpack_file_with(firmware.to_str().unwrap(), out.to_str().unwrap(), &options).is_err());
+    }
+
+    #[test]
+    fn test_build_variants() {
+        let temp_dir = TempDir::new().unwrap();
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        create_rkaf_tree(&tree);
+        fs::create_dir(temp_dir.path().join("b")).unwrap();
+        fs::write(temp_dir.path().join("b/parameter.txt"), b"MACHINE_ID: 42\nCMDLINE: board-b\n").unwrap();
+        fs::write(temp_dir.path().join("build.json"), r#"{
+            "input": "tree",
+            "model": "RK3326",
+            "manufacturer": "RK3326",
+            "variants": [
+                { "name": "a", "output": "out/a.img" },
+                { "name": "b", "output": "out/b.img", "model": "Board B",
+                  "files": { "parameter": "b/parameter.txt" } }
+            ]
+        }"#).unwrap();
+
+        let manifest = temp_dir.path().join("build.json");
+        let built = build_variants(manifest.to_str().unwrap()).unwrap();
+        assert_eq!(built.len(), 2);
+
+        // 每个变体与单独打包的结果逐字节一致
+        let expected_a = temp_dir.path().join("a.img");
+        pack_rkaf(tree.to_str().unwrap(), expected_a.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        assert_eq!(fs::read(temp_dir.path().join("out/a.img")).unwrap(), fs::read(&expected_a).unwrap());
+
+        let expected_b = temp_dir.path().join("b.img");
+        let options = RkafOptions {
+            overrides: vec![("parameter".to_string(), temp_dir.path().join("b/parameter.txt"))],
+            ..Default::default()
+        };
+        pack_rkaf_with(tree.to_str().unwrap(), expected_b.to_str().unwrap(), "Board B", "RK3326", &options).unwrap();
+        assert_eq!(fs::read(temp_dir.path().join("out/b.img")).unwrap(), fs::read(&expected_b).unwrap());
+        assert_ne!(fs::read(&expected_a).unwrap(), fs::read(&expected_b).unwrap());
+    }
+
+    #[test]
+    fn test_transplant_part() {
+        let temp_dir = TempDir::new().unwrap();
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        create_rkaf_tree(&tree);
+        let target = temp_dir.path().join("target.img");
+        pack_rkaf(tree.to_str().unwrap(), target.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 供体的 boot 大小不同
+        let donor_tree = temp_dir.path().join("donor");
+        fs::create_dir(&donor_tree).unwrap();
+        create_rkaf_tree(&donor_tree);
+        fs::write(donor_tree.join("boot.img"), vec![0x5a; 6000]).unwrap();
+        let donor = temp_dir.path().join("donor.img");
+        pack_rkaf(donor_tree.to_str().unwrap(), donor.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let expected = temp_dir.path().join("expected.img");
+        let options = RkafOptions {
+            overrides: vec![("boot".to_string(), donor_tree.join("boot.img"))],
+            ..Default::default()
+        };
+        pack_rkaf_with(tree.to_str().unwrap(), expected.to_str().unwrap(), "RK3326", "RK3326", &options).unwrap();
+
+        let result = temp_dir.path().join("result.img");
+        transplant_part(donor.to_str().unwrap(), "boot", target.to_str().unwrap(), Some(result.as_path())).unwrap();
+        assert_eq!(fs::read(&result).unwrap(), fs::read(&expected).unwrap());
+
+        // RKFW 目标原地替换
+        let loader = temp_dir.path().join("loader.bin");
+        fs::write(&loader, b"BOOT loader blob").unwrap();
+        let firmware = temp_dir.path().join("firmware.img");
+        wrap_rkaf(target.to_str().unwrap(), loader.to_str().unwrap(), firmware.to_str().unwrap(),
+                  "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+        transplant_part(donor.to_str().unwrap(), "boot", firmware.to_str().unwrap(), None).unwrap();
+        let expected_firmware = temp_dir.path().join("expected-firmware.img");
+        wrap_rkaf(expected.to_str().unwrap(), loader.to_str().unwrap(), expected_firmware.to_str().unwrap(),
+                  "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+        assert_eq!(fs::read(&firmware).unwrap(), fs::read(&expected_firmware).unwrap());
+
+        // 超出目标 flash 分区大小（供体自身的 boot 分区更大）
+        set_boot_flash_size(&donor_tree, 0x40);
+        fs::write(donor_tree.join("boot.img"), vec![0x5a; 20000]).unwrap();
+        pack_rkaf(donor_tree.to_str().unwrap(), donor.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        assert!(transplant_part(donor.to_str().unwrap(), "boot", target.to_str().unwrap(), None).is_err());
+    }
+
+    #[test]
+    fn test_write_part_to_device() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path().to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let image = image.to_str().unwrap();
+
+        // 模拟分区设备，剩余部分保持不变
+        let device = temp_dir.path().join("sdx2");
+        fs::write(&device, vec![0xff; 8192]).unwrap();
+        write_part_to_device(image, "boot", device.to_str().unwrap(), true).unwrap();
+        let written = fs::read(&device).unwrap();
+        assert_eq!(&written[..5000], &fs::read(temp_dir.path().join("boot.img")).unwrap()[..]);
+        assert!(written[5000..].iter().all(|&b| b == 0xff));
+
+        // 设备太小或不存在
+        let small = temp_dir.path().join("small");
+        fs::write(&small, vec![0u8; 4096]).unwrap();
+        assert!(write_part_to_device(image, "boot", small.to_str().unwrap(), false).is_err());
+        assert_eq!(fs::read(&small).unwrap(), vec![0u8; 4096]);
+        let missing = temp_dir.path().join("missing");
+        assert!(write_part_to_device(image, "boot", missing.to_str().unwrap(), false).is_err());
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn test_list_print_dd() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path().to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let parts = list_partitions(image.to_str().unwrap()).unwrap();
+        let names: Vec<&str> = parts.iter().map(|part| part.name.as_str()).collect();
+        assert_eq!(names, ["package-file", "parameter", "boot"]);
+
+        // boot 位于 0x1800，长度 5000，块大小取两者的最大公共 2 的幂
+        let boot = &parts[2];
+        assert_eq!(dd_command("update.img", boot), "dd if=update.img of=boot.img bs=8 skip=768 count=625");
+        assert_eq!(dd_command("my image.img", boot), "dd if='my image.img' of=boot.img bs=8 skip=768 count=625");
+    }
+
+    #[test]
+    fn test_trim_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        fs::write(src.join("package-file"), "package-file\tpackage-file\nboot\tboot.img\nrootfs\trootfs.img\n").unwrap();
+        let mut boot: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8 | 1).collect();
+        boot.resize(15000, 0);
+        fs::write(src.join("boot.img"), &boot).unwrap();
+        // 模拟 ext4：超级块声明 8 个 1 KiB 块，尾部全零
+        let mut rootfs = vec![0u8; 20000];
+        rootfs[1024 + 4..1024 + 8].copy_from_slice(&8u32.to_le_bytes());
+        rootfs[1024 + 56..1024 + 58].copy_from_slice(&[0x53, 0xef]);
+        fs::write(src.join("rootfs.img"), &rootfs).unwrap();
+        let mut metadata = fs::read_to_string(src.join("partition-metadata.txt")).unwrap();
+        metadata.push_str("rootfs,rootfs.img,0x00000100,0x00000040,0x00000000,0x00005000,0x00000000\n");
+        fs::write(src.join("partition-metadata.txt"), metadata).unwrap();
+
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(src.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let out = temp_dir.path().join("out");
+        unpack_file(image.to_str().unwrap(), out.to_str().unwrap()).unwrap();
+
+        let reports = trim_tree(out.to_str().unwrap(), true).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(fs::metadata(out.join("boot.img")).unwrap().len(), 15000);
+
+        let reports = trim_tree(out.to_str().unwrap(), false).unwrap();
+        let sizes: Vec<(&str, u64)> = reports.iter().map(|r| (r.path.as_str(), r.trimmed_size)).collect();
+        assert_eq!(sizes, [("boot.img", 5120), ("rootfs.img", 8192)]);
+        assert_eq!(fs::read(out.join("boot.img")).unwrap(), &boot[..5120]);
+        // 元数据与校验和同步更新
+        assert!(verify_tree(out.to_str().unwrap()).is_ok());
+        assert!(trim_tree(out.to_str().unwrap(), false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_skip_zero_partitions() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        fs::write(src.join("package-file"), "package-file\tpackage-file\nboot\tboot.img\nuserdata\tuserdata.img\n").unwrap();
+        fs::write(src.join("userdata.img"), vec![0u8; 100000]).unwrap();
+        let mut metadata = fs::read_to_string(src.join("partition-metadata.txt")).unwrap();
+        metadata.push_str("userdata,userdata.img,0x00000100,0x00000040,0x00000000,0x00018800,0x00000000\n");
+        fs::write(src.join("partition-metadata.txt"), metadata).unwrap();
+
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(src.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let out = temp_dir.path().join("out");
+        let options = UnpackOptions { skip_zero: true, ..Default::default() };
+        unpack_file_with(image.to_str().unwrap(), out.to_str().unwrap(), &options).unwrap();
+        assert!(!out.join("userdata.img").exists());
+        assert!(out.join("boot.img").exists());
+        let metadata = fs::read_to_string(out.join("partition-metadata.txt")).unwrap();
+        assert!(metadata.lines().any(|line| line.starts_with("userdata,") && line.ends_with(",zero")));
+        assert!(!metadata.lines().any(|line| line.starts_with("boot,") && line.ends_with(",zero")));
+
+        // 重新打包时补齐全零分区
+        fs::copy(src.join("parameter.txt"), out.join("parameter.txt")).unwrap();
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkaf(out.to_str().unwrap(), repacked.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), fs::read(&image).unwrap());
+    }
+
+    #[test]
+    fn test_placeholder_partitions() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        // misc 分区由其他途径烧写，镜像中只有占位项
+        fs::write(src.join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt\nmisc\tmisc.img\nboot\tboot.img\n").unwrap();
+        let mut metadata = fs::read_to_string(src.join("partition-metadata.txt")).unwrap();
+        metadata.push_str("misc,misc.img,0x00000008,0x00000030,0x00000000,0x00000000,0x00000000,placeholder\n");
+        fs::write(src.join("partition-metadata.txt"), metadata).unwrap();
+
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(src.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let parts = list_partitions(image.to_str().unwrap()).unwrap();
+        let misc = parts.iter().find(|part| part.name == "misc").unwrap();
+        assert!(misc.placeholder);
+        assert_eq!((misc.byte_count, misc.flash_offset), (0, 0x30));
+        assert!(!parts.iter().find(|part| part.name == "boot").unwrap().placeholder);
+
+        let out = temp_dir.path().join("out");
+        unpack_file(image.to_str().unwrap(), out.to_str().unwrap()).unwrap();
+        assert!(!out.join("misc.img").exists());
+        let metadata = fs::read_to_string(out.join("partition-metadata.txt")).unwrap();
+        assert!(metadata.lines().any(|line| line.starts_with("misc,") && line.ends_with(",placeholder")));
+        verify_tree(out.to_str().unwrap()).unwrap();
+
+        // 重新打包时保留占位项
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkaf(out.to_str().unwrap(), repacked.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), fs::read(&image).unwrap());
+    }
+
+    #[test]
+    fn test_write_padding() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        fs::write(src.join("package-file"), "package-file\tpackage-file\nboot\tboot.img\nuserdata\tuserdata.img\n").unwrap();
+        let mut metadata = fs::read_to_string(src.join("partition-metadata.txt")).unwrap();
+        metadata.push_str("userdata,userdata.img,0x00000100,0x00000040,0x00000000,0x00018800,0x000186a0,zero\n");
+        fs::write(src.join("partition-metadata.txt"), metadata).unwrap();
+
+        // 稀疏输出与显式写零的输出内容一致
+        let sparse = temp_dir.path().join("sparse.img");
+        let dense = temp_dir.path().join("dense.img");
+        pack_rkaf(src.to_str().unwrap(), sparse.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let options = RkafOptions { write_padding: true, ..Default::default() };
+        pack_rkaf_with(src.to_str().unwrap(), dense.to_str().unwrap(), "RK3326", "RK3326", &options).unwrap();
+        assert_eq!(fs::read(&dense).unwrap(), fs::read(&sparse).unwrap());
+    }
+
+    #[test]
+    fn test_assemble_disk() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        fs::write(src.join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tboot.img\nuserdata\tuserdata.img\n").unwrap();
+        fs::write(src.join("parameter.txt"),
+                  "FIRMWARE_VER: 1.0\nCMDLINE: console=ttyFIQ0 mtdparts=rk29xxnand:0x00000020@0x00000010(boot),-@0x00000040(userdata:grow)\n").unwrap();
+        fs::write(src.join("userdata.img"), vec![7u8; 1000]).unwrap();
+        let mut metadata = fs::read_to_string(src.join("partition-metadata.txt")).unwrap();
+        metadata.push_str("userdata,userdata.img,0x00000000,0x00000040,0x00000000,0x00000800,0x00000000\n");
+        fs::write(src.join("partition-metadata.txt"), metadata).unwrap();
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(src.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let boot = fs::read(src.join("boot.img")).unwrap();
+        let mut expected = vec![0u8; 0x40 * 512 + 1000];
+        expected[0x10 * 512..0x10 * 512 + boot.len()].copy_from_slice(&boot);
+        expected[0x40 * 512..].fill(7);
+
+        // 从映像和解包目录生成的磁盘映像一致
+        for input in [&image, &src] {
+            let disk = temp_dir.path().join("disk.img");
+            let size = assemble_disk(input.to_str().unwrap(), None, disk.to_str().unwrap(), None).unwrap();
+            assert_eq!(size, expected.len() as u64);
+            assert_eq!(fs::read(&disk).unwrap(), expected);
+        }
+
+        // 指定的磁盘大小太小
+        let disk = temp_dir.path().join("small.img");
+        assert!(assemble_disk(image.to_str().unwrap(), None, disk.to_str().unwrap(), Some(4096)).is_err());
+    }
+
+    #[test]
+    fn test_hash_image() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path().to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let digests = hash_image(image.to_str().unwrap()).unwrap();
+        let names: Vec<&str> = digests.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["package-file", "parameter", "boot"]);
+        for (name, file) in [("parameter", "parameter.txt"), ("boot", "boot.img")] {
+            let mut context = md5::Context::new();
+            context.consume(fs::read(temp_dir.path().join(file)).unwrap());
+            let expected = format!("{:x}", context.finalize());
+            assert_eq!(digests.iter().find(|(n, _)| n == name).unwrap().1, expected);
+        }
+    }
+
+    #[test]
+    fn test_grep_image() {
+        use flate2::{write::GzEncoder, Compression};
+
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        // 在 boot 中放入跨越 1 MiB 读取块边界的字符串和 UTF-16 字符串
+        set_boot_flash_size(temp_dir.path(), 0xc00);
+        let mut boot = vec![0u8; 1536 * 1024];
+        boot[1024 * 1024 - 4..1024 * 1024 + 4].copy_from_slice(b"VERSION!");
+        let wide = utf16le_pattern("VERSION!");
+        boot[4096..4096 + wide.len()].copy_from_slice(&wide);
+        fs::write(temp_dir.path().join("boot.img"), &boot).unwrap();
+        // gzip 压缩的分区
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"kernel cmdline VERSION! here").unwrap();
+        fs::write(temp_dir.path().join("kernel.gz"), encoder.finish().unwrap()).unwrap();
+        fs::write(temp_dir.path().join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tboot.img\nkernel\tkernel.gz\n").unwrap();
+        let mut metadata = fs::OpenOptions::new().append(true).open(temp_dir.path().join("partition-metadata.txt")).unwrap();
+        metadata.write_all(b"kernel,kernel.gz,0x00000020,0x00001000,0x00000000,0x00000800,0x00000000\n").unwrap();
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path().to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let image = image.to_str().unwrap();
+
+        let patterns = vec![b"VERSION!".to_vec(), wide];
+        let matches = grep_image(image, &patterns, None, false).unwrap();
+        let found: Vec<(&str, usize, u64)> = matches.iter().map(|m| (m.part.as_str(), m.pattern, m.offset)).collect();
+        assert_eq!(found, [("boot", 1, 4096), ("boot", 0, 1024 * 1024 - 4)]);
+        let boot_part = list_partitions(image).unwrap().into_iter().find(|p| p.name == "boot").unwrap();
+        assert_eq!(matches[1].file_offset, Some(boot_part.file_offset + 1024 * 1024 - 4));
+
+        // 解压后才能找到 kernel 中的字符串
+        let matches = grep_image(image, &patterns[..1], Some("kernel"), true).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!((matches[0].offset, matches[0].file_offset), (15, None));
+
+        let matches = grep_image(image, &[hex_pattern("56 45 52").unwrap()], Some("boot"), false).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert!(hex_pattern("abc").is_err());
+    }
+
+    // 构造最小的 ext4 镜像（1 KiB 块）：/system/build.prop
+    fn create_ext4_image(build_prop: &str) -> Vec<u8> {
+        let mut fs = vec![0u8; 16 * 1024];
+        let put16 = |fs: &mut Vec<u8>, at: usize, v: u16| fs[at..at + 2].copy_from_slice(&v.to_le_bytes());
+        let put32 = |fs: &mut Vec<u8>, at: usize, v: u32| fs[at..at + 4].copy_from_slice(&v.to_le_bytes());
+        // 超级块
+        put32(&mut fs, 1024 + 4, 16);
+        put32(&mut fs, 1024 + 20, 1);
+        put32(&mut fs, 1024 + 40, 16);
+        put16(&mut fs, 1024 + 56, 0xef53);
+        put32(&mut fs, 1024 + 76, 1);
+        put16(&mut fs, 1024 + 88, 128);
+        // 块组描述符：inode 表位于块 4
+        put32(&mut fs, 2048 + 8, 4);
+        let inode = |n: usize| 4096 + (n - 1) * 128;
+        // 根目录（inode 2）和 system 目录（inode 11）使用 extent
+        for (n, block) in [(2, 8u32), (11, 9)] {
+            put16(&mut fs, inode(n), 0x41ed);
+            put32(&mut fs, inode(n) + 4, 1024);
+            put32(&mut fs, inode(n) + 32, 0x80000);
+            put16(&mut fs, inode(n) + 40, 0xf30a);
+            put16(&mut fs, inode(n) + 42, 1);
+            put16(&mut fs, inode(n) + 44, 4);
+            put16(&mut fs, inode(n) + 56, 1);
+            put32(&mut fs, inode(n) + 60, block);
+        }
+        // build.prop（inode 12）使用传统块映射
+        put16(&mut fs, inode(12), 0x81a4);
+        put32(&mut fs, inode(12) + 4, build_prop.len() as u32);
+        put32(&mut fs, inode(12) + 40, 10);
+        let dirent = |fs: &mut Vec<u8>, at: usize, n: u32, rec_len: u16, name: &str| {
+            put32(fs, at, n);
+            put16(fs, at + 4, rec_len);
+            fs[at + 6] = name.len() as u8;
+            fs[at + 8..at + 8 + name.len()].copy_from_slice(name.as_bytes());
+        };
+        dirent(&mut fs, 8 * 1024, 2, 12, ".");
+        dirent(&mut fs, 8 * 1024 + 12, 2, 12, "..");
+        dirent(&mut fs, 8 * 1024 + 24, 11, 1000, "system");
+        dirent(&mut fs, 9 * 1024, 12, 1024, "build.prop");
+        fs[10 * 1024..10 * 1024 + build_prop.len()].copy_from_slice(build_prop.as_bytes());
+        fs
+    }
+
+    // 把镜像包装成 Android sparse 格式：前三个 4 KiB 块为 raw，最后一块为 don't care
+    fn create_sparse_image(raw: &[u8]) -> Vec<u8> {
+        let mut sparse = Vec::new();
+        sparse.extend_from_slice(&0xed26ff3au32.to_le_bytes());
+        sparse.extend_from_slice(&[1, 0, 0, 0, 28, 0, 12, 0]);
+        for value in [4096u32, 4, 2, 0] {
+            sparse.extend_from_slice(&value.to_le_bytes());
+        }
+        for (kind, blocks, total) in [(0xcac1u16, 3u32, 12 + 3 * 4096u32), (0xcac3, 1, 12)] {
+            sparse.extend_from_slice(&kind.to_le_bytes());
+            sparse.extend_from_slice(&[0, 0]);
+            sparse.extend_from_slice(&blocks.to_le_bytes());
+            sparse.extend_from_slice(&total.to_le_bytes());
+            if kind == 0xcac1 {
+                sparse.extend_from_slice(&raw[..3 * 4096]);
+            }
+        }
+        sparse
+    }
+
+    #[test]
+    fn test_android_build_info() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let build_prop = "# begin build properties\n\
+            ro.build.version.release=11\n\
+            ro.build.version.security_patch=2021-08-05\n\
+            ro.build.fingerprint=rockchip/rk3326/rk3326:11/RQ3A/eng.20210805:userdebug/test-keys\n";
+        let system = create_ext4_image(build_prop);
+        fs::write(temp_dir.path().join("system.img"), &system).unwrap();
+        fs::write(temp_dir.path().join("vendor.img"), create_sparse_image(&system)).unwrap();
+        fs::write(temp_dir.path().join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tboot.img\nsystem\tsystem.img\nvendor\tvendor.img\n").unwrap();
+        let mut metadata = fs::OpenOptions::new().append(true).open(temp_dir.path().join("partition-metadata.txt")).unwrap();
+        metadata.write_all(b"system,system.img,0x00000040,0x00000100,0x00000000,0x00004000,0x00000000\n\
+            vendor,vendor.img,0x00000040,0x00000200,0x00000000,0x00003800,0x00000000\n").unwrap();
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path().to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let builds = android_build_info(image.to_str().unwrap()).unwrap();
+        let parts: Vec<&str> = builds.iter().map(|build| build.part.as_str()).collect();
+        assert_eq!(parts, ["system", "vendor"]);
+        for build in &builds {
+            assert_eq!(build.path, "/system/build.prop");
+            assert_eq!(build.fingerprint.as_deref(), Some("rockchip/rk3326/rk3326:11/RQ3A/eng.20210805:userdebug/test-keys"));
+            assert_eq!(build.android_version.as_deref(), Some("11"));
+            assert_eq!(build.security_patch.as_deref(), Some("2021-08-05"));
+        }
+    }
+
+    #[test]
+    fn test_parameter_file_round_trip() {
+        let text = "\u{feff}FIRMWARE_VER:8.1\r\nMACHINE_MODEL:RK3326\r\nMACHINE_ID:007\r\n# 注释\r\nMAGIC: 0x5041524B\r\n\
+            CMDLINE:console=ttyFIQ0 mtdparts=rk29xxnand:0x00002000@0x00002000(uboot),0x00010000@0x00004000(boot:bootable),-@0x00014000(userdata:grow) rootwait\r\n";
+        let mut parameter = ParameterFile::parse(text);
+        // 未修改时原样输出
+        assert_eq!(parameter.to_string(), text);
+        assert_eq!(parameter.keys().collect::<Vec<_>>(), ["FIRMWARE_VER", "MACHINE_MODEL", "MACHINE_ID", "MAGIC", "CMDLINE"]);
+        assert_eq!(parameter.firmware_version(), Some("8.1"));
+        assert_eq!(parameter.machine_model(), Some("RK3326"));
+        assert_eq!(parameter.manufacturer(), None);
+        assert_eq!(parameter.get("MAGIC"), Some("0x5041524B"));
+
+        let mut parts = parameter.mtdparts().unwrap();
+        assert_eq!(parts[1], MtdPart { name: "boot".into(), offset: 0x4000, size: Some(0x10000), flags: Some("bootable".into()) });
+        assert_eq!((parts[2].size, parts[2].flags.as_deref()), (None, Some("grow")));
+
+        parts[1].size = Some(0x12000);
+        parts[2].offset = 0x16000;
+        parameter.set_mtdparts(&parts).unwrap();
+        parameter.set("MACHINE_ID", "008");
+        parameter.set("MANUFACTURER", "rockchip");
+        let edited = parameter.to_string();
+        assert!(edited.starts_with("\u{feff}FIRMWARE_VER:8.1\r\nMACHINE_MODEL:RK3326\r\nMACHINE_ID:008\r\n# 注释\r\n"));
+        assert!(edited.contains("mtdparts=rk29xxnand:0x00002000@0x00002000(uboot),0x00012000@0x00004000(boot:bootable),-@0x00016000(userdata:grow) rootwait\r\n"));
+        assert!(edited.ends_with("MANUFACTURER:rockchip\r\n"));
+        assert_eq!(ParameterFile::parse(&edited).mtdparts().unwrap(), parts);
+    }
+
+    #[test]
+    fn test_output_name_template() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        let template = format!("{}/update_{{model}}_{{machine_id}}_{{version}}_{{date}}.img", dir);
+        pack_rkaf(dir, &template, "RK3326", "RK3326").unwrap();
+        let today = chrono::Utc::now().format("%Y%m%d").to_string();
+        let update = temp_dir.path().join(format!("update_RK3326_007_1.0.0_{}.img", today));
+        assert!(update.is_file());
+
+        // RKFW 的日期取自时间戳
+        fs::copy(&update, temp_dir.path().join("embedded-update.img")).unwrap();
+        fs::write(temp_dir.path().join("BOOT"), vec![0x55u8; 512]).unwrap();
+        let template = format!("{}/{{chip}}-{{version}}-{{date}}.img", dir);
+        pack_rkfw(dir, &template, "RK3326", "8.1.0", 1731031994, "0x02000000").unwrap();
+        assert!(temp_dir.path().join("RK3326-8.1.0-20241108.img").is_file());
+
+        let error = pack_rkaf(dir, format!("{}/{{serial}}.img", dir), "RK3326", "RK3326").unwrap_err();
+        assert!(error.to_string().contains("{serial}"));
+    }
+
+    #[test]
+    fn test_compare_images() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        let a = temp_dir.path().join("a.img");
+        let b = temp_dir.path().join("b.img");
+        pack_rkaf(dir, a.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 分区数据顺序不同、保留字节不同，但语义相同
+        fs::write(temp_dir.path().join("package-file"), "package-file\tpackage-file\nboot\tboot.img\nparameter\tparameter.txt\n").unwrap();
+        pack_rkaf(dir, b.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let mut data = fs::read(&b).unwrap();
+        data[0x7f0] = 0xaa;
+        fs::write(&b, &data).unwrap();
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+        assert_eq!(compare_images(a, b).unwrap(), None);
+
+        let mut boot = fs::read(temp_dir.path().join("boot.img")).unwrap();
+        boot[100] ^= 0xff;
+        fs::write(temp_dir.path().join("boot.img"), &boot).unwrap();
+        pack_rkaf(dir, b, "RK3326", "RK3326").unwrap();
+        assert_eq!(compare_images(a, b).unwrap().as_deref(), Some("Content of boot differs"));
+
+        pack_rkaf(dir, b, "RK3566", "RK3326").unwrap();
+        assert!(compare_images(a, b).unwrap().unwrap().starts_with("Model differs"));
+    }
+
+    #[test]
+    fn test_machine_id() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        let image = temp_dir.path().join("update.img");
+        let image = image.to_str().unwrap();
+
+        // 默认取自 parameter.txt 的 MACHINE_ID
+        pack_rkaf(dir, image, "RK3326", "RK3326").unwrap();
+        let header = read_update_header(image).unwrap();
+        assert_eq!(header.id(), "007");
+        assert_eq!(header.model_name(), "RK3326");
+
+        let options = RkafOptions { machine_id: Some("R35S".into()), ..Default::default() };
+        pack_rkaf_with(dir, image, "RK3326", "RK3326", &options).unwrap();
+        assert_eq!(read_update_header(image).unwrap().id(), "R35S");
+
+        let mut header = UpdateHeader::default();
+        header.set_id(&"x".repeat(40));
+        assert_eq!(header.id().len(), 28);
+        header.set_id("");
+        assert_eq!(header.id(), "");
+    }
+
+    #[test]
+    fn test_rkaf_version() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let image = temp_dir.path().join("update.img");
+
+        // 默认 1.0.0
+        pack_rkaf(temp_dir.path(), &image, "RK3326", "RK3326").unwrap();
+        assert_eq!(read_update_header(&image).unwrap().version, 0x01000000);
+
+        let options = RkafOptions { version: Some("8.1.2".into()), ..Default::default() };
+        pack_rkaf_with(temp_dir.path(), &image, "RK3326", "RK3326", &options).unwrap();
+        let header = read_update_header(&image).unwrap();
+        assert_eq!((header.version, header.version_text()), (0x08010002, "8.1.2".to_string()));
+
+        // 解包报告并记录版本，重新打包时沿用
+        let out = temp_dir.path().join("out");
+        let report = unpack_file_with(&image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        assert_eq!(report.version, "8.1.2");
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkaf(&out, &repacked, "RK3326", "RK3326").unwrap();
+        assert_eq!(read_update_header(&repacked).unwrap().version_text(), "8.1.2");
+
+        // 没有记录时，从目录打包 RKFW 的更新映像沿用固件版本
+        fs::remove_file(out.join("update-manifest.json")).unwrap();
+        fs::write(out.join("parameter.txt"), "MACHINE_MODEL: RK3326\nMANUFACTURER: RK3326\n").unwrap();
+        fs::write(out.join("BOOT"), b"BOOT loader blob").unwrap();
+        let firmware = temp_dir.path().join("firmware.img");
+        pack_rkfw(&out, &firmware, "RK3326", "2.3.4", 1731031994, "0x02000000").unwrap();
+        let update = temp_dir.path().join("embedded.img");
+        unwrap_rkfw(&firmware, &update, None).unwrap();
+        assert_eq!(read_update_header(&update).unwrap().version_text(), "2.3.4");
+
+        let options = RkafOptions { version: Some("1.2".into()), ..Default::default() };
+        assert!(pack_rkaf_with(temp_dir.path(), &image, "RK3326", "RK3326", &options).is_err());
+    }
+
+    #[test]
+    fn test_unpack_reports_rkaf_header() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path(), &image, "RK3326", "RK3326").unwrap();
+        let header = read_update_header(&image).unwrap();
+
+        let out = temp_dir.path().join("out");
+        let report = unpack_file_with(&image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        let update = report.update.unwrap();
+        assert_eq!(update.version.as_deref(), Some("1.0.0"));
+        assert_eq!(update.machine_id.as_deref(), Some("007"));
+        assert_eq!((update.length, update.num_parts), (Some(header.image_length().unwrap()), Some(3)));
+        assert_eq!(UpdateManifest::load(&out).unwrap().unwrap().unknown1, 0);
+
+        // parameter.txt 没有 MACHINE_ID 时沿用记录的值
+        fs::write(out.join("parameter.txt"), "FIRMWARE_VER: 1.0\n").unwrap();
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkaf(&out, &repacked, "RK3326", "RK3326").unwrap();
+        assert_eq!(read_update_header(&repacked).unwrap().id(), "007");
+    }
+
+    #[test]
+    fn test_gbk_header_strings() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        let image = temp_dir.path().join("update.img");
+        let image = image.to_str().unwrap();
+
+        let options = RkafOptions { gbk: true, ..Default::default() };
+        pack_rkaf_with(dir, image, "瑞芯微平板", "瑞芯微电子", &options).unwrap();
+        let header = read_update_header(image).unwrap();
+        // "瑞芯微" 的 GBK 编码
+        assert_eq!(&header.model[..7], b" \xc8\xf0\xd0\xbe\xce\xa2");
+        assert_eq!(header.model_name(), "瑞芯微平板");
+        assert_eq!(header.manufacturer_name(), "瑞芯微电子");
+
+        // 默认仍为 UTF-8
+        pack_rkaf(dir, image, "瑞芯微平板", "RK3326").unwrap();
+        let header = read_update_header(image).unwrap();
+        assert_eq!(&header.model[..16], " 瑞芯微平板".as_bytes());
+        assert_eq!(header.model_name(), "瑞芯微平板");
+    }
+
+    #[test]
+    fn test_expected_chip() {
+        let temp_dir = TempDir::new().unwrap();
+        let rkfw_path = temp_dir.path().join("mock.rkfw");
+        fs::write(&rkfw_path, create_mock_rkfw()).unwrap();
+        let rkfw_path = rkfw_path.to_str().unwrap();
+
+        // 模拟镜像的芯片为 PX30
+        check_chip(rkfw_path, "PX30").unwrap();
+        let error = check_chip(rkfw_path, "RK3566").unwrap_err();
+        assert!(error.to_string().contains("is for PX30"));
+
+        let out = temp_dir.path().join("out");
+        let options = UnpackOptions { expected_chip: Some("RK3326".into()), ..Default::default() };
+        assert!(unpack_file_with(rkfw_path, out.to_str().unwrap(), &options).is_err());
+        assert!(!out.exists());
+
+        // 没有 RKFW 头的 RKAF 镜像无法检查芯片
+        create_rkaf_tree(temp_dir.path());
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path().to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        assert!(check_chip(image.to_str().unwrap(), "RK3326").is_err());
+    }
+
+    #[test]
+    fn test_layout_diff() {
+        let temp_dir = TempDir::new().unwrap();
+        let old = temp_dir.path().join("old.txt");
+        let new = temp_dir.path().join("new.txt");
+        fs::write(&old, "CMDLINE:mtdparts=rk29xxnand:0x2000@0x2000(uboot),0x2000@0x4000(misc),0x10000@0x6000(boot),0x8000@0x16000(cache),-@0x1e000(userdata:grow)\n").unwrap();
+        fs::write(&new, "CMDLINE:mtdparts=rk29xxnand:0x2000@0x2000(uboot),0x2000@0x4000(misc2),0x12000@0x6000(boot),-@0x18000(userdata:grow),0x1000@0x40000(oem)\n").unwrap();
+
+        let changes = diff_layouts(old.to_str().unwrap(), new.to_str().unwrap()).unwrap();
+        assert_eq!(changes[0], LayoutChange::Renamed { from: "misc".into(), to: "misc2".into() });
+        assert_eq!(changes[1], LayoutChange::Resized { name: "boot".into(), from: Some(0x10000), to: Some(0x12000) });
+        assert_eq!(changes[2], LayoutChange::Moved { name: "userdata".into(), from: 0x1e000, to: 0x18000 });
+        assert!(matches!(&changes[3], LayoutChange::Added(part) if part.name == "oem"));
+        assert!(matches!(&changes[4], LayoutChange::Removed(part) if part.name == "cache"));
+        assert_eq!(changes.len(), 5);
+        assert!(!changes[3].ota_unsafe());
+        assert!(changes[2].ota_unsafe());
+
+        // 同一布局没有差异
+        assert!(diff_layouts(old.to_str().unwrap(), old.to_str().unwrap()).unwrap().is_empty());
+    }
+
+    // 最小的 Rockchip loader：一个 loader 条目，数据 16 字节
+    fn create_loader() -> Vec<u8> {
+        let mut loader = vec![0u8; 0x66 + 0x39];
+        loader[..4].copy_from_slice(b"BOOT");
+        loader[4] = 0x66;
+        loader[21..25].copy_from_slice(&0x33333043u32.to_le_bytes());
+        loader[37] = 1;
+        loader[38..42].copy_from_slice(&0x66u32.to_le_bytes());
+        loader[42] = 0x39;
+        loader[0x66] = 0x39;
+        loader[0x66 + 45..0x66 + 49].copy_from_slice(&((0x66 + 0x39) as u32).to_le_bytes());
+        loader[0x66 + 49..0x66 + 53].copy_from_slice(&16u32.to_le_bytes());
+        loader.extend_from_slice(&[0xabu8; 16 + 4]);
+        loader
+    }
+
+    #[test]
+    fn test_scan_dump() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        let rkaf_path = temp_dir.path().join("embedded-update.img");
+        pack_rkaf(dir, rkaf_path.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        fs::write(temp_dir.path().join("BOOT"), create_loader()).unwrap();
+        let rkfw_path = temp_dir.path().join("update.img");
+        pack_rkfw(dir, rkfw_path.to_str().unwrap(), "RK3326", "8.1.0", 1731031994, "0x02000000").unwrap();
+        let rkaf = fs::read(&rkaf_path).unwrap();
+        let rkfw = fs::read(&rkfw_path).unwrap();
+
+        // 模拟闪存转储：干扰文本、RKFW，以及跨越 4 MiB 读取块边界的 RKAF
+        let mut dump = b"junk BOOT RKAF text ".repeat(50);
+        let rkfw_offset = dump.len() as u64;
+        dump.extend_from_slice(&rkfw);
+        let rkaf_offset = 4 * 1024 * 1024 - 2;
+        dump.resize(rkaf_offset, 0);
+        dump.extend_from_slice(&rkaf);
+        dump.extend_from_slice(&[0u8; 4096]);
+        let dump_path = temp_dir.path().join("dump.bin");
+        fs::write(&dump_path, &dump).unwrap();
+        let dump_path = dump_path.to_str().unwrap();
+
+        let hits = scan_dump(dump_path).unwrap();
+        let plausible: Vec<(ImageKind, u64, Option<u64>)> = hits.iter()
+            .filter(|hit| hit.plausible)
+            .map(|hit| (hit.kind, hit.offset, hit.inside))
+            .collect();
+        assert_eq!(plausible, [
+            (ImageKind::Rkfw, rkfw_offset, None),
+            (ImageKind::Loader, rkfw_offset + 0x66, Some(rkfw_offset)),
+            (ImageKind::Rkaf, rkfw_offset + 0x66 + create_loader().len() as u64, Some(rkfw_offset)),
+            (ImageKind::Rkaf, rkaf_offset as u64, None),
+        ]);
+        assert!(hits.iter().any(|hit| !hit.plausible && hit.offset == 5));
+
+        let out = temp_dir.path().join("carved.img");
+        let rkfw_hit = hits.iter().find(|hit| hit.offset == rkfw_offset).unwrap();
+        for (hit, expected) in [(rkfw_hit, &rkfw), (hits.last().unwrap(), &rkaf)] {
+            carve_hit(dump_path, hit, out.to_str().unwrap()).unwrap();
+            assert_eq!(&fs::read(&out).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_timings_report() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        let out = temp_dir.path().join("update.img");
+
+        enable_timings();
+        pack_rkaf(dir, out.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        unpack_file(out.to_str().unwrap(), temp_dir.path().join("out").to_str().unwrap()).unwrap();
+
+        // 打包和解包涉及全部四个阶段
+        let report = timings_report();
+        for name in ["parse", "layout", "io", "hash"] {
+            let timing = report.iter().find(|timing| timing.name == name).unwrap();
+            assert!(timing.count > 0, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_compat_check() {
+        let temp_dir = TempDir::new().unwrap();
+        let build = |name: &str, chip: &str, version: &str, mtdparts: &str, loader_version: u8| {
+            let dir = temp_dir.path().join(name);
+            fs::create_dir_all(&dir).unwrap();
+            create_rkaf_tree(&dir);
+            fs::write(dir.join("parameter.txt"), format!("FIRMWARE_VER: 1.0\nCMDLINE:mtdparts=rk29xxnand:{}\n", mtdparts)).unwrap();
+            let mut loader = create_loader();
+            loader[6] = loader_version;
+            fs::write(dir.join("BOOT"), loader).unwrap();
+            let dir = dir.to_str().unwrap();
+            let rkaf = format!("{}/embedded-update.img", dir);
+            pack_rkaf(dir, &rkaf, "RK3326", "RK3326").unwrap();
+            let image = format!("{}/update.img", dir);
+            pack_rkfw(dir, &image, chip, version, 1731031994, "0x02000000").unwrap();
+            image
+        };
+        let layout = "0x2000@0x2000(uboot),0x10000@0x4000(boot),-@0x14000(userdata:grow)";
+        let installed = build("installed", "RK3326", "1.0.0", layout, 0x10);
+
+        // 新版本、相同布局：可以直接升级
+        let report = check_compat(&installed, build("newer", "RK3326", "1.1.0", layout, 0x11)).unwrap();
+        assert_eq!(report.verdict, Compatibility::Safe);
+
+        // 追加分区仍然安全，loader 降级只作提示
+        let added = format!("{},0x1000@0x40000(oem)", layout);
+        let report = check_compat(&installed, build("added", "RK3326", "1.1.0", &added, 0x0f)).unwrap();
+        assert_eq!(report.verdict, Compatibility::Safe);
+        assert!(report.findings.iter().any(|finding| finding.contains("Loader version goes down: 0.10 -> 0.0f")));
+
+        // boot 变大、userdata 移动：需要迁移
+        let resized = "0x2000@0x2000(uboot),0x12000@0x4000(boot),-@0x16000(userdata:grow)";
+        let report = check_compat(&installed, build("resized", "RK3326", "1.1.0", resized, 0x10)).unwrap();
+        assert_eq!(report.verdict, Compatibility::NeedsMigration);
+
+        // 芯片不同：不同的硬件
+        let report = check_compat(&installed, build("other", "RK3566", "1.1.0", resized, 0x10)).unwrap();
+        assert_eq!(report.verdict, Compatibility::DifferentHardware);
+        assert!(report.findings.iter().any(|finding| finding.starts_with("Chip family differs")));
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        let image = temp_dir.path().join("update.img");
+        let image_str = image.to_str().unwrap();
+        pack_rkaf(dir, image_str, "RK3326", "RK3326").unwrap();
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+
+        // 取消后不留下半成品
+        let output = temp_dir.path().join("cancelled.img");
+        let options = RkafOptions { cancel: cancel.clone(), ..Default::default() };
+        let err = pack_rkaf_with(dir, output.to_str().unwrap(), "RK3326", "RK3326", &options).unwrap_err();
+        assert!(matches!(err, AfptoolError::Cancelled(_)));
+        assert!(!output.exists());
+        assert!(!temp_dir.path().join("cancelled.img.tmp").exists());
+
+        let out = temp_dir.path().join("out");
+        let options = UnpackOptions { cancel: cancel.clone(), ..Default::default() };
+        assert!(matches!(unpack_file_with(image_str, out.to_str().unwrap(), &options).unwrap_err(), AfptoolError::Cancelled(_)));
+        assert!(!out.exists());
+
+        let options = VerifyOptions { cancel, ..Default::default() };
+        assert!(matches!(verify_device_with(image_str, "boot", image_str, &options).unwrap_err(), AfptoolError::Cancelled(_)));
+
+        // 未取消的令牌不影响操作
+        let options = UnpackOptions { cancel: CancelToken::new(), ..Default::default() };
+        unpack_file_with(image_str, out.to_str().unwrap(), &options).unwrap();
+    }
+
+    // 按 rkfp.rs 文档的布局手工构造一个不带 GPT 的 RKFP 容器
+    fn create_mock_rkfp(entries: &[(&str, &str, &[u8])]) -> Vec<u8> {
+        let table_offset = 0x200;
+        let hash_offset = table_offset + entries.len() * 0x80;
+        let mut data_offset = (hash_offset + (entries.len() + 1) * 16).div_ceil(512) * 512;
+        let mut table = Vec::new();
+        let mut hashes = Vec::new();
+        let mut payload = Vec::new();
+        for (i, (name, path, bytes)) in entries.iter().enumerate() {
+            let mut entry = vec![0u8; 0x80];
+            entry[..name.len()].copy_from_slice(name.as_bytes());
+            entry[0x20..0x20 + path.len()].copy_from_slice(path.as_bytes());
+            entry[0x60..0x68].copy_from_slice(&(data_offset as u64).to_le_bytes());
+            entry[0x68..0x70].copy_from_slice(&(bytes.len() as u64).to_le_bytes());
+            entry[0x70..0x78].copy_from_slice(&(0x2000u64 * (i as u64 + 1)).to_le_bytes());
+            entry[0x78..0x80].copy_from_slice(&0x2000u64.to_le_bytes());
+            table.extend(entry);
+            hashes.extend(md5::compute(bytes).0);
+            payload.push((data_offset, bytes.to_vec()));
+            data_offset += bytes.len().div_ceil(512) * 512;
+        }
+
+        let mut data = vec![0u8; data_offset];
+        data[0..4].copy_from_slice(b"RKFP");
+        data[4..8].copy_from_slice(&0x200u32.to_le_bytes());
+        data[8..12].copy_from_slice(&0x01020003u32.to_le_bytes());
+        data[0x0c..0x10].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+        data[0x10..0x14].copy_from_slice(&(table_offset as u32).to_le_bytes());
+        data[0x14..0x18].copy_from_slice(&0x80u32.to_le_bytes());
+        data[0x18..0x1c].copy_from_slice(&(hash_offset as u32).to_le_bytes());
+        data[0x1c..0x20].copy_from_slice(&16u32.to_le_bytes());
+        data[0x30..0x38].copy_from_slice(&(data_offset as u64).to_le_bytes());
+        data[0x40..0x46].copy_from_slice(b"RK3588");
+        data[0x80..0x88].copy_from_slice(b"Rockchip");
+        data[hash_offset..hash_offset + 16].copy_from_slice(&md5::compute(&table).0);
+        data[hash_offset + 16..hash_offset + 16 + hashes.len()].copy_from_slice(&hashes);
+        data[table_offset..hash_offset].copy_from_slice(&table);
+        for (offset, bytes) in payload {
+            data[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+        data
+    }
+
+    #[test]
+    fn test_unpack_rkfp() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = temp_dir.path().join("rkfp.img");
+        let boot = vec![0x42u8; 3000];
+        fs::write(&image, create_mock_rkfp(&[("boot", "Image/boot.img", &boot), ("misc", "Image/misc.img", b"misc")])).unwrap();
+        let image = image.to_str().unwrap();
+
+        // 每个条目按其路径解出，并写出 rkfp-manifest.json
+        let out = temp_dir.path().join("out");
+        unpack_file(image, out.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(out.join("Image/boot.img")).unwrap(), boot);
+        assert_eq!(fs::read(out.join("Image/misc.img")).unwrap(), b"misc");
+        let manifest: serde_json::Value = serde_json::from_str(&fs::read_to_string(out.join("rkfp-manifest.json")).unwrap()).unwrap();
+        assert_eq!(manifest["version"], "1.2.3");
+        assert_eq!(manifest["model"], "RK3588");
+        assert_eq!(manifest["partitions"][1]["name"], "misc");
+        assert_eq!(manifest["partitions"][1]["flash_offset"], 0x4000);
+        verify_tree(out.to_str().unwrap()).unwrap();
+
+        // RKFP 没有 RKAF 头，list 等命令明确拒绝
+        assert!(list_partitions(image).unwrap_err().to_string().contains("RKFP"));
+
+        // 数据与哈希区不符时拒绝解包
+        let mut data = fs::read(image).unwrap();
+        let len = data.len();
+        data[len - 512] ^= 0xff;
+        fs::write(image, data).unwrap();
+        let err = unpack_file(image, temp_dir.path().join("bad").to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("Image/misc.img does not match its MD5"));
+        assert!(!temp_dir.path().join("bad").exists());
+    }
+
+    #[test]
+    fn test_rkfp_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = temp_dir.path().join("rkfp.img");
+        let system = (0..5000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(&image, create_mock_rkfp(&[("boot", "Image/boot.img", &[0x42u8; 3000]), ("system", "Image/system.img", &system)])).unwrap();
+        let out = temp_dir.path().join("out");
+        unpack_file(image.to_str().unwrap(), out.to_str().unwrap()).unwrap();
+
+        // 最后一个分区改为自动增长，打包时生成 GPT
+        let manifest_path = out.join("rkfp-manifest.json");
+        let mut manifest: serde_json::Value = serde_json::from_str(&fs::read_to_string(&manifest_path).unwrap()).unwrap();
+        manifest["partitions"][1]["flash_size"] = serde_json::Value::Null;
+        fs::write(&manifest_path, manifest.to_string()).unwrap();
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkfp(out.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+
+        // 解包打包结果得到相同的文件和清单，再次打包逐字节一致
+        let again = temp_dir.path().join("again");
+        unpack_file(repacked.to_str().unwrap(), again.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(again.join("Image/system.img")).unwrap(), system);
+        assert_eq!(fs::read(again.join("Image/boot.img")).unwrap(), vec![0x42u8; 3000]);
+        let unpacked: serde_json::Value = serde_json::from_str(&fs::read_to_string(again.join("rkfp-manifest.json")).unwrap()).unwrap();
+        assert_eq!(unpacked["partitions"], manifest["partitions"]);
+        assert_eq!(unpacked["version"], "1.2.3");
+        let repacked_again = temp_dir.path().join("repacked-again.img");
+        pack_rkfp(again.to_str().unwrap(), repacked_again.to_str().unwrap()).unwrap();
+        let data = fs::read(&repacked).unwrap();
+        assert_eq!(fs::read(&repacked_again).unwrap(), data);
+
+        // GPT 分区表: 第二个分区一直延伸到最后一个可用扇区
+        let gpt_offset = u64::from_le_bytes(data[0x20..0x28].try_into().unwrap()) as usize;
+        let gpt = &data[gpt_offset..gpt_offset + 34 * 512];
+        assert_eq!(&gpt[510..512], &[0x55, 0xaa]);
+        assert_eq!(&gpt[512..520], b"EFI PART");
+        let last_usable = &gpt[512 + 48..512 + 56];
+        assert_eq!(&gpt[1024 + 128 + 40..1024 + 128 + 48], last_usable);
+        assert_eq!(&gpt[1024 + 56..1024 + 64], &[b'b', 0, b'o', 0, b'o', 0, b't', 0]);
+
+        // GPT 损坏时拒绝解包
+        let mut data = data;
+        data[gpt_offset + 1024 + 32] ^= 0xff;
+        fs::write(&repacked, data).unwrap();
+        let err = unpack_file(repacked.to_str().unwrap(), temp_dir.path().join("bad").to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("GPT partition entries CRC mismatch"));
+        // --force 仍然解包，不匹配记录为警告
+        let options = UnpackOptions { force: true, quiet: true, ..Default::default() };
+        let report = unpack_file_with(&repacked, temp_dir.path().join("forced"), &options).unwrap();
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("GPT partition entries CRC mismatch"));
+    }
+
+    // 按 boot_merger 布局构造 loader：头、三个条目表、数据（无 CRC）
+    fn build_loader(entries: &[(EntryKind, &str, &[u8])]) -> Vec<u8> {
+        let mut loader = vec![0u8; 0x66];
+        loader[..4].copy_from_slice(b"BOOT");
+        loader[4] = 0x66;
+        loader[14..16].copy_from_slice(&2024u16.to_le_bytes());
+        loader[16] = 5;
+        loader[17] = 17;
+        loader[21..25].copy_from_slice(&0x33333043u32.to_le_bytes());
+        let mut table_offset = 0x66u32;
+        for (kind, field) in [(EntryKind::Code471, 25), (EntryKind::Code472, 31), (EntryKind::Loader, 37)] {
+            let count = entries.iter().filter(|(k, _, _)| *k == kind).count();
+            loader[field] = count as u8;
+            loader[field + 1..field + 5].copy_from_slice(&table_offset.to_le_bytes());
+            loader[field + 5] = 0x39;
+            table_offset += count as u32 * 0x39;
+        }
+        let mut data_offset = table_offset;
+        let mut tables = Vec::new();
+        for kind in [EntryKind::Code471, EntryKind::Code472, EntryKind::Loader] {
+            for (_, name, data) in entries.iter().filter(|(k, _, _)| *k == kind) {
+                let mut entry = vec![0u8; 0x39];
+                entry[0] = 0x39;
+                for (i, c) in name.encode_utf16().enumerate() {
+                    entry[5 + i * 2..7 + i * 2].copy_from_slice(&c.to_le_bytes());
+                }
+                entry[45..49].copy_from_slice(&data_offset.to_le_bytes());
+                entry[49..53].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                data_offset += data.len() as u32;
+                tables.extend_from_slice(&entry);
+            }
+        }
+        loader.extend_from_slice(&tables);
+        for kind in [EntryKind::Code471, EntryKind::Code472, EntryKind::Loader] {
+            for (_, _, data) in entries.iter().filter(|(k, _, _)| *k == kind) {
+                loader.extend_from_slice(data);
+            }
+        }
+        loader
+    }
+
+    #[test]
+    fn test_unpack_boot() {
+        let temp_dir = TempDir::new().unwrap();
+        let loader = build_loader(&[
+            (EntryKind::Code471, "DDR", &[1u8; 100]),
+            (EntryKind::Code472, "usbplug", &[2u8; 200]),
+            (EntryKind::Loader, "FlashData", &[3u8; 300]),
+            (EntryKind::Loader, "FlashBoot", &[4u8; 400]),
+        ]);
+        // loader 嵌在 RKFW 镜像的 BOOT 段里
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        pack_rkaf(dir, format!("{}/embedded-update.img", dir), "RK3326", "RK3326").unwrap();
+        fs::write(temp_dir.path().join("BOOT"), &loader).unwrap();
+        let image = format!("{}/update.img", dir);
+        pack_rkfw(dir, &image, "RK3326", "1.0.0", 1731031994, "0x02000000").unwrap();
+
+        let out = temp_dir.path().join("BOOT.d");
+        let parsed = unpack_boot(&image, out.to_str().unwrap(), false).unwrap();
+        assert_eq!(parsed.chip, 0x33333043);
+        assert_eq!(parsed.release_time_text(), "2024-05-17 00:00:00");
+        assert_eq!(parsed.entries.len(), 4);
+        assert_eq!(fs::read(out.join("471-DDR.bin")).unwrap(), [1u8; 100]);
+        assert_eq!(fs::read(out.join("472-usbplug.bin")).unwrap(), [2u8; 200]);
+        assert_eq!(fs::read(out.join("loader-FlashBoot.bin")).unwrap(), [4u8; 400]);
+        let metadata = fs::read_to_string(out.join("boot-metadata.txt")).unwrap();
+        assert!(metadata.contains("ENTRY: loader,FlashData,loader-FlashData.bin,0"));
+        // 手工构造的 loader 没有 CRC
+        assert!(!parsed.crc_ok);
+    }
+
+    #[test]
+    fn test_pack_boot() {
+        let temp_dir = TempDir::new().unwrap();
+        let loader_path = temp_dir.path().join("MiniLoaderAll.bin");
+        fs::write(&loader_path, build_loader(&[
+            (EntryKind::Code471, "DDR", &[1u8; 100]),
+            (EntryKind::Code472, "usbplug", &[2u8; 200]),
+            (EntryKind::Loader, "FlashBoot", &[4u8; 400]),
+        ])).unwrap();
+        let out = temp_dir.path().join("BOOT.d");
+        unpack_boot(loader_path.to_str().unwrap(), out.to_str().unwrap(), false).unwrap();
+
+        // 替换 DDR 初始化代码后重新打包
+        fs::write(out.join("471-DDR.bin"), [9u8; 150]).unwrap();
+        let packed = temp_dir.path().join("BOOT");
+        pack_boot(out.to_str().unwrap(), packed.to_str().unwrap()).unwrap();
+
+        let rebuilt = BootLoader::parse(&fs::read(&packed).unwrap()).unwrap();
+        assert!(rebuilt.crc_ok);
+        assert_eq!(rebuilt.chip, 0x33333043);
+        assert_eq!(rebuilt.release_time_text(), "2024-05-17 00:00:00");
+        let entries: Vec<(EntryKind, &str, usize)> = rebuilt.entries.iter()
+            .map(|entry| (entry.kind, entry.name.as_str(), entry.data.len()))
+            .collect();
+        assert_eq!(entries, [(EntryKind::Code471, "DDR", 150), (EntryKind::Code472, "usbplug", 200), (EntryKind::Loader, "FlashBoot", 400)]);
+        assert_eq!(rebuilt.entries[0].data, [9u8; 150]);
+
+        // 再解包、再打包结果不变
+        let again = temp_dir.path().join("again");
+        unpack_boot(packed.to_str().unwrap(), again.to_str().unwrap(), false).unwrap();
+        let repacked = temp_dir.path().join("BOOT2");
+        pack_boot(again.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), fs::read(&packed).unwrap());
+    }
+
+    #[test]
+    fn test_boot_rc4() {
+        let temp_dir = TempDir::new().unwrap();
+        let loader_path = temp_dir.path().join("MiniLoaderAll.bin");
+        let loader = build_loader(&[(EntryKind::Code471, "DDR", &[0u8; 600])]);
+        fs::write(&loader_path, &loader).unwrap();
+
+        // 全零数据解密后即为 Rockchip 密钥的 RC4 密钥流，每 512 字节重新开始
+        let out = temp_dir.path().join("BOOT.d");
+        unpack_boot(loader_path.to_str().unwrap(), out.to_str().unwrap(), true).unwrap();
+        let plain = fs::read(out.join("471-DDR.bin")).unwrap();
+        let keystream = [0x6e, 0x26, 0x2c, 0xf3, 0xbe, 0x9f, 0x9d, 0x51];
+        assert_eq!(plain[..8], keystream);
+        assert_eq!(plain[512..520], keystream);
+
+        // 打包时重新加密，恢复原始数据
+        let packed = temp_dir.path().join("BOOT");
+        pack_boot(out.to_str().unwrap(), packed.to_str().unwrap()).unwrap();
+        let rebuilt = BootLoader::parse(&fs::read(&packed).unwrap()).unwrap();
+        assert_eq!(rebuilt.entries[0].data, [0u8; 600]);
+    }
+
+    #[test]
+    fn test_read_rkfw_info() {
+        let temp_dir = TempDir::new().unwrap();
+        let rkfw_path = temp_dir.path().join("firmware.img");
+        fs::write(&rkfw_path, create_mock_rkfw()).unwrap();
+
+        let info = read_rkfw_info(rkfw_path.to_str().unwrap()).unwrap().unwrap();
+        assert_eq!(info.version, "8.1.0");
+        assert_eq!(info.chip_code, 0x30);
+        assert_eq!((info.boot_offset, info.boot_size), (0x66, 0x10));
+        assert_eq!((info.update_offset, info.update_size), (0x76, 0x20));
+
+        // 裸 RKAF 镜像没有 RKFW 头
+        create_rkaf_tree(temp_dir.path());
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path().to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        assert!(read_rkfw_info(image.to_str().unwrap()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_verify_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let update = temp_dir.path().join("update.img");
+        let loader = temp_dir.path().join("loader.bin");
+        let firmware = temp_dir.path().join("firmware.img");
+        pack_rkaf(src.to_str().unwrap(), update.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        fs::write(&loader, b"BOOT loader blob").unwrap();
+        wrap_rkaf(update.to_str().unwrap(), loader.to_str().unwrap(), firmware.to_str().unwrap(),
+                  "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+
+        let checks = verify_image(firmware.to_str().unwrap()).unwrap();
+        assert!(checks.iter().all(|check| check.passed), "{:?}", checks);
+        let names: Vec<&str> = checks.iter().map(|check| check.name.as_str()).collect();
+        assert!(names.contains(&"RKFW MD5") && names.contains(&"RKAF CRC") && names.contains(&"Partition boot"));
+
+        // 损坏分区数据后 MD5 和 CRC 都不再匹配
+        let mut data = fs::read(&firmware).unwrap();
+        let boot = list_partitions(firmware.to_str().unwrap()).unwrap().into_iter().find(|part| part.name == "boot").unwrap();
+        data[boot.file_offset as usize] ^= 0xff;
+        fs::write(&firmware, &data).unwrap();
+        let failed: Vec<String> = verify_image(firmware.to_str().unwrap()).unwrap()
+            .into_iter().filter(|check| !check.passed).map(|check| check.name).collect();
+        assert_eq!(failed, ["RKFW MD5", "RKAF CRC"]);
+
+        // 16 字节二进制 MD5 尾部同样可以识别
+        data[boot.file_offset as usize] ^= 0xff;
+        let body_len = data.len() - 32;
+        let digest = md5::compute(&data[..body_len]);
+        data.truncate(body_len);
+        data.extend_from_slice(&digest.0);
+        fs::write(&firmware, &data).unwrap();
+        assert!(verify_image(firmware.to_str().unwrap()).unwrap().iter().all(|check| check.passed));
+    }
+
+    #[test]
+    fn test_unpack_crc_check() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(src.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 损坏一个字节后 CRC 不匹配，默认拒绝解包
+        let boot = list_partitions(image.to_str().unwrap()).unwrap().into_iter().find(|part| part.name == "boot").unwrap();
+        let mut data = fs::read(&image).unwrap();
+        data[boot.file_offset as usize] ^= 0xff;
+        fs::write(&image, &data).unwrap();
+        let out = temp_dir.path().join("out");
+        let error = unpack_file(image.to_str().unwrap(), out.to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("CRC mismatch"));
+        assert!(!out.exists());
+
+        // --force 仍然解包，CRC 不匹配记录在报告的警告中
+        let options = UnpackOptions { force: true, ..Default::default() };
+        let report = unpack_file_with(image.to_str().unwrap(), out.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(out.join("boot.img")).unwrap()[0], data[boot.file_offset as usize]);
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("CRC mismatch"));
+
+        // 长度与文件大小不符同样拒绝解包，--force 时只是警告
+        pack_rkaf(src.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let mut data = fs::read(&image).unwrap();
+        data.extend_from_slice(&[0u8; 512]);
+        fs::write(&image, &data).unwrap();
+        let padded = temp_dir.path().join("padded");
+        let error = unpack_file(image.to_str().unwrap(), padded.to_str().unwrap()).unwrap_err();
+        assert!(matches!(error, AfptoolError::Parse(_)), "{}", error);
+        assert!(!padded.exists());
+        let report = unpack_file_with(image.to_str().unwrap(), padded.to_str().unwrap(), &options).unwrap();
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("length"));
+        assert!(padded.join("boot.img").exists());
+    }
+
+    #[test]
+    fn test_unpack_rkfw_md5() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let update = temp_dir.path().join("update.img");
+        let loader = temp_dir.path().join("loader.bin");
+        let firmware = temp_dir.path().join("firmware.img");
+        pack_rkaf(src.to_str().unwrap(), update.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        fs::write(&loader, b"BOOT loader blob").unwrap();
+        wrap_rkaf(update.to_str().unwrap(), loader.to_str().unwrap(), firmware.to_str().unwrap(),
+                  "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+        let report = unpack_file(firmware.to_str().unwrap(), temp_dir.path().join("good").to_str().unwrap()).unwrap();
+        assert!(report.warnings.is_empty());
+
+        // 损坏 BOOT 区域后 MD5 不匹配
+        let mut data = fs::read(&firmware).unwrap();
+        data[0x66 + 4] ^= 0xff;
+        fs::write(&firmware, &data).unwrap();
+        let out = temp_dir.path().join("out");
+        let error = unpack_file(firmware.to_str().unwrap(), out.to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("MD5 mismatch"));
+
+        let options = UnpackOptions { force: true, ..Default::default() };
+        let report = unpack_file_with(firmware.to_str().unwrap(), out.to_str().unwrap(), &options).unwrap();
+        assert!(out.join("BOOT").exists());
+        assert!(report.warnings.iter().any(|warning| warning.contains("MD5 mismatch")), "{:?}", report.warnings);
+    }
+
+    #[test]
+    fn test_pack_checksums_large_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        // 超过一个 CRC 分块，分块并行计算后合并的结果必须与逐字节计算一致
+        let boot: Vec<u8> = (0..17 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+        set_boot_flash_size(&src, 0x10000);
+        fs::write(src.join("boot.img"), &boot).unwrap();
+        let update = temp_dir.path().join("update.img");
+        let loader = temp_dir.path().join("loader.bin");
+        let firmware = temp_dir.path().join("firmware.img");
+        pack_rkaf(src.to_str().unwrap(), update.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        fs::write(&loader, b"BOOT loader blob").unwrap();
+        wrap_rkaf(update.to_str().unwrap(), loader.to_str().unwrap(), firmware.to_str().unwrap(),
+                  "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+
+        let checks = verify_image(firmware.to_str().unwrap()).unwrap();
+        assert!(checks.iter().all(|check| check.passed), "{:?}", checks);
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(src.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 校验失败
+        let mut data = fs::read(&image).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&image, &data).unwrap();
+        let error = unpack_file(image.to_str().unwrap(), temp_dir.path().join("out").to_str().unwrap()).unwrap_err();
+        assert!(matches!(error, AfptoolError::Checksum(_)));
+
+        // 无法识别的镜像
+        let junk = temp_dir.path().join("junk.img");
+        fs::write(&junk, b"JUNKJUNKJUNK").unwrap();
+        let error = read_update_header(junk.to_str().unwrap()).unwrap_err();
+        assert!(matches!(error, AfptoolError::Parse(_)));
+
+        // 缺少分区元数据
+        fs::remove_file(src.join("partition-metadata.txt")).unwrap();
+        let error = pack_rkaf(src.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap_err();
+        assert!(matches!(error, AfptoolError::Layout(_)));
+    }
+
+    #[test]
+    fn test_unpack_report() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let update = temp_dir.path().join("update.img");
+        let loader = temp_dir.path().join("loader.bin");
+        let firmware = temp_dir.path().join("firmware.img");
+        pack_rkaf(src.to_str().unwrap(), update.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        fs::write(&loader, b"BOOT loader blob").unwrap();
+        wrap_rkaf(update.to_str().unwrap(), loader.to_str().unwrap(), firmware.to_str().unwrap(),
+                  "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+
+        let report = unpack_file(firmware.to_str().unwrap(), temp_dir.path().join("fw").to_str().unwrap()).unwrap();
+        assert_eq!(report.kind, ImageKind::Rkfw);
+        assert_eq!(report.version, "1.2.3");
+        assert_eq!(report.chip.as_deref(), Some("RK3326"));
+        assert_eq!(report.timestamp, Some(1731031994));
+        let names: Vec<&str> = report.parts.iter().map(|part| part.name.as_str()).collect();
+        assert_eq!(names, ["BOOT", "embedded-update.img"]);
+        assert_eq!(report.parts[0].size, 16);
+
+        let out = temp_dir.path().join("out");
+        let report = unpack_file(update.to_str().unwrap(), out.to_str().unwrap()).unwrap();
+        assert_eq!(report.kind, ImageKind::Rkaf);
+        assert_eq!(report.model.as_deref(), Some("RK3326"));
+        let boot = report.parts.iter().find(|part| part.name == "boot").unwrap();
+        assert_eq!(boot.path, "boot.img");
+        assert_eq!(boot.size, 5000);
+        assert_eq!(fs::metadata(out.join(&boot.path)).unwrap().len(), boot.size);
+    }
+
+    #[test]
+    fn test_windows_text_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let unix = temp_dir.path().join("unix");
+        fs::create_dir_all(unix.join("Image")).unwrap();
+        create_rkaf_tree(&unix);
+        fs::rename(unix.join("boot.img"), unix.join("Image/boot.img")).unwrap();
+        fs::write(unix.join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tImage/boot.img\n").unwrap();
+        let metadata = fs::read_to_string(unix.join("partition-metadata.txt")).unwrap().replace("boot.img", "Image/boot.img");
+        fs::write(unix.join("partition-metadata.txt"), &metadata).unwrap();
+
+        // 在 Windows 上编辑过的同一目录：BOM、CRLF 和反斜杠路径
+        let windows = temp_dir.path().join("windows");
+        fs::create_dir_all(windows.join("Image")).unwrap();
+        fs::copy(unix.join("Image/boot.img"), windows.join("Image/boot.img")).unwrap();
+        for name in ["package-file", "parameter.txt", "partition-metadata.txt"] {
+            let text = fs::read_to_string(unix.join(name)).unwrap().replace('\n', "\r\n").replace("Image/", "Image\\");
+            fs::write(windows.join(name), format!("\u{feff}{}", text)).unwrap();
+        }
+
+        let unix_image = temp_dir.path().join("unix.img");
+        let windows_image = temp_dir.path().join("windows.img");
+        pack_rkaf(unix.to_str().unwrap(), unix_image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        pack_rkaf(windows.to_str().unwrap(), windows_image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        // 文本分区内容不同（CRLF），但分区表和 boot 数据一致
+        let describe = |image: &Path| -> Vec<(String, String, u32, u32)> {
+            list_partitions(image.to_str().unwrap()).unwrap().into_iter()
+                .map(|part| (part.name, part.full_path, part.flash_offset, part.flash_size))
+                .collect()
+        };
+        assert_eq!(describe(&windows_image), describe(&unix_image));
+        assert_eq!(describe(&windows_image)[2].1, "Image/boot.img");
+        let boot_digest = |image: &Path| hash_image(image.to_str().unwrap()).unwrap()[2].clone();
+        assert_eq!(boot_digest(&windows_image), boot_digest(&unix_image));
+    }
+
+    #[test]
+    fn test_nested_partition_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir_all(tree.join("Image/vendor/a")).unwrap();
+        create_rkaf_tree(&tree);
+        fs::rename(tree.join("boot.img"), tree.join("Image/vendor/a/boot.img")).unwrap();
+        fs::write(tree.join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tImage\\vendor\\a\\boot.img\n").unwrap();
+        let metadata = fs::read_to_string(tree.join("partition-metadata.txt")).unwrap().replace("boot.img", "Image/vendor/a/boot.img");
+        fs::write(tree.join("partition-metadata.txt"), &metadata).unwrap();
+
+        // 输入目录带结尾分隔符也能打包
+        let image = temp_dir.path().join("update.img");
+        let tree_arg = format!("{}{}", tree.to_str().unwrap(), std::path::MAIN_SEPARATOR);
+        pack_rkaf(&tree_arg, image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 解包时自动创建多级子目录
+        let out = temp_dir.path().join("out");
+        unpack_file(image.to_str().unwrap(), out.to_str().unwrap()).unwrap();
+        assert_eq!(
+            fs::read(out.join("Image").join("vendor").join("a").join("boot.img")).unwrap(),
+            fs::read(tree.join("Image/vendor/a/boot.img")).unwrap()
+        );
+        verify_tree(out.to_str().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_lang_from_locale() {
+        assert_eq!(Lang::from_locale("zh_CN.UTF-8"), Some(Lang::Zh));
+        assert_eq!(Lang::from_locale("zh-TW"), Some(Lang::Zh));
+        assert_eq!(Lang::from_locale("en_US"), Some(Lang::En));
+        assert_eq!(Lang::from_locale("C"), Some(Lang::En));
+        assert_eq!(Lang::from_locale("fr_FR"), None);
+
+        // 未知的消息键原样返回
+        assert_eq!(translate("no-such-key", &[]), "no-such-key");
+    }
+
     #[test]
     fn test_update_header_from_bytes() {
         let mock_rkaf = create_mock_rkaf();
-        let header = UpdateHeader::from_bytes(&mock_rkaf);
+        let header = UpdateHeader::from_bytes(&mock_rkaf).unwrap();
         
         assert_eq!(&header.magic, RKAF_SIGNATURE);
-        // 使用临时变量避免 packed struct 对齐问题
-        let length = header.length;
-        assert_eq!(length, 0x800);
+        assert_eq!(header.length, 0x800);
         
         // 检查厂商信息
         let manufacturer = b"RK3326\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
@@ -109,9 +1814,20 @@ mod tests {
         let model = b"RK3326\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
         assert_eq!(&header.model[..], model);
         
-        // 检查分区数量（使用临时变量避免 packed struct 对齐问题）
-        let num_parts = header.num_parts;
-        assert_eq!(num_parts, 0);
+        // 检查分区数量
+        assert_eq!(header.num_parts, 0);
+
+        // 编码后再解码得到相同的字节
+        assert_eq!(header.to_bytes(), mock_rkaf);
+
+        // 过短的缓冲区、错误的签名和过多的分区都会被拒绝
+        assert!(UpdateHeader::from_bytes(&mock_rkaf[..100]).is_err());
+        let mut bad_magic = mock_rkaf.clone();
+        bad_magic[0] = b'X';
+        assert!(UpdateHeader::from_bytes(&bad_magic).is_err());
+        let mut too_many = mock_rkaf.clone();
+        too_many[136] = 17;
+        assert!(UpdateHeader::from_bytes(&too_many).is_err());
     }
     
     #[test]
@@ -174,4 +1890,1325 @@ mod tests {
         fs::remove_file(&rkfw_path).unwrap();
         fs::remove_file(&rkaf_path).unwrap();
     }
+
+    #[test]
+    fn test_rkaf_builder() {
+        let temp_dir = TempDir::new().unwrap();
+        let parameter = temp_dir.path().join("parameter.txt");
+        fs::write(&parameter, "FIRMWARE_VER: 1.0\nCMDLINE: mtdparts=rk29xxnand:0x00002000@0x00004000(misc)\n").unwrap();
+        let builder = RkafBuilder::new("RK3326", "Rockchip")
+            .machine_id("007")
+            .part(RkafPart::from_file("parameter", "parameter.txt", parameter.to_str().unwrap()))
+            .part(RkafPart::from_bytes("misc", "Image/misc.img", vec![0x5au8; 5000]).at(0x4000, 0x2000));
+
+        // 写入内存中的 Vec，大小与 image_size 一致
+        let mut bytes = Vec::new();
+        let written = builder.write_to(&mut bytes).unwrap();
+        assert_eq!(written, bytes.len() as u64);
+        assert_eq!(builder.image_size().unwrap(), written);
+
+        let image = temp_dir.path().join("update.img");
+        builder.write_file(image.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&image).unwrap(), bytes);
+        assert!(verify_image(image.to_str().unwrap()).unwrap().iter().all(|check| check.passed));
+
+        let header = read_update_header(image.to_str().unwrap()).unwrap();
+        assert_eq!(header.id(), "007");
+        let parts = list_partitions(image.to_str().unwrap()).unwrap();
+        let misc = parts.iter().find(|part| part.name == "misc").unwrap();
+        assert_eq!((misc.flash_offset, misc.flash_size, misc.byte_count), (0x4000, 0x2000, 5000));
+        assert_eq!(misc.file_offset % 2048, 0);
+
+        // 对齐可以调整，分区数量不能超过上限
+        let small = RkafBuilder::new("RK3326", "Rockchip").alignment(512)
+            .part(RkafPart::from_bytes("misc", "Image/misc.img", vec![1u8; 100]));
+        assert_eq!(small.image_size().unwrap(), 2048 + 512 + 4);
+        let mut many = RkafBuilder::new("RK3326", "Rockchip");
+        for i in 0..17 {
+            many = many.part(RkafPart::from_bytes(&format!("p{}", i), "Image/p.img", vec![0u8; 1]));
+        }
+        assert!(matches!(many.image_size().unwrap_err(), AfptoolError::Layout(_)));
+    }
+
+    #[test]
+    fn test_rkfw_builder() {
+        let temp_dir = TempDir::new().unwrap();
+        let rkaf = RkafBuilder::new("RK3326", "Rockchip")
+            .part(RkafPart::from_bytes("misc", "Image/misc.img", vec![7u8; 3000]).at(0x4000, 0x2000));
+        let builder = RkfwBuilder::new("RK3326", "1.2.3", 1731031994, 0x02000000)
+            .loader(PartData::Bytes(b"BOOT loader blob".to_vec()))
+            .update(RkafPayload::Builder(rkaf.clone()));
+
+        let image = temp_dir.path().join("firmware.img");
+        let written = builder.write_file(image.to_str().unwrap()).unwrap();
+        assert_eq!(written, fs::metadata(&image).unwrap().len());
+        assert!(verify_image(image.to_str().unwrap()).unwrap().iter().all(|check| check.passed));
+        let rkfw = read_rkfw_info(image.to_str().unwrap()).unwrap().unwrap();
+        assert_eq!((rkfw.version.as_str(), rkfw.code, rkfw.boot_size), ("1.2.3", 0x02000000, 16));
+
+        // 与先写出 update.img 再用 wrap_rkaf 包装的结果一致
+        let update = temp_dir.path().join("update.img");
+        let loader = temp_dir.path().join("loader.bin");
+        let wrapped = temp_dir.path().join("wrapped.img");
+        rkaf.write_file(update.to_str().unwrap()).unwrap();
+        fs::write(&loader, b"BOOT loader blob").unwrap();
+        wrap_rkaf(update.to_str().unwrap(), loader.to_str().unwrap(), wrapped.to_str().unwrap(),
+                  "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+        assert_eq!(fs::read(&wrapped).unwrap(), fs::read(&image).unwrap());
+        let mut bytes = Vec::new();
+        RkfwBuilder::new("RK3326", "1.2.3", 1731031994, 0x02000000)
+            .loader(PartData::File(loader.clone()))
+            .update(RkafPayload::File(update.clone()))
+            .write_to(&mut bytes).unwrap();
+        assert_eq!(bytes, fs::read(&image).unwrap());
+
+        // 载荷不是 RKAF 或缺少 loader 时报错
+        let bad = RkfwBuilder::new("RK3326", "1.2.3", 1731031994, 0)
+            .loader(PartData::Bytes(vec![0u8; 4]))
+            .update(RkafPayload::Bytes(b"nope".to_vec()));
+        assert!(bad.write_to(&mut Vec::new()).is_err());
+        let no_loader = RkfwBuilder::new("RK3326", "1.2.3", 1731031994, 0).update(RkafPayload::Builder(rkaf));
+        assert!(no_loader.write_to(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_reader_writer_variants() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+
+        // 路径参数可以直接传 PathBuf
+        let update = temp_dir.path().join("update.img");
+        pack_rkaf(&src, &update, "RK3326", "RK3326").unwrap();
+
+        // 打包到内存缓冲区，结果与写文件相同
+        let mut packed = Vec::new();
+        let written = pack_rkaf_to_writer(&src, &mut packed, "RK3326", "RK3326", &RkafOptions::default()).unwrap();
+        assert_eq!(written, packed.len() as u64);
+        assert_eq!(packed, fs::read(&update).unwrap());
+
+        // 从内存中读取、校验和解包
+        let mut reader = std::io::Cursor::new(packed);
+        let names: Vec<String> = list_partitions_from_reader(&mut reader).unwrap().into_iter().map(|part| part.name).collect();
+        assert!(names.contains(&"boot".to_string()));
+        assert!(verify_image_from_reader(&mut reader).unwrap().iter().all(|check| check.passed));
+        let out = temp_dir.path().join("out");
+        let report = unpack_from_reader(&mut reader, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        assert!(report.parts.iter().any(|part| part.name == "boot"));
+        assert!(out.join("partition-metadata.txt").is_file());
+        verify_tree(&out).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_rejects_unsafe_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let out = temp_dir.path().join("nested").join("out");
+        for hostile in ["../evil.img", "Image/../../../evil.img", "Image\\..\\..\\..\\evil.img", "/evil.img", "C:evil.img", "./"] {
+            let image = temp_dir.path().join("hostile.img");
+            RkafBuilder::new("RK3326", "RK3326")
+                .part(RkafPart::from_bytes("misc", hostile, vec![0x41u8; 512]))
+                .write_file(&image).unwrap();
+            // 恶意路径必须报错，且不能在输出目录之外留下文件
+            let err = unpack_file_with(&image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap_err();
+            assert!(matches!(err, AfptoolError::Parse(_)), "{}: {}", hostile, err);
+            assert!(!temp_dir.path().join("evil.img").exists());
+            assert!(!temp_dir.path().join("nested").join("evil.img").exists());
+            assert!(!out.exists());
+        }
+
+        // RKFP 条目的路径同样检查
+        let image = temp_dir.path().join("hostile-rkfp.img");
+        fs::write(&image, create_mock_rkfp(&[("misc", "Image/../../evil.img", b"misc")])).unwrap();
+        let err = unpack_file_with(&image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap_err();
+        assert!(matches!(err, AfptoolError::Parse(_)), "{}", err);
+        assert!(!temp_dir.path().join("evil.img").exists());
+
+        // 普通的嵌套路径照常解包
+        let image = temp_dir.path().join("normal.img");
+        RkafBuilder::new("RK3326", "RK3326")
+            .part(RkafPart::from_bytes("misc", "Image/./sub/misc.img", vec![0x41u8; 512]))
+            .write_file(&image).unwrap();
+        unpack_file_with(&image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        assert!(out.join("Image").join("sub").join("misc.img").is_file());
+    }
+
+    #[test]
+    fn test_damaged_images_never_panic() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let update = temp_dir.path().join("update.img");
+        let loader = temp_dir.path().join("loader.bin");
+        let firmware = temp_dir.path().join("firmware.img");
+        pack_rkaf(&src, &update, "RK3326", "RK3326").unwrap();
+        fs::write(&loader, b"BOOT loader blob").unwrap();
+        wrap_rkaf(&update, &loader, &firmware, "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+
+        let mut damaged: Vec<Vec<u8>> = Vec::new();
+        for original in [fs::read(&firmware).unwrap(), fs::read(&update).unwrap()] {
+            let base = if original.starts_with(RKFW_SIGNATURE) { 0x66 + 16 } else { 0 };
+            // 各种截断长度
+            for len in [3, 4, 0x20, 0x66, 0x80, base + 4, base + 200, base + 2048, base + 4096, original.len() - 40, original.len() - 1] {
+                damaged.push(original[..len.min(original.len())].to_vec());
+            }
+            // 头部中的偏移、长度和分区数被改成极端值
+            let mut fields = vec![base + 4, base + 136];
+            if base > 0 {
+                fields.extend([0x19, 0x1d, 0x21, 0x25]);
+            }
+            for part in 0..3 {
+                fields.extend([92, 96, 100, 104, 108].map(|field| base + 140 + part * 112 + field));
+            }
+            for at in fields {
+                for value in [0xffff_ffffu32, 0x7fff_ffff, 0x1000_0000, 1] {
+                    let mut data = original.clone();
+                    data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+                    damaged.push(data);
+                }
+            }
+            // 路径末尾的 'H' 高 32 位使偏移加大小溢出 u64
+            for part in 0..3 {
+                let mut data = original.clone();
+                let path = base + 140 + part * 112 + 32;
+                for at in [path + 50, path + 55] {
+                    data[at] = b'H';
+                    data[at + 1..at + 5].copy_from_slice(&[0xff; 4]);
+                }
+                damaged.push(data);
+            }
+        }
+
+        let image = temp_dir.path().join("damaged.img");
+        let out = temp_dir.path().join("out");
+        let options = UnpackOptions { quiet: true, force: true, ..Default::default() };
+        for data in damaged {
+            fs::write(&image, &data).unwrap();
+            // 结果可以是 Ok 或 Err，但绝不能 panic
+            let _ = unpack_file_with(&image, &out, &options);
+            let _ = fs::remove_dir_all(&out);
+            let _ = verify_image(&image);
+            let _ = list_partitions(&image);
+            let _ = image_info(&image);
+            let _ = hash_image(&image);
+            let _ = android_build_info(&image);
+            let _ = compare_images(&image, &firmware);
+            let _ = check_compat(&firmware, &image);
+        }
+
+        // 偏移加大小溢出时报告解析错误
+        let mut data = fs::read(&update).unwrap();
+        let path = 140 + 32;
+        for at in [path + 50, path + 55] {
+            data[at] = b'H';
+            data[at + 1..at + 5].copy_from_slice(&[0xff; 4]);
+        }
+        fs::write(&image, &data).unwrap();
+        let err = unpack_file_with(&image, &out, &options).unwrap_err();
+        assert!(matches!(err, AfptoolError::Parse(_)), "{}", err);
+        assert!(matches!(verify_image(&image), Err(AfptoolError::Parse(_))));
+        assert!(matches!(list_partitions(&image), Err(AfptoolError::Parse(_))));
+
+        // 截断的分区数据给出明确的错误，而不是读到一半失败
+        let data = fs::read(&update).unwrap();
+        fs::write(&image, &data[..data.len() - 2000]).unwrap();
+        let err = unpack_file_with(&image, &out, &options).unwrap_err();
+        assert!(err.to_string().contains("runs past the end"), "{}", err);
+        let data = fs::read(&firmware).unwrap();
+        fs::write(&image, &data[..data.len() - 100]).unwrap();
+        let err = unpack_file_with(&image, &out, &options).unwrap_err();
+        assert!(matches!(err, AfptoolError::Parse(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_unpack_boot_signature() {
+        let temp_dir = TempDir::new().unwrap();
+        let rkaf = RkafBuilder::new("RK3326", "RK3326")
+            .part(RkafPart::from_bytes("misc", "Image/misc.img", vec![1u8; 512]));
+        let image = temp_dir.path().join("firmware.img");
+        let out = temp_dir.path().join("out");
+        RkfwBuilder::new("RK3326", "1.0.0", 1731031994, 0)
+            .loader(PartData::Bytes(b"MZ not a loader".to_vec()))
+            .update(RkafPayload::Builder(rkaf))
+            .write_file(&image).unwrap();
+
+        // BOOT 段没有引导程序签名时报错并指出找到的内容
+        let err = unpack_file_with(&image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap_err();
+        assert!(matches!(err, AfptoolError::Parse(_)));
+        assert!(err.to_string().contains("\"MZ n\" (4d 5a 20 6e)"), "{}", err);
+
+        // --lenient 时照常解包
+        let options = UnpackOptions { quiet: true, lenient: true, ..Default::default() };
+        let report = unpack_file_with(&image, &out, &options).unwrap();
+        assert_eq!(fs::read(out.join("BOOT")).unwrap(), b"MZ not a loader");
+        assert!(report.warnings.iter().any(|warning| warning.contains("\"MZ n\"")), "{:?}", report.warnings);
+
+        // 更新映像偏移处不是 RKAF 时同样说明找到的内容
+        let mut data = fs::read(&image).unwrap();
+        let update_offset = 0x66 + 15;
+        data[update_offset..update_offset + 4].copy_from_slice(b"JUNK");
+        fs::write(&image, &data).unwrap();
+        let err = unpack_file_with(&image, temp_dir.path().join("out2"), &UnpackOptions { force: true, ..options }).unwrap_err();
+        assert!(err.to_string().contains("\"JUNK\""), "{}", err);
+    }
+
+    #[test]
+    fn test_unpack_writes_package_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let parameter = "FIRMWARE_VER: 1.0\nMACHINE_ID: 007\n";
+        let image = temp_dir.path().join("update.img");
+        RkafBuilder::new("RK3326", "RK3326")
+            .part(RkafPart::from_bytes("parameter", "parameter.txt", parameter.as_bytes()))
+            .part(RkafPart::from_bytes("misc", "Image/misc.img", vec![3u8; 3000]).at(0x4000, 0x2000))
+            .part(RkafPart::from_bytes("backup", "RESERVED", Vec::new()))
+            .write_file(&image).unwrap();
+
+        // 映像里没有 package-file 时按分区表顺序生成，包括 RESERVED 项
+        let out = temp_dir.path().join("out");
+        unpack_file_with(&image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        let package_file = fs::read_to_string(out.join("package-file")).unwrap();
+        let entries: Vec<&str> = package_file.lines().filter(|line| !line.starts_with('#')).collect();
+        assert_eq!(entries, ["parameter\tparameter.txt", "misc\tImage/misc.img", "backup\tRESERVED"]);
+
+        // 映像自带的 package-file 原样保留
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let packed = temp_dir.path().join("packed.img");
+        pack_rkaf(&src, &packed, "RK3326", "RK3326").unwrap();
+        let out = temp_dir.path().join("out2");
+        unpack_file_with(&packed, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        assert_eq!(fs::read(out.join("package-file")).unwrap(), fs::read(src.join("package-file")).unwrap());
+    }
+
+    #[test]
+    fn test_vendor_package_file_syntax() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        fs::write(temp_dir.path().join("misc.img"), vec![1u8; 100]).unwrap();
+        // 原版 afptool 的注释头、SELF/RESERVED、制表符与空格混用、行尾注释和地址/大小列
+        fs::write(temp_dir.path().join("package-file"), "# NAME\tRelative path\n#\n#HWDEF\tHWDEF\n\
+            package-file\tpackage-file\n\
+            parameter  \t parameter.txt\n\
+            boot\t\tboot.img   # kernel\n\
+            misc misc.img 0x6000 0x2000\n\
+            update SELF\n\
+            backup\t\tRESERVED\n").unwrap();
+        let dir = temp_dir.path().to_str().unwrap();
+        let image = temp_dir.path().join("update.img");
+        let image = image.to_str().unwrap();
+        pack_rkaf(dir, image, "RK3326", "RK3326").unwrap();
+
+        let header = read_update_header(image).unwrap();
+        let part = |name: &str| *header.parts[..header.num_parts as usize].iter()
+            .find(|part| part.name.starts_with(name.as_bytes()) && part.name[name.len()] == 0).unwrap();
+        assert_eq!(header.num_parts, 6);
+        // 没有分区元数据的 misc 取地址列
+        assert_eq!((part("misc").flash_offset, part("misc").flash_size, part("misc").part_byte_count), (0x6000, 0x2000, 100));
+        assert_eq!(part("boot").flash_offset, 0x10);
+        assert_eq!((part("update").part_offset, part("update").part_byte_count), (0, fs::metadata(image).unwrap().len() as u32));
+        assert_eq!((part("backup").part_offset, part("backup").part_byte_count), (0, 0));
+        assert!(verify_image(image).is_ok());
+
+        // 字段过多的行报出行号
+        fs::write(temp_dir.path().join("package-file"), "package-file\tpackage-file\nboot boot.img 0x10 0x20 extra\n").unwrap();
+        let err = pack_rkaf(dir, image, "RK3326", "RK3326").unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_pack_self_entry() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        let image = temp_dir.path().join("update.img");
+        let image = image.to_str().unwrap();
+
+        // 默认不加
+        pack_rkaf(dir, image, "RK3326", "RK3326").unwrap();
+        assert_eq!(read_update_header(image).unwrap().num_parts, 3);
+
+        let options = RkafOptions { self_entry: true, ..Default::default() };
+        pack_rkaf_with(dir, image, "RK3326", "RK3326", &options).unwrap();
+        let header = read_update_header(image).unwrap();
+        assert_eq!(header.num_parts, 5);
+        let (update, backup) = (&header.parts[3], &header.parts[4]);
+        assert!(update.full_path.starts_with(b"SELF\0"));
+        assert_eq!((update.part_offset, update.part_byte_count), (0, fs::metadata(image).unwrap().len() as u32));
+        assert!(backup.full_path.starts_with(b"RESERVED\0"));
+        assert_eq!((backup.part_offset, backup.part_byte_count), (0, 0));
+
+        // verify、list 和 hash 不把这两项当作分区
+        let failed: Vec<String> = verify_image(image).unwrap().into_iter().filter(|check| !check.passed).map(|check| check.name).collect();
+        assert!(failed.is_empty(), "{:?}", failed);
+        let markers: Vec<String> = list_partitions(image).unwrap().into_iter().filter(|part| part.is_marker()).map(|part| part.name).collect();
+        assert_eq!(markers, ["update", "backup"]);
+        assert_eq!(hash_image(image).unwrap().len(), 3);
+
+        // 解包跳过这两项，重新打包仍得到同样的映像
+        let out = temp_dir.path().join("out");
+        unpack_file_with(image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkaf_with(&out, &repacked, "RK3326", "RK3326", &options).unwrap();
+        assert_eq!(fs::read(image).unwrap(), fs::read(&repacked).unwrap());
+    }
+
+    #[test]
+    fn test_firmware_manifest_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = temp_dir.path().join("firmware.img");
+        RkfwBuilder::new("RK3326", "1.2.3", 1731031994, 0x02000000)
+            .loader(PartData::Bytes(b"BOOT loader blob".to_vec()))
+            .update(RkafPayload::Builder(RkafBuilder::new("RK3326", "RK3326")
+                .part(RkafPart::from_bytes("misc", "Image/misc.img", vec![7u8; 3000]).at(0x4000, 0x2000))))
+            .write_file(&image).unwrap();
+
+        let out = temp_dir.path().join("out");
+        unpack_file_with(&image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        let manifest = FirmwareManifest::load(&out).unwrap().unwrap();
+        assert_eq!(manifest.chip.as_deref(), Some("RK3326"));
+        assert_eq!((manifest.version.as_str(), manifest.timestamp, manifest.code.as_str()), ("1.2.3", 1731031994, "0x02000000"));
+        assert_eq!((manifest.boot.offset, manifest.boot.size), (0x66, 16));
+
+        // 不带参数重新打包得到原映像
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkfw_with(&out, &repacked, &RkfwOptions::default()).unwrap();
+        assert_eq!(fs::read(&image).unwrap(), fs::read(&repacked).unwrap());
+
+        // 命令行参数覆盖清单
+        let options = RkfwOptions { version: Some("1.2.4".into()), ..Default::default() };
+        pack_rkfw_with(&out, &repacked, &options).unwrap();
+        assert_eq!(read_rkfw_info(&repacked).unwrap().unwrap().version, "1.2.4");
+
+        // 没有清单时缺少的字段报错
+        fs::remove_file(out.join("firmware-manifest.json")).unwrap();
+        let err = pack_rkfw_with(&out, &repacked, &options).unwrap_err();
+        assert!(err.to_string().contains("--chip"), "{}", err);
+    }
+
+    #[test]
+    fn test_unknown_header_fields_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = temp_dir.path().join("firmware.img");
+        RkfwBuilder::new("RK3326", "1.2.3", 1731031994, 0x02000000)
+            .loader(PartData::Bytes(b"BOOT loader blob".to_vec()))
+            .update(RkafPayload::Builder(RkafBuilder::new("RK3326", "RK3326")
+                .part(RkafPart::from_bytes("misc", "Image/misc.img", vec![7u8; 3000]).at(0x4000, 0x2000))))
+            .write_file(&image).unwrap();
+
+        // 改写 RKFW 保留区和 RKAF 的 unknown1 与尾部保留字节，再修正校验
+        let mut bytes = fs::read(&image).unwrap();
+        bytes[0x40] = 0xab;
+        let update_offset = read_rkfw_info(&image).unwrap().unwrap().update_offset as usize;
+        let rkaf = update_offset..update_offset + UPDATE_HEADER_SIZE;
+        let mut header = UpdateHeader::from_bytes(&bytes[rkaf.clone()]).unwrap();
+        header.unknown1 = 0x12345678;
+        let mut encoded = header.to_bytes();
+        encoded[UPDATE_HEADER_SIZE - 1] = 0xcd;
+        bytes[rkaf].copy_from_slice(&encoded);
+        fs::write(&image, &bytes).unwrap();
+        fix_image(&image).unwrap();
+        assert!(verify_image(&image).unwrap().iter().all(|check| check.passed));
+
+        // 递归解包：内嵌 RKAF 头也要从清单恢复
+        let out = temp_dir.path().join("out");
+        unpack_file_with(&image, &out, &UnpackOptions { quiet: true, recursive: true, ..Default::default() }).unwrap();
+        assert!(out.join("update-manifest.json").is_file());
+        assert!(FirmwareManifest::load(&out).unwrap().unwrap().reserved.is_some());
+
+        // 重新打包逐字节一致
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkfw_with(&out, &repacked, &RkfwOptions::default()).unwrap();
+        assert_eq!(fs::read(&image).unwrap(), fs::read(&repacked).unwrap());
+
+        // 手工指定的字节覆盖清单记录的值
+        let header_bytes = vec![parse_header_byte("0x2d=0x02").unwrap(), parse_header_byte("101=255").unwrap()];
+        pack_rkfw_with(&out, &repacked, &RkfwOptions { header_bytes, ..Default::default() }).unwrap();
+        let bytes = fs::read(&repacked).unwrap();
+        assert_eq!((bytes[0x2d], bytes[0x40], bytes[0x65]), (0x02, 0xab, 0xff));
+        assert!(verify_image(&repacked).unwrap().iter().all(|check| check.passed));
+
+        // 分区表内的偏移和非法写法都报错
+        let options = RkfwOptions { header_bytes: vec![(0x21, 0)], ..Default::default() };
+        assert!(pack_rkfw_with(&out, &repacked, &options).is_err());
+        assert!(parse_header_byte("0x2d").is_err());
+        assert!(parse_header_byte("0x2d=256").is_err());
+    }
+
+    #[test]
+    fn test_model_from_parameter() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let image = temp_dir.path().join("update.img");
+
+        // parameter.txt 里没有时必须给出型号
+        let err = pack_rkaf(temp_dir.path(), &image, "", "RK3326").unwrap_err();
+        assert!(err.to_string().contains("MACHINE_MODEL"), "{}", err);
+
+        fs::write(temp_dir.path().join("parameter.txt"),
+                  "FIRMWARE_VER: 1.0\nMACHINE_MODEL: R36S\nMACHINE_ID: 007\nMANUFACTURER: Game Console\n").unwrap();
+        pack_rkaf(temp_dir.path(), &image, "", "").unwrap();
+        let header = read_update_header(&image).unwrap();
+        assert_eq!((header.model_name().as_str(), header.manufacturer_name().as_str(), header.id().as_str()), ("R36S", "Game Console", "007"));
+
+        // 显式给出的优先
+        pack_rkaf(temp_dir.path(), &image, "RK3326", "").unwrap();
+        let header = read_update_header(&image).unwrap();
+        assert_eq!((header.model_name().as_str(), header.manufacturer_name().as_str()), ("RK3326", "Game Console"));
+    }
+
+    #[test]
+    fn test_layout_from_mtdparts() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        fs::write(temp_dir.path().join("userdata.img"), vec![1u8; 100]).unwrap();
+        fs::write(temp_dir.path().join("package-file"),
+                  "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tboot.img\nuserdata\tuserdata.img\n").unwrap();
+        fs::write(temp_dir.path().join("parameter.txt"), "FIRMWARE_VER: 1.0\n\
+            CMDLINE: console=ttyFIQ0 mtdparts=rk29xxnand:0x00002000@0x00004000(boot),-@0x00010000(userdata:grow)\n").unwrap();
+        let image = temp_dir.path().join("update.img");
+        let placement = |name: &str| {
+            let header = read_update_header(&image).unwrap();
+            let part = header.parts[..header.num_parts as usize].iter()
+                .find(|part| part.name.starts_with(name.as_bytes()) && part.name[name.len()] == 0).copied().unwrap();
+            (part.flash_offset, part.flash_size)
+        };
+
+        // partition-metadata.txt 里有 boot，优先于 mtdparts；userdata 只能取自 mtdparts
+        pack_rkaf(temp_dir.path(), &image, "RK3326", "RK3326").unwrap();
+        assert_eq!(placement("boot"), (0x10, 0x20));
+        // "-" 大小写成 FLASH_SIZE_GROW，而不是 0
+        assert_eq!(placement("userdata"), (0x10000, FLASH_SIZE_GROW));
+        assert_eq!(placement("parameter"), (0, 0));
+
+        let options = RkafOptions { mtdparts_primary: true, ..Default::default() };
+        pack_rkaf_with(temp_dir.path(), &image, "RK3326", "RK3326", &options).unwrap();
+        assert_eq!(placement("boot"), (0x4000, 0x2000));
+
+        // 没有元数据文件时完全由 mtdparts 决定
+        fs::remove_file(temp_dir.path().join("partition-metadata.txt")).unwrap();
+        pack_rkaf(temp_dir.path(), &image, "RK3326", "RK3326").unwrap();
+        assert_eq!(placement("boot"), (0x4000, 0x2000));
+        assert_eq!(placement("userdata"), (0x10000, FLASH_SIZE_GROW));
+        assert!(verify_image(&image).is_ok());
+    }
+
+    #[test]
+    fn test_layout_validation() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        fs::write(temp_dir.path().join("misc.img"), vec![1u8; 100]).unwrap();
+        fs::write(temp_dir.path().join("package-file"),
+                  "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tboot.img\nmisc\tmisc.img 0x14 0x8\n").unwrap();
+        // boot.img 有 5000 字节，放不进 8 个扇区；misc 与 boot 重叠
+        set_boot_flash_size(temp_dir.path(), 0x8);
+        let image = temp_dir.path().join("update.img");
+        let err = pack_rkaf(temp_dir.path(), &image, "RK3326", "RK3326").unwrap_err();
+        assert!(matches!(err, AfptoolError::Layout(_)));
+        let message = err.to_string();
+        assert!(message.contains("2 problems"), "{}", message);
+        assert!(message.lines().any(|line| line.trim_start().starts_with("boot") && line.contains("5000 bytes do not fit")), "{}", message);
+        assert!(message.lines().any(|line| line.trim_start().starts_with("misc") && line.contains("overlaps boot")), "{}", message);
+        assert!(!image.exists());
+
+        // 相邻而不重叠的分区可以打包
+        set_boot_flash_size(temp_dir.path(), 0x10);
+        fs::write(temp_dir.path().join("package-file"),
+                  "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tboot.img\nmisc\tmisc.img 0x20 0x8\n").unwrap();
+        pack_rkaf(temp_dir.path(), &image, "RK3326", "RK3326").unwrap();
+
+        // 代码构建的映像同样检查
+        let err = RkafBuilder::new("RK3326", "RK3326")
+            .part(RkafPart::from_bytes("a", "a.img", vec![0u8; 100]).at(0x100, 0x100))
+            .part(RkafPart::from_bytes("b", "b.img", vec![0u8; 100]).at(0x180, 0x100))
+            .image_size().unwrap_err();
+        assert!(err.to_string().contains("overlaps a"), "{}", err);
+    }
+
+    #[test]
+    fn test_rkcrc_wrap() {
+        let temp_dir = TempDir::new().unwrap();
+        let parameter = b"FIRMWARE_VER: 1.0\nMACHINE_ID: 007\n";
+        let wrapped = wrap_rkcrc(CrcWrapper::Parm, parameter).unwrap();
+        assert_eq!(&wrapped[..8], &[b'P', b'A', b'R', b'M', parameter.len() as u8, 0, 0, 0]);
+        assert_eq!(ParameterFile::from_bytes(&wrapped).machine_id(), Some("007"));
+
+        // CRC 与 RKAF 映像尾部使用的相同
+        let mut image = Vec::new();
+        RkafBuilder::new("RK3326", "RK3326")
+            .part(RkafPart::from_bytes("parameter", "parameter.txt", parameter.to_vec()))
+            .write_to(&mut image).unwrap();
+        let (body, crc) = image.split_at(image.len() - 4);
+        assert_eq!(&wrap_rkcrc(CrcWrapper::Krnl, body).unwrap()[8 + body.len()..], crc);
+
+        // 文件往返，末尾填充被忽略
+        let (input, kernel, output) = (temp_dir.path().join("Image"), temp_dir.path().join("kernel.img"), temp_dir.path().join("out"));
+        fs::write(&input, vec![0x42u8; 5000]).unwrap();
+        rkcrc_wrap_file(&input, &kernel, CrcWrapper::Krnl).unwrap();
+        let mut data = fs::read(&kernel).unwrap();
+        data.extend_from_slice(&[0u8; 100]);
+        fs::write(&kernel, &data).unwrap();
+        assert_eq!(rkcrc_unwrap_file(&kernel, &output).unwrap(), CrcWrapper::Krnl);
+        assert_eq!(fs::read(&output).unwrap(), fs::read(&input).unwrap());
+
+        // 损坏的数据与截断的文件
+        data[20] ^= 1;
+        let err = unwrap_rkcrc(&data).unwrap_err();
+        assert!(matches!(err, AfptoolError::Checksum(_)));
+        let err = unwrap_rkcrc(&wrapped[..wrapped.len() - 2]).unwrap_err();
+        assert!(matches!(err, AfptoolError::Parse(_)));
+        assert!(unwrap_rkcrc(b"RKAF").is_err());
+    }
+
+    #[test]
+    fn test_parameter_parm_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let parameter = b"FIRMWARE_VER: 1.0\nMACHINE_ID: 007\n";
+        let image = temp_dir.path().join("update.img");
+        RkafBuilder::new("RK3326", "RK3326")
+            .part(RkafPart::from_bytes("parameter", "Image/parameter.txt", wrap_rkcrc(CrcWrapper::Parm, parameter).unwrap()))
+            .part(RkafPart::from_bytes("misc", "Image/misc.img", vec![3u8; 3000]).at(0x4000, 0x2000))
+            .write_file(&image).unwrap();
+
+        // 解包得到去掉 PARM 头的文本，原始数据另存一份
+        let out = temp_dir.path().join("out");
+        unpack_file_with(&image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        assert_eq!(fs::read(out.join("Image/parameter.txt")).unwrap(), parameter);
+        assert_eq!(fs::read(out.join("Image/parameter.txt.parm")).unwrap(), wrap_rkcrc(CrcWrapper::Parm, parameter).unwrap());
+        let metadata = fs::read_to_string(out.join("partition-metadata.txt")).unwrap();
+        assert!(metadata.lines().any(|line| line.starts_with("parameter,") && line.ends_with(",parm")));
+        verify_tree(&out).unwrap();
+
+        // 修改文本后重新打包，自动加回 PARM 头
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkaf(&out, &repacked, "RK3326", "RK3326").unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), fs::read(&image).unwrap());
+        fs::write(out.join("Image/parameter.txt"), b"FIRMWARE_VER: 2.0\nMACHINE_ID: 007\n").unwrap();
+        let mut bytes = Vec::new();
+        pack_rkaf_to_writer(&out, &mut bytes, "RK3326", "RK3326", &RkafOptions::default()).unwrap();
+        fs::write(&repacked, &bytes).unwrap();
+        let part = list_partitions(&repacked).unwrap().into_iter().find(|part| part.name == "parameter").unwrap();
+        let data = &bytes[part.file_offset as usize..part.file_offset as usize + part.byte_count as usize];
+        assert_eq!(unwrap_rkcrc(data).unwrap(), (CrcWrapper::Parm, &b"FIRMWARE_VER: 2.0\nMACHINE_ID: 007\n"[..]));
+    }
+
+    #[test]
+    fn test_unpack_only() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        let rkaf = format!("{}/embedded-update.img", dir);
+        pack_rkaf(dir, &rkaf, "RK3326", "RK3326").unwrap();
+        fs::write(temp_dir.path().join("BOOT"), b"BOOT loader").unwrap();
+        let image = format!("{}/update.img", dir);
+        pack_rkfw(dir, &image, "RK3326", "1.0.0", 1731031994, "0x02000000").unwrap();
+
+        // RKFW 镜像中直接取出内嵌 update.img 里的分区，不写元数据
+        let out = temp_dir.path().join("out");
+        let options = UnpackOptions { only: vec!["boot".to_string()], verify: true, quiet: true, ..Default::default() };
+        let report = unpack_file_with(&image, &out, &options).unwrap();
+        assert_eq!(report.parts.len(), 1);
+        assert_eq!(fs::read(out.join("boot.img")).unwrap(), fs::read(temp_dir.path().join("boot.img")).unwrap());
+        for skipped in ["BOOT", "embedded-update.img", "parameter.txt", "partition-metadata.txt", "package-file", "firmware-manifest.json"] {
+            assert!(!out.join(skipped).exists(), "{}", skipped);
+        }
+
+        // 通配符按路径匹配，--with-metadata 仍写出完整的元数据
+        let out = temp_dir.path().join("out2");
+        let options = UnpackOptions { only: vec!["*.txt".to_string()], with_metadata: true, quiet: true, ..Default::default() };
+        unpack_file_with(&rkaf, &out, &options).unwrap();
+        assert!(out.join("parameter.txt").exists() && !out.join("boot.img").exists());
+        assert_eq!(fs::read_to_string(out.join("partition-metadata.txt")).unwrap().lines().count(), 3);
+
+        // 没有匹配任何分区的模式报错
+        let options = UnpackOptions { only: vec!["boot".to_string(), "nope".to_string()], quiet: true, ..Default::default() };
+        let err = unpack_file_with(&image, temp_dir.path().join("out3"), &options).unwrap_err();
+        assert!(err.to_string().contains("nope"));
+        assert!(!temp_dir.path().join("out3").exists());
+    }
+
+    #[test]
+    fn test_cat_partition() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path(), &image, "RK3326", "RK3326").unwrap();
+
+        // 输出分区的原始字节，不含对齐填充
+        let mut out = Vec::new();
+        assert_eq!(cat_partition(&image, "boot", &mut out).unwrap(), 5000);
+        assert_eq!(out, fs::read(temp_dir.path().join("boot.img")).unwrap());
+        assert!(cat_partition(&image, "nope", &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_replace_part() {
+        let temp_dir = TempDir::new().unwrap();
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        create_rkaf_tree(&tree);
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(&tree, &image, "RK3326", "RK3326").unwrap();
+        let size = fs::metadata(&image).unwrap().len();
+
+        // 更大的数据则重新布局，padded_size 随之增大
+        let large = temp_dir.path().join("large.img");
+        fs::write(&large, vec![0xa5; 7000]).unwrap();
+        let result = temp_dir.path().join("result.img");
+        replace_part(&image, "boot", &large, Some(result.as_path())).unwrap();
+        assert!(verify_image(&result).unwrap().iter().all(|check| check.passed));
+        let boot = read_update_header(&result).unwrap().parts[2];
+        assert_eq!((boot.part_byte_count, boot.padded_size), (7000, 0x2000));
+        assert_eq!(fs::metadata(&result).unwrap().len(), size + 0x800);
+
+        // 新数据放得下原来的空间时原地改写，镜像大小不变，校验和重新计算
+        let small = temp_dir.path().join("small.img");
+        fs::write(&small, vec![0x5a; 6000]).unwrap();
+        replace_part(&image, "boot", &small, None).unwrap();
+        assert_eq!(fs::metadata(&image).unwrap().len(), size);
+        assert!(verify_image(&image).unwrap().iter().all(|check| check.passed));
+        let mut data = Vec::new();
+        cat_partition(&image, "boot", &mut data).unwrap();
+        assert_eq!(data, [0x5a; 6000]);
+
+        // RKFW 镜像原地替换后 MD5 仍然正确
+        let loader = temp_dir.path().join("loader.bin");
+        fs::write(&loader, b"BOOT loader blob").unwrap();
+        let firmware = temp_dir.path().join("firmware.img");
+        wrap_rkaf(&image, &loader, &firmware, "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+        replace_part(&firmware, "boot", &small, None).unwrap();
+        assert!(verify_image(&firmware).unwrap().iter().all(|check| check.passed));
+    }
+
+    #[test]
+    fn test_edit_header() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path(), &image, "A much longer model name", "RK3326").unwrap();
+        let loader = temp_dir.path().join("loader.bin");
+        fs::write(&loader, b"BOOT loader blob").unwrap();
+        let firmware = temp_dir.path().join("firmware.img");
+        wrap_rkaf(&image, &loader, &firmware, "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+        let size = fs::metadata(&firmware).unwrap().len();
+
+        // 原地改写两层头部，旧的长型号不留残余，校验和仍然正确
+        let edit = HeaderEdit { model: Some("X1".to_string()), version: Some("2.0.7".to_string()), ..Default::default() };
+        edit_header(&firmware, &edit).unwrap();
+        assert_eq!(fs::metadata(&firmware).unwrap().len(), size);
+        assert!(verify_image(&firmware).unwrap().iter().all(|check| check.passed));
+        let header = read_update_header(&firmware).unwrap();
+        assert_eq!((header.model_name(), header.manufacturer_name()), ("X1".to_string(), "RK3326".to_string()));
+        assert_eq!(header.version, 0x02000007);
+        assert_eq!(read_rkfw_info(&firmware).unwrap().unwrap().version, "2.0.7");
+
+        assert!(edit_header(&firmware, &HeaderEdit::default()).is_err());
+    }
+
+    #[test]
+    fn test_fix_image() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path(), &image, "RK3326", "RK3326").unwrap();
+        let original = fs::read(&image).unwrap();
+        assert!(fix_image(&image).unwrap().is_empty());
+
+        // 手工改了一个字节：只需重算 CRC
+        let mut edited = original.clone();
+        let boot = read_update_header(&image).unwrap().parts[2];
+        edited[boot.part_offset as usize] ^= 0xff;
+        fs::write(&image, &edited).unwrap();
+        assert_eq!(fix_image(&image).unwrap().len(), 1);
+        assert!(verify_image(&image).unwrap().iter().all(|check| check.passed));
+
+        // 截掉 boot 之后的填充和 CRC：长度改为数据末尾
+        let data_end = (boot.part_offset + boot.part_byte_count) as usize;
+        fs::write(&image, &original[..data_end]).unwrap();
+        fix_image(&image).unwrap();
+        assert_eq!(read_update_header(&image).unwrap().length as usize, data_end);
+        assert!(verify_image(&image).unwrap().iter().all(|check| check.passed));
+
+        // RKFW：内嵌镜像被截短后 update 大小和 MD5 都要改
+        fs::write(&image, &original).unwrap();
+        let loader = temp_dir.path().join("loader.bin");
+        fs::write(&loader, b"BOOT loader blob").unwrap();
+        let firmware = temp_dir.path().join("firmware.img");
+        wrap_rkaf(&image, &loader, &firmware, "RK3326", "1.2.3", 1731031994, "0x02000000").unwrap();
+        let wrapped = fs::read(&firmware).unwrap();
+        let update_offset = read_rkfw_info(&firmware).unwrap().unwrap().update_offset as usize;
+        fs::write(&firmware, &wrapped[..update_offset + data_end]).unwrap();
+        assert_eq!(fix_image(&firmware).unwrap().len(), 4);
+        assert!(verify_image(&firmware).unwrap().iter().all(|check| check.passed));
+
+        // 分区数据本身缺失则无法修复
+        fs::write(&image, &original[..data_end - 1]).unwrap();
+        assert!(fix_image(&image).is_err());
+    }
+
+    #[test]
+    fn test_chip_table() {
+        // 名称不区分大小写，可省略 RK 前缀，别名对应同一代码
+        assert_eq!(chip_name_to_code("rk3326").unwrap(), u32::from_le_bytes(*b"6233"));
+        assert_eq!(chip_name_to_code("3326").unwrap(), chip_name_to_code("RK3326").unwrap());
+        assert_eq!(chip_name_to_code("RK3399PRO").unwrap(), chip_name_to_code("RK3399").unwrap());
+        assert_eq!(chip_name_to_code("PX30").unwrap(), 0x30);
+        assert!(chip_name_to_code("RK9999").is_err());
+        // 同一代码字节的芯片靠数字区分，只有代码字节时也能识别
+        assert_eq!(chip_code_to_name(u32::from_le_bytes(*b"A303")), Some("RK3036"));
+        assert_eq!(chip_code_to_name(u32::from_le_bytes(*b"A023")), Some("RK3288"));
+        assert_eq!(chip_code_to_name(0x36), Some("RK3326"));
+        assert_eq!(chip_code_to_name(u32::from_le_bytes(*b"A999")), None);
+        // 芯片字段来自 Rockchip 的芯片标识，不一定是型号里的数字
+        for (chip, stock) in [("RK3399", b"330C"), ("RK3328", b"322H"), ("RK3288", b"320A"), ("RK3368", b"330A")] {
+            let mut field = *stock;
+            field.reverse();
+            assert_eq!(chip_name_to_code(chip).unwrap(), u32::from_le_bytes(field), "{}", chip);
+            assert_eq!(chip_code_to_name(u32::from_le_bytes(field)), Some(chip));
+        }
+
+        // 新芯片的四字节芯片字段写入 RKFW 头并在解包时识别
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        pack_rkaf(dir, format!("{}/embedded-update.img", dir), "RK3588", "RK3588").unwrap();
+        fs::write(temp_dir.path().join("BOOT"), b"BOOT loader").unwrap();
+        let image = format!("{}/update.img", dir);
+        pack_rkfw(dir, &image, "rk3588", "1.0.0", 1731031994, "0x02000000").unwrap();
+        assert_eq!(&fs::read(&image).unwrap()[0x15..0x19], b"8853");
+        let report = unpack_file_with(&image, temp_dir.path().join("out"), &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        assert_eq!(report.chip.as_deref(), Some("RK3588"));
+        check_chip(&image, "RK3588").unwrap();
+        assert!(check_chip(&image, "RK3566").is_err());
+    }
+
+    #[test]
+    fn test_raw_chip_code() {
+        assert_eq!(chip_field(0x36, Some("332")).unwrap(), chip_name_to_code("RK3326").unwrap());
+        assert_eq!(chip_field(0x12345678, None).unwrap(), 0x12345678);
+        assert!(chip_field(0x1234, Some("332")).is_err());
+
+        // 表中没有的芯片代码原样写入
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        pack_rkaf(dir, format!("{}/embedded-update.img", dir), "X", "X").unwrap();
+        fs::write(temp_dir.path().join("BOOT"), b"BOOT loader").unwrap();
+        let image = temp_dir.path().join("update.img");
+        let options = RkfwOptions {
+            chip_code: Some(chip_field(0x5a, Some("987")).unwrap()),
+            version: Some("1.0.0".into()),
+            timestamp: Some(1731031994),
+            code: Some("0x02000000".into()),
+            ..Default::default()
+        };
+        pack_rkfw_with(dir, &image, &options).unwrap();
+        assert_eq!(&fs::read(&image).unwrap()[0x15..0x19], b"Z789");
+
+        // 解包后不带参数重新打包，得到逐字节相同的映像
+        let out = temp_dir.path().join("out");
+        let report = unpack_file_with(&image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        assert_eq!(report.chip, None);
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkfw_with(&out, &repacked, &RkfwOptions::default()).unwrap();
+        assert_eq!(fs::read(&image).unwrap(), fs::read(&repacked).unwrap());
+    }
+
+    #[test]
+    fn test_chip_db() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = temp_dir.path().join("chips.toml");
+        fs::write(&db, r#"# 厂商自定义芯片
+[[chip]]
+name = "RK7777"
+aliases = ["RK7777J", "RK7778"]  # 别名
+code = "Q"
+tag = "776"            # 与名称数字不同
+default_code = "0x03000000"
+
+[[chip]]
+name = "RK7701"
+code = 0x71
+"#).unwrap();
+        assert_eq!(load_chip_db(&db).unwrap(), 2);
+        let field = chip_name_to_code("rk7777j").unwrap();
+        assert_eq!(field.to_le_bytes(), *b"Q677");
+        assert_eq!(chip_code_to_name(field), Some("RK7777"));
+        assert_eq!(chip_default_code(field), Some(0x03000000));
+        assert_eq!(chip_default_code(chip_name_to_code("RK7701").unwrap()), Some(0x02000000));
+
+        // 未给 --code 时使用数据库里的默认值
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        pack_rkaf(dir, format!("{}/embedded-update.img", dir), "X", "X").unwrap();
+        fs::write(temp_dir.path().join("BOOT"), b"BOOT loader").unwrap();
+        let image = temp_dir.path().join("update.img");
+        let options = RkfwOptions {
+            chip: Some("RK7777".into()),
+            version: Some("1.0.0".into()),
+            timestamp: Some(1731031994),
+            ..Default::default()
+        };
+        pack_rkfw_with(dir, &image, &options).unwrap();
+        assert_eq!(&fs::read(&image).unwrap()[0x0a..0x0e], &0x03000000u32.to_le_bytes());
+
+        fs::write(&db, "[[chip]]\nname = \"RK7779\"\n").unwrap();
+        assert!(load_chip_db(&db).unwrap_err().to_string().contains("no code"));
+    }
+
+    #[test]
+    fn test_large_offsets() {
+        // 4 GiB 以上的偏移量和大小保存在路径字段末尾
+        let offset = 5u64 << 30;
+        let mut header = UpdateHeader::default();
+        header.magic.copy_from_slice(RKAF_SIGNATURE);
+        header.num_parts = 1;
+        let part = &mut header.parts[0];
+        part.name[..4].copy_from_slice(b"data");
+        part.full_path[..14].copy_from_slice(b"Image/data.img");
+        part.set_offset(offset).unwrap();
+        part.set_byte_count(16).unwrap();
+        header.set_image_length(offset + 2048);
+        let bytes = header.to_bytes();
+        assert_eq!(bytes[0x8c + 32 + 50], b'H');
+        let parsed = UpdateHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.parts[0].offset(), offset);
+        assert_eq!(parsed.parts[0].byte_count(), 16);
+        assert_eq!(parsed.image_length().unwrap(), offset + 2048);
+
+        // 稀疏文件中的分区可以列出和读取
+        let temp_dir = TempDir::new().unwrap();
+        let image = temp_dir.path().join("large.img");
+        let mut file = File::create(&image).unwrap();
+        file.write_all(&bytes).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(b"large partition!").unwrap();
+        file.set_len(offset + 2048 + 4).unwrap();
+        drop(file);
+        let parts = list_partitions(&image).unwrap();
+        assert_eq!((parts[0].file_offset, parts[0].byte_count), (offset, 16));
+        let mut data = Vec::new();
+        cat_partition(&image, "data", &mut data).unwrap();
+        assert_eq!(data, b"large partition!");
+
+        // 路径太长时无法保存高位，报错而不是截断
+        let mut part = UpdatePart::default();
+        part.full_path[..55].fill(b'a');
+        let err = part.set_offset(offset).unwrap_err();
+        assert!(matches!(err, AfptoolError::Layout(_)));
+        part.set_offset(4096).unwrap();
+
+        // RKFW 头用 HI 标记保存更新映像大小的高位
+        let rkfw = temp_dir.path().join("large-rkfw.img");
+        let mut data = create_mock_rkfw();
+        data[0x37..0x39].copy_from_slice(b"HI");
+        data[0x39] = 1;
+        fs::write(&rkfw, &data).unwrap();
+        let low = u32::from_le_bytes([data[0x25], data[0x26], data[0x27], data[0x28]]) as u64;
+        assert_eq!(read_rkfw_info(&rkfw).unwrap().unwrap().update_size, 1 << 32 | low);
+    }
+
+    #[test]
+    fn test_grow_partition() {
+        let temp_dir = TempDir::new().unwrap();
+        let tree = temp_dir.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        create_rkaf_tree(&tree);
+        // userdata 用 "-" 表示占满剩余的 flash
+        let mut package = fs::read_to_string(tree.join("package-file")).unwrap();
+        package.push_str("userdata\tuserdata.img\n");
+        fs::write(tree.join("package-file"), package).unwrap();
+        let mut metadata = fs::read_to_string(tree.join("partition-metadata.txt")).unwrap();
+        metadata.push_str("userdata,userdata.img,-,0x00000100,0x00000000,0x00000800,0x00000000\n");
+        fs::write(tree.join("partition-metadata.txt"), &metadata).unwrap();
+        fs::write(tree.join("userdata.img"), vec![0x11; 3000]).unwrap();
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(&tree, &image, "RK3326", "RK3326").unwrap();
+        let userdata = list_partitions(&image).unwrap().into_iter().find(|part| part.name == "userdata").unwrap();
+        assert_eq!(userdata.flash_size, FLASH_SIZE_GROW);
+        assert!(read_update_header(&image).unwrap().parts[3].grows());
+
+        // 解包保留该标记，重新打包得到相同的映像
+        let out = temp_dir.path().join("out");
+        unpack_file_with(&image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        assert!(fs::read_to_string(out.join("partition-metadata.txt")).unwrap().contains("userdata,userdata.img,0xffffffff,"));
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkaf(&out, &repacked, "RK3326", "RK3326").unwrap();
+        assert_eq!(fs::read(&image).unwrap(), fs::read(&repacked).unwrap());
+
+        // 没有大小上限，更大的数据也可以替换
+        let large = temp_dir.path().join("large.img");
+        fs::write(&large, vec![0x22; 1 << 20]).unwrap();
+        replace_part(&image, "userdata", &large, None).unwrap();
+        assert!(verify_image(&image).unwrap().iter().all(|check| check.passed));
+
+        // 放在它后面的分区与之重叠
+        fs::write(tree.join("partition-metadata.txt"), metadata.replace("boot,boot.img,0x00000020,0x00000010", "boot,boot.img,0x00000020,0x00000200")).unwrap();
+        let err = pack_rkaf(&tree, temp_dir.path().join("bad.img"), "RK3326", "RK3326").unwrap_err();
+        assert!(matches!(err, AfptoolError::Layout(_)));
+    }
+
+    #[test]
+    fn test_timestamp_forms() {
+        assert_eq!(parse_timestamp("1731031994").unwrap(), 1731031994);
+        assert_eq!(parse_timestamp("2024-11-08T12:00:00").unwrap(), 1731067200);
+        assert_eq!(parse_timestamp("2024-11-08 12:00:00").unwrap(), 1731067200);
+        assert_eq!(parse_timestamp("2024-11-08").unwrap(), 1731024000);
+        assert!(parse_timestamp("08/11/2024").is_err());
+
+        // 不给 --timestamp 也没有清单时使用默认时间
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        pack_rkaf(dir, format!("{}/embedded-update.img", dir), "X", "X").unwrap();
+        fs::write(temp_dir.path().join("BOOT"), b"BOOT loader").unwrap();
+        let image = temp_dir.path().join("update.img");
+        let options = RkfwOptions {
+            chip: Some("RK3326".into()),
+            version: Some("1.0.0".into()),
+            code: Some("0x02000000".into()),
+            ..Default::default()
+        };
+        pack_rkfw_with(dir, &image, &options).unwrap();
+        let expected = chrono::DateTime::from_timestamp(default_timestamp().unwrap(), 0).unwrap();
+        let header = read_rkfw_info(&image).unwrap().unwrap();
+        assert!(header.date.starts_with(&expected.format("%Y-").to_string()));
+    }
+
+    #[test]
+    fn test_typical_code() {
+        assert_eq!(chip_default_code(chip_name_to_code("RK3288").unwrap()), Some(0x01030000));
+        assert_eq!(chip_default_code(chip_name_to_code("RK3326").unwrap()), Some(0x02000000));
+        assert_eq!(chip_default_code(0x6b), None);
+
+        // 省略 --code 时按芯片系列填入常用值
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        let dir = temp_dir.path().to_str().unwrap();
+        pack_rkaf(dir, format!("{}/embedded-update.img", dir), "X", "X").unwrap();
+        fs::write(temp_dir.path().join("BOOT"), b"BOOT loader").unwrap();
+        let image = temp_dir.path().join("update.img");
+        let options = RkfwOptions {
+            chip: Some("RK3288".into()),
+            version: Some("1.0.0".into()),
+            timestamp: Some(1731031994),
+            ..Default::default()
+        };
+        pack_rkfw_with(dir, &image, &options).unwrap();
+        assert_eq!(&fs::read(&image).unwrap()[0x0a..0x0e], &0x01030000u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_recursive_unpack() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        // v0 Android 启动镜像：2048 字节页，kernel 3000 字节，ramdisk 100 字节
+        let mut boot = vec![0u8; 6144 + 100];
+        boot[..8].copy_from_slice(b"ANDROID!");
+        boot[8..12].copy_from_slice(&3000u32.to_le_bytes());
+        boot[16..20].copy_from_slice(&100u32.to_le_bytes());
+        boot[36..40].copy_from_slice(&2048u32.to_le_bytes());
+        boot[2048..5048].fill(0x4b);
+        boot[6144..].fill(0x52);
+        fs::write(src.join("boot.img"), &boot).unwrap();
+        let dir = src.to_str().unwrap();
+        pack_rkaf(dir, format!("{}/embedded-update.img", dir), "X", "X").unwrap();
+        fs::write(src.join("BOOT"), b"BOOT loader").unwrap();
+        let image = temp_dir.path().join("update.img");
+        pack_rkfw(dir, &image, "RK3326", "1.0.0", 1731031994, "0x02000000").unwrap();
+
+        // 一条命令展开 RKFW、内嵌 RKAF 和启动镜像
+        let out = temp_dir.path().join("out");
+        let options = UnpackOptions { recursive: true, quiet: true, ..Default::default() };
+        let report = unpack_file_with(&image, &out, &options).unwrap();
+        assert!(out.join("BOOT").exists());
+        assert!(!out.join("embedded-update.img").exists());
+        assert!(out.join("partition-metadata.txt").exists());
+        assert_eq!(fs::read(out.join("boot.img")).unwrap(), boot);
+        assert_eq!(fs::read(out.join("boot.img.d/kernel")).unwrap(), vec![0x4b; 3000]);
+        assert_eq!(fs::read(out.join("boot.img.d/ramdisk")).unwrap(), vec![0x52; 100]);
+        assert!(report.parts.iter().any(|part| part.name == "boot/kernel"));
+        verify_tree(&out).unwrap();
+    }
+
+    #[test]
+    fn test_pack_rkfw_from_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let dir = src.to_str().unwrap();
+        pack_rkaf(dir, format!("{}/embedded-update.img", dir), "Model", "Vendor").unwrap();
+        fs::write(src.join("BOOT"), b"BOOT loader").unwrap();
+        let image = temp_dir.path().join("update.img");
+        pack_rkfw(dir, &image, "RK3326", "1.0.0", 1731031994, "0x02000000").unwrap();
+
+        // 递归解包后不再有 embedded-update.img，pack-rkfw 一步重建出相同的映像
+        let out = temp_dir.path().join("out");
+        unpack_file_with(&image, &out, &UnpackOptions { recursive: true, quiet: true, ..Default::default() }).unwrap();
+        let manifest = FirmwareManifest::load(&out).unwrap().unwrap();
+        assert_eq!(manifest.model.as_deref(), Some("Model"));
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkfw_with(&out, &repacked, &RkfwOptions::default()).unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), fs::read(&image).unwrap());
+        assert!(!temp_dir.path().join("repacked.img.rkaf.tmp").exists());
+    }
+
+    #[test]
+    fn test_desparse_unpack() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let raw: Vec<u8> = (0..3 * 4096u32).map(|i| (i % 253) as u8).collect();
+        fs::write(src.join("vendor.img"), create_sparse_image(&raw)).unwrap();
+        fs::write(src.join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tboot.img\nvendor\tvendor.img\n").unwrap();
+        let mut metadata = fs::OpenOptions::new().append(true).open(src.join("partition-metadata.txt")).unwrap();
+        metadata.write_all(b"vendor,vendor.img,0x00000040,0x00000200,0x00000000,0x00003800,0x00000000\n").unwrap();
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(src.to_str().unwrap(), &image, "RK3326", "RK3326").unwrap();
+
+        // 展开为 raw 镜像：三个 raw 块加一个 don't care 块（补零）
+        let out = temp_dir.path().join("out");
+        unpack_file_with(&image, &out, &UnpackOptions { desparse: true, quiet: true, ..Default::default() }).unwrap();
+        let mut expected = raw.clone();
+        expected.resize(4 * 4096, 0);
+        assert_eq!(fs::read(out.join("vendor.img")).unwrap(), expected);
+        assert_eq!(fs::read(out.join("boot.img")).unwrap(), fs::read(src.join("boot.img")).unwrap());
+        let metadata = fs::read_to_string(out.join("partition-metadata.txt")).unwrap();
+        assert!(metadata.lines().any(|line| line.starts_with("vendor,") && line.ends_with(",desparsed")));
+        verify_tree(&out).unwrap();
+
+        // 展开后的树重新打包：填充大小按 raw 文件重算，而不是沿用稀疏时的值
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkaf(out.to_str().unwrap(), &repacked, "RK3326", "RK3326").unwrap();
+        assert!(verify_image(&repacked).unwrap().iter().all(|check| check.passed));
+        let header = read_update_header(&repacked).unwrap();
+        let vendor = header.parts.iter().find(|part| part.name.starts_with(b"vendor\0")).unwrap();
+        assert_eq!((vendor.part_byte_count, vendor.padded_size), (4 * 4096, 4 * 4096));
+    }
+
+    #[test]
+    fn test_sparse_pack() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        // 1 个数据块、3 个全零块和不足一块的尾部
+        let mut raw: Vec<u8> = (0..4096u32).map(|i| (i % 253) as u8).collect();
+        raw.resize(4 * 4096, 0);
+        raw.extend_from_slice(b"tail");
+        fs::write(src.join("system.img"), &raw).unwrap();
+        fs::write(src.join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tboot.img\nsystem\tsystem.img\n").unwrap();
+        let mut metadata = fs::OpenOptions::new().append(true).open(src.join("partition-metadata.txt")).unwrap();
+        metadata.write_all(b"system,system.img,0x00000040,0x00000200,0x00000000,0x00005000,0x00000000\n").unwrap();
+
+        let image = temp_dir.path().join("update.img");
+        let options = RkafOptions { sparse: vec!["system".into()], ..Default::default() };
+        pack_rkaf_with(src.to_str().unwrap(), &image, "RK3326", "RK3326", &options).unwrap();
+        let parts = list_partitions(&image).unwrap();
+        let system = parts.iter().find(|part| part.name == "system").unwrap();
+        // 头 28 + raw 块 12+4096 + fill 12+4 + 尾块 raw 12+4096
+        assert_eq!(system.byte_count, 28 + 12 + 4096 + 16 + 12 + 4096);
+
+        // 解包时展开，得到补零到整块的原始数据
+        let out = temp_dir.path().join("out");
+        unpack_file_with(&image, &out, &UnpackOptions { desparse: true, quiet: true, ..Default::default() }).unwrap();
+        raw.resize(5 * 4096, 0);
+        assert_eq!(fs::read(out.join("system.img")).unwrap(), raw);
+    }
+
+    #[test]
+    fn test_boot_image_info() {
+        let temp_dir = TempDir::new().unwrap();
+        create_rkaf_tree(temp_dir.path());
+        // v2 启动镜像：kernel、ramdisk 和 dtb，Android 11、2021-08 补丁
+        let mut boot = vec![0u8; 4 * 2048];
+        boot[..8].copy_from_slice(b"ANDROID!");
+        for (at, value) in [(8, 2048u32), (16, 100), (36, 2048), (40, 2), (1648, 10)] {
+            boot[at..at + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        let os_version = ((11u32 << 14) << 11) | (21 << 4) | 8;
+        boot[44..48].copy_from_slice(&os_version.to_le_bytes());
+        boot[48..53].copy_from_slice(b"rk356");
+        boot[64..85].copy_from_slice(b"console=ttyFIQ0 quiet");
+        let path = temp_dir.path().join("boot.img");
+        fs::write(&path, &boot).unwrap();
+
+        let info = boot_image_info(&path).unwrap().unwrap();
+        assert_eq!(info.header_version, 2);
+        assert_eq!(info.section_size("kernel"), 2048);
+        assert_eq!(info.section_size("ramdisk"), 100);
+        assert_eq!(info.sections.last().map(|section| (section.name.as_str(), section.offset)), Some(("dtb", 3 * 2048)));
+        assert_eq!(info.name, "rk356");
+        assert_eq!(info.cmdline, "console=ttyFIQ0 quiet");
+        assert_eq!(info.os_version.as_deref(), Some("11.0.0"));
+        assert_eq!(info.os_patch_level.as_deref(), Some("2021-08"));
+        assert!(boot_image_info(temp_dir.path().join("parameter.txt")).unwrap().is_none());
+
+        // 固件里的 boot 分区也被识别
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path().to_str().unwrap(), &image, "RK3326", "RK3326").unwrap();
+        let boot_images = image_info(&image).unwrap().boot_images;
+        assert_eq!(boot_images.len(), 1);
+        assert_eq!(boot_images[0].part.as_deref(), Some("boot"));
+        assert_eq!(boot_images[0].cmdline, info.cmdline);
+    }
+
+    #[test]
+    fn test_resource_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = ResourceImage {
+            version: 0,
+            table_version: 0,
+            files: vec![
+                ResourceFile { path: "rk-kernel.dtb".into(), data: vec![0xd0, 0x0d, 0xfe, 0xed, 1, 2, 3] },
+                ResourceFile { path: "logo.bmp".into(), data: b"BM".repeat(300) },
+            ],
+        };
+        let bytes = image.to_bytes().unwrap();
+        // 头块、两个表项块，再是按 512 字节对齐的文件数据
+        assert_eq!(&bytes[..4], b"RSCE");
+        assert_eq!(&bytes[512..516], b"ENTR");
+        assert_eq!(bytes.len(), 512 * 3 + 512 + 1024);
+        assert_eq!(ResourceImage::parse(&bytes).unwrap(), image);
+
+        // 打进固件的 resource 分区，解包、替换 logo 后再打包
+        create_rkaf_tree(temp_dir.path());
+        fs::write(temp_dir.path().join("resource.img"), &bytes).unwrap();
+        fs::write(temp_dir.path().join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tboot.img\nresource\tresource.img\n").unwrap();
+        let mut metadata = fs::OpenOptions::new().append(true).open(temp_dir.path().join("partition-metadata.txt")).unwrap();
+        metadata.write_all(b"resource,resource.img,0x00000040,0x00000100,0x00000000,0x00000a00,0x00000000\n").unwrap();
+        let firmware = temp_dir.path().join("update.img");
+        pack_rkaf(temp_dir.path().to_str().unwrap(), &firmware, "RK3326", "RK3326").unwrap();
+        let out = temp_dir.path().join("res");
+        assert_eq!(unpack_resource(&firmware, &out).unwrap(), image);
+        assert_eq!(fs::read(out.join("logo.bmp")).unwrap(), image.files[1].data);
+        let packed = temp_dir.path().join("resource-new.img");
+        pack_resource(&out, &packed).unwrap();
+        assert_eq!(fs::read(&packed).unwrap(), bytes);
+        fs::write(out.join("logo.bmp"), b"new logo").unwrap();
+        let rebuilt = pack_resource(&out, &packed).unwrap();
+        assert_eq!(rebuilt.files[1].data, b"new logo");
+        assert_eq!(ResourceImage::parse(&fs::read(&packed).unwrap()).unwrap(), rebuilt);
+    }
+
+    #[test]
+    fn test_dedupe_identical_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        // recovery 与 boot 内容相同但路径不同
+        fs::copy(src.join("boot.img"), src.join("recovery.img")).unwrap();
+        fs::write(src.join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt\nboot\tboot.img\nrecovery\trecovery.img\n").unwrap();
+        let mut metadata = fs::OpenOptions::new().append(true).open(src.join("partition-metadata.txt")).unwrap();
+        metadata.write_all(b"recovery,recovery.img,0x00000020,0x00000030,0x00000000,0x00001800,0x00000000\n").unwrap();
+
+        let plain = temp_dir.path().join("plain.img");
+        pack_rkaf_with(src.to_str().unwrap(), &plain, "RK3326", "RK3326", &RkafOptions::default()).unwrap();
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf_with(src.to_str().unwrap(), &image, "RK3326", "RK3326", &RkafOptions { dedupe: true, ..Default::default() }).unwrap();
+
+        let parts = list_partitions(&image).unwrap();
+        let offset = |name: &str| parts.iter().find(|part| part.name == name).unwrap().file_offset;
+        assert_eq!(offset("boot"), offset("recovery"));
+        assert!(fs::metadata(&image).unwrap().len() < fs::metadata(&plain).unwrap().len());
+
+        // 解包后两个文件内容仍然一致
+        let out = temp_dir.path().join("out");
+        unpack_file_with(&image, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        assert_eq!(fs::read(out.join("recovery.img")).unwrap(), fs::read(src.join("boot.img")).unwrap());
+    }
+
+    #[test]
+    fn test_compressed_unpack() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf_with(src.to_str().unwrap(), &image, "RK3326", "RK3326", &RkafOptions::default()).unwrap();
+
+        let out = temp_dir.path().join("out");
+        let options = UnpackOptions { compress: Some(Compression::Gzip), quiet: true, ..Default::default() };
+        let report = unpack_file_with(&image, &out, &options).unwrap();
+        // package-file 保持原样，其余分区写成 .gz
+        assert!(out.join("package-file").exists());
+        assert!(!out.join("boot.img").exists());
+        let mut boot = Vec::new();
+        flate2::read::GzDecoder::new(fs::File::open(out.join("boot.img.gz")).unwrap()).read_to_end(&mut boot).unwrap();
+        assert_eq!(boot, fs::read(src.join("boot.img")).unwrap());
+        let part = report.parts.iter().find(|part| part.name == "boot").unwrap();
+        assert_eq!(part.path, "boot.img.gz");
+        assert_eq!(part.md5, format!("{:x}", md5::compute(&boot)));
+        // checksums.md5 记录压缩文件本身
+        verify_tree(&out).unwrap();
+    }
+
+    #[test]
+    fn test_pack_compressed_inputs() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let image = temp_dir.path().join("update.img");
+        pack_rkaf_with(src.to_str().unwrap(), &image, "RK3326", "RK3326", &RkafOptions::default()).unwrap();
+
+        // 直接从 unpack --compress 的结果重新打包，得到相同的映像
+        let out = temp_dir.path().join("out");
+        unpack_file_with(&image, &out, &UnpackOptions { compress: Some(Compression::Gzip), quiet: true, ..Default::default() }).unwrap();
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkaf_with(out.to_str().unwrap(), &repacked, "RK3326", "RK3326", &RkafOptions::default()).unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), fs::read(&image).unwrap());
+
+        // 加 decompress 后 package-file 可直接引用 .gz，映像中记录去掉后缀的路径
+        fs::write(out.join("package-file"), "package-file\tpackage-file\nparameter\tparameter.txt.gz\nboot\tboot.img.gz\n").unwrap();
+        pack_rkaf_with(out.to_str().unwrap(), &repacked, "RK3326", "RK3326", &RkafOptions { decompress: true, ..Default::default() }).unwrap();
+        let parts = list_partitions(&repacked).unwrap();
+        let boot = parts.iter().find(|part| part.name == "boot").unwrap();
+        assert_eq!((boot.full_path.as_str(), boot.byte_count), ("boot.img", 5000));
+
+        // 增量打包：缓存记录了 .gz 原样存入，改用 decompress 后不能复用旧数据
+        let incremental = temp_dir.path().join("incremental.img");
+        let options = RkafOptions { incremental: true, ..Default::default() };
+        pack_rkaf_with(out.to_str().unwrap(), &incremental, "RK3326", "RK3326", &options).unwrap();
+        let options = RkafOptions { incremental: true, decompress: true, ..Default::default() };
+        pack_rkaf_with(out.to_str().unwrap(), &incremental, "RK3326", "RK3326", &options).unwrap();
+        assert_eq!(fs::read(&incremental).unwrap(), fs::read(&repacked).unwrap());
+    }
+
+    #[test]
+    fn test_unpack_batch() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        create_rkaf_tree(&src);
+        let firmwares = temp_dir.path().join("firmwares");
+        fs::create_dir(&firmwares).unwrap();
+        pack_rkaf(src.to_str().unwrap(), firmwares.join("a.img").to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        fs::copy(firmwares.join("a.img"), firmwares.join("b.img")).unwrap();
+        fs::copy(firmwares.join("a.img"), firmwares.join("b.bin")).unwrap();
+        // 不是固件的文件被忽略，损坏的映像记录错误但不影响其他映像
+        fs::write(firmwares.join("notes.txt"), "release notes").unwrap();
+        let mut broken = fs::read(firmwares.join("a.img")).unwrap();
+        broken.truncate(4096);
+        fs::write(firmwares.join("broken.img"), broken).unwrap();
+
+        let out = temp_dir.path().join("out");
+        let entries = unpack_batch(&firmwares, &out, &UnpackOptions { quiet: true, ..Default::default() }).unwrap();
+        let inputs: Vec<&str> = entries.iter().map(|entry| entry.input.as_str()).collect();
+        assert_eq!(inputs, ["a.img", "b.bin", "b.img", "broken.img"]);
+        assert!(entries[3].report.is_none() && entries[3].error.is_some());
+        assert!(!out.join("broken").exists());
+
+        // 同名不同扩展名的映像各自保留扩展名作为目录名
+        for dir in ["a", "b.bin", "b.img"] {
+            assert_eq!(fs::read(out.join(dir).join("boot.img")).unwrap(), fs::read(src.join("boot.img")).unwrap());
+        }
+        assert_eq!(entries[0].report.as_ref().unwrap().parts.len(), 3);
+    }
 }